@@ -11,38 +11,61 @@
 extern crate crossterm;
 extern crate ratatui;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fmt;
 use std::fs::File;
-use std::io::{stdout, Write};
+use std::io::{stdout, Read, Seek, SeekFrom, Write};
 use std::ops::Sub;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs, io};
 
-use crossterm::event::{poll, Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers};
+use crossterm::event::{
+    poll, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyEventKind,
+    KeyEventState, KeyModifiers,
+};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, Clear};
 use crossterm::ExecutableCommand;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style };
-use ratatui::text::Text;
+use ratatui::text::{Line, Text};
 use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, Paragraph, Wrap};
 use ratatui::Frame;
-use ratatui_image::picker::Picker;
-use ratatui_image::StatefulImage;
+use ratatui_image::picker::{Picker, ProtocolType};
+use ratatui_image::{protocol::StatefulProtocol, StatefulImage};
 use rodio::{OutputStream, Sink, Source};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 
 use audiotags::{types::Album, Tag};
-use dirs;
 use mp3_metadata::read_from_file;
 use textwrap::wrap;
 use uuid::Uuid;
 use image::{self, load_from_memory_with_format, DynamicImage, ImageBuffer, ImageFormat, Rgba};
+use rand::seq::SliceRandom;
 
 /// Supported music file formats.
 const MUSIC_FORMATS: [&str; 4] = ["mp3", "wav", "flac", "aac"];
 
+/// Number of past searches kept for recall.
+const SEARCH_HISTORY_LIMIT: usize = 50;
+
+/// How long a status message stays visible.
+const STATUS_DURATION: Duration = Duration::from_secs(5);
+
+/// How long to wait for input while a song is playing, so the progress bar moves smoothly,
+/// when `progress_interval_ms` isn't set.
+const PLAYING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shortest and longest `progress_interval_ms` can be, in milliseconds.
+const PROGRESS_INTERVAL_RANGE: (u64, u64) = (20, 1000);
+
+/// How long to wait for input while nothing is playing.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Represents a song with metadata.
 #[derive(Clone)]
 struct Song {
@@ -51,14 +74,30 @@ struct Song {
     title: String,
     /// Artist of the song.
     artist: String,
-    /// Cover art of the song/album.
-    cover: Option<DynamicImage>,
+    /// Title folded with [`fold_case_and_accents`], used for searching and sorting. The folded
+    /// fields are derived by [`Song::update_search_keys`], which has to run whenever the title,
+    /// artist or album changes.
+    title_lower: String,
+    /// Artist folded with [`fold_case_and_accents`], used for searching and sorting.
+    artist_lower: String,
+    /// Album name folded with [`fold_case_and_accents`], used for searching.
+    album_lower: String,
+    /// Cover art of the song/album, shared by the songs of a folder using the same cover file.
+    cover: Option<Arc<DynamicImage>>,
     /// File path to the song.
     path: PathBuf,
     /// Album name of the song.
     album: String,
+    /// Artist credited for the whole album, like "Various Artists" on a compilation.
+    album_artist: Option<String>,
     /// Duration of the song in seconds.
     duration: f64,
+    /// Unsynchronized lyrics embedded in the file's tags.
+    lyrics: Option<String>,
+    /// What a radio stream says is on air, from its ICY metadata.
+    stream_title: Arc<Mutex<Option<String>>>,
+    /// Volume factor evening out loudness, applied while the song plays; 1 until analyzed.
+    gain: Arc<Mutex<f32>>,
     /// Indicates if the song is currently playing.
     is_playing: bool,
 }
@@ -66,6 +105,8 @@ struct Song {
 impl Song {
     /// Creates a new `Song` instance.
     ///
+    /// The title, artist and album are normalized with [`normalize_tag`].
+    ///
     /// # Arguments
     /// * `title` - The title of the song.
     /// * `artist` - The artist of the song.
@@ -73,1594 +114,11341 @@ impl Song {
     /// * `album` - The album name of the song.
     /// * `duration` - The duration of the song in seconds.
     fn new(title: String, artist: String, cover: Option<DynamicImage>, path: PathBuf, album: String, duration: f64) -> Self {
-        Song {
-            id: Uuid::new_v5(&Uuid::NAMESPACE_DNS, path.to_str().unwrap().as_bytes()),
+        let title = normalize_tag(&title);
+        let artist = normalize_tag(&artist);
+        let album = normalize_tag(&album);
+        let mut song = Song {
+            id: song_id(&path),
+            title_lower: String::new(),
+            artist_lower: String::new(),
+            album_lower: String::new(),
             title,
             artist,
-            cover,
+            cover: cover.map(Arc::new),
             path,
             album,
+            album_artist: None,
             duration,
+            lyrics: None,
+            stream_title: Arc::new(Mutex::new(None)),
+            gain: Arc::new(Mutex::new(1.0)),
             is_playing: false,
+        };
+        song.update_search_keys();
+        song
+    }
+
+    /// Recomputes the folded title, artist and album used for searching and sorting.
+    fn update_search_keys(&mut self) {
+        self.title_lower = fold_case_and_accents(&self.title);
+        self.artist_lower = fold_case_and_accents(&self.artist);
+        self.album_lower = fold_case_and_accents(&self.album);
+    }
+
+    /// Returns the album artist, or the artist for songs without one; songs of an album
+    /// share it even when each has a different artist.
+    fn grouping_artist(&self) -> &str {
+        self.album_artist.as_deref().unwrap_or(&self.artist)
+    }
+
+    /// Replaces the title, keeping the copy used for searching and sorting in step.
+    ///
+    /// # Arguments
+    /// * `title` - The new title, normalized with [`normalize_tag`].
+    fn set_title(&mut self, title: &str) {
+        self.title = normalize_tag(title);
+        self.update_search_keys();
+    }
+
+    /// Opens the file or stream and sets up its decoder, which can take a moment for large
+    /// files on slow storage and for streams.
+    ///
+    /// # Arguments
+    /// * `fade_in` - How long the song takes to fade in from silence.
+    /// * `mono` - Whether to mix the channels down to mono.
+    /// * `balance` - The left/right balance to follow while playing.
+    fn open(
+        &self,
+        fade_in: Duration,
+        mono: bool,
+        balance: Arc<Mutex<f32>>,
+    ) -> io::Result<SongSource> {
+        if let Some(url) = stream_url(&self.path) {
+            *self.stream_title.lock().unwrap() = None;
+            let source = open_stream(url, self.stream_title.clone())?;
+            Ok(Box::new(with_balance(
+                with_gain(
+                    Downmix::new(source.fade_in(fade_in), mono),
+                    self.gain.clone(),
+                ),
+                balance,
+            )))
+        } else {
+            let file = fs::File::open(&self.path)?;
+            let source = rodio::Decoder::new(io::BufReader::new(file)).map_err(io::Error::other)?;
+            Ok(Box::new(with_balance(
+                with_gain(
+                    Downmix::new(source.fade_in(fade_in), mono),
+                    self.gain.clone(),
+                ),
+                balance,
+            )))
         }
     }
 
+    /// Opens the song's file for playing from a position, without fading in.
+    ///
+    /// Decoding up to the position can take a while in long songs.
+    ///
+    /// # Arguments
+    /// * `position` - Where to start, from the beginning of the song.
+    /// * `mono` - Whether to mix the channels down to mono.
+    /// * `balance` - The left/right balance to follow while playing.
+    fn open_at(
+        &self,
+        position: Duration,
+        mono: bool,
+        balance: Arc<Mutex<f32>>,
+    ) -> io::Result<SongSource> {
+        let file = fs::File::open(&self.path)?;
+        let source = rodio::Decoder::new(io::BufReader::new(file)).map_err(io::Error::other)?;
+        Ok(Box::new(with_balance(
+            with_gain(
+                Downmix::new(source.skip_duration(position), mono),
+                self.gain.clone(),
+            ),
+            balance,
+        )))
+    }
+
     /// Plays the song using the provided `Sink`.
     ///
+    /// Fails without queueing anything if the file or stream can't be opened or decoded.
+    ///
     /// # Arguments
     /// * `sink` - The `Sink` to play the song through.
-    fn play(&self, sink: &Arc<Mutex<Sink>>) {
-        let file = fs::File::open(&self.path).unwrap();
-        let source = rodio::Decoder::new(io::BufReader::new(file)).unwrap();
-        sink.lock().unwrap().append(source);
-        sink.lock().unwrap().play();
+    /// * `fade_in` - How long the song takes to fade in from silence.
+    /// * `mono` - Whether to mix the channels down to mono.
+    /// * `balance` - The left/right balance to follow while playing.
+    fn play(
+        &self,
+        sink: &Arc<Mutex<Sink>>,
+        fade_in: Duration,
+        mono: bool,
+        balance: Arc<Mutex<f32>>,
+    ) -> io::Result<()> {
+        let source = self.open(fade_in, mono, balance)?;
+        let sink = sink.lock().unwrap();
+        sink.append(source);
+        sink.play();
+        Ok(())
     }
 }
 
-/// Enum representing the criteria for searching songs.
-enum SearchCriteria {
-    Title,
-    Artist,
-    Album,
-}
+/// The decoded audio of a song, ready to be played.
+type SongSource = Box<dyn Source<Item = i16> + Send>;
 
-/// Enum representing the criteria for sorting songs.
-#[derive(PartialEq, Eq, Debug)]
-enum SortCriteria {
-    Title,
-    Artist,
-    Duration,
+/// How long starting a song waits for it to be opened before showing it as loading.
+const LOADING_WAIT: Duration = Duration::from_millis(100);
+
+/// Frames of the spinner shown while a song is loading.
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// A song being opened on a background thread.
+struct LoadingSong {
+    id: Uuid,
+    started: Instant,
+    receiver: mpsc::Receiver<io::Result<SongSource>>,
+    /// Where to start once the song is open, in seconds, when it was seeked while loading.
+    start_at: Option<f64>,
 }
 
-impl SortCriteria {
-    /// Returns the next sorting criteria in the sequence.
-    fn next(&self) -> SortCriteria {
-        match self {
-            SortCriteria::Title => SortCriteria::Artist,
-            SortCriteria::Artist => SortCriteria::Duration,
-            SortCriteria::Duration => SortCriteria::Title,
-        }
-    }
+/// A seek in the current song, decoding up to the new position on a background thread while
+/// the song keeps playing where it was.
+struct PendingSeek {
+    id: Uuid,
+    /// The new position, in seconds from the start of the song.
+    target: f64,
+    started: Instant,
+    receiver: mpsc::Receiver<io::Result<SongSource>>,
 }
 
-impl ToString for SortCriteria {
-    /// Converts the sorting criteria to a string representation.
-    fn to_string(&self) -> String {
-        match self {
-            SortCriteria::Title => "Title".to_string(),
-            SortCriteria::Artist => "Artist".to_string(),
-            SortCriteria::Duration => "Duration".to_string(),
-        }
-    }
+/// Applies a song's loudness correction to its audio, following changes to it while playing.
+///
+/// # Arguments
+/// * `source` - The decoded audio.
+/// * `gain` - The song's volume factor.
+fn with_gain<S>(source: S, gain: Arc<Mutex<f32>>) -> impl Source<Item = i16> + Send
+where
+    S: Source<Item = i16> + Send,
+{
+    let initial_gain = *gain.lock().unwrap();
+    source
+        .amplify(initial_gain)
+        .periodic_access(Duration::from_millis(200), move |source| {
+            source.set_factor(*gain.lock().unwrap())
+        })
 }
 
-struct PopupState {
-    visible: bool,
+/// Mixes a source down to mono by averaging the channels of each frame. The channel count is
+/// kept, so the average comes out of every speaker.
+struct Downmix<S> {
+    source: S,
+    /// Whether to mix down; when off the source plays unchanged.
+    enabled: bool,
+    /// The averaged frame being played.
+    frame: Vec<i16>,
+    /// Position of the next sample in `frame`.
+    position: usize,
 }
 
-impl PopupState {
-    fn toggle(&mut self) {
-        self.visible = !self.visible;
+impl<S: Source<Item = i16>> Downmix<S> {
+    /// Wraps a source, mixing it down to mono if `enabled`.
+    ///
+    /// # Arguments
+    /// * `source` - The decoded audio.
+    /// * `enabled` - Whether to mix down.
+    fn new(source: S, enabled: bool) -> Downmix<S> {
+        Downmix {
+            source,
+            enabled,
+            frame: Vec::new(),
+            position: 0,
+        }
     }
 }
 
-/// The main application struct.
-pub struct MyApp {
-    songs: Box<Vec<Song>>, // List of all songs
-    filtered_songs: Vec<Song>,
-    sink: Arc<Mutex<Sink>>,
-    selected_song_id: Option<Uuid>, // Index of the currently selected song
-    currently_playing_song: Option<Uuid>, // Index of the currently playing song
-    search_criteria: SearchCriteria, // Criteria to filter/search songs
-    sort_criteria: SortCriteria,    // Criteria to sort songs
-    hint_popup_state: PopupState,   // Controls the visibility of popups
-    playlist_input_popup: PopupState,
-    selected_playlist_index: usize,
-    playlist_name_input: String, // Input buffer for the playlist name
-    playlists: BTreeMap<String, Vec<Uuid>>, // Playlists with song indices
-    search_text: String,
-    previous_volume: f32,
-    list_offset: usize,
-    playlist_list_offset: usize,
-    paused_time: Option<Instant>,
-    chosen_song_ids: Vec<Uuid>,
-    song_time: Option<Instant>,
-}
+impl<S: Source<Item = i16>> Iterator for Downmix<S> {
+    type Item = i16;
 
-impl MyApp {
-    // Initialize a new MyApp instance with default values
-    pub fn new() -> MyApp {
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        MyApp {
-            songs: Box::new(Vec::new()),
-            filtered_songs: Vec::new(),
-            sink: Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap())),
-            selected_song_id: None,
-            currently_playing_song: None,
-            search_criteria: SearchCriteria::Title,
-            sort_criteria: SortCriteria::Title,
-            selected_playlist_index: 0,
-            hint_popup_state: PopupState { visible: false },
-            playlist_input_popup: PopupState { visible: false },
-            playlist_name_input: String::new(),
-            playlists: BTreeMap::new(),
-            search_text: String::new(),
-            previous_volume: 0.0,
-            list_offset: 0,
-            playlist_list_offset: 0,
-            paused_time: None,
-            chosen_song_ids: vec![],
-            song_time: None,
+    fn next(&mut self) -> Option<i16> {
+        if !self.enabled {
+            return self.source.next();
+        }
+        if self.position == self.frame.len() {
+            let channels = self.source.channels().max(1) as usize;
+            self.frame.clear();
+            self.frame.extend(self.source.by_ref().take(channels));
+            if self.frame.is_empty() {
+                return None;
+            }
+            let sum: i32 = self.frame.iter().map(|&sample| i32::from(sample)).sum();
+            let average = (sum / self.frame.len() as i32) as i16;
+            self.frame.fill(average);
+            self.position = 0;
         }
+        self.position += 1;
+        Some(self.frame[self.position - 1])
     }
+}
 
-    // Function to load songs into the app
-    pub fn load_songs(&mut self) {
-        self.songs = Box::new(scan_folder_for_music());
-        let ids: Vec<Uuid> = self.songs.iter().map(|song| song.id).collect();
-        self.playlists.insert("All Songs".to_string(), ids);
-        self.sort_songs(); // Sort based on current criteria after loading
+impl<S: Source<Item = i16>> Source for Downmix<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        // Samples already taken from the source into `frame` are still to be played
+        let buffered = self.frame.len() - self.position;
+        self.source.current_frame_len().map(|len| len + buffered)
     }
 
-    // Function to handle song selection
-    pub fn select_song(&mut self, index: Uuid) {
-        self.selected_song_id = Some(index);
+    fn channels(&self) -> u16 {
+        self.source.channels()
     }
 
-    fn find_song_by_id(&mut self, id: Uuid) -> Option<&mut Song> {
-        self.songs.iter_mut().find(|song| song.id == id)
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
     }
 
-    // Function to play a song
-    pub fn play_song(&mut self) {
-        if let Some(index) = self.selected_song_id {
-            self.currently_playing_song = Some(index);
-            let song = self.find_song_by_id(index).unwrap().clone();
-            song.play(&self.sink);
-            self.find_song_by_id(index).unwrap().is_playing = true;
-        }
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
     }
+}
 
-    // Function to stop the current song
-    pub fn stop_song(&mut self) {
-        if let Some(index) = self.currently_playing_song {
-            self.songs[index.as_u128() as usize].is_playing = false;
-            self.currently_playing_song = None;
+/// Turns down the left or right channel of a source to shift the balance to the other side.
+struct Balance<S> {
+    source: S,
+    /// Volume factors of the left and right channels.
+    gains: [f32; 2],
+    /// Channel of the next sample within its frame.
+    channel: usize,
+}
+
+impl<S: Source<Item = i16>> Balance<S> {
+    /// Sets the balance, from -1 (left only) through 0 (centered) to 1 (right only).
+    ///
+    /// # Arguments
+    /// * `balance` - The new balance.
+    fn set_balance(&mut self, balance: f32) {
+        let balance = balance.clamp(-1.0, 1.0);
+        self.gains = [1.0 - balance.max(0.0), 1.0 + balance.min(0.0)];
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Balance<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.source.next()?;
+        let channels = self.source.channels().max(1) as usize;
+        let channel = self.channel;
+        self.channel = (channel + 1) % channels;
+        // Mono sources play the same on both sides, and further channels are left alone
+        match self.gains.get(channel) {
+            Some(gain) if channels > 1 => Some((f32::from(sample) * gain) as i16),
+            _ => Some(sample),
         }
     }
+}
 
-    // Function to toggle popup visibility
-    pub fn toggle_popup(&mut self) {
-        self.hint_popup_state.toggle();
+impl<S: Source<Item = i16>> Source for Balance<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
     }
 
-    // Function to change sorting criteria
-    fn set_sort_criteria(&mut self, criteria: SortCriteria) {
-        self.sort_criteria = criteria;
-        self.sort_songs(); // Re-sort the songs based on new criteria
+    fn channels(&self) -> u16 {
+        self.source.channels()
     }
 
-    // Sort the list of songs based on the current sort criteria
-    fn sort_songs(&mut self) {
-        sort_songs(&mut self.songs, &self.sort_criteria);
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
     }
 
-    /// Saves the current playlists to a file.
-    ///
-    /// # Returns
-    /// A `Result` indicating success or failure.
-    fn save_playlist(&self) -> std::io::Result<()> {
-        let serialized = serde_json::to_string(&self.playlists)?;
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
 
-        if let Some(roaming_dir) = dirs::config_local_dir() {
-            let myapp_dir: PathBuf = roaming_dir.join("cli-rhythm");
-            fs::create_dir_all(&myapp_dir)?;
+/// Applies the left/right balance to audio, following changes to it while playing.
+///
+/// # Arguments
+/// * `source` - The decoded audio.
+/// * `balance` - The balance, from -1 (left only) to 1 (right only).
+fn with_balance<S>(source: S, balance: Arc<Mutex<f32>>) -> impl Source<Item = i16> + Send
+where
+    S: Source<Item = i16> + Send,
+{
+    let mut balanced = Balance {
+        source,
+        gains: [1.0; 2],
+        channel: 0,
+    };
+    balanced.set_balance(*balance.lock().unwrap());
+    balanced.periodic_access(Duration::from_millis(200), move |source| {
+        source.set_balance(*balance.lock().unwrap())
+    })
+}
 
-            let playlist_file_path = myapp_dir.join("data.json");
+/// A volume ramp the playing audio follows, so pausing and stopping fade out on the audio
+/// thread rather than holding up the interface.
+#[derive(Clone, Copy, Debug)]
+struct Fade {
+    /// Volume factor at the start of the ramp.
+    from: f32,
+    /// Volume factor at the end of the ramp.
+    to: f32,
+    started: Instant,
+    duration: Duration,
+}
 
-            let mut file = File::create(playlist_file_path)?;
-            file.write_all(serialized.as_bytes())?;
+impl Fade {
+    /// A fade that stays at `level`.
+    ///
+    /// # Arguments
+    /// * `level` - The volume factor, from 0 to 1.
+    fn steady(level: f32) -> Fade {
+        Fade {
+            from: level,
+            to: level,
+            started: Instant::now(),
+            duration: Duration::ZERO,
         }
-
-        Ok(())
     }
 
-    /// Loads playlists from a file.
+    /// Starts a new ramp from wherever this one got to.
     ///
     /// # Arguments
-    /// * `filepath` - The path to the file containing the playlists.
-    ///
-    /// # Returns
-    /// A `Result` indicating success or failure.
-    pub fn load_playlists(&mut self, filepath: &str) -> std::io::Result<()> {
-        let file = File::open(filepath)?;
-        let playlists: BTreeMap<String, Vec<Uuid>> = serde_json::from_reader(file)?;
-        self.playlists = playlists;
-        Ok(())
+    /// * `to` - The volume factor to end at.
+    /// * `duration` - How long the ramp takes.
+    fn toward(&self, to: f32, duration: Duration) -> Fade {
+        Fade {
+            from: self.level(),
+            to,
+            started: Instant::now(),
+            duration,
+        }
+    }
+
+    /// Returns the volume factor at this moment.
+    fn level(&self) -> f32 {
+        if self.finished() {
+            return self.to;
+        }
+        let progress = self.started.elapsed().as_secs_f32() / self.duration.as_secs_f32();
+        self.from + (self.to - self.from) * progress
+    }
+
+    /// Returns whether the ramp has reached its end.
+    fn finished(&self) -> bool {
+        self.started.elapsed() >= self.duration
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize terminal
-    enable_raw_mode()?;
-    let mut terminal = ratatui::init();
-    let picker = Picker::from_fontsize((7, 14));
+/// What happens to playback once a fade out ends.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum FadeEnd {
+    Pause,
+    Stop,
+}
 
-    stdout().execute(Clear(crossterm::terminal::ClearType::All))?;
+/// Applies the shared fade to audio, following it while playing.
+///
+/// # Arguments
+/// * `source` - The decoded audio.
+/// * `fade` - The fade to follow.
+fn with_fade<S>(source: S, fade: Arc<Mutex<Fade>>) -> impl Source<Item = i16> + Send
+where
+    S: Source<Item = i16> + Send,
+{
+    let initial_level = fade.lock().unwrap().level();
+    source
+        .amplify(initial_level)
+        .periodic_access(FADE_STEP, move |source| {
+            source.set_factor(fade.lock().unwrap().level())
+        })
+}
 
-    let mut myapp = MyApp::new();
-    match myapp.load_playlists(
-        dirs::config_local_dir()
-            .unwrap()
-            .join("cli-rhythm")
-            .join("data.json")
-            .to_str()
-            .unwrap(),
-    ) {
-        Ok(_) => {}
-        Err(_) => {}
-    }
-    myapp.load_songs();
+/// Songs without a known duration that end sooner than this after starting are skipped.
+const MIN_PLAY_TIME: Duration = Duration::from_secs(1);
 
-    let mut visible_song_count: usize = 0;
-    let mut visible_playlist_count: usize = 0;
+/// How many seconds past its duration a song is taken as finished even though the sink
+/// hasn't played it out, so a sink that never drains doesn't hold playback up.
+const END_GRACE: f64 = 2.0;
 
-    sort_songs(&mut myapp.songs, &myapp.sort_criteria);
+/// Songs skipped in a row before playback stops instead.
+const MAX_SKIPPED_SONGS: usize = 10;
 
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap()));
+/// How long after the playlists change they are saved.
+const PLAYLIST_SAVE_DELAY: Duration = Duration::from_secs(2);
 
-    // Run event loop
-    loop {
-        let search_bar_title = match myapp.search_criteria {
-            SearchCriteria::Title => "Search by Title",
-            SearchCriteria::Artist => "Search by Artist",
-            SearchCriteria::Album => "Search by Album",
-        };
+/// How often the position of a long song is saved while it plays.
+const POSITION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
 
-        // Render search bar
-        let search_bar = Paragraph::new(Text::raw(format!("{}", myapp.search_text)))
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title(search_bar_title),
-            )
-            .style(Style::default().fg(Color::White));
+/// Positions this close to the start or end of a long song, in seconds, aren't resumed.
+const RESUME_MARGIN: f64 = 10.0;
 
-        let playlist_name = match myapp.playlists.keys().nth(myapp.selected_playlist_index) {
-            Some(name) => name,
-            None => &String::new(),
-        };
+/// How long songs fade in and out when `fade_ms` isn't set.
+const DEFAULT_FADE: Duration = Duration::from_millis(200);
 
-        let playlist_songs = match myapp.playlists.get(playlist_name) {
-            Some(songs) => songs,
-            None => &vec![],
-        };
+/// Longest `fade_ms` can make a fade.
+const MAX_FADE: Duration = Duration::from_secs(5);
 
-        // Filter songs based on search text
-        myapp.filtered_songs = myapp
-            .songs
-            .iter()
-            .filter(|s| match myapp.search_criteria {
-                SearchCriteria::Title => s
-                    .title
-                    .to_lowercase()
-                    .contains(&myapp.search_text.to_lowercase()),
-                SearchCriteria::Artist => s
-                    .artist
-                    .to_lowercase()
-                    .contains(&myapp.search_text.to_lowercase()),
-                SearchCriteria::Album => s
-                    .album
-                    .to_lowercase()
-                    .contains(&myapp.search_text.to_lowercase()),
-            })
-            .filter(|song| playlist_songs.contains(&song.id))
-            .cloned()
-            .collect();
+/// How often the playing audio catches up with a fade in progress.
+const FADE_STEP: Duration = Duration::from_millis(5);
 
-        if let Some(selected_id) = myapp.selected_song_id {
-            if !myapp
-                .filtered_songs
-                .iter()
-                .any(|song| song.id == selected_id)
-            {
-                if let Some(first_song) = myapp.filtered_songs.first() {
-                    myapp.selected_song_id = Some(first_song.id);
-                    myapp.list_offset = 0;
-                } else {
-                    myapp.selected_song_id = None;
-                }
-            }
-        } else if !myapp.filtered_songs.is_empty() {
-            if let Some(first_song) = myapp.filtered_songs.first() {
-                myapp.selected_song_id = Some(first_song.id);
-                myapp.list_offset = 0;
-            }
-        };
+/// How many seconds into a song going back restarts it, when `restart_after_seconds` isn't set.
+const DEFAULT_RESTART_AFTER: f64 = 3.0;
 
-        let selected_song = match myapp.selected_song_id {
-            Some(index) => myapp.find_song_by_id(index),
-            None => None,
-        };
+/// What's written to the now-playing file when `now_playing_format` isn't set.
+const DEFAULT_NOW_PLAYING_FORMAT: &str = "{artist} - {title}";
 
-        let selected_song_details = if let Some(song) = selected_song {
-            let contents = format!(
-                "Artist: {}\nSong: {}\nAlbum: {}\nDuration: {:02}:{:02}",
-                song.artist,
-                song.title,
-                song.album,
-                (song.duration / 60.0).floor(),
-                (song.duration % 60.0).round()
-            );
-            let wrapped_details = wrap(&contents, 29);
+/// Share of the screen width, in percent, the song list takes in the column layout when
+/// `song_list_width` isn't set.
+const DEFAULT_SONG_LIST_WIDTH: u16 = 60;
 
-            wrapped_details.join("\n")
-        } else {
-            "No song selected".to_string()
-        };
+/// Narrowest and widest the song list can be made, in percent of the screen width.
+const SONG_LIST_WIDTH_RANGE: (u16, u16) = (30, 90);
 
-        let playing_song_details = if let Some(song_id) = myapp.currently_playing_song {
-            let song = myapp.find_song_by_id(song_id).unwrap();
-            let contents = format!(
-                "Artist: {}\nSong: {}\nAlbum: {}\nDuration: {:02}:{:02}",
-                song.artist,
-                song.title,
-                song.album,
-                (song.duration / 60.0).floor(),
-                (song.duration % 60.0).round()
-            );
-            let wrapped_details = wrap(&contents, 29);
+/// How much Alt+Left/Right narrow or widen the song list, in percent of the screen width.
+const SONG_LIST_WIDTH_STEP: u16 = 5;
 
-            wrapped_details.join("\n")
-        } else {
-            "No song playing".to_string()
-        };
+/// Loudness songs are evened out to with `normalize_loudness`, in LUFS.
+const TARGET_LOUDNESS: f64 = -18.0;
 
-        let selected_song_info = Paragraph::new(selected_song_details)
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title("Selected Song"),
-            )
-            .style(Style::default().fg(Color::White));
+/// Measures the integrated EBU R128 loudness of a song, in LUFS. Returns `None` for files
+/// that can't be decoded or are silent.
+///
+/// # Arguments
+/// * `path` - The path of the audio file.
+fn measure_loudness(path: &Path) -> Option<f64> {
+    let file = File::open(path).ok()?;
+    let decoder = rodio::Decoder::new(io::BufReader::new(file)).ok()?;
+    let mut meter = ebur128::EbuR128::new(
+        u32::from(decoder.channels()),
+        decoder.sample_rate(),
+        ebur128::Mode::I,
+    )
+    .ok()?;
+    let channels = usize::from(decoder.channels());
+    let mut samples: Vec<i16> = Vec::with_capacity(channels * 4096);
+    for sample in decoder {
+        samples.push(sample);
+        if samples.len() == samples.capacity() {
+            meter.add_frames_i16(&samples).ok()?;
+            samples.clear();
+        }
+    }
+    let whole_frames = samples.len() - samples.len() % channels;
+    meter.add_frames_i16(&samples[..whole_frames]).ok()?;
+    meter.loudness_global().ok().filter(|loudness| loudness.is_finite())
+}
 
-        let playing_song_info = Paragraph::new(playing_song_details)
-            .block(Block::default()).style(Style::default().fg(Color::White));
-        
-        let playing_song_cover = if let Some(song_id) = myapp.currently_playing_song {
-            myapp.find_song_by_id(song_id)
-                .and_then(|song| song.cover.clone())
-                .unwrap_or_else(|| {
-                    let img = ImageBuffer::from_fn(4, 4, |_, _| Rgba([0, 0, 0, 0]));
-                    DynamicImage::ImageRgba8(img)
-                })
-        } else {
-                let img = ImageBuffer::from_fn(4, 4, |_, _| Rgba([0, 0, 0, 0]));
-                DynamicImage::ImageRgba8(img)
-        };
-        let mut pic = picker.new_resize_protocol(playing_song_cover);
-        let img = StatefulImage::default();
-        
-        // Check if a song is playing
-        if let Some(current_song_id) = myapp.currently_playing_song {
-            if let Some(song) = myapp.find_song_by_id(current_song_id).cloned() {
-                if song.is_playing {
-
-                    // Update song time
-                    myapp.song_time = Some(
-                        myapp.song_time.unwrap_or(Instant::now()) + Duration::from_secs_f64(0.1),
-                    );
-
-                    // If the song is finished, play the next one
-                    if myapp.song_time.unwrap().elapsed().as_secs_f64() >= song.duration {
+/// Returns the volume factor bringing a song of the given loudness to [`TARGET_LOUDNESS`],
+/// limited to avoid extreme boosts of quiet songs.
+///
+/// # Arguments
+/// * `loudness` - The measured loudness, in LUFS.
+fn loudness_gain(loudness: f64) -> f32 {
+    10f64.powf((TARGET_LOUDNESS - loudness) / 20.0).clamp(0.1, 4.0) as f32
+}
 
-                        if let Some(current_song) = myapp.find_song_by_id(current_song_id) {
-                            current_song.is_playing = false;
-                        }
+/// Measures the loudness of songs on a background thread, one at a time.
+struct LoudnessAnalyzer {
+    requests: mpsc::Sender<PathBuf>,
+    results: mpsc::Receiver<(PathBuf, Option<f64>)>,
+}
 
-                        let next_index = myapp
-                            .filtered_songs
-                            .iter()
-                            .position(|s| s.id == current_song_id)
-                            .map(|idx| (idx + 1) % myapp.filtered_songs.len())
-                            .unwrap_or(0);
-
-                        // Play the next song
-                        let next_song = myapp
-                            .find_song_by_id(myapp.filtered_songs[next_index].id)
-                            .cloned();
-
-                        if let Some(song) = next_song {
-                            let file = fs::File::open(&song.path).unwrap();
-                            let source = rodio::Decoder::new(io::BufReader::new(file)).unwrap();
-                            myapp.song_time = Some(Instant::now());
-                            myapp.currently_playing_song =
-                                Some(myapp.filtered_songs[next_index].id);
-                            myapp.selected_song_id = Some(myapp.filtered_songs[next_index].id);
-                            myapp.paused_time = None;
-                            myapp.filtered_songs[next_index].is_playing = true; // !!!!!BIG PROBLEMO!!!!
-                            sink.lock().unwrap().clear();
-                            sink.lock().unwrap().append(source);
-                            sink.lock().unwrap().play();
-                        }
-                    }
+impl LoudnessAnalyzer {
+    fn spawn() -> LoudnessAnalyzer {
+        let (requests, request_receiver) = mpsc::channel::<PathBuf>();
+        let (result_sender, results) = mpsc::channel();
+        std::thread::spawn(move || {
+            for path in request_receiver {
+                let loudness = measure_loudness(&path);
+                if result_sender.send((path, loudness)).is_err() {
+                    break;
                 }
             }
+        });
+        LoudnessAnalyzer { requests, results }
+    }
+}
+
+/// Name of the playlist listing the streams played with `--stream`, regenerated on every start.
+const STREAMS_PLAYLIST: &str = "Streams";
+
+/// Name of the playlist listing the radio stations from `stations.json`, regenerated on every
+/// start.
+const RADIO_PLAYLIST: &str = "Radio";
+
+/// How much of the start of a stream is kept so decoders can probe its format.
+const STREAM_HEAD_LIMIT: usize = 256 * 1024;
+
+/// Returns the URL of a song that is a network stream rather than a file.
+///
+/// # Arguments
+/// * `path` - The path of the song.
+fn stream_url(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+/// Connects to a network stream and prepares it for playback.
+///
+/// # Arguments
+/// * `url` - The `http(s)://` URL of the stream.
+/// * `title` - Updated with the title the stream announces, if it sends ICY metadata.
+fn open_stream(
+    url: &str,
+    title: Arc<Mutex<Option<String>>>,
+) -> io::Result<rodio::Decoder<StreamReader<Box<dyn Read + Send + Sync>>>> {
+    let response = ureq::get(url)
+        .set("Icy-MetaData", "1")
+        .timeout(Duration::from_secs(10))
+        .call()
+        .map_err(io::Error::other)?;
+    let metaint = response
+        .header("icy-metaint")
+        .and_then(|metaint| metaint.trim().parse().ok())
+        .filter(|metaint| *metaint > 0);
+    let reader = response.into_reader();
+    let reader: Box<dyn Read + Send + Sync> = match metaint {
+        Some(metaint) => Box::new(IcyReader::new(reader, metaint, title)),
+        None => reader,
+    };
+    rodio::Decoder::new(StreamReader::new(reader))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Separates the ICY metadata that radio streams interleave with the audio.
+///
+/// After every `metaint` bytes of audio comes a length byte and that many 16-byte blocks of
+/// metadata such as `StreamTitle='Artist - Song';`.
+struct IcyReader<R> {
+    inner: R,
+    metaint: usize,
+    /// Audio bytes left before the next metadata block.
+    audio_left: usize,
+    title: Arc<Mutex<Option<String>>>,
+}
+
+impl<R: Read> IcyReader<R> {
+    fn new(inner: R, metaint: usize, title: Arc<Mutex<Option<String>>>) -> Self {
+        IcyReader {
+            inner,
+            metaint,
+            audio_left: metaint,
+            title,
         }
+    }
 
-        let song_id = myapp
-            .currently_playing_song
-            .or(myapp.selected_song_id)
-            .unwrap_or_else(|| myapp.songs.first().map(|song| song.id).unwrap_or_default());
+    fn read_metadata(&mut self) -> io::Result<()> {
+        let mut length = [0; 1];
+        self.inner.read_exact(&mut length)?;
+        let mut metadata = vec![0; usize::from(length[0]) * 16];
+        self.inner.read_exact(&mut metadata)?;
+        // Most blocks repeat the title or are empty; keep the last one announced
+        if let Some(title) = parse_icy_title(&String::from_utf8_lossy(&metadata)) {
+            *self.title.lock().unwrap() = Some(title);
+        }
+        self.audio_left = self.metaint;
+        Ok(())
+    }
+}
 
-        let progress_ratio = match myapp.find_song_by_id(song_id).cloned() {
-            Some(song) if song.duration > 0.0 && !sink.lock().unwrap().is_paused() => {
-                if let Some(song_time) = myapp.song_time {
-                    let elapsed_time = song_time.elapsed().as_secs_f64().min(song.duration);
-                    if elapsed_time >= song.duration {
-                        // If the song is over, set progress to 0
-                        0.0
-                    } else {
-                        elapsed_time / song.duration
-                    }
-                } else {
-                    0.0
-                }
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.audio_left == 0 {
+            self.read_metadata()?;
+        }
+        let max = buf.len().min(self.audio_left);
+        let read = self.inner.read(&mut buf[..max])?;
+        self.audio_left -= read;
+        Ok(read)
+    }
+}
+
+/// Returns the title in an ICY metadata block, if it announces a non-empty one.
+///
+/// # Arguments
+/// * `metadata` - The metadata, e.g. `StreamTitle='Artist - Song';StreamUrl='';`.
+fn parse_icy_title(metadata: &str) -> Option<String> {
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &metadata[start..];
+    let end = rest.find("';").unwrap_or(rest.trim_end_matches('\0').len());
+    let title = normalize_tag(&rest[..end]);
+    (!title.is_empty()).then_some(title)
+}
+
+/// Makes a network stream seekable enough for format detection.
+///
+/// Decoders read the start of a source to detect its format and then seek back. The first
+/// [`STREAM_HEAD_LIMIT`] bytes are kept for that; past them the stream can only be read on.
+struct StreamReader<R> {
+    inner: R,
+    head: Vec<u8>,
+    position: u64,
+    /// How many bytes have been read from `inner`.
+    inner_position: u64,
+}
+
+impl<R: Read> StreamReader<R> {
+    fn new(inner: R) -> Self {
+        StreamReader {
+            inner,
+            head: Vec::new(),
+            position: 0,
+            inner_position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let head_len = self.head.len() as u64;
+        if self.position < head_len {
+            let start = self.position as usize;
+            let read = buf.len().min(self.head.len() - start);
+            buf[..read].copy_from_slice(&self.head[start..start + read]);
+            self.position += read as u64;
+            return Ok(read);
+        }
+
+        let read = self.inner.read(buf)?;
+        if self.inner_position == head_len && self.head.len() < STREAM_HEAD_LIMIT {
+            self.head.extend_from_slice(&buf[..read]);
+        }
+        self.position += read as u64;
+        self.inner_position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R> Seek for StreamReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        let head_len = self.head.len() as u64;
+        match target {
+            Some(target)
+                if target == self.position
+                    || (target <= head_len && self.inner_position == head_len) =>
+            {
+                self.position = target;
+                Ok(target)
             }
-            Some(song) if song.duration > 0.0 && sink.lock().unwrap().is_paused() => {
-                let mut ratio: f64 = 0.0;
-                if let Some(song_time) = myapp.song_time {
-                    if let Some(paused_time) = myapp.paused_time {
-                        let elapsed_time = song_time.elapsed().as_secs_f64().min(song.duration);
-                        ratio =
-                            (elapsed_time - paused_time.elapsed().as_secs_f64()).max(0.0) / song.duration;
-                    }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "streams can only seek within their start",
+            )),
+        }
+    }
+}
+
+/// Cleans up a tag value: composes it to Unicode NFC, drops control characters such as null
+/// padding and byte order marks, and trims surrounding whitespace.
+///
+/// # Arguments
+/// * `value` - The raw tag value.
+fn normalize_tag(value: &str) -> String {
+    value
+        .nfc()
+        .filter(|c| !c.is_control() && *c != '\u{feff}')
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Lowercases text and strips its accents, so "bjork" finds "Björk".
+///
+/// # Arguments
+/// * `value` - The text to fold.
+fn fold_case_and_accents(value: &str) -> String {
+    value
+        .nfd()
+        .filter(|c| !unicode_normalization::char::is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Lyrics of a song, read from a sidecar file next to it.
+#[derive(Debug, Clone, PartialEq)]
+enum Lyrics {
+    /// Lines from an `.lrc` file with their start time in seconds, sorted by time.
+    Synced(Vec<(f64, String)>),
+    /// Plain text lyrics without timing, from a `.txt` file or the song's tags.
+    Plain(String),
+}
+
+impl Lyrics {
+    /// Loads the lyrics for a song, preferring a synced `.lrc` file over a plain `.txt` one.
+    ///
+    /// # Arguments
+    /// * `song_path` - The path of the audio file.
+    fn load(song_path: &Path) -> Option<Lyrics> {
+        fs::read_to_string(song_path.with_extension("lrc"))
+            .ok()
+            .and_then(|contents| Lyrics::parse_lrc(&contents))
+            .or_else(|| {
+                fs::read_to_string(song_path.with_extension("txt"))
+                    .ok()
+                    .filter(|contents| !contents.trim().is_empty())
+                    .map(Lyrics::Plain)
+            })
+    }
+
+    /// Parses the `[mm:ss.xx]` timestamped lines of an `.lrc` file.
+    ///
+    /// A line may carry several timestamps; metadata tags such as `[ar:Artist]` are skipped.
+    /// Returns `None` if there are no timestamped lines.
+    ///
+    /// # Arguments
+    /// * `contents` - The contents of the `.lrc` file.
+    fn parse_lrc(contents: &str) -> Option<Lyrics> {
+        let parse_timestamp = |tag: &str| {
+            let (minutes, seconds) = tag.split_once(':')?;
+            let minutes: u32 = minutes.trim().parse().ok()?;
+            let seconds: f64 = seconds.trim().parse().ok()?;
+            (0.0..60.0)
+                .contains(&seconds)
+                .then_some(f64::from(minutes) * 60.0 + seconds)
+        };
+
+        let mut lines = Vec::new();
+        for line in contents.lines() {
+            let mut rest = line.trim();
+            let mut times = Vec::new();
+            while let Some((tag, text)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                match parse_timestamp(tag) {
+                    Some(time) => times.push(time),
+                    None => break,
                 }
-                ratio
+                rest = text;
             }
-            _ => 0.0,
-        };
+            lines.extend(times.into_iter().map(|time| (time, rest.trim().to_string())));
+        }
+        lines.sort_by(|a, b| a.0.total_cmp(&b.0));
 
-        let song_progress = if let Some(song) = myapp.find_song_by_id(song_id).cloned() {
-            let elapsed_time = if let Some(paused_time) = myapp.paused_time {
-                myapp
-                    .song_time
-                    .unwrap_or(Instant::now())
-                    .elapsed()
-                    .as_secs_f64()
-                    .sub(paused_time.elapsed().as_secs_f64())
-                    .min(song.duration)
-            } else {
-                myapp
-                    .song_time
-                    .unwrap_or(Instant::now())
-                    .elapsed()
-                    .as_secs_f64()
-                    .min(song.duration)
-            };
-            let elapsed_minutes = (elapsed_time / 60.0).floor() as u64;
-            let elapsed_seconds = (elapsed_time % 60.0).round() as u64;
-            let duration_minutes = (song.duration / 60.0).floor() as u64;
-            let duration_seconds = (song.duration % 60.0).round() as u64;
+        (!lines.is_empty()).then_some(Lyrics::Synced(lines))
+    }
 
-            Gauge::default()
-                .block(Block::default().borders(Borders::ALL).title("Progress"))
-                .gauge_style(Style::default().fg(Color::LightBlue))
-                .label(format!(
-                    "{:02}:{:02}/{:02}:{:02}",
-                    elapsed_minutes, elapsed_seconds, duration_minutes, duration_seconds
-                ))
-                .ratio(progress_ratio)
-        } else {
-            Gauge::default()
-                .block(Block::default().borders(Borders::ALL).title("Progress"))
-                .gauge_style(Style::default().fg(Color::LightBlue))
-                .label("No song selected")
-                .ratio(0.0)
-        };
+    /// Returns the index of the line being sung at `elapsed` seconds, if the lyrics are synced
+    /// and the first line has started.
+    ///
+    /// # Arguments
+    /// * `elapsed` - How far into the song playback is, in seconds.
+    fn current_line(&self, elapsed: f64) -> Option<usize> {
+        match self {
+            Lyrics::Synced(lines) => lines
+                .partition_point(|(time, _)| *time <= elapsed)
+                .checked_sub(1),
+            Lyrics::Plain(_) => None,
+        }
+    }
+}
 
-        // Volume bar
-        let volume_bar = Gauge::default()
-            .block(Block::default().borders(Borders::ALL).title("Volume"))
-            .gauge_style(Style::default().fg(Color::LightBlue))
-            .label(format!("{:.0}%", sink.lock().unwrap().volume() * 100.0))
-            .ratio(sink.lock().unwrap().volume() as f64);
+/// Name of the playlist holding the whole library, regenerated on every start.
+const ALL_SONGS_PLAYLIST: &str = "All Songs";
+
+/// Playlists rebuilt on every start rather than stored in `data.json`. Their names can't be
+/// taken by other playlists, and they can't be deleted or reordered.
+const GENERATED_PLAYLISTS: [&str; 3] = [ALL_SONGS_PLAYLIST, STREAMS_PLAYLIST, RADIO_PLAYLIST];
+
+/// What happens when the current song finishes.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+enum AdvanceMode {
+    /// Move on to the next song.
+    #[default]
+    Continue,
+    /// Stop once, then go back to `Continue`.
+    StopAfterCurrent,
+}
 
-        let hint = Paragraph::new("F1 for controls")
-            .style(
-                Style::default()
-                    .fg(Color::Gray)
-                    .add_modifier(Modifier::ITALIC),
-            )
-            .alignment(Alignment::Right);
+/// Which songs repeat once playback reaches their end.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+enum RepeatMode {
+    /// Stop at the end of the list or queue.
+    Off,
+    /// Play the current song again.
+    One,
+    /// Start over from the beginning of the list or queue.
+    #[default]
+    All,
+}
 
-        terminal.draw(|f| {
-            let vertical_layout = Layout::default()
-                .direction(Direction::Vertical)
-                .margin(1)
-                .constraints([
-                    Constraint::Fill(1),
-                ])
-                .split(f.area());
+impl RepeatMode {
+    /// Returns the next repeat mode in the sequence.
+    fn next(self) -> RepeatMode {
+        match self {
+            RepeatMode::Off => RepeatMode::One,
+            RepeatMode::One => RepeatMode::All,
+            RepeatMode::All => RepeatMode::Off,
+        }
+    }
+}
 
-                let song_tab_layout = Layout::default()
-                    .direction(Direction::Vertical)
-                    .constraints([
-                        Constraint::Percentage(7),
-                        Constraint::Percentage(86),
-                        Constraint::Percentage(7),
-                    ])
-                    .split(vertical_layout[0]);
-                f.render_widget(search_bar, song_tab_layout[0]);
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RepeatMode::Off => "Off",
+            RepeatMode::One => "Current song",
+            RepeatMode::All => "All",
+        };
+        write!(f, "{}", name)
+    }
+}
 
-                let chunks = Layout::default()
-                    .direction(Direction::Horizontal)
-                    .constraints([
-                        Constraint::Percentage(20),
-                        Constraint::Percentage(60),
-                        Constraint::Percentage(20),
-                    ])
-                    .split(song_tab_layout[1]);
+/// Enum representing the criteria for searching songs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SearchCriteria {
+    Title,
+    Artist,
+    Album,
+}
 
-                visible_playlist_count = (chunks[0].height - 2) as usize;
-                visible_song_count = (chunks[1].height - 2) as usize;
+impl SearchCriteria {
+    /// Returns the next search criteria in the sequence.
+    fn next(&self) -> SearchCriteria {
+        match self {
+            SearchCriteria::Title => SearchCriteria::Artist,
+            SearchCriteria::Artist => SearchCriteria::Album,
+            SearchCriteria::Album => SearchCriteria::Title,
+        }
+    }
 
-                let song_items: Vec<ListItem> = myapp
-                    .filtered_songs
-                    .iter()
-                    .enumerate()
-                    .skip(myapp.list_offset)
-                    .take(visible_song_count as usize)
-                    .map(|(index, song)| {
-                        let mut style = Style::default();
-                        if myapp.chosen_song_ids.contains(&myapp.songs[index].id) {
-                            style = Style::default()
-                                .fg(Color::LightRed)
-                                .add_modifier(Modifier::RAPID_BLINK);
-                        }
-                        if let Some(selected_id) = myapp.selected_song_id {
-                            if selected_id == song.id {
-                                style = Style::default()
-                                    .fg(Color::LightBlue)
-                                    .add_modifier(Modifier::BOLD);
-                            }
-                        }
-                        ListItem::new(song.title.clone()).style(style)
-                    })
-                    .collect();
+    /// Returns the previous search criteria in the sequence.
+    fn prev(&self) -> SearchCriteria {
+        match self {
+            SearchCriteria::Title => SearchCriteria::Album,
+            SearchCriteria::Artist => SearchCriteria::Title,
+            SearchCriteria::Album => SearchCriteria::Artist,
+        }
+    }
+}
 
-                let song_list = List::new(song_items)
-                    .block(
-                        Block::default()
-                            .borders(Borders::ALL)
-                            .title(format!("Songs----------------------------------------------------------------------Sort by: {}", 
-                                myapp.sort_criteria.to_string(),))
-                    )
-                    .highlight_style(
-                        Style::default()
-                            .fg(Color::Yellow)
-                            .add_modifier(Modifier::BOLD),
-                    );
+/// Enum representing the criteria for sorting songs.
+#[derive(PartialEq, Eq, Debug)]
+enum SortCriteria {
+    Title,
+    Artist,
+    /// By album artist and album, keeping the songs of an album together.
+    Album,
+    Duration,
+}
 
-                let playlist_items: Vec<ListItem> = myapp
-                    .playlists
-                    .iter()
-                    .enumerate()
-                    .map(|(index, (playlist_name, _songs))| {
-                        let mut style = Style::default();
-                        if myapp.selected_playlist_index == index {
-                            style = Style::default()
-                                .fg(Color::LightBlue)
-                                .add_modifier(Modifier::BOLD);
-                        }
-                        ListItem::new(playlist_name.clone()).style(style)
-                    })
-                    .collect();
+impl SortCriteria {
+    /// Parses a sorting criteria by name, ignoring case, e.g. `artist`.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the criteria.
+    fn from_name(name: &str) -> Option<SortCriteria> {
+        match name.trim().to_lowercase().as_str() {
+            "title" => Some(SortCriteria::Title),
+            "artist" => Some(SortCriteria::Artist),
+            "album" => Some(SortCriteria::Album),
+            "duration" => Some(SortCriteria::Duration),
+            _ => None,
+        }
+    }
 
-                let playlist_list = List::new(playlist_items)
-                    .block(Block::default().borders(Borders::ALL).title("Playlists"))
-                    .highlight_style(
-                        Style::default()
-                            .fg(Color::Yellow)
-                            .add_modifier(Modifier::BOLD),
-                    );
+    /// Returns the next sorting criteria in the sequence.
+    fn next(&self) -> SortCriteria {
+        match self {
+            SortCriteria::Title => SortCriteria::Artist,
+            SortCriteria::Artist => SortCriteria::Album,
+            SortCriteria::Album => SortCriteria::Duration,
+            SortCriteria::Duration => SortCriteria::Title,
+        }
+    }
 
-                f.render_widget(playlist_list, chunks[0]);
+    /// Returns the previous sorting criteria in the sequence.
+    fn prev(&self) -> SortCriteria {
+        match self {
+            SortCriteria::Title => SortCriteria::Duration,
+            SortCriteria::Artist => SortCriteria::Title,
+            SortCriteria::Album => SortCriteria::Artist,
+            SortCriteria::Duration => SortCriteria::Album,
+        }
+    }
+}
 
-                f.render_widget(song_list, chunks[1]);
+impl fmt::Display for SortCriteria {
+    /// Formats the sorting criteria as a string.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SortCriteria::Title => "Title",
+            SortCriteria::Artist => "Artist",
+            SortCriteria::Album => "Album",
+            SortCriteria::Duration => "Duration",
+        };
+        write!(f, "{}", name)
+    }
+}
 
-                let songs_info = Layout::default()
-                    .direction(Direction::Vertical)
-                    .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
-                    .split(chunks[2]);
+/// The part of the interface that receives navigation keys.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Focus {
+    /// Left/Right, Home/End and Delete edit the search text.
+    Search,
+    /// Left/Right seek and Up/Down move through the song list.
+    SongList,
+    /// Left/Right seek and Up/Down move through the playlists.
+    Playlists,
+}
 
-                f.render_widget(selected_song_info, songs_info[0]);
+impl Focus {
+    /// Returns the next region in Tab order.
+    fn next(&self) -> Focus {
+        match self {
+            Focus::Search => Focus::SongList,
+            Focus::SongList => Focus::Playlists,
+            Focus::Playlists => Focus::Search,
+        }
+    }
 
-                let playing_song_block = Block::default()
-                    .borders(Borders::ALL)
-                    .title("Currently playing");
-                let inner_layout = Layout::default()
-                    .direction(Direction::Vertical)
-                    .constraints([Constraint::Percentage(40), Constraint::Fill(1)])
-                    .split(playing_song_block.inner(songs_info[1]));
+    /// Returns the previous region in Tab order.
+    fn prev(&self) -> Focus {
+        match self {
+            Focus::Search => Focus::Playlists,
+            Focus::SongList => Focus::Search,
+            Focus::Playlists => Focus::SongList,
+        }
+    }
+}
 
-                f.render_widget(playing_song_info, inner_layout[0]);
-                f.render_stateful_widget(img, inner_layout[1], &mut pic);
-                f.render_widget(playing_song_block, songs_info[1]);
-                
-                let footer = Layout::default()
-                    .direction(Direction::Horizontal)
-                    .constraints([Constraint::Percentage(80), Constraint::Percentage(20)])
-                    .split(song_tab_layout[2]);
+struct PopupState {
+    visible: bool,
+}
 
-                f.render_widget(song_progress, footer[0]);
+/// What the text typed into the input popup is for.
+#[derive(Debug, Clone, PartialEq)]
+enum InputPurpose {
+    /// The name of a new playlist of the chosen songs.
+    PlaylistName,
+    /// The label of a bookmark at a position in a song.
+    BookmarkLabel { path: PathBuf, position: f64 },
+    /// One of the tags of songs, edited one after another before they're written to the files.
+    /// `current` holds the tags already in the file of a single song.
+    SongTags {
+        ids: Vec<Uuid>,
+        field: TagField,
+        tags: SongTags,
+        current: SongTags,
+    },
+}
 
-                f.render_widget(volume_bar, footer[1]);
+/// A tag edited in the input popup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TagField {
+    Title,
+    Artist,
+    Album,
+}
 
-                if myapp.hint_popup_state.visible {
-                    let _ = draw_popup(f);
-                }
+impl TagField {
+    /// Returns the tag edited after this one, or `None` for the last.
+    fn next(self) -> Option<TagField> {
+        match self {
+            TagField::Title => Some(TagField::Artist),
+            TagField::Artist => Some(TagField::Album),
+            TagField::Album => None,
+        }
+    }
+}
 
-                if myapp.playlist_input_popup.visible {
-                    let _ = draw_playlist_name_input_popup(f, &myapp.playlist_name_input);
-                }
-                
-            f.render_widget(
-                hint,
-                Rect::new(
-                    f.area().width.saturating_sub(20 as u16),
-                    f.area().height - 1,
-                    20 as u16,
-                    1,
-                ),
-            );
-        })?;
-
-        // Handle input events
-        if poll(Duration::from_millis(200))? {
-            if let Event::Key(key) = crossterm::event::read()? {
-                match key {
-                    KeyEvent {
-                        code: KeyCode::Char('q'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        let _ = myapp.save_playlist();
-                        break;
-                    }
-                    KeyEvent {
-                        code: KeyCode::Down,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if let Some(selected_id) = myapp.selected_song_id {
-                            // Find the index of the currently selected song by Uuid
-                            if let Some(index) = myapp
-                                .filtered_songs
-                                .iter()
-                                .position(|song| song.id == selected_id)
-                            {
-                                if index < myapp.filtered_songs.len() - 1 {
-                                    let next_song = &myapp.filtered_songs[index + 1];
-                                    myapp.selected_song_id = Some(next_song.id);
-
-                                    // Scroll down if selected index goes out of view
-                                    if let Some(new_index) = myapp
-                                        .filtered_songs
-                                        .iter()
-                                        .position(|song| song.id == myapp.selected_song_id.unwrap())
-                                    {
-                                        if new_index >= myapp.list_offset + visible_song_count - 1 {
-                                            myapp.list_offset =
-                                                (new_index - visible_song_count + 2).max(0);
-
-                                            // Ensure the list_offset does not exceed the maximum allowed offset
-                                            myapp.list_offset = myapp.list_offset.min(
-                                                myapp
-                                                    .filtered_songs
-                                                    .len()
-                                                    .saturating_sub(visible_song_count),
-                                            );
-                                        }
-                                    }
-                                } else {
-                                    // Wrap around to the beginning
-                                    let first_song = &myapp.filtered_songs[0];
-                                    myapp.selected_song_id = Some(first_song.id);
-                                    myapp.list_offset = 0;
-                                }
-                            }
-                        } else if !myapp.filtered_songs.is_empty() {
-                            // Select the first song if none is selected
-                            let first_song = &myapp.filtered_songs[0];
-                            myapp.selected_song_id = Some(first_song.id);
-                            myapp.list_offset = 0;
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Up,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if let Some(selected_id) = myapp.selected_song_id {
-                            // Find the index of the currently selected song by Uuid
-                            if let Some(index) = myapp
-                                .filtered_songs
-                                .iter()
-                                .position(|song| song.id == selected_id)
-                            {
-                                if index > 0 {
-                                    let previous_song = &myapp.filtered_songs[index - 1];
-                                    myapp.selected_song_id = Some(previous_song.id);
-
-                                    // Scroll up if selected index goes out of view
-                                    if index <= myapp.list_offset + 1 {
-                                        myapp.list_offset = myapp.list_offset.saturating_sub(1);
-                                    }
-                                } else {
-                                    // Wrap around to the last song
-                                    let last_song =
-                                        &myapp.filtered_songs[myapp.filtered_songs.len() - 1];
-                                    myapp.selected_song_id = Some(last_song.id);
-                                    myapp.list_offset = myapp
-                                        .filtered_songs
-                                        .len()
-                                        .saturating_sub(visible_song_count);
-                                }
-                            }
-                        } else if !myapp.filtered_songs.is_empty() {
-                            // Select the last song if none is selected
-                            let last_song = &myapp.filtered_songs[myapp.filtered_songs.len() - 1];
-                            myapp.selected_song_id = Some(last_song.id);
-                            myapp.list_offset = myapp
-                                .filtered_songs
-                                .len()
-                                .saturating_sub(visible_song_count);
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('j'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        // Move playlist selection down
-                        if myapp.selected_playlist_index < myapp.playlists.len() - 1 {
-                            myapp.selected_playlist_index = myapp.selected_playlist_index + 1;
-                            if myapp.selected_playlist_index
-                                >= myapp.playlist_list_offset + visible_playlist_count - 1
-                            {
-                                myapp.playlist_list_offset =
-                                    (myapp.selected_playlist_index - visible_playlist_count + 2)
-                                        .max(0);
-
-                                // Ensure the playlist_list_offset does not exceed the maximum allowed offset
-                                myapp.playlist_list_offset = myapp.playlist_list_offset.min(
-                                    myapp.playlists.len().saturating_sub(visible_playlist_count),
-                                );
-                            }
-                        } else {
-                            myapp.selected_playlist_index = 0;
-                            myapp.playlist_list_offset = 0;
-                        }
-                        myapp.selected_song_id = None;
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('k'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        // Move playlist selection up
-                        if myapp.selected_playlist_index > 0 {
-                            myapp.selected_playlist_index = myapp.selected_playlist_index - 1;
-
-                            // Scroll up if selected index goes out of view
-                            if myapp.selected_playlist_index <= myapp.playlist_list_offset + 1 {
-                                myapp.playlist_list_offset =
-                                    myapp.playlist_list_offset.saturating_sub(1);
-                            }
-                        } else {
-                            myapp.selected_playlist_index = myapp.playlists.len() - 1;
-                            myapp.playlist_list_offset =
-                                myapp.playlists.len().saturating_sub(visible_playlist_count);
-                        }
-                        myapp.selected_song_id = None;
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char(' '),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if let Some(selected_id) = myapp.selected_song_id {
-                            if let Some(index) = myapp
-                                .filtered_songs
-                                .iter()
-                                .position(|song| song.id == selected_id)
-                            {
-                                if myapp.currently_playing_song.is_none()
-                                    || Some(selected_id) != myapp.currently_playing_song
-                                {
-                                    sink.lock().unwrap().clear();
-                                    let selected_song = &myapp.filtered_songs[index];
-                                    selected_song.play(&sink);
-                                    myapp.song_time = Some(Instant::now());
-                                    myapp.currently_playing_song = Some(selected_id);
-
-                                    // Set is_playing field to true
-                                    if let Some(song) =
-                                        myapp.songs.iter_mut().find(|s| s.id == selected_id)
-                                    {
-                                        song.is_playing = true;
-                                    }
-                                } else {
-                                    // Stop the currently playing song
-                                    sink.lock().unwrap().clear();
-                                    myapp.song_time = None;
-                                    myapp.currently_playing_song = None;
-
-                                    // Set is_playing field to false
-                                    if let Some(song) =
-                                        myapp.songs.iter_mut().find(|s| s.id == selected_id)
-                                    {
-                                        song.is_playing = false;
-                                    }
-
-                                    // Update song_time if the song was playing
-                                    if let Some(start_time) = myapp.song_time {
-                                        let elapsed_time = start_time.elapsed().as_secs_f64().min(
-                                            myapp
-                                                .songs
-                                                .iter()
-                                                .find(|s| s.id == selected_id)
-                                                .map_or(0.0, |s| s.duration),
-                                        );
-                                        let adjusted_time =
-                                            if let Some(paused_at) = myapp.paused_time {
-                                                elapsed_time + paused_at.elapsed().as_secs_f64()
-                                            } else {
-                                                elapsed_time
-                                            };
-                                        myapp.song_time = Some(
-                                            start_time + Duration::from_secs_f64(adjusted_time),
-                                        );
-                                    } else {
-                                        myapp.song_time = Some(Instant::now());
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('p'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if sink.lock().unwrap().is_paused() {
-                            if let Some(current_id) = myapp.currently_playing_song {
-                                if let Some(song) =
-                                    myapp.songs.iter_mut().find(|s| s.id == current_id)
-                                {
-                                    sink.lock().unwrap().play();
-                                    song.is_playing = true;
-                                }
-                                // Calculate elapsed time during the pause
-                                if let Some(paused_at) = myapp.paused_time {
-                                    let elapsed_during_pause = paused_at.elapsed();
-                                    myapp.song_time =
-                                        myapp.song_time.map(|t| t + elapsed_during_pause);
-                                    myapp.paused_time = None;
-                                }
-                            }
-                        } else {
-                            if let Some(current_id) = myapp.currently_playing_song {
-                                if let Some(song) =
-                                    myapp.songs.iter_mut().find(|s| s.id == current_id)
-                                {
-                                    sink.lock().unwrap().pause();
-                                    song.is_playing = false;
-                                    // Record the time when playback was paused
-                                    myapp.paused_time = Some(Instant::now());
-                                }
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('c'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        myapp.playlist_input_popup.visible = true;
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('h'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if let Some(current_id) = myapp.currently_playing_song {
-                            if let Some(current_index) = myapp
-                                .filtered_songs
-                                .iter()
-                                .position(|song| song.id == current_id)
-                            {
-                                if current_index > 0 {
-                                    let previous_id = myapp.filtered_songs[current_index - 1].id;
-                                    sink.lock().unwrap().clear();
-                                    if let Some(previous_song) = myapp
-                                        .filtered_songs
-                                        .iter()
-                                        .find(|song| song.id == previous_id)
-                                    {
-                                        previous_song.play(&sink);
-                                        myapp.currently_playing_song = Some(previous_id);
-                                        myapp.selected_song_id = Some(previous_id);
-                                        myapp.song_time = Some(Instant::now());
-                                        myapp.paused_time = None; // Reset paused time when starting a new song
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('l'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if let Some(current_id) = myapp.currently_playing_song {
-                            if let Some(current_index) = myapp
-                                .filtered_songs
-                                .iter()
-                                .position(|song| song.id == current_id)
-                            {
-                                if current_index < myapp.filtered_songs.len() - 1 {
-                                    let next_id = myapp.filtered_songs[current_index + 1].id;
-                                    sink.lock().unwrap().clear();
-                                    if let Some(next_song) =
-                                        myapp.filtered_songs.iter().find(|song| song.id == next_id)
-                                    {
-                                        next_song.play(&sink);
-                                        myapp.selected_song_id = Some(next_id);
-                                        myapp.currently_playing_song = Some(next_id);
-                                        myapp.song_time = Some(Instant::now());
-                                        myapp.paused_time = None; // Reset paused time when starting a new song
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Left,
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        // Decrease volume by 5%
-                        let sink = &mut sink.lock().unwrap();
-                        let volume = sink.volume();
-                        if volume >= 0.05 {
-                            sink.set_volume(volume - 0.05);
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Right,
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        // Increase volume by 5%
-                        let sink = &mut sink.lock().unwrap();
-                        let volume = sink.volume();
-                        if volume <= 0.95 {
-                            sink.set_volume(volume + 0.05);
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('m'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        let sink = &mut sink.lock().unwrap();
-                        if sink.volume() > 0.0 {
-                            // Mute music
-                            myapp.previous_volume = sink.volume(); // Save current volume
-                            sink.set_volume(0.0);
-                        } else {
-                            // Unmute music
-                            sink.set_volume(myapp.previous_volume); // Restore previous volume
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char(c),
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if myapp.playlist_input_popup.visible {
-                            myapp.playlist_name_input.push(c);
-                        } else {
-                            myapp.search_text.push(c);
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char(c),
-                        modifiers: KeyModifiers::SHIFT,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if myapp.playlist_input_popup.visible {
-                            myapp
-                                .playlist_name_input
-                                .push(c.to_uppercase().last().unwrap());
-                        } else {
-                            myapp.search_text.push(c.to_uppercase().last().unwrap());
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Backspace,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if myapp.playlist_input_popup.visible {
-                            myapp.playlist_name_input.pop();
-                        } else {
-                            myapp.search_text.pop();
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('s'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        myapp.search_criteria = match myapp.search_criteria {
-                            SearchCriteria::Title => SearchCriteria::Artist,
-                            SearchCriteria::Artist => SearchCriteria::Album,
-                            SearchCriteria::Album => SearchCriteria::Title,
-                        };
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('t'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        myapp.set_sort_criteria(myapp.sort_criteria.next());
-                    }
-                    KeyEvent {
-                        code: KeyCode::Right,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if let Some(current_id) = myapp.currently_playing_song {
-                            if let Some(current_song) =
-                                myapp.songs.iter().find(|song| song.id == current_id)
-                            {
-                                let file = fs::File::open(&current_song.path).unwrap();
-                                let source = rodio::Decoder::new(io::BufReader::new(file)).unwrap();
-
-                                let time = myapp
-                                    .song_time
-                                    .unwrap_or_else(Instant::now)
-                                    .elapsed()
-                                    .saturating_add(Duration::from_secs(5));
-                                myapp.song_time = Some(Instant::now() - time);
-
-                                let source = source.skip_duration(time);
-
-                                let sink = sink.lock().unwrap();
-                                sink.clear();
-                                sink.append(source);
-                                sink.play();
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Left,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        if let Some(current_id) = myapp.currently_playing_song {
-                            if let Some(current_song) =
-                                myapp.songs.iter().find(|song| song.id == current_id)
-                            {
-                                let file = fs::File::open(&current_song.path).unwrap();
-                                let source = rodio::Decoder::new(io::BufReader::new(file)).unwrap();
-
-                                let time = myapp
-                                    .song_time
-                                    .unwrap_or_else(Instant::now)
-                                    .elapsed()
-                                    .saturating_sub(Duration::from_secs(5));
-                                myapp.song_time = Some(Instant::now() - time);
-
-                                let source = source.skip_duration(time);
-
-                                let sink = sink.lock().unwrap();
-                                sink.clear();
-                                sink.append(source);
-                                sink.play();
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::F(1),
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        myapp.hint_popup_state.toggle();
-                    }
-                    KeyEvent {
-                        code: KeyCode::Esc,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        // Close the popup if it's open
-                        myapp.playlist_input_popup.visible = false;
-                        myapp.playlist_name_input = String::new();
-                        myapp.hint_popup_state.visible = false;
-                    }
-                    KeyEvent {
-                        code: KeyCode::Enter,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-
-                        match (
-                            myapp.playlist_name_input.is_empty(),
-                            myapp.chosen_song_ids.is_empty(),
-                        ) {
-                            (true, true) => {
-                                myapp.playlist_name_input =
-                                    "Need a name and at least 1 song".to_string()
-                            }
-                            (true, false) => myapp.playlist_name_input = "Need a name ".to_string(),
-                            (false, true) => {
-                                myapp.playlist_name_input = "Need at least 1 song".to_string()
-                            }
-                            (false, false) => {
-                                myapp.playlist_input_popup.visible = false;
-                                myapp.playlists.insert(
-                                    myapp.playlist_name_input.clone(),
-                                    myapp.chosen_song_ids.clone(),
-                                );
-                                myapp.chosen_song_ids.clear();
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('a'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        let selected_song_id = myapp
-                            .selected_song_id
-                            .unwrap_or(Uuid::new_v5(&Uuid::NAMESPACE_DNS, b"rust-lang.org"));
-                        match myapp.chosen_song_ids.contains(&selected_song_id) {
-                            true => {
-                                myapp.chosen_song_ids.retain(|id| *id != selected_song_id);
-                            }
-                            false => {
-                                myapp.chosen_song_ids.push(selected_song_id);
-                            }
-                        }
-                    }
-                    KeyEvent {
-                        code: KeyCode::Char('x'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    } => {
-                        // Get the playlist name at the selected index
-                        let playlist_name = myapp
-                            .playlists
-                            .keys()
-                            .nth(myapp.selected_playlist_index)
-                            .cloned();
-
-                        if let Some(name) = playlist_name {
-                            myapp.playlists.remove(&name);
-                            myapp.selected_playlist_index = 0;
-                        }
-                    }
-                    _ => {}
+/// The editable tags of a song. Tags that are `None` are left as they are in the file.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SongTags {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+}
+
+impl SongTags {
+    /// Returns a tag's value for editing.
+    ///
+    /// # Arguments
+    /// * `field` - The tag.
+    fn get_mut(&mut self, field: TagField) -> &mut Option<String> {
+        match field {
+            TagField::Title => &mut self.title,
+            TagField::Artist => &mut self.artist,
+            TagField::Album => &mut self.album,
+        }
+    }
+}
+
+/// A named position in a song, for finding places in podcasts and audiobooks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Bookmark {
+    label: String,
+    /// Position in the song, in seconds.
+    position: f64,
+}
+
+/// The play queue as saved between runs, with songs stored by path since ids of streams and
+/// songs in a rescanned library can't be relied on.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct SavedQueue {
+    /// Name of the playlist the queue was built from.
+    source: Option<String>,
+    /// The queued songs, starting with the one that was playing.
+    songs: Vec<PathBuf>,
+}
+
+/// A song in the playlist file.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum PlaylistEntry {
+    Id(Uuid),
+    /// Relative to the music directory, with `/` between folders, for songs inside it and
+    /// absolute for the rest; written with `relative_playlist_paths`.
+    Path(String),
+}
+
+/// Derives a song's id from its path, so the same file always gets the same id.
+///
+/// File names that aren't valid UTF-8 are hashed as they are; for all others the bytes are
+/// the UTF-8 text of the path.
+///
+/// # Arguments
+/// * `path` - The path of the song's file, or the URL of a stream.
+fn song_id(path: &Path) -> Uuid {
+    Uuid::new_v5(&Uuid::NAMESPACE_DNS, path.as_os_str().as_encoded_bytes())
+}
+
+/// Returns how a song is stored in the playlist file with `relative_playlist_paths`, or `None`
+/// if its path can't be written as text.
+///
+/// # Arguments
+/// * `path` - The path of the song.
+/// * `music_dir` - The directory the library is scanned from.
+fn playlist_path(path: &Path, music_dir: &Path) -> Option<String> {
+    match path.strip_prefix(music_dir) {
+        Ok(relative) => relative
+            .components()
+            .map(|component| component.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+            .map(|parts| parts.join("/")),
+        Err(_) => path.to_str().map(str::to_string),
+    }
+}
+
+/// Turns a path from the playlist file back into the path the library scan gives the song.
+///
+/// # Arguments
+/// * `entry` - The path as stored.
+/// * `music_dir` - The directory the library is scanned from.
+fn resolve_playlist_path(entry: &str, music_dir: &Path) -> PathBuf {
+    let path = Path::new(entry);
+    if path.is_absolute() || stream_url(path).is_some() {
+        path.to_path_buf()
+    } else {
+        music_dir.join(path).components().collect()
+    }
+}
+
+/// Formats a position or duration in seconds as `m:ss`.
+///
+/// # Arguments
+/// * `seconds` - The time to format.
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Formats a total length of time in seconds as `m:ss`, or `h:mm:ss` from an hour on.
+///
+/// # Arguments
+/// * `seconds` - The time to format.
+fn format_total_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    match seconds / 3600 {
+        0 => format_time(seconds as f64),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+impl PopupState {
+    fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+}
+
+/// Command-line options accepted by the player.
+#[derive(Default, Debug, PartialEq)]
+struct CliArgs {
+    /// Where to write JSON playback events (`-` for stdout), if anywhere.
+    json_events: Option<String>,
+    /// Run without the terminal interface, playing the library in order.
+    headless: bool,
+    /// Named pipe to read playback commands from, if enabled.
+    control_fifo: Option<PathBuf>,
+    /// URL of a network stream to play on start.
+    stream: Option<String>,
+    /// Directories to scan for music instead of the configured ones; `--music-dir` can be
+    /// given more than once.
+    music_dirs: Vec<PathBuf>,
+    /// Directory to keep playlists in instead of the configured one.
+    playlist_dir: Option<PathBuf>,
+    /// What to sort by on startup instead of the configured criteria.
+    sort_by: Option<String>,
+    /// Sort in descending order on startup.
+    sort_descending: bool,
+    /// Skip album art for this run.
+    no_art: bool,
+}
+
+impl CliArgs {
+    /// Parses the command-line arguments, ignoring any that are not recognized.
+    ///
+    /// # Arguments
+    /// * `args` - The arguments, without the program name.
+    fn parse(args: impl Iterator<Item = String>) -> CliArgs {
+        let mut cli_args = CliArgs::default();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--json-events" => {
+                    // The path is optional; without one events go to stdout.
+                    let path = match args.peek() {
+                        Some(next) if !next.starts_with("--") => args.next().unwrap(),
+                        _ => "-".to_string(),
+                    };
+                    cli_args.json_events = Some(path);
                 }
-            } else {
-                continue;
+                "--headless" => cli_args.headless = true,
+                "--stream" => cli_args.stream = args.next(),
+                "--music-dir" => cli_args.music_dirs.extend(args.next().map(PathBuf::from)),
+                "--playlist-dir" => cli_args.playlist_dir = args.next().map(PathBuf::from),
+                "--sort" => cli_args.sort_by = args.next(),
+                "--sort-descending" => cli_args.sort_descending = true,
+                "--no-art" => cli_args.no_art = true,
+                "--fifo" => {
+                    // The path is optional; without one the pipe lives in the config dir.
+                    cli_args.control_fifo = match args.peek() {
+                        Some(next) if !next.starts_with("--") => args.next().map(PathBuf::from),
+                        _ => app_config_dir().map(|dir| dir.join("control.fifo")),
+                    };
+                }
+                _ => {}
             }
         }
+        cli_args
     }
+}
 
-    // Cleanup
-    disable_raw_mode()?;
-    stdout().execute(Clear(crossterm::terminal::ClearType::All))?;
-    Ok(())
+/// How the album art of the playing song is drawn.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ArtMode {
+    /// Ask the terminal which graphics protocol it supports, falling back to half blocks.
+    #[default]
+    Auto,
+    /// Unicode half blocks with ANSI colors; works in any color terminal.
+    Halfblocks,
+    Sixel,
+    Kitty,
+    Iterm2,
+    /// Don't draw the cover, only a text placeholder.
+    Placeholder,
 }
 
-fn scan_folder_for_music() -> Vec<Song> {
-    let current_folder = match dirs::audio_dir() {
-        Some(dir) => dir,
-        None => env::current_dir().unwrap(),
-    };
+impl ArtMode {
+    /// Creates the image picker for this mode, or `None` when only a placeholder is drawn.
+    ///
+    /// `Auto` queries the terminal over stdio, so this must run after entering the alternate
+    /// screen but before reading terminal events.
+    fn picker(self) -> Option<Picker> {
+        let forced = match self {
+            ArtMode::Auto => {
+                return Some(Picker::from_query_stdio().unwrap_or_else(|_| {
+                    let mut picker = Picker::from_fontsize((7, 14));
+                    picker.set_protocol_type(ProtocolType::Halfblocks);
+                    picker
+                }));
+            }
+            ArtMode::Placeholder => return None,
+            ArtMode::Halfblocks => ProtocolType::Halfblocks,
+            ArtMode::Sixel => ProtocolType::Sixel,
+            ArtMode::Kitty => ProtocolType::Kitty,
+            ArtMode::Iterm2 => ProtocolType::Iterm2,
+        };
+        let mut picker = Picker::from_fontsize((7, 14));
+        picker.set_protocol_type(forced);
+        Some(picker)
+    }
+}
 
-    let song_paths = match fs::read_dir(&current_folder) {
-        Ok(entries) => {
-            let music_files: Vec<PathBuf> = entries
-                .filter_map(|entry| {
-                    if let Ok(entry) = entry {
-                        let path = entry.path();
-                        if path.is_file() {
-                            if let Some(ext) = path.extension() {
-                                if let Some(ext_str) = ext.to_str() {
-                                    if MUSIC_FORMATS.contains(&ext_str) {
-                                        Some(path)
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-            music_files
+/// Colors and text attributes of a row in the song or playlist list.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+struct StyleConfig {
+    /// Text color, e.g. `"lightblue"` or `"#ff8800"`.
+    fg: Option<Color>,
+    /// Background color.
+    bg: Option<Color>,
+    bold: bool,
+    italic: bool,
+    underlined: bool,
+    /// Swap the text and background colors.
+    reversed: bool,
+    blink: bool,
+}
+
+impl StyleConfig {
+    /// Converts the configuration to a terminal style.
+    ///
+    /// # Arguments
+    /// * `no_blink` - Leave out blinking even if configured.
+    fn to_style(&self, no_blink: bool) -> Style {
+        let mut style = Style::default();
+        if let Some(fg) = self.fg {
+            style = style.fg(fg);
         }
-        Err(e) => {
-            eprintln!("Error reading directory: {}", e);
-            panic!("awawawa");
+        if let Some(bg) = self.bg {
+            style = style.bg(bg);
         }
-    };
-
-    let mut song_list: Vec<Song> = Vec::new();
-    for song in song_paths {
-        let current_song;
-        if song.ends_with("mp3") {
-            let mp3_meta = read_from_file(&song).unwrap();
-            let mp3_clone = read_from_file(&song).unwrap();
-            let mp3_a = read_from_file(&song).unwrap();
-
-            current_song = Song::new(
-                mp3_meta.tag.unwrap().title,
-                mp3_a.tag.unwrap().artist,
-                None,
-                song.clone(),
-                mp3_clone.tag.unwrap().album,
-                mp3_meta.duration.as_secs_f64(),
-            );
-        } else {
-            let mut mp3_duration: f64 = 0.0;
-            if song.extension().unwrap().to_str().unwrap() == "mp3" {
-                mp3_duration = read_from_file(&song).unwrap().duration.as_secs_f64();
+        let modifiers = [
+            (self.bold, Modifier::BOLD),
+            (self.italic, Modifier::ITALIC),
+            (self.underlined, Modifier::UNDERLINED),
+            (self.reversed, Modifier::REVERSED),
+            (self.blink && !no_blink, Modifier::SLOW_BLINK),
+        ];
+        for (enabled, modifier) in modifiers {
+            if enabled {
+                style = style.add_modifier(modifier);
             }
-            let meta = Tag::new().read_from_path(&song).unwrap();
+        }
+        style
+    }
+}
 
-            current_song = Song::new(
-                meta.title().unwrap_or("No Title").to_string(),
-                meta.artist().unwrap_or("No Title").to_string(),
-                {
-                    meta.album_cover().and_then(|cover| {
-                        let format = match cover.mime_type {
-                            audiotags::MimeType::Jpeg => ImageFormat::Jpeg,
-                            audiotags::MimeType::Png => ImageFormat::Png,
-                            audiotags::MimeType::Gif => ImageFormat::Gif,
-                            audiotags::MimeType::Bmp => ImageFormat::Bmp,
-                            audiotags::MimeType::Tiff => ImageFormat::Tiff,
-                        };
+/// Built-in sets of list row styles.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum ThemePreset {
+    #[default]
+    Default,
+    /// White and yellow on the terminal background, with states told apart by reversed
+    /// and underlined text.
+    HighContrast,
+    /// Blue and orange from the Okabe-Ito palette, distinguishable with common color vision
+    /// deficiencies, with states also told apart by bold and underlined text.
+    Colorblind,
+}
 
-                        load_from_memory_with_format(cover.data, format).ok()
-                    })
+impl ThemePreset {
+    /// Returns the styles of the selected and the chosen rows.
+    fn styles(self) -> (StyleConfig, StyleConfig) {
+        match self {
+            ThemePreset::Default => (
+                StyleConfig {
+                    fg: Some(Color::LightBlue),
+                    bold: true,
+                    ..Default::default()
                 },
-                song.clone(),
-                meta.album()
-                    .unwrap_or(Album {
-                        title: "None",
-                        artist: None,
-                        cover: None,
-                    })
-                    .title
-                    .to_string(),
-                if let Some(ext) = song.extension().and_then(|e| e.to_str()) {
-                    match ext {
-                        "mp3" => mp3_duration,
-                        _ => meta.duration().unwrap_or(0.0_f64),
-                    }
-                } else {
-                    meta.duration().unwrap_or(0.0_f64)
+                StyleConfig {
+                    fg: Some(Color::LightRed),
+                    reversed: true,
+                    ..Default::default()
                 },
-            );
+            ),
+            ThemePreset::HighContrast => (
+                StyleConfig {
+                    fg: Some(Color::White),
+                    bold: true,
+                    reversed: true,
+                    ..Default::default()
+                },
+                StyleConfig {
+                    fg: Some(Color::Yellow),
+                    bold: true,
+                    underlined: true,
+                    ..Default::default()
+                },
+            ),
+            ThemePreset::Colorblind => (
+                StyleConfig {
+                    fg: Some(Color::Rgb(86, 180, 233)),
+                    bold: true,
+                    ..Default::default()
+                },
+                StyleConfig {
+                    fg: Some(Color::Rgb(230, 159, 0)),
+                    underlined: true,
+                    ..Default::default()
+                },
+            ),
         }
-        song_list.push(current_song);
     }
+}
 
-    if song_list.is_empty() {
-        song_list.push(Song::new(
-            "No songs in \"Music\" and current directory!".to_string(),
-            "No Title".to_string(),
-            None,
-            PathBuf::new(),
-            Album {
-                title: "None",
-                artist: None,
-                cover: None,
-            }
-            .title
-            .to_string(),
-            0.0_f64,
-        ));
+/// When a song counts as played, for play statistics and scrobbling: after the given share of
+/// it or the given time, whichever comes first. Either rule can be turned off with `null`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+struct PlayedThreshold {
+    /// Share of the song, in percent.
+    percent: Option<f64>,
+    /// Time into the song, in seconds.
+    seconds: Option<f64>,
+}
+
+impl Default for PlayedThreshold {
+    fn default() -> Self {
+        PlayedThreshold {
+            percent: Some(50.0),
+            seconds: Some(240.0),
+        }
     }
+}
 
-    song_list
+impl PlayedThreshold {
+    /// Returns whether a song has played long enough to count as played.
+    ///
+    /// # Arguments
+    /// * `elapsed` - How far into the song playback is, in seconds.
+    /// * `duration` - The duration of the song in seconds; streams without one only use the
+    ///   time rule.
+    fn reached(&self, elapsed: f64, duration: f64) -> bool {
+        let by_percent = self
+            .percent
+            .is_some_and(|percent| duration > 0.0 && elapsed >= duration * percent / 100.0);
+        let by_seconds = self.seconds.is_some_and(|seconds| elapsed >= seconds);
+        by_percent || by_seconds
+    }
 }
 
-fn draw_popup(f: &mut Frame) -> Result<(), io::Error> {
-    let size = f.area();
-    let popup_width = size.width / 3;
-    let popup_height = size.height / 3 + 8;
-    let popup_area = Rect::new(
-        (size.width - popup_width) / 2,
-        (size.height - popup_height) / 2,
-        popup_width,
-        popup_height,
-    );
+/// Styles of the list rows; unset ones come from the preset.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+struct Theme {
+    /// The built-in styles to start from.
+    preset: ThemePreset,
+    /// The selected song and playlist.
+    selected: Option<StyleConfig>,
+    /// Songs chosen for a new playlist with Ctrl+A.
+    chosen: Option<StyleConfig>,
+}
 
-    f.render_widget(ratatui::widgets::Clear, popup_area);
-    f.render_widget(
-        Block::default()
-            .borders(Borders::ALL)
-            .border_type(ratatui::widgets::BorderType::Rounded),
-        popup_area,
-    );
+impl Theme {
+    /// Returns the style of the selected song and playlist.
+    ///
+    /// # Arguments
+    /// * `no_blink` - Leave out blinking even if configured.
+    fn selected_style(&self, no_blink: bool) -> Style {
+        self.selected
+            .clone()
+            .unwrap_or_else(|| self.preset.styles().0)
+            .to_style(no_blink)
+    }
 
-    let popup_text = Paragraph::new(
-        "Controls
-- Use Up/Down Arrow Keys to navigate songs
-- Ctrl + Spacebar: Play/Stop
-- Ctrl + P: Pause/Unpause
-- Ctrl + M: Mute/Unmute
-- Ctrl + S: Change search criteria
-- Ctrl + T: Change sorting criteria
-- Ctrl + Left/Right Arrow Keys: Adjust Volume
-- Ctrl + L: Next song
-- Ctrl + H: Previous song
-- Left Arrow Key: -5 seconds on current song
-- Right Arrow Key: +5 seconds on current song
-- Backspace: Delete characters in the search bar
-- Ctrl + A: Select a song to be added
- to the new playlist
-- Ctrl + C: New playlist name input popup
-- Ctrl + K: Move playlist selection up
-- Ctrl + J: Move playlist selection down
-- Enter: Create a new playlist with given name
-- Ctrl + X: Delete selected playlist
-- F1: Toggle Controls Popup
-- Esc or F1: Close Popup",
-    )
-    .block(Block::default().borders(Borders::NONE))
-    .alignment(Alignment::Center)
-    .style(Style::default().fg(Color::White));
-    f.render_widget(popup_text, popup_area);
+    /// Returns the style of songs chosen for a new playlist.
+    ///
+    /// # Arguments
+    /// * `no_blink` - Leave out blinking even if configured.
+    fn chosen_style(&self, no_blink: bool) -> Style {
+        self.chosen
+            .clone()
+            .unwrap_or_else(|| self.preset.styles().1)
+            .to_style(no_blink)
+    }
+}
 
-    Ok(())
+/// How the main screen is arranged.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LayoutMode {
+    /// Playlists, songs and song details side by side.
+    #[default]
+    Columns,
+    /// Only the search bar, song list and progress bar with the playing song, stacked.
+    Stacked,
 }
 
-fn draw_playlist_name_input_popup(f: &mut Frame, input: &str) -> Result<(), io::Error> {
-    let size = f.area();
-    let popup_width = size.width / 4;
-    let popup_height = size.height / 8;
-    let popup_area = Rect::new(
-        (size.width - popup_width) / 2,
-        (size.height - popup_height) / 2,
-        popup_width,
-        popup_height,
-    );
+/// How exported songs are arranged in the export folder.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ExportLayout {
+    /// All songs directly in the export folder.
+    #[default]
+    Flat,
+    /// Songs in `Artist/Album` folders.
+    ArtistAlbum,
+}
 
-    f.render_widget(ratatui::widgets::Clear, popup_area);
-    f.render_widget(
-        Block::default()
-            .title("Enter Playlist Name")
-            .borders(Borders::ALL),
-        popup_area,
-    );
+/// What songs without a title tag are called.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum MissingTitle {
+    /// "No Title".
+    #[default]
+    NoTitle,
+    /// The file name without its extension, with underscores turned into spaces.
+    Filename,
+    /// Like `Filename`, leaving out a leading track number such as `03 - `, `7.` or `01_`.
+    FilenameWithoutNumber,
+}
+
+impl MissingTitle {
+    /// Returns the title derived from the song's file name, if titles are taken from file names.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the song.
+    fn title_for(self, path: &Path) -> Option<String> {
+        if self == MissingTitle::NoTitle {
+            return None;
+        }
+        let stem = path.file_stem()?.to_string_lossy();
+        let title = |name: &str| name.replace('_', " ").trim().to_string();
+        if self == MissingTitle::FilenameWithoutNumber {
+            let after_number = stem.trim_start_matches(|c: char| c.is_ascii_digit());
+            let digits = stem.len() - after_number.len();
+            let spaced = after_number.trim_start_matches(' ');
+            // Only strip a number followed by a separator, or a zero-padded one, so names like
+            // "1999" or "99 Luftballons" stay whole
+            let separated = spaced.starts_with(['-', '.', '_']);
+            let zero_padded =
+                digits > 1 && stem.starts_with('0') && spaced.len() < after_number.len();
+            let rest = title(after_number.trim_start_matches([' ', '.', '-', '_']));
+            if digits > 0 && (separated || zero_padded) && !rest.is_empty() {
+                return Some(rest);
+            }
+        }
+        Some(title(&stem))
+    }
+}
+
+/// A song to copy when exporting a playlist.
+struct ExportTrack {
+    path: PathBuf,
+    title: String,
+    artist: String,
+    /// Names the artist folder; the album artist, or the artist for songs without one.
+    album_artist: String,
+    album: String,
+    duration: f64,
+}
+
+/// Progress of a playlist export running on a background thread.
+enum ExportUpdate {
+    /// The number of songs handled so far and the number of songs to export.
+    Progress(usize, usize),
+    /// The export finished, having copied the given number of songs and failed on the others.
+    Done {
+        dir: PathBuf,
+        copied: usize,
+        failed: Vec<(PathBuf, io::Error)>,
+    },
+}
+
+/// How much of the "Currently playing" panel the album art takes up.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ArtSize {
+    Small,
+    #[default]
+    Medium,
+    Large,
+    /// A custom share of the panel height, from 0 to 100.
+    Percent(u16),
+    /// Don't show album art; the song details use the whole panel.
+    Hidden,
+}
+
+impl ArtSize {
+    /// Returns the percentage of the panel height used by the art, 0 when hidden.
+    fn percentage(self) -> u16 {
+        match self {
+            ArtSize::Small => 40,
+            ArtSize::Medium => 60,
+            ArtSize::Large => 80,
+            ArtSize::Percent(percent) => percent.min(100),
+            ArtSize::Hidden => 0,
+        }
+    }
+}
+
+/// User preferences, read from `settings.json` in the config directory.
+///
+/// Missing fields fall back to their defaults, so the file only needs the options being changed.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(default)]
+struct Settings {
+    /// Use vim-style normal/insert modes for navigation.
+    vim_mode: bool,
+    /// Rows kept between the selected song and the edge of the song list while scrolling;
+    /// unset keeps the selection centered.
+    scroll_margin: Option<usize>,
+    /// How album art is drawn; `auto` detects the terminal's graphics support.
+    art_mode: ArtMode,
+    /// Height of the album art in the "Currently playing" panel.
+    art_size: ArtSize,
+    /// Skip album art entirely: covers aren't read while scanning and none is drawn.
+    no_art: bool,
+    /// Leading articles, e.g. `["The", "A", "An"]`, ignored when sorting by artist.
+    sort_ignored_articles: Vec<String>,
+    /// What the songs are sorted by on startup, e.g. `artist`; unset sorts by title.
+    sort_by: Option<String>,
+    /// Sort in descending order from startup on.
+    sort_descending: bool,
+    /// Even out the loudness of songs, measuring each one in the background when first played.
+    normalize_loudness: bool,
+    /// Directory scanned for music; unset uses the system music directory.
+    music_dir: Option<PathBuf>,
+    /// Further directories scanned along with `music_dir`, merged into one library.
+    extra_music_dirs: Vec<PathBuf>,
+    /// Directory the playlists are kept in; unset uses the cli-rhythm config directory.
+    playlist_dir: Option<PathBuf>,
+    /// How the main screen is arranged; switched with F3.
+    layout: LayoutMode,
+    /// Styles of the list rows.
+    theme: Theme,
+    /// Never blink, whatever the theme says.
+    no_blink: bool,
+    /// Groups digits of large counts, e.g. `","` for 12,345; unset follows the locale.
+    thousands_separator: Option<String>,
+    /// Playlists listed first, in this order; toggled with F6.
+    pinned_playlists: Vec<String>,
+    /// When a song counts as played.
+    played_threshold: PlayedThreshold,
+    /// Songs at least this many minutes long continue where they were left off; unset uses 20.
+    resume_after_minutes: Option<u64>,
+    /// How long songs fade in when they start or resume and fade out when paused or stopped,
+    /// in milliseconds, up to 5000; unset uses 200 and 0 turns fading off.
+    fade_ms: Option<u64>,
+    /// Directory playlists are exported to with F7; unset uses the downloads directory.
+    export_dir: Option<PathBuf>,
+    /// How exported songs are arranged.
+    export_layout: ExportLayout,
+    /// Write an `.m3u` playlist with relative paths next to the exported songs.
+    export_m3u: bool,
+    /// What songs without a title tag are called.
+    missing_title: MissingTitle,
+    /// Volume in percent, kept between runs; unset uses 100.
+    volume: Option<u32>,
+    /// Mix the channels down to mono, e.g. for a single speaker; switched with Alt+M.
+    mono: bool,
+    /// Left/right balance in percent, from -100 (left only) to 100 (right only); 0 is
+    /// centered.
+    balance: i32,
+    /// Seconds into a song after which going back restarts it instead of playing the previous
+    /// song; unset uses 3 and 0 always plays the previous song.
+    restart_after_seconds: Option<f64>,
+    /// File the playing song is written to, e.g. for a stream overlay; unset writes nothing.
+    now_playing_file: Option<PathBuf>,
+    /// What's written to `now_playing_file`; unset uses `{artist} - {title}`.
+    now_playing_format: Option<String>,
+    /// Store playlist songs by path, relative to the music directory where possible, instead of
+    /// by id.
+    relative_playlist_paths: bool,
+    /// Share of the screen width, in percent, the song list takes in the column layout, the
+    /// rest split between the playlists and the song details; unset uses 60.
+    song_list_width: Option<u16>,
+    /// How often the screen is updated while a song plays, in milliseconds; unset uses 100.
+    progress_interval_ms: Option<u64>,
+}
+
+impl Settings {
+    /// Loads the settings, using the defaults if the file is missing or invalid. A balance
+    /// out of range is brought back into it.
+    ///
+    /// # Arguments
+    /// * `filepath` - The path to the settings file.
+    fn load(filepath: &Path) -> Settings {
+        let mut settings: Settings = fs::read_to_string(filepath)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        settings.balance = settings.balance.clamp(-100, 100);
+        settings
+    }
+
+    /// Returns the share of the "Currently playing" panel the album art takes, in percent; 0
+    /// when it's hidden or turned off with `no_art`.
+    fn art_percentage(&self) -> u16 {
+        if self.no_art {
+            0
+        } else {
+            self.art_size.percentage()
+        }
+    }
+
+    /// Creates the image picker for the album art, or `None` when no art is drawn; hidden art
+    /// skips the terminal query and never builds an image protocol.
+    fn art_picker(&self) -> Option<Picker> {
+        if self.art_percentage() == 0 {
+            return None;
+        }
+        self.art_mode.picker()
+    }
+
+    /// Writes the settings to a file.
+    ///
+    /// # Arguments
+    /// * `filepath` - The path to the settings file.
+    fn save(&self, filepath: &Path) -> io::Result<()> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        if let Some(dir) = filepath.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomically(filepath, serialized.as_bytes())
+    }
+}
+
+/// A snapshot of the playback state, emitted as a line of JSON by `--json-events`.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+struct PlaybackEvent {
+    /// What changed since the previous event: `track`, `state`, `played`, `volume` or
+    /// `position`.
+    event: &'static str,
+    /// `playing`, `paused` or `stopped`.
+    state: &'static str,
+    /// Whether the current song has played long enough to count as played.
+    played: bool,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    path: Option<PathBuf>,
+    /// Elapsed time of the current song in whole seconds.
+    position: u64,
+    /// Duration of the current song in whole seconds.
+    duration: u64,
+    /// Volume in percent.
+    volume: u32,
+}
+
+impl PlaybackEvent {
+    /// Builds an event from the current application and sink state.
+    ///
+    /// # Arguments
+    /// * `myapp` - The application state.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn from_state(myapp: &MyApp, sink: &Sink) -> PlaybackEvent {
+        let song = myapp
+            .currently_playing_song
+            .and_then(|id| myapp.get_song(id));
+        let state = match song {
+            Some(_) if sink.is_paused() || myapp.pausing() => "paused",
+            Some(_) => "playing",
+            None => "stopped",
+        };
+
+        PlaybackEvent {
+            event: "state",
+            state,
+            played: song.is_some() && myapp.counted_as_played,
+            title: song.map(|song| song.title.clone()),
+            artist: song.map(|song| song.artist.clone()),
+            album: song.map(|song| song.album.clone()),
+            path: song.map(|song| song.path.clone()),
+            position: song.map_or(0, |song| myapp.elapsed_time(song.duration).max(0.0) as u64),
+            duration: song.map_or(0, |song| song.duration as u64),
+            volume: (sink.volume() * 100.0).round() as u32,
+        }
+    }
+
+    /// Names the change between `previous` and this event, or `None` if nothing changed.
+    fn change_from(&self, previous: Option<&PlaybackEvent>) -> Option<&'static str> {
+        let Some(previous) = previous else {
+            return Some("track");
+        };
+        if self.path != previous.path {
+            Some("track")
+        } else if self.state != previous.state {
+            Some("state")
+        } else if self.played != previous.played {
+            Some("played")
+        } else if self.volume != previous.volume {
+            Some("volume")
+        } else if self.position != previous.position {
+            Some("position")
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes a line of JSON for every playback state change.
+struct EventWriter {
+    out: Box<dyn Write>,
+    last_event: Option<PlaybackEvent>,
+}
+
+impl EventWriter {
+    /// Opens the event output.
+    ///
+    /// # Arguments
+    /// * `path` - The file to append events to, or `-` for stdout.
+    fn open(path: &str) -> io::Result<EventWriter> {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(stdout())
+        } else {
+            Box::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            )
+        };
+        Ok(EventWriter {
+            out,
+            last_event: None,
+        })
+    }
+
+    /// Emits an event if the playback state differs from the last one written.
+    ///
+    /// # Arguments
+    /// * `myapp` - The application state.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn update(&mut self, myapp: &MyApp, sink: &Arc<Mutex<Sink>>) -> io::Result<()> {
+        let mut event = PlaybackEvent::from_state(myapp, &sink.lock().unwrap());
+        if let Some(change) = event.change_from(self.last_event.as_ref()) {
+            event.event = change;
+            writeln!(self.out, "{}", serde_json::to_string(&event)?)?;
+            self.out.flush()?;
+            self.last_event = Some(event);
+        }
+        Ok(())
+    }
+}
+
+/// A playback command read from the control FIFO.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Play,
+    Pause,
+    Next,
+    Previous,
+    /// Seek by the given number of seconds.
+    Seek(i64),
+    /// Set the volume, from 0.0 to 1.0.
+    Volume(f32),
+}
+
+impl Command {
+    /// Parses a command such as `play`, `seek +5` or `volume 80`.
+    ///
+    /// # Arguments
+    /// * `line` - A single line of input.
+    ///
+    /// # Returns
+    /// `None` if the line isn't a valid command.
+    fn parse(line: &str) -> Option<Command> {
+        let mut words = line.split_whitespace();
+        let command = match (words.next()?, words.next()) {
+            ("play", None) => Command::Play,
+            ("pause", None) => Command::Pause,
+            ("next", None) => Command::Next,
+            ("prev", None) => Command::Previous,
+            ("seek", Some(offset)) => Command::Seek(offset.parse().ok()?),
+            ("volume", Some(volume)) => {
+                Command::Volume(volume.parse::<u32>().ok()?.min(100) as f32 / 100.0)
+            }
+            _ => return None,
+        };
+
+        match words.next() {
+            Some(_) => None,
+            None => Some(command),
+        }
+    }
+}
+
+/// A named pipe that external scripts write playback commands to, one per line.
+struct ControlFifo {
+    file: File,
+    buffer: Vec<u8>, // Bytes read after the last complete line
+}
+
+impl ControlFifo {
+    /// Creates the FIFO if needed and opens it for non-blocking reads.
+    ///
+    /// # Arguments
+    /// * `path` - Where the FIFO lives.
+    #[cfg(unix)]
+    fn open(path: &Path) -> io::Result<ControlFifo> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+        match fs::metadata(path) {
+            Ok(metadata) if !metadata.file_type().is_fifo() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a FIFO", path.display()),
+                ));
+            }
+            Ok(_) => {}
+            Err(_) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let c_path = CString::new(path.as_os_str().as_bytes())?;
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+
+        // Opening for writing too keeps reads from hitting end-of-file between writers.
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        Ok(ControlFifo {
+            file,
+            buffer: Vec::new(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn open(_path: &Path) -> io::Result<ControlFifo> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "control FIFOs are only supported on Unix",
+        ))
+    }
+
+    /// Returns the complete lines written to the FIFO since the last call.
+    fn read_lines(&mut self) -> Vec<String> {
+        let mut chunk = [0; 512];
+        while let Ok(read) = self.file.read(&mut chunk) {
+            if read == 0 {
+                break;
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+
+        // Only whole lines are decoded, so characters split between reads stay intact
+        let mut lines = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                lines.push(line.trim().to_string());
+            }
+        }
+        lines
+    }
+}
+
+/// The main application struct.
+pub struct MyApp {
+    songs: Vec<Song>, // List of all songs
+    filtered_songs: Vec<Song>,
+    sink: Arc<Mutex<Sink>>,
+    selected_song_id: Option<Uuid>, // Index of the currently selected song
+    currently_playing_song: Option<Uuid>, // Index of the currently playing song
+    search_criteria: SearchCriteria, // Criteria to filter/search songs
+    sort_criteria: SortCriteria,    // Criteria to sort songs
+    sort_descending: bool,          // Whether songs are sorted in reverse
+    hint_popup_state: PopupState,   // Controls the visibility of popups
+    input_popup: PopupState,
+    input_purpose: InputPurpose, // What the text in the input popup is for
+    lyrics_popup: PopupState,
+    queue_popup: PopupState,
+    queue_selected: usize, // Index in `play_queue` of the entry selected in the queue view
+    bookmarks_popup: PopupState,
+    bookmark_selected: usize, // Index of the bookmark selected in the bookmarks popup
+    bookmarks: BTreeMap<PathBuf, Vec<Bookmark>>, // Bookmarks per song file, by position
+    bookmarks_file: Option<PathBuf>, // Where the bookmarks are saved
+    resume_positions: BTreeMap<PathBuf, f64>, // Where long songs were left off, in seconds
+    resume_positions_file: Option<PathBuf>, // Where the positions of long songs are saved
+    position_saved_at: Option<Instant>, // When the position of the playing song was last saved
+    skipped_songs: usize,     // Songs skipped in a row because they couldn't be played
+    counted_as_played: bool,  // Whether the playing song reached the `played_threshold`
+    audio_error: Option<String>, // Why there's no audio output; nothing can be played when set
+    lyrics: Option<(Uuid, Option<Lyrics>)>, // Lyrics of the playing song, loaded on demand
+    lyrics_scroll: u16, // First line shown in the lyrics popup for unsynced lyrics
+    selected_playlist_index: usize,
+    input_text: String, // Input buffer of the input popup
+    playlists: BTreeMap<String, Vec<Uuid>>, // Playlists with song indices
+    search_text: String,
+    search_cursor: usize, // Cursor position in the search text, in characters
+    search_history: Vec<String>, // Recent searches, oldest first
+    search_history_index: Option<usize>, // Entry of `search_history` shown while recalling
+    search_draft: String, // Search text typed before recalling older searches
+    search_history_file: Option<PathBuf>, // Where the search history is saved
+    focus: Focus,
+    settings: Settings,
+    muted: bool, // Whether the sink is silenced; the volume to go back to stays in the settings
+    balance: Arc<Mutex<f32>>, // Left/right balance the playing song follows, from -1 to 1
+    fade: Arc<Mutex<Fade>>, // Fade the playing song follows when paused, resumed or stopped
+    after_fade: Option<FadeEnd>, // What happens once the fade out in progress ends
+    list_offset: usize,
+    playlist_list_offset: usize,
+    paused_time: Option<Instant>,
+    chosen_song_ids: Vec<Uuid>,
+    last_selected_songs: BTreeMap<String, Uuid>, // Song last selected in each playlist
+    last_playlist: Option<String>, // Playlist other than "All Songs" last viewed, for Ctrl+N
+    cut_song: Option<(String, Uuid)>, // Song cut from a playlist, waiting to be pasted back into it
+    advance_mode: AdvanceMode,
+    repeat_mode: RepeatMode,
+    play_queue: Vec<Uuid>, // Songs queued for playback; the first one is the current song
+    queue_source: Option<String>, // Name of the playlist the queue was built from
+    play_queue_file: Option<PathBuf>, // Where the play queue is saved on exit
+    song_time: Option<Instant>,
+    needs_redraw: bool, // Whether the screen is out of date
+    loudness_analyzer: Option<LoudnessAnalyzer>, // Set when `normalize_loudness` is on
+    loudness: HashMap<PathBuf, Option<f64>>, // Measured loudness per file; `None` while pending
+    playlist_file_modified: Option<SystemTime>, // When the playlist file was last loaded or saved
+    playlists_unsaved: bool, // Whether the stored playlists changed since they were last loaded or saved
+    playlists_changed_at: Option<Instant>, // When unsaved playlist changes were first noticed
+    overwrite_confirmed: bool, // Whether Ctrl+Q may replace playlists changed by another program
+    settings_file: Option<PathBuf>, // Where settings changed in the app are saved
+    rescan: Option<mpsc::Receiver<(Vec<Song>, Vec<String>)>>, // Songs and problems from a rescan in progress
+    loading: Option<LoadingSong>, // The current song while its file is still being opened
+    seeking: Option<PendingSeek>, // A seek in the current song that hasn't reached its position yet
+    now_playing_written: Option<(Option<Uuid>, Option<String>)>, // Song and on-air title last written to the now-playing file
+    scan_log: Vec<String>, // Problems found by the latest library scan
+    scan_log_popup: PopupState,
+    scan_log_scroll: u16, // First line shown in the scan log popup
+    export: Option<mpsc::Receiver<ExportUpdate>>, // Progress of a playlist export
+    status_message: Option<(String, Instant)>, // Last status message and when it was set
+    quit_message: Option<String>, // Printed once the terminal is restored after quitting
+    read_only: bool, // Whether saving is off because the config directory is unwritable
+    playlists_read_only: bool, // Whether the playlists can't be saved to their directory either
+}
+
+impl Default for MyApp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MyApp {
+    // Initialize a new MyApp instance with default values
+    pub fn new() -> MyApp {
+        MyApp {
+            songs: Vec::new(),
+            filtered_songs: Vec::new(),
+            // Not connected to an output; playback goes through the sink created in `main`
+            sink: Arc::new(Mutex::new(Sink::new_idle().0)),
+            selected_song_id: None,
+            currently_playing_song: None,
+            search_criteria: SearchCriteria::Title,
+            sort_criteria: SortCriteria::Title,
+            sort_descending: false,
+            selected_playlist_index: 0,
+            hint_popup_state: PopupState { visible: false },
+            input_popup: PopupState { visible: false },
+            input_purpose: InputPurpose::PlaylistName,
+            lyrics_popup: PopupState { visible: false },
+            queue_popup: PopupState { visible: false },
+            queue_selected: 1,
+            bookmarks_popup: PopupState { visible: false },
+            bookmark_selected: 0,
+            bookmarks: BTreeMap::new(),
+            bookmarks_file: None,
+            resume_positions: BTreeMap::new(),
+            resume_positions_file: None,
+            position_saved_at: None,
+            skipped_songs: 0,
+            audio_error: None,
+            counted_as_played: false,
+            lyrics: None,
+            lyrics_scroll: 0,
+            input_text: String::new(),
+            playlists: BTreeMap::new(),
+            search_text: String::new(),
+            search_cursor: 0,
+            search_history: Vec::new(),
+            search_history_index: None,
+            search_draft: String::new(),
+            search_history_file: None,
+            focus: Focus::SongList,
+            settings: Settings::default(),
+            muted: false,
+            balance: Arc::new(Mutex::new(0.0)),
+            fade: Arc::new(Mutex::new(Fade::steady(1.0))),
+            after_fade: None,
+            list_offset: 0,
+            playlist_list_offset: 0,
+            paused_time: None,
+            chosen_song_ids: vec![],
+            last_selected_songs: BTreeMap::new(),
+            last_playlist: None,
+            cut_song: None,
+            advance_mode: AdvanceMode::Continue,
+            repeat_mode: RepeatMode::All,
+            play_queue: Vec::new(),
+            queue_source: None,
+            play_queue_file: None,
+            song_time: None,
+            needs_redraw: true,
+            loudness_analyzer: None,
+            loudness: HashMap::new(),
+            playlist_file_modified: None,
+            playlists_unsaved: false,
+            playlists_changed_at: None,
+            overwrite_confirmed: false,
+            settings_file: None,
+            rescan: None,
+            loading: None,
+            seeking: None,
+            now_playing_written: None,
+            scan_log: Vec::new(),
+            scan_log_popup: PopupState { visible: false },
+            scan_log_scroll: 0,
+            export: None,
+            status_message: None,
+            quit_message: None,
+            read_only: false,
+            playlists_read_only: false,
+        }
+    }
+
+    // Function to load songs into the app
+    pub fn load_songs(&mut self) {
+        let mut scan_log = Vec::new();
+        self.songs = scan_folder_for_music(
+            self.settings.music_dir.as_deref(),
+            &self.settings.extra_music_dirs,
+            self.settings.missing_title,
+            !self.settings.no_art,
+            &mut scan_log,
+        );
+        self.set_scan_log(scan_log);
+        let ids: Vec<Uuid> = self.songs.iter().map(|song| song.id).collect();
+        self.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), ids);
+        self.sort_songs(); // Sort based on current criteria after loading
+    }
+
+    /// Scans the music directory again on a background thread; see [`MyApp::update_rescan`].
+    fn start_rescan(&mut self) {
+        if self.rescan.is_some() {
+            return;
+        }
+        let music_dir = self.settings.music_dir.clone();
+        let extra_music_dirs = self.settings.extra_music_dirs.clone();
+        let missing_title = self.settings.missing_title;
+        let load_art = !self.settings.no_art;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut scan_log = Vec::new();
+            let songs = scan_folder_for_music(
+                music_dir.as_deref(),
+                &extra_music_dirs,
+                missing_title,
+                load_art,
+                &mut scan_log,
+            );
+            sender.send((songs, scan_log))
+        });
+        self.rescan = Some(receiver);
+    }
+
+    /// Replaces the scan log with the problems found by the latest scan, pointing them out in
+    /// the status line.
+    ///
+    /// # Arguments
+    /// * `scan_log` - The problems found while scanning.
+    fn set_scan_log(&mut self, scan_log: Vec<String>) {
+        self.scan_log = scan_log;
+        self.scan_log_scroll = 0;
+        if let Some(summary) = self.scan_log_summary() {
+            self.set_status(summary);
+        }
+    }
+
+    /// Describes how many problems the latest scan found, if any.
+    fn scan_log_summary(&self) -> Option<String> {
+        let problems = self
+            .scan_log
+            .iter()
+            .filter(|entry| *entry != SCAN_LOG_TRUNCATED)
+            .count();
+        (problems > 0).then(|| {
+            format!(
+                "{} problems while scanning, Shift + F5 shows them",
+                self.format_count(problems)
+            )
+        })
+    }
+
+    /// Merges the songs of a finished rescan into the library.
+    ///
+    /// Songs that are still there keep their playback state, the playing song stays even if its
+    /// file is gone, and streams are kept. Playlists refer to songs by ids derived from their
+    /// paths, so they pick up the new songs as they are.
+    fn update_rescan(&mut self) {
+        let Some(receiver) = &self.rescan else {
+            return;
+        };
+        let (mut songs, scan_log) = match receiver.try_recv() {
+            Ok(scanned) => scanned,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.rescan = None;
+                self.set_status("Rescanning the library failed".to_string());
+                return;
+            }
+        };
+        self.rescan = None;
+        self.needs_redraw = true;
+
+        let ids: Vec<Uuid> = songs.iter().map(|song| song.id).collect();
+        for song in &mut songs {
+            if let Some(old_song) = self.get_song(song.id) {
+                song.is_playing = old_song.is_playing;
+                song.gain = old_song.gain.clone();
+                song.stream_title = old_song.stream_title.clone();
+            }
+        }
+        let kept_songs: Vec<Song> = self
+            .songs
+            .iter()
+            .filter(|song| {
+                stream_url(&song.path).is_some()
+                    || (Some(song.id) == self.currently_playing_song && !ids.contains(&song.id))
+            })
+            .cloned()
+            .collect();
+        songs.extend(kept_songs);
+
+        self.songs = songs;
+        self.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        self.sort_songs();
+        self.scan_log = scan_log;
+        self.scan_log_scroll = 0;
+        let mut status = format!("Library rescanned, {} songs", self.format_count(ids.len()));
+        if let Some(summary) = self.scan_log_summary() {
+            status = format!("{}; {}", status, summary);
+        }
+        self.set_status(status);
+    }
+
+    /// Copies the songs of the selected playlist into a folder named after it, on a background
+    /// thread; see [`MyApp::update_export`]. Streams are left out.
+    fn export_selected_playlist(&mut self) {
+        if self.export.is_some() {
+            self.set_status("An export is already running".to_string());
+            return;
+        }
+        let Some(name) = self.selected_playlist_name().cloned() else {
+            return;
+        };
+        let tracks: Vec<ExportTrack> = self.playlists[&name]
+            .iter()
+            .filter_map(|id| self.get_song(*id))
+            .filter(|song| stream_url(&song.path).is_none())
+            .map(|song| ExportTrack {
+                path: song.path.clone(),
+                title: song.title.clone(),
+                artist: song.artist.clone(),
+                album_artist: song.grouping_artist().to_string(),
+                album: song.album.clone(),
+                duration: song.duration,
+            })
+            .collect();
+        let Some(export_dir) = self
+            .settings
+            .export_dir
+            .clone()
+            .or_else(dirs::download_dir)
+            .or_else(|| env::current_dir().ok())
+        else {
+            return;
+        };
+        let dir = export_dir.join(path_component(&name));
+        let layout = self.settings.export_layout;
+        let m3u_name = self
+            .settings
+            .export_m3u
+            .then(|| format!("{}.m3u", path_component(&name)));
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let progress = |done, total| {
+                let _ = sender.send(ExportUpdate::Progress(done, total));
+            };
+            let (copied, failed) =
+                export_tracks(&tracks, &dir, layout, m3u_name.as_deref(), progress);
+            let _ = sender.send(ExportUpdate::Done {
+                dir,
+                copied,
+                failed,
+            });
+        });
+        self.export = Some(receiver);
+        self.set_status(format!("Exporting \"{}\"", name));
+    }
+
+    /// Shows the progress of a running playlist export and a summary once it's done.
+    fn update_export(&mut self) {
+        let Some(receiver) = &self.export else {
+            return;
+        };
+        let mut status = None;
+        let mut finished = false;
+        loop {
+            match receiver.try_recv() {
+                Ok(ExportUpdate::Progress(done, total)) => {
+                    status = Some(format!("Exporting, {} of {} songs", done, total));
+                }
+                Ok(ExportUpdate::Done {
+                    dir,
+                    copied,
+                    failed,
+                }) => {
+                    let mut summary = format!(
+                        "Exported {} songs to {}",
+                        self.format_count(copied),
+                        dir.display()
+                    );
+                    if let Some((path, e)) = failed.first() {
+                        summary.push_str(&format!(
+                            ", {} failed ({}: {})",
+                            self.format_count(failed.len()),
+                            path.display(),
+                            e
+                        ));
+                    }
+                    status = Some(summary);
+                    finished = true;
+                    break;
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    status = Some("Exporting the playlist failed".to_string());
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if finished {
+            self.export = None;
+        }
+        if let Some(status) = status {
+            self.set_status(status);
+            self.needs_redraw = true;
+        }
+    }
+
+    /// Adds a network stream to the songs and a playlist, unless it's there already. Returns the
+    /// id of its song.
+    ///
+    /// # Arguments
+    /// * `playlist` - The playlist to list the stream in, "Streams" or "Radio".
+    /// * `title` - The title to show for the stream.
+    /// * `url` - The `http(s)://` URL of the stream.
+    fn add_stream(&mut self, playlist: &str, title: &str, url: &str) -> Uuid {
+        let song = Song::new(
+            title.to_string(),
+            "Stream".to_string(),
+            None,
+            PathBuf::from(url),
+            String::new(),
+            0.0,
+        );
+        let id = song.id;
+        if self.get_song(id).is_none() {
+            self.songs.push(song);
+        }
+        let streams = self.playlists.entry(playlist.to_string()).or_default();
+        if !streams.contains(&id) {
+            streams.push(id);
+        }
+        id
+    }
+
+    /// Returns the URLs in the "Streams" playlist.
+    fn stream_urls(&self) -> Vec<String> {
+        self.playlists
+            .get(STREAMS_PLAYLIST)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.get_song(*id))
+            .filter_map(|song| stream_url(&song.path).map(str::to_string))
+            .collect()
+    }
+
+    /// Saves the URLs in the "Streams" playlist to a JSON file.
+    ///
+    /// # Arguments
+    /// * `path` - The file listing the streams.
+    fn save_streams(&self, path: &Path) -> io::Result<()> {
+        let serialized = serde_json::to_string(&self.stream_urls())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomically(path, serialized.as_bytes())
+    }
+
+    /// Adds the streams listed in a JSON file of URLs.
+    ///
+    /// # Arguments
+    /// * `path` - The file listing the streams.
+    fn load_streams(&mut self, path: &Path) -> io::Result<()> {
+        let urls: Vec<String> = serde_json::from_reader(File::open(path)?)?;
+        self.playlists.remove(STREAMS_PLAYLIST);
+        for url in urls.iter().filter(|url| stream_url(Path::new(url)).is_some()) {
+            self.add_stream(STREAMS_PLAYLIST, url, url);
+        }
+        Ok(())
+    }
+
+    /// Adds the radio stations in a JSON file mapping station names to stream URLs, listing
+    /// them in the "Radio" playlist.
+    ///
+    /// # Arguments
+    /// * `path` - The file listing the stations.
+    fn load_stations(&mut self, path: &Path) -> io::Result<()> {
+        let stations: BTreeMap<String, String> = serde_json::from_reader(File::open(path)?)?;
+        self.playlists.remove(RADIO_PLAYLIST);
+        for (name, url) in &stations {
+            if stream_url(Path::new(url)).is_some() {
+                self.add_stream(RADIO_PLAYLIST, name, url);
+            }
+        }
+        Ok(())
+    }
+
+    // Function to handle song selection
+    pub fn select_song(&mut self, index: Uuid) {
+        self.selected_song_id = Some(index);
+    }
+
+    /// Returns the song with the given id for changing it; see [`MyApp::get_song`] for reading.
+    ///
+    /// # Arguments
+    /// * `id` - The id of the song.
+    fn find_song_by_id(&mut self, id: Uuid) -> Option<&mut Song> {
+        self.songs.iter_mut().find(|song| song.id == id)
+    }
+
+    /// Returns the song with the given id.
+    ///
+    /// # Arguments
+    /// * `id` - The id of the song.
+    fn get_song(&self, id: Uuid) -> Option<&Song> {
+        self.songs.iter().find(|song| song.id == id)
+    }
+
+    // Function to play a song
+    pub fn play_song(&mut self) {
+        if let Some(index) = self.selected_song_id {
+            self.currently_playing_song = Some(index);
+            let song = self.find_song_by_id(index).unwrap().clone();
+            if song
+                .play(
+                    &self.sink,
+                    self.fade_duration(),
+                    self.settings.mono,
+                    self.balance.clone(),
+                )
+                .is_ok()
+            {
+                self.find_song_by_id(index).unwrap().is_playing = true;
+            }
+        }
+    }
+
+    /// Advances the playback clock and starts the next song in the play queue, or in
+    /// `filtered_songs` without one, once the current song has finished. With
+    /// [`AdvanceMode::StopAfterCurrent`] playback stops instead.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn update_playback(&mut self, sink: &Arc<Mutex<Sink>>) {
+        self.update_fade(sink);
+        if self.loading.is_some() {
+            self.update_loading(sink);
+            return;
+        }
+        if self.seeking.is_some() {
+            self.update_seek(sink);
+            return;
+        }
+        // Check if a song is playing
+        if let Some(current_song_id) = self.currently_playing_song {
+            if let Some(song) = self.find_song_by_id(current_song_id).cloned() {
+                if song.is_playing {
+                    self.song_time.get_or_insert_with(Instant::now);
+
+                    // If the song is finished, play the next one
+                    let finished = self.song_ended(&song, sink);
+                    if !self.counted_as_played
+                        && self
+                            .settings
+                            .played_threshold
+                            .reached(self.elapsed_time(f64::MAX), song.duration)
+                    {
+                        self.counted_as_played = true;
+                        self.needs_redraw = true;
+                    }
+                    if finished {
+                        self.finish_song(current_song_id, sink);
+                    } else if self
+                        .position_saved_at
+                        .is_none_or(|saved_at| saved_at.elapsed() >= POSITION_SAVE_INTERVAL)
+                    {
+                        self.remember_position();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether the playing song has ended.
+    ///
+    /// The clock can run a little ahead of the audio, so a song only ends once the sink has
+    /// played it out too, rather than losing its last moments, or `END_GRACE` after its end.
+    /// Live streams have no duration and end when the connection does.
+    ///
+    /// # Arguments
+    /// * `song` - The playing song.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn song_ended(&self, song: &Song, sink: &Arc<Mutex<Sink>>) -> bool {
+        if song.duration <= 0.0 {
+            return sink.lock().unwrap().empty();
+        }
+        let elapsed = self.elapsed_time(f64::MAX);
+        elapsed >= song.duration
+            && (elapsed >= song.duration + END_GRACE || sink.lock().unwrap().empty())
+    }
+
+    /// Moves on from a song that played to its end, following the advance and repeat modes.
+    ///
+    /// Songs that can't be played, or end as soon as they start, are skipped, up to
+    /// `MAX_SKIPPED_SONGS` in a row so a library of broken files doesn't loop forever. A song
+    /// that isn't the current one anymore has already been moved on from, so it's ignored.
+    ///
+    /// # Arguments
+    /// * `song_id` - The id of the finished song.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn finish_song(&mut self, song_id: Uuid, sink: &Arc<Mutex<Sink>>) {
+        if self.currently_playing_song != Some(song_id) {
+            return;
+        }
+        self.needs_redraw = true;
+        self.forget_position(song_id);
+        // Without a known duration, a song ending right away had nothing to play
+        let played_nothing = self
+            .get_song(song_id)
+            .is_some_and(|song| song.duration <= 0.0)
+            && self
+                .song_time
+                .is_none_or(|song_time| song_time.elapsed() < MIN_PLAY_TIME);
+        self.skipped_songs = if played_nothing {
+            self.skipped_songs + 1
+        } else {
+            0
+        };
+        if let Some(song) = self.find_song_by_id(song_id) {
+            song.is_playing = false;
+        }
+        self.play_after(song_id, sink);
+    }
+
+    /// Starts the song after one that finished or couldn't be played, skipping songs that can't
+    /// be played either, or stops playback.
+    ///
+    /// # Arguments
+    /// * `song_id` - The id of the song to move on from.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn play_after(&mut self, song_id: Uuid, sink: &Arc<Mutex<Sink>>) {
+        if self.advance_mode == AdvanceMode::StopAfterCurrent {
+            self.advance_mode = AdvanceMode::Continue;
+            self.stop_playback(sink);
+            return;
+        }
+
+        let mut current_id = song_id;
+        while self.skipped_songs < MAX_SKIPPED_SONGS {
+            let Some(next_id) = self.next_song_after_end(current_id) else {
+                break;
+            };
+            if self.try_start_song(next_id, sink).is_ok() {
+                if self.skipped_songs > 0 {
+                    self.set_status(format!(
+                        "Skipped {} songs that couldn't be played",
+                        self.skipped_songs
+                    ));
+                }
+                return;
+            }
+            self.skipped_songs += 1;
+            current_id = next_id;
+        }
+        self.stop_playback(sink);
+        if self.skipped_songs >= MAX_SKIPPED_SONGS {
+            self.set_status(format!(
+                "Stopped after {} songs in a row couldn't be played",
+                self.skipped_songs
+            ));
+            self.skipped_songs = 0;
+        }
+    }
+
+    /// Writes the playing song to the `now_playing_file` setting whenever it changes.
+    ///
+    /// Nothing playing empties the file. For streams, `{title}` is what's on air and
+    /// `{artist}` the station.
+    fn update_now_playing(&mut self) {
+        if self.settings.now_playing_file.is_none() {
+            return;
+        }
+        let song = self.currently_playing_song.and_then(|id| self.get_song(id));
+        let on_air = song.and_then(|song| song.stream_title.lock().unwrap().clone());
+        let written = (song.map(|song| song.id), on_air);
+        if self.now_playing_written.as_ref() == Some(&written) {
+            return;
+        }
+        let format = self
+            .settings
+            .now_playing_format
+            .as_deref()
+            .unwrap_or(DEFAULT_NOW_PLAYING_FORMAT);
+        let text = song
+            .map(|song| now_playing_text(format, song))
+            .unwrap_or_default();
+        self.now_playing_written = Some(written);
+        self.write_now_playing(&text);
+    }
+
+    /// Empties the now-playing file, so an overlay doesn't keep showing a song after quitting.
+    fn clear_now_playing(&mut self) {
+        if self.now_playing_written.is_some() {
+            self.write_now_playing("");
+        }
+    }
+
+    /// Replaces the contents of the now-playing file.
+    ///
+    /// # Arguments
+    /// * `text` - The new contents.
+    fn write_now_playing(&mut self, text: &str) {
+        let Some(path) = self.settings.now_playing_file.clone() else {
+            return;
+        };
+        if let Err(e) = write_atomically(&path, text.as_bytes()) {
+            self.set_status(format!("Couldn't write the now-playing file: {}", e));
+        }
+    }
+
+    /// Collects finished loudness measurements and asks for the playing song to be measured.
+    ///
+    /// Until its measurement is done, a song plays without correction.
+    fn update_loudness(&mut self) {
+        let Some(analyzer) = &self.loudness_analyzer else {
+            return;
+        };
+        for (path, loudness) in analyzer.results.try_iter() {
+            if let Some(loudness) = loudness {
+                for song in self.songs.iter().filter(|song| song.path == path) {
+                    *song.gain.lock().unwrap() = loudness_gain(loudness);
+                }
+            }
+            self.loudness.insert(path, loudness);
+        }
+
+        let playing_path = self
+            .currently_playing_song
+            .and_then(|id| self.songs.iter().find(|song| song.id == id))
+            .filter(|song| stream_url(&song.path).is_none())
+            .map(|song| song.path.clone());
+        if let Some(path) = playing_path {
+            if !self.loudness.contains_key(&path) && analyzer.requests.send(path.clone()).is_ok() {
+                self.loudness.insert(path, None);
+            }
+        }
+    }
+
+    /// Returns how far into the currently playing song playback is, in seconds.
+    ///
+    /// # Arguments
+    /// * `duration` - The duration of the song, used to clamp the result.
+    fn elapsed_time(&self, duration: f64) -> f64 {
+        let elapsed = self
+            .song_time
+            .unwrap_or(Instant::now())
+            .elapsed()
+            .as_secs_f64();
+        match self.paused_time {
+            Some(paused_time) => elapsed.sub(paused_time.elapsed().as_secs_f64()),
+            None => elapsed,
+        }
+        .min(duration)
+    }
+
+    /// Returns how long is left to play from the current position on, and in how many songs,
+    /// counting the current one: the rest of the play queue, or of the song list without one.
+    /// Songs of unknown length, like streams, add no time.
+    fn remaining_time(&self) -> Option<(f64, usize)> {
+        let current_id = self.currently_playing_song?;
+        let current = self.get_song(current_id)?;
+        let position = match &self.seeking {
+            Some(seek) => seek.target,
+            None => self.elapsed_time(current.duration),
+        };
+        let upcoming: Vec<f64> = if self.play_queue.first() == Some(&current_id) {
+            let durations: HashMap<Uuid, f64> = self
+                .songs
+                .iter()
+                .map(|song| (song.id, song.duration))
+                .collect();
+            self.play_queue[1..]
+                .iter()
+                .filter_map(|id| durations.get(id).copied())
+                .collect()
+        } else {
+            self.filtered_songs
+                .iter()
+                .skip_while(|song| song.id != current_id)
+                .skip(1)
+                .map(|song| song.duration)
+                .collect()
+        };
+        let left = (current.duration - position).max(0.0)
+            + upcoming
+                .iter()
+                .map(|duration| duration.max(0.0))
+                .sum::<f64>();
+        Some((left, upcoming.len() + 1))
+    }
+
+    /// Returns how long the main loop should wait for input before updating again.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn poll_interval(&self, sink: &Arc<Mutex<Sink>>) -> Duration {
+        let playing = self.currently_playing_song.is_some() && !sink.lock().unwrap().is_paused();
+        if playing || self.after_fade.is_some() {
+            let (min, max) = PROGRESS_INTERVAL_RANGE;
+            self.settings
+                .progress_interval_ms
+                .map_or(PLAYING_POLL_INTERVAL, |ms| {
+                    Duration::from_millis(ms.clamp(min, max))
+                })
+        } else {
+            IDLE_POLL_INTERVAL
+        }
+    }
+
+    /// Returns whether the screen has to be drawn again and marks it as up to date.
+    ///
+    /// Playing songs are redrawn on every update to move the progress bar, and an expired
+    /// status message is cleared with one last redraw.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn take_redraw(&mut self, sink: &Arc<Mutex<Sink>>) -> bool {
+        if self.status_message.is_some() && self.current_status().is_none() {
+            self.status_message = None;
+            self.needs_redraw = true;
+        }
+        let playing = self.currently_playing_song.is_some() && !sink.lock().unwrap().is_paused();
+        std::mem::take(&mut self.needs_redraw) || playing
+    }
+
+    /// Returns how much of the current song has been played, from 0 to 1. A finished song stays
+    /// full until the next one starts.
+    ///
+    /// # Arguments
+    /// * `duration` - The duration of the song, in seconds.
+    fn progress_ratio(&self, duration: f64) -> f64 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        // A seek in progress shows where it's going, not the old position still playing
+        if let Some(seek) = &self.seeking {
+            return (seek.target / duration).clamp(0.0, 1.0);
+        }
+        if self.song_time.is_none() {
+            return 0.0;
+        }
+        (self.elapsed_time(duration) / duration).clamp(0.0, 1.0)
+    }
+
+    /// Returns the lyrics of the currently playing song, reading them from disk only when the
+    /// playing song has changed.
+    ///
+    /// Sidecar files are preferred over lyrics embedded in the tags, since `.lrc` files are timed.
+    fn current_lyrics(&mut self) -> Option<&Lyrics> {
+        let song_id = self.currently_playing_song?;
+        if self.lyrics.as_ref().map(|(id, _)| *id) != Some(song_id) {
+            let lyrics = self.get_song(song_id).and_then(|song| {
+                Lyrics::load(&song.path).or_else(|| song.lyrics.clone().map(Lyrics::Plain))
+            });
+            self.lyrics = Some((song_id, lyrics));
+            self.lyrics_scroll = 0;
+        }
+        self.lyrics.as_ref().and_then(|(_, lyrics)| lyrics.as_ref())
+    }
+
+    /// Plays the selected song, or stops it if it is the one already playing.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn toggle_play(&mut self, sink: &Arc<Mutex<Sink>>) {
+        if let Some(selected_id) = self.selected_song_id {
+            if self
+                .filtered_songs
+                .iter()
+                .any(|song| song.id == selected_id)
+            {
+                if self.currently_playing_song.is_none()
+                    || Some(selected_id) != self.currently_playing_song
+                {
+                    // Playing a song from the list leaves the play queue, unless it's the song
+                    // the queue was left at
+                    let continues_queue = self.currently_playing_song.is_none()
+                        && self.play_queue.first() == Some(&selected_id);
+                    if !continues_queue {
+                        self.play_queue.clear();
+                        self.queue_source = None;
+                    }
+                    self.start_song(selected_id, sink);
+                } else {
+                    // Stop the currently playing song
+                    self.play_queue.clear();
+                    self.queue_source = None;
+                    self.remember_position();
+                    self.fade_out(sink, FadeEnd::Stop);
+                    self.song_time = None;
+                    self.currently_playing_song = None;
+                    self.loading = None;
+                    self.seeking = None;
+
+                    // Set is_playing field to false
+                    if let Some(song) =
+                        self.songs.iter_mut().find(|s| s.id == selected_id)
+                    {
+                        song.is_playing = false;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pauses the currently playing song, or resumes it if it is paused.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn toggle_pause(&mut self, sink: &Arc<Mutex<Sink>>) {
+        // A song still fading out to a pause counts as paused already
+        if sink.lock().unwrap().is_paused() || self.pausing() {
+            if let Some(current_id) = self.currently_playing_song {
+                self.fade_in();
+                if let Some(song) =
+                    self.songs.iter_mut().find(|s| s.id == current_id)
+                {
+                    sink.lock().unwrap().play();
+                    song.is_playing = true;
+                }
+                // Calculate elapsed time during the pause
+                if let Some(paused_at) = self.paused_time {
+                    let elapsed_during_pause = paused_at.elapsed();
+                    self.song_time =
+                        self.song_time.map(|t| t + elapsed_during_pause);
+                    self.paused_time = None;
+                }
+            }
+        } else {
+            if let Some(current_id) = self.currently_playing_song {
+                self.remember_position();
+                if let Some(song) =
+                    self.songs.iter_mut().find(|s| s.id == current_id)
+                {
+                    song.is_playing = false;
+                }
+                // The sink is paused, and the time recorded, once the song has faded out
+                self.fade_out(sink, FadeEnd::Pause);
+            }
+        }
+    }
+
+    /// Returns the volume in percent, from the `volume` setting.
+    fn volume_percent(&self) -> u32 {
+        self.settings.volume.unwrap_or(100).min(100)
+    }
+
+    /// Sets and saves the volume, unmuting the sink.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    /// * `percent` - The new volume in percent, up to 100.
+    fn set_volume(&mut self, sink: &Arc<Mutex<Sink>>, percent: u32) {
+        let percent = percent.min(100);
+        self.muted = false;
+        sink.lock().unwrap().set_volume(percent as f32 / 100.0);
+        if self.settings.volume != Some(percent) {
+            self.change_settings(|settings| settings.volume = Some(percent));
+        }
+    }
+
+    /// Silences the sink, or brings back the volume from before it was muted. Works the same
+    /// whether or not anything is playing.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn toggle_mute(&mut self, sink: &Arc<Mutex<Sink>>) {
+        self.muted = !self.muted;
+        let volume = if self.muted {
+            0.0
+        } else {
+            self.volume_percent() as f32 / 100.0
+        };
+        sink.lock().unwrap().set_volume(volume);
+    }
+
+    /// Switches between mono and stereo and saves the choice to the settings. The playing song
+    /// is opened again where it is to switch right away; a stream switches when it's next
+    /// played.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn toggle_mono(&mut self, sink: &Arc<Mutex<Sink>>) {
+        let mono = !self.settings.mono;
+        self.change_settings(|settings| settings.mono = mono);
+        self.set_status(if mono { "Mono" } else { "Stereo" }.to_string());
+
+        let Some(duration) = self
+            .currently_playing_song
+            .and_then(|id| self.get_song(id))
+            .map(|song| song.duration)
+        else {
+            return;
+        };
+        // A song still being opened is opened again once it's open, where it would start
+        let position = match (&self.loading, &self.seeking) {
+            (Some(loading), _) => loading.start_at.unwrap_or_else(|| {
+                self.currently_playing_song
+                    .and_then(|id| self.get_song(id))
+                    .and_then(|song| self.resume_positions.get(&song.path))
+                    .copied()
+                    .unwrap_or(0.0)
+            }),
+            (None, Some(seek)) => seek.target,
+            (None, None) => self.elapsed_time(duration),
+        };
+        self.seek_to(sink, position);
+    }
+
+    /// Sets and saves the left/right balance, which the playing song follows right away.
+    ///
+    /// # Arguments
+    /// * `percent` - From -100 (left only) through 0 (centered) to 100 (right only).
+    fn set_balance(&mut self, percent: i32) {
+        let percent = percent.clamp(-100, 100);
+        *self.balance.lock().unwrap() = percent as f32 / 100.0;
+        if self.settings.balance != percent {
+            self.change_settings(|settings| settings.balance = percent);
+        }
+        let label = self.balance_label();
+        self.set_status(format!(
+            "Balance: {}",
+            label.as_deref().unwrap_or("centered")
+        ));
+    }
+
+    /// Describes an off-center balance, e.g. "L20" when turned 20% to the left.
+    fn balance_label(&self) -> Option<String> {
+        match self.settings.balance {
+            0 => None,
+            percent if percent < 0 => Some(format!("L{}", -percent)),
+            percent => Some(format!("R{}", percent)),
+        }
+    }
+
+    /// Returns how long songs fade in and out, from the `fade_ms` setting, up to `MAX_FADE`.
+    fn fade_duration(&self) -> Duration {
+        self.settings
+            .fade_ms
+            .map_or(DEFAULT_FADE, |ms| Duration::from_millis(ms).min(MAX_FADE))
+    }
+
+    /// Starts fading the playing song out, to pause or stop it once it's silent. The fade runs
+    /// on the audio thread and [`MyApp::update_fade`] finishes it; while paused or with nothing
+    /// to play it finishes right away.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    /// * `then` - Whether to pause or stop once faded out.
+    fn fade_out(&mut self, sink: &Arc<Mutex<Sink>>, then: FadeEnd) {
+        let silent = {
+            let sink = sink.lock().unwrap();
+            sink.is_paused() || sink.empty()
+        };
+        let duration = if silent {
+            Duration::ZERO
+        } else {
+            self.fade_duration()
+        };
+        {
+            let mut fade = self.fade.lock().unwrap();
+            *fade = fade.toward(0.0, duration);
+        }
+        self.after_fade = Some(then);
+        self.update_fade(sink);
+    }
+
+    /// Fades the playing song back in from wherever a fade out got to.
+    fn fade_in(&mut self) {
+        self.after_fade = None;
+        let mut fade = self.fade.lock().unwrap();
+        *fade = fade.toward(1.0, self.fade_duration());
+    }
+
+    /// Drops any fade in progress, so the next song plays at full volume right away.
+    fn reset_fade(&mut self) {
+        self.after_fade = None;
+        *self.fade.lock().unwrap() = Fade::steady(1.0);
+    }
+
+    /// Returns whether the playing song is fading out to a pause.
+    fn pausing(&self) -> bool {
+        self.after_fade == Some(FadeEnd::Pause)
+    }
+
+    /// Pauses or stops the sink once a fade out has ended.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn update_fade(&mut self, sink: &Arc<Mutex<Sink>>) {
+        let Some(then) = self.after_fade else {
+            return;
+        };
+        if !self.fade.lock().unwrap().finished() {
+            return;
+        }
+        self.after_fade = None;
+        match then {
+            FadeEnd::Pause => {
+                sink.lock().unwrap().pause();
+                // Record the time when playback was paused
+                self.paused_time = Some(Instant::now());
+            }
+            FadeEnd::Stop => {
+                sink.lock().unwrap().clear();
+                *self.fade.lock().unwrap() = Fade::steady(1.0);
+            }
+        }
+        self.needs_redraw = true;
+    }
+
+    /// Starts playing a song from the beginning, replacing whatever was playing.
+    ///
+    /// # Arguments
+    /// * `song_id` - The song to play.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn start_song(&mut self, song_id: Uuid, sink: &Arc<Mutex<Sink>>) {
+        if let Some(e) = &self.audio_error {
+            self.set_status(format!(
+                "No audio output device, can't play anything: {}",
+                e
+            ));
+            return;
+        }
+        self.skipped_songs = 0;
+        if let Err(e) = self.try_start_song(song_id, sink) {
+            let title = self
+                .get_song(song_id)
+                .map_or("", |song| song.title.as_str());
+            self.set_status(format!("Couldn't play {}: {}", title, e));
+        }
+    }
+
+    /// Starts playing a song from the beginning, replacing whatever was playing. If the song
+    /// can't be played, playback stops.
+    ///
+    /// # Arguments
+    /// * `song_id` - The song to play.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn try_start_song(&mut self, song_id: Uuid, sink: &Arc<Mutex<Sink>>) -> io::Result<()> {
+        let Some(song) = self.get_song(song_id).cloned() else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "song not found"));
+        };
+        self.remember_position();
+        let previous_id = self.currently_playing_song;
+        if let Some(previous_song) = previous_id.and_then(|id| self.find_song_by_id(id)) {
+            previous_song.is_playing = false;
+        }
+
+        self.counted_as_played = false;
+        self.loading = None;
+        self.seeking = None;
+        self.reset_fade();
+        {
+            let sink = sink.lock().unwrap();
+            sink.clear();
+            sink.play();
+        }
+
+        // Open the song in the background, so the interface can show that it's loading if
+        // that takes a while
+        let fade_in = self.fade_duration();
+        let mono = self.settings.mono;
+        let balance = self.balance.clone();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || sender.send(song.open(fade_in, mono, balance)));
+        let opened = match receiver.recv_timeout(LOADING_WAIT) {
+            Ok(opened) => Some(opened),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Some(Err(io::Error::other("the decoder stopped")))
+            }
+        };
+        if let Some(Err(e)) = opened {
+            self.stop_playback(sink);
+            return Err(e);
+        }
+
+        self.currently_playing_song = Some(song_id);
+        self.selected_song_id = Some(song_id);
+        self.song_time = None;
+        self.paused_time = None;
+        if let Some(song) = self.find_song_by_id(song_id) {
+            song.is_playing = true;
+        }
+        match opened {
+            Some(Ok(source)) => self.begin_playback(source, None, sink),
+            _ => {
+                self.loading = Some(LoadingSong {
+                    id: song_id,
+                    started: Instant::now(),
+                    receiver,
+                    start_at: None,
+                });
+                self.needs_redraw = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Plays the opened current song from where it was seeked to while loading, or else
+    /// continuing where it was left off if its position was saved. A song paused while it was
+    /// loading stays paused.
+    ///
+    /// # Arguments
+    /// * `source` - The decoded song.
+    /// * `start_at` - Where the song was seeked to while loading, in seconds.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn begin_playback(
+        &mut self,
+        source: SongSource,
+        start_at: Option<f64>,
+        sink: &Arc<Mutex<Sink>>,
+    ) {
+        let paused = {
+            let sink = sink.lock().unwrap();
+            sink.append(with_fade(source, self.fade.clone()));
+            sink.is_paused()
+        };
+        let now = Instant::now();
+        self.song_time = Some(now);
+        self.paused_time = paused.then_some(now);
+        if let Some(position) = start_at {
+            self.seek_to(sink, position);
+            return;
+        }
+        let position = self
+            .currently_playing_song
+            .and_then(|id| self.get_song(id))
+            .and_then(|song| self.resume_positions.get(&song.path))
+            .copied();
+        if let Some(position) = position {
+            self.seek_to(sink, position);
+            self.set_status(format!("Resumed at {}", format_time(position)));
+        }
+    }
+
+    /// Plays the current song once it has been opened in the background, or moves on to the
+    /// next one if it couldn't be.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn update_loading(&mut self, sink: &Arc<Mutex<Sink>>) {
+        let Some(loading) = &self.loading else {
+            return;
+        };
+        let song_id = loading.id;
+        let start_at = loading.start_at;
+        let opened = match loading.receiver.try_recv() {
+            Ok(opened) => opened,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => Err(io::Error::other("the decoder stopped")),
+        };
+        self.loading = None;
+        self.needs_redraw = true;
+        if self.currently_playing_song != Some(song_id) {
+            return;
+        }
+        match opened {
+            Ok(source) => self.begin_playback(source, start_at, sink),
+            Err(e) => {
+                let title = self
+                    .get_song(song_id)
+                    .map_or("", |song| song.title.as_str());
+                self.set_status(format!("Couldn't play {}: {}", title, e));
+                if let Some(song) = self.find_song_by_id(song_id) {
+                    song.is_playing = false;
+                }
+                self.skipped_songs += 1;
+                self.play_after(song_id, sink);
+            }
+        }
+    }
+
+    /// Returns a spinner and "Loading" while the current song is being opened, or "Seeking"
+    /// and the new position while a seek is on its way, turning a little further every tenth
+    /// of a second.
+    fn loading_indicator(&self) -> Option<String> {
+        let (started, action) = match (&self.loading, &self.seeking) {
+            (Some(loading), _) => (loading.started, "Loading…".to_string()),
+            (None, Some(seek)) => (
+                seek.started,
+                format!("Seeking to {}…", format_time(seek.target)),
+            ),
+            (None, None) => return None,
+        };
+        let frame = (started.elapsed().as_millis() / 100) as usize % SPINNER.len();
+        Some(format!("{} {}", SPINNER[frame], action))
+    }
+
+    /// Returns how long songs must be for their position to be remembered, from the
+    /// `resume_after_minutes` setting.
+    fn resume_threshold(&self) -> f64 {
+        self.settings.resume_after_minutes.unwrap_or(20) as f64 * 60.0
+    }
+
+    /// Remembers where the playing song is if it's long enough to resume later, or forgets
+    /// its position when it's near the start or end, and saves the positions.
+    ///
+    /// Paused songs were remembered when they were paused, and songs still loading haven't
+    /// moved from where they were. During a seek the position isn't known yet.
+    fn remember_position(&mut self) {
+        let Some(song) = self.currently_playing_song.and_then(|id| self.get_song(id)) else {
+            return;
+        };
+        if self.loading.is_some()
+            || self.seeking.is_some()
+            || !song.is_playing
+            || song.duration <= 0.0
+            || song.duration < self.resume_threshold()
+        {
+            return;
+        }
+        let position = self.elapsed_time(song.duration);
+        let path = song.path.clone();
+        let changed = if position < RESUME_MARGIN || position > song.duration - RESUME_MARGIN {
+            self.resume_positions.remove(&path).is_some()
+        } else {
+            self.resume_positions.insert(path, position);
+            true
+        };
+        if changed {
+            self.save_resume_positions();
+        }
+        self.position_saved_at = Some(Instant::now());
+    }
+
+    /// Forgets where a song was, once it played to its end.
+    ///
+    /// # Arguments
+    /// * `song_id` - The song that finished.
+    fn forget_position(&mut self, song_id: Uuid) {
+        let Some(path) = self.get_song(song_id).map(|song| song.path.clone()) else {
+            return;
+        };
+        if self.resume_positions.remove(&path).is_some() {
+            self.save_resume_positions();
+        }
+    }
+
+    /// Writes the positions of long songs to their file, if there is one.
+    fn save_resume_positions(&mut self) {
+        let Some(path) = &self.resume_positions_file else {
+            return;
+        };
+        let saved = serde_json::to_string(&self.resume_positions)
+            .map_err(io::Error::from)
+            .and_then(|serialized| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                write_atomically(path, serialized.as_bytes())
+            });
+        if let Err(e) = saved {
+            self.set_status(format!("Couldn't save the playback positions: {}", e));
+        }
+    }
+
+    /// Writes the play queue to its file, if there is one.
+    fn save_play_queue(&mut self) {
+        let Some(path) = &self.play_queue_file else {
+            return;
+        };
+        let queue = SavedQueue {
+            source: self.queue_source.clone(),
+            songs: self
+                .play_queue
+                .iter()
+                .filter_map(|id| self.get_song(*id))
+                .map(|song| song.path.clone())
+                .collect(),
+        };
+        let saved = serde_json::to_string(&queue)
+            .map_err(io::Error::from)
+            .and_then(|serialized| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                write_atomically(path, serialized.as_bytes())
+            });
+        if let Err(e) = saved {
+            self.set_status(format!("Couldn't save the play queue: {}", e));
+        }
+    }
+
+    /// Restores the play queue saved in a file, leaving out songs that are no longer in the
+    /// library, and selects the song it was left at so playing it continues the queue.
+    ///
+    /// # Arguments
+    /// * `path` - The file holding the play queue.
+    fn load_play_queue(&mut self, path: &Path) -> io::Result<()> {
+        let queue: SavedQueue = serde_json::from_reader(File::open(path)?)?;
+        self.play_queue = queue
+            .songs
+            .iter()
+            .filter_map(|path| self.songs.iter().find(|song| song.path == *path))
+            .map(|song| song.id)
+            .collect();
+        self.queue_source = queue
+            .source
+            .filter(|name| self.playlists.contains_key(name));
+        if let Some(&first_id) = self.play_queue.first() {
+            self.selected_song_id = Some(first_id);
+            let count = self.format_count(self.play_queue.len());
+            self.set_status(format!("Restored the play queue ({} songs)", count));
+        }
+        Ok(())
+    }
+
+    /// Loads the positions of long songs saved in a file.
+    ///
+    /// # Arguments
+    /// * `path` - The file holding the positions.
+    fn load_resume_positions(&mut self, path: &Path) -> io::Result<()> {
+        self.resume_positions = serde_json::from_reader(File::open(path)?)?;
+        Ok(())
+    }
+
+    /// Decides which song plays after `current_id` finishes, following the repeat mode.
+    /// Returns `None` when playback should stop.
+    ///
+    /// In the play queue, repeating everything cycles through the queue, while with repeat off
+    /// finished songs leave the queue. Without a queue, `filtered_songs` is played in order.
+    ///
+    /// # Arguments
+    /// * `current_id` - The song that just finished.
+    fn next_song_after_end(&mut self, current_id: Uuid) -> Option<Uuid> {
+        let in_queue = self.play_queue.first() == Some(&current_id);
+        match (self.repeat_mode, in_queue) {
+            (RepeatMode::One, _) => Some(current_id),
+            (RepeatMode::All, true) => self.advance_queue(true),
+            (RepeatMode::Off, true) => {
+                self.play_queue.remove(0);
+                self.play_queue.first().copied()
+            }
+            (repeat_mode, false) => {
+                let position = self
+                    .filtered_songs
+                    .iter()
+                    .position(|song| song.id == current_id);
+                let next_index = match position {
+                    Some(index) if index + 1 < self.filtered_songs.len() => index + 1,
+                    Some(_) if repeat_mode == RepeatMode::Off => return None,
+                    _ => 0,
+                };
+                self.filtered_songs.get(next_index).map(|song| song.id)
+            }
+        }
+    }
+
+    /// Cycles the repeat mode between off, the current song and everything.
+    fn cycle_repeat_mode(&mut self) {
+        self.repeat_mode = self.repeat_mode.next();
+        self.set_status(format!("Repeat: {}", self.repeat_mode));
+    }
+
+    /// Stops playback, leaving no song playing.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn stop_playback(&mut self, sink: &Arc<Mutex<Sink>>) {
+        self.loading = None;
+        self.seeking = None;
+        self.reset_fade();
+        sink.lock().unwrap().clear();
+        if let Some(song) = self
+            .currently_playing_song
+            .and_then(|song_id| self.songs.iter_mut().find(|song| song.id == song_id))
+        {
+            song.is_playing = false;
+        }
+        self.currently_playing_song = None;
+        self.song_time = None;
+        self.paused_time = None;
+    }
+
+    /// Returns the glyphs shown in the footer for the active playback modes.
+    fn mode_indicators(&self) -> String {
+        let repeat = match self.repeat_mode {
+            RepeatMode::Off => None,
+            RepeatMode::One => Some("↻1"),
+            RepeatMode::All => Some("↻"),
+        };
+        let stop = match self.advance_mode {
+            AdvanceMode::Continue => None,
+            AdvanceMode::StopAfterCurrent => Some("⏹"),
+        };
+        [repeat, stop].into_iter().flatten().collect::<Vec<_>>().join(" ")
+    }
+
+    /// Toggles stopping once the current song ends.
+    fn toggle_stop_after_current(&mut self) {
+        self.advance_mode = match self.advance_mode {
+            AdvanceMode::Continue => AdvanceMode::StopAfterCurrent,
+            AdvanceMode::StopAfterCurrent => AdvanceMode::Continue,
+        };
+        let message = match self.advance_mode {
+            AdvanceMode::Continue => "Playback continues after the current song",
+            AdvanceMode::StopAfterCurrent => "Playback stops after the current song",
+        };
+        self.set_status(message.to_string());
+    }
+
+    /// Moves the play queue one song forward or back, wrapping around so the whole queue
+    /// repeats, and returns the song to play next.
+    ///
+    /// Returns `None` if the current song isn't playing from the queue.
+    ///
+    /// # Arguments
+    /// * `forward` - Whether to move to the next song rather than the previous one.
+    fn advance_queue(&mut self, forward: bool) -> Option<Uuid> {
+        let current_id = self.currently_playing_song?;
+        if self.play_queue.first() != Some(&current_id) {
+            return None;
+        }
+        if forward {
+            self.play_queue.rotate_left(1);
+        } else {
+            self.play_queue.rotate_right(1);
+        }
+        self.play_queue.first().copied()
+    }
+
+    /// Replaces the play queue with the songs of the selected playlist, in their stored order,
+    /// and starts playing the first one.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn play_selected_playlist(&mut self, sink: &Arc<Mutex<Sink>>) {
+        let Some((name, song_ids)) = self
+            .selected_playlist_name()
+            .and_then(|name| self.playlists.get_key_value(name))
+        else {
+            return;
+        };
+        let queue: Vec<Uuid> = song_ids
+            .iter()
+            .filter(|id| self.songs.iter().any(|song| song.id == **id))
+            .copied()
+            .collect();
+        let name = name.clone();
+
+        let Some(&first_id) = queue.first() else {
+            self.set_status(format!("Playlist \"{}\" has no songs to play", name));
+            return;
+        };
+        let count = self.format_count(queue.len());
+        self.set_status(format!("Playing \"{}\" ({} songs)", name, count));
+        self.play_queue = queue;
+        self.queue_source = Some(name);
+        self.start_song(first_id, sink);
+    }
+
+    /// Selects and plays a random song from the song list, leaving the play queue.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    /// * `whole_library` - Pick from all songs except streams instead; a song that isn't in the
+    ///   list shows "All Songs" without a search so it can be selected.
+    /// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+    fn play_random_song(
+        &mut self,
+        sink: &Arc<Mutex<Sink>>,
+        whole_library: bool,
+        visible_playlist_count: usize,
+    ) {
+        let candidates: Vec<Uuid> = if whole_library {
+            self.songs
+                .iter()
+                .filter(|song| stream_url(&song.path).is_none())
+                .map(|song| song.id)
+                .collect()
+        } else {
+            self.filtered_songs.iter().map(|song| song.id).collect()
+        };
+        let Some(&song_id) = candidates.choose(&mut rand::thread_rng()) else {
+            return;
+        };
+
+        if !self.filtered_songs.iter().any(|song| song.id == song_id) {
+            self.search_text.clear();
+            self.search_cursor = 0;
+            if let Some(index) = self
+                .playlist_names()
+                .iter()
+                .position(|name| *name == ALL_SONGS_PLAYLIST)
+            {
+                self.select_playlist_at(index, visible_playlist_count);
+            }
+            self.filter_songs();
+        }
+        self.select_song(song_id);
+        self.play_queue.clear();
+        self.queue_source = None;
+        self.start_song(song_id, sink);
+    }
+
+    /// Moves the selection in the queue view, skipping the current song at the top.
+    ///
+    /// # Arguments
+    /// * `down` - Whether to move towards the end of the queue.
+    fn select_queue_entry(&mut self, down: bool) {
+        let last_index = self.play_queue.len().saturating_sub(1).max(1);
+        self.queue_selected = if down {
+            (self.queue_selected + 1).min(last_index)
+        } else {
+            self.queue_selected.saturating_sub(1).max(1)
+        };
+    }
+
+    /// Moves the selected upcoming song one place up or down in the play queue. The current
+    /// song always stays at the top.
+    ///
+    /// # Arguments
+    /// * `down` - Whether to move the song towards the end of the queue.
+    fn move_queue_entry(&mut self, down: bool) {
+        let index = self.queue_selected;
+        if index == 0 || index >= self.play_queue.len() {
+            return;
+        }
+        let other = if down { index + 1 } else { index - 1 };
+        if other == 0 || other >= self.play_queue.len() {
+            return;
+        }
+        self.play_queue.swap(index, other);
+        self.queue_selected = other;
+    }
+
+    /// Removes the selected upcoming song from the play queue; the current song can't be removed.
+    fn remove_queue_entry(&mut self) {
+        let index = self.queue_selected;
+        if index == 0 || index >= self.play_queue.len() {
+            return;
+        }
+        self.play_queue.remove(index);
+        self.queue_selected = index.min(self.play_queue.len().saturating_sub(1)).max(1);
+    }
+
+    /// Plays the song before the current one in the play queue, or in `filtered_songs`
+    /// without one. A song that has played for longer than `restart_after_seconds` is
+    /// restarted instead, so pressing again goes back.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn play_previous(&mut self, sink: &Arc<Mutex<Sink>>) {
+        let restart_after = self
+            .settings
+            .restart_after_seconds
+            .unwrap_or(DEFAULT_RESTART_AFTER);
+        let seekable = self
+            .currently_playing_song
+            .and_then(|id| self.get_song(id))
+            .is_some_and(|song| song.duration > 0.0);
+        if seekable && restart_after > 0.0 && self.elapsed_time(f64::MAX) > restart_after {
+            self.seek_to(sink, 0.0);
+            return;
+        }
+        if let Some(previous_id) = self.advance_queue(false) {
+            self.start_song(previous_id, sink);
+            return;
+        }
+        if let Some(current_id) = self.currently_playing_song {
+            if let Some(current_index) = self
+                .filtered_songs
+                .iter()
+                .position(|song| song.id == current_id)
+            {
+                if current_index > 0 {
+                    let previous_id = self.filtered_songs[current_index - 1].id;
+                    self.start_song(previous_id, sink);
+                }
+            }
+        }
+    }
+
+    /// Plays the song after the current one in the play queue, or in `filtered_songs`
+    /// without one.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn play_next(&mut self, sink: &Arc<Mutex<Sink>>) {
+        if let Some(next_id) = self.advance_queue(true) {
+            self.start_song(next_id, sink);
+            return;
+        }
+        if let Some(current_id) = self.currently_playing_song {
+            if let Some(current_index) = self
+                .filtered_songs
+                .iter()
+                .position(|song| song.id == current_id)
+            {
+                if current_index < self.filtered_songs.len() - 1 {
+                    let next_id = self.filtered_songs[current_index + 1].id;
+                    self.start_song(next_id, sink);
+                }
+            }
+        }
+    }
+
+    /// Moves the playback position of the current song. A paused song stays paused at the
+    /// new position.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    /// * `seconds` - How far to seek; negative values seek backwards, at most to the start.
+    fn seek_by(&mut self, sink: &Arc<Mutex<Sink>>, seconds: i64) {
+        if let Some(current_song) = self.currently_playing_song.and_then(|id| self.get_song(id)) {
+            // Seeking again before a seek is done goes on from where that one is going
+            let position = match &self.seeking {
+                Some(seek) => seek.target,
+                None => self.elapsed_time(current_song.duration),
+            };
+            self.seek_to(sink, position + seconds as f64);
+        }
+    }
+
+    /// Moves the playback position of the current song to a point in it. A paused song stays
+    /// paused at the new position.
+    ///
+    /// Getting to the new position can take a moment in long songs; until then the song keeps
+    /// playing where it was and the progress bar shows it as seeking. A newer seek replaces
+    /// one that isn't done yet. A song that is still loading starts at the new position.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    /// * `target` - The new position, in seconds from the start of the song.
+    fn seek_to(&mut self, sink: &Arc<Mutex<Sink>>, target: f64) {
+        // A song still being opened starts at the new position once it's open
+        if let Some(loading) = &mut self.loading {
+            loading.start_at = Some(target);
+            return;
+        }
+        let Some(current_song) = self.currently_playing_song.and_then(|id| self.get_song(id))
+        else {
+            return;
+        };
+        // Streams without a duration can't seek
+        let duration = current_song.duration;
+        if duration <= 0.0 {
+            return;
+        }
+        let current_id = current_song.id;
+        // Seeking past the end finishes the song, like playing to the end would
+        if target >= duration {
+            self.seeking = None;
+            self.finish_song(current_id, sink);
+            return;
+        }
+        let target = target.max(0.0);
+
+        let song = current_song.clone();
+        let mono = self.settings.mono;
+        let balance = self.balance.clone();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            sender.send(song.open_at(Duration::from_secs_f64(target), mono, balance))
+        });
+        let seek = PendingSeek {
+            id: current_id,
+            target,
+            started: Instant::now(),
+            receiver,
+        };
+        match seek.receiver.recv_timeout(LOADING_WAIT) {
+            Ok(opened) => self.finish_seek(target, opened, sink),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.seeking = Some(seek);
+                self.needs_redraw = true;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                self.finish_seek(target, Err(io::Error::other("the decoder stopped")), sink)
+            }
+        }
+    }
+
+    /// Switches to the new position once a seek in the background has got there.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn update_seek(&mut self, sink: &Arc<Mutex<Sink>>) {
+        let Some(seek) = &self.seeking else {
+            return;
+        };
+        let opened = match seek.receiver.try_recv() {
+            Ok(opened) => opened,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => Err(io::Error::other("the decoder stopped")),
+        };
+        let (song_id, target) = (seek.id, seek.target);
+        if self.currently_playing_song == Some(song_id) {
+            self.finish_seek(target, opened, sink);
+        } else {
+            self.seeking = None;
+        }
+    }
+
+    /// Plays the current song from the position a seek got to, or reports why it couldn't
+    /// and leaves it playing where it was.
+    ///
+    /// # Arguments
+    /// * `target` - The new position, in seconds from the start of the song.
+    /// * `opened` - The song decoded up to `target`.
+    /// * `sink` - The `Sink` the songs are played through.
+    fn finish_seek(
+        &mut self,
+        target: f64,
+        opened: io::Result<SongSource>,
+        sink: &Arc<Mutex<Sink>>,
+    ) {
+        self.seeking = None;
+        self.needs_redraw = true;
+        let source = match opened {
+            Ok(source) => source,
+            Err(e) => {
+                let title = self
+                    .currently_playing_song
+                    .and_then(|id| self.get_song(id))
+                    .map_or("", |song| song.title.as_str());
+                self.set_status(format!("Couldn't seek in {}: {}", title, e));
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        self.song_time = Some(now - Duration::from_secs_f64(target));
+        let sink = sink.lock().unwrap();
+        let paused = sink.is_paused();
+        sink.clear();
+        sink.append(with_fade(source, self.fade.clone()));
+        if paused {
+            // Restart the pause at the new position
+            self.paused_time = Some(now);
+        } else {
+            sink.play();
+        }
+    }
+
+    /// Shows a message in the status line.
+    ///
+    /// # Arguments
+    /// * `message` - The message to show.
+    fn set_status(&mut self, message: String) {
+        self.status_message = Some((message, Instant::now()));
+    }
+
+    /// Returns the status message, if it hasn't expired yet.
+    fn current_status(&self) -> Option<&str> {
+        match &self.status_message {
+            Some((message, set_at)) if set_at.elapsed() < STATUS_DURATION => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Appends pasted text to the active input field, either the playlist name or the search bar.
+    ///
+    /// # Arguments
+    /// * `text` - The pasted text; control characters and newlines are dropped.
+    fn paste(&mut self, text: &str) {
+        for c in text.chars().filter(|c| !c.is_control()) {
+            self.insert_char(c);
+        }
+    }
+
+    /// Types a character into the active input field, at the cursor for the search bar.
+    ///
+    /// # Arguments
+    /// * `c` - The character to insert.
+    fn insert_char(&mut self, c: char) {
+        if self.input_popup.visible {
+            self.input_text.push(c);
+        } else {
+            let index = self.search_byte_index(self.search_cursor);
+            self.search_text.insert(index, c);
+            self.search_cursor += 1;
+        }
+    }
+
+    /// Deletes the character before the cursor in the active input field.
+    fn delete_char_before_cursor(&mut self) {
+        if self.input_popup.visible {
+            self.input_text.pop();
+        } else if self.search_cursor > 0 {
+            self.search_cursor -= 1;
+            let index = self.search_byte_index(self.search_cursor);
+            self.search_text.remove(index);
+        }
+    }
+
+    /// Returns whether a popup that isn't typed into is open, such as the play queue or the
+    /// bookmarks.
+    fn list_popup_open(&self) -> bool {
+        [
+            &self.hint_popup_state,
+            &self.queue_popup,
+            &self.bookmarks_popup,
+            &self.scan_log_popup,
+            &self.lyrics_popup,
+        ]
+        .iter()
+        .any(|popup| popup.visible)
+    }
+
+    /// Clears the text of the input popup, or else the search.
+    fn clear_input(&mut self) {
+        if self.input_popup.visible {
+            self.input_text.clear();
+        } else if !self.list_popup_open() {
+            self.search_text.clear();
+            self.search_cursor = 0;
+        }
+    }
+
+    /// Deletes the word before the cursor, and the spaces after it, in the input popup or the
+    /// focused search bar.
+    fn delete_word_before_cursor(&mut self) {
+        let word_start = |before: &str| {
+            before
+                .trim_end()
+                .char_indices()
+                .rev()
+                .find(|(_, c)| c.is_whitespace())
+                .map_or(0, |(index, c)| index + c.len_utf8())
+        };
+        if self.input_popup.visible {
+            let start = word_start(&self.input_text);
+            self.input_text.truncate(start);
+        } else if self.focus == Focus::Search && !self.list_popup_open() {
+            let end = self.search_byte_index(self.search_cursor);
+            let start = word_start(&self.search_text[..end]);
+            self.search_cursor -= self.search_text[start..end].chars().count();
+            self.search_text.replace_range(start..end, "");
+        }
+    }
+
+    /// Deletes the character under the cursor in the search bar.
+    fn delete_char_at_cursor(&mut self) {
+        if self.search_cursor < self.search_text.chars().count() {
+            let index = self.search_byte_index(self.search_cursor);
+            self.search_text.remove(index);
+        }
+    }
+
+    /// Moves the search cursor, keeping it within the search text.
+    ///
+    /// # Arguments
+    /// * `position` - The new cursor position, in characters.
+    fn move_search_cursor(&mut self, position: usize) {
+        self.search_cursor = position.min(self.search_text.chars().count());
+    }
+
+    /// Moves the focus, remembering the search when leaving the search bar.
+    ///
+    /// # Arguments
+    /// * `focus` - The area to focus.
+    fn set_focus(&mut self, focus: Focus) {
+        if self.focus == Focus::Search && focus != Focus::Search {
+            self.remember_search();
+        }
+        self.focus = focus;
+    }
+
+    /// Adds the search text to the search history and saves it, skipping empty searches and
+    /// repeats of the latest one.
+    fn remember_search(&mut self) {
+        self.search_history_index = None;
+        let search = self.search_text.trim();
+        if search.is_empty() || self.search_history.last().is_some_and(|last| last == search) {
+            return;
+        }
+        self.search_history.push(search.to_string());
+        let excess = self.search_history.len().saturating_sub(SEARCH_HISTORY_LIMIT);
+        self.search_history.drain(..excess);
+        if let Some(path) = &self.search_history_file {
+            if let Err(e) = save_search_history(path, &self.search_history) {
+                self.set_status(format!("Couldn't save the search history: {}", e));
+            }
+        }
+    }
+
+    /// Replaces the search text with an older or newer search from the history. Going past the
+    /// newest search brings back the text typed before recalling.
+    ///
+    /// # Arguments
+    /// * `older` - Whether to recall the search before the one shown, or else the one after it.
+    fn recall_search(&mut self, older: bool) {
+        let index = match (self.search_history_index, older) {
+            (None, true) if !self.search_history.is_empty() => {
+                self.search_draft = self.search_text.clone();
+                Some(self.search_history.len() - 1)
+            }
+            (None, _) => return,
+            (Some(index), true) => Some(index.saturating_sub(1)),
+            (Some(index), false) if index + 1 < self.search_history.len() => Some(index + 1),
+            (Some(_), false) => None,
+        };
+        self.search_history_index = index;
+        self.search_text = match index {
+            Some(index) => self.search_history[index].clone(),
+            None => std::mem::take(&mut self.search_draft),
+        };
+        self.move_search_cursor(usize::MAX);
+    }
+
+    /// Converts a character position in the search text to a byte index.
+    fn search_byte_index(&self, position: usize) -> usize {
+        self.search_text
+            .char_indices()
+            .nth(position)
+            .map_or(self.search_text.len(), |(index, _)| index)
+    }
+
+    /// Selects the next song in `filtered_songs`, wrapping around at the end.
+    ///
+    /// # Arguments
+    /// * `visible_song_count` - Number of rows visible in the song list.
+    fn select_next_song(&mut self, visible_song_count: usize) {
+        let next_index = match (self.selected_song_id, self.selected_song_index()) {
+            (Some(_), Some(index)) if index + 1 < self.filtered_songs.len() => index + 1,
+            // Wrap around to the beginning
+            (Some(_), Some(_)) | (None, _) => 0,
+            (Some(_), None) => return,
+        };
+        self.select_song_at(next_index, visible_song_count);
+    }
+
+    /// Selects the previous song in `filtered_songs`, wrapping around at the start.
+    ///
+    /// # Arguments
+    /// * `visible_song_count` - Number of rows visible in the song list.
+    fn select_previous_song(&mut self, visible_song_count: usize) {
+        let previous_index = match (self.selected_song_id, self.selected_song_index()) {
+            (Some(_), Some(index)) if index > 0 => index - 1,
+            // Wrap around to the last song
+            (Some(_), Some(_)) | (None, _) => usize::MAX,
+            (Some(_), None) => return,
+        };
+        self.select_song_at(previous_index, visible_song_count);
+    }
+
+    /// Selects the next playlist, wrapping around at the end.
+    ///
+    /// # Arguments
+    /// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+    fn select_next_playlist(&mut self, visible_playlist_count: usize) {
+        // Move playlist selection down
+        if self.selected_playlist_index < self.playlists.len() - 1 {
+            self.selected_playlist_index += 1;
+            if self.selected_playlist_index + 1
+                >= self.playlist_list_offset + visible_playlist_count
+            {
+                self.playlist_list_offset = (self.selected_playlist_index + 2)
+                    .saturating_sub(visible_playlist_count);
+
+                // Ensure the playlist_list_offset does not exceed the maximum allowed offset
+                self.playlist_list_offset = self.playlist_list_offset.min(
+                    self.playlists.len().saturating_sub(visible_playlist_count),
+                );
+            }
+        } else {
+            self.selected_playlist_index = 0;
+            self.playlist_list_offset = 0;
+        }
+        self.selected_song_id = None;
+    }
+
+    /// Selects the previous playlist, wrapping around at the start.
+    ///
+    /// # Arguments
+    /// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+    fn select_previous_playlist(&mut self, visible_playlist_count: usize) {
+        // Move playlist selection up
+        if self.selected_playlist_index > 0 {
+            self.selected_playlist_index -= 1;
+
+            // Scroll up if selected index goes out of view
+            if self.selected_playlist_index <= self.playlist_list_offset + 1 {
+                self.playlist_list_offset =
+                    self.playlist_list_offset.saturating_sub(1);
+            }
+        } else {
+            self.selected_playlist_index = self.playlists.len() - 1;
+            self.playlist_list_offset =
+                self.playlists.len().saturating_sub(visible_playlist_count);
+        }
+        self.selected_song_id = None;
+    }
+
+    /// Selects the song at `index` in `filtered_songs`, scrolling just enough to keep it visible.
+    ///
+    /// # Arguments
+    /// * `index` - Position in `filtered_songs`; clamped to the last song.
+    /// * `visible_song_count` - Number of rows visible in the song list.
+    fn select_song_at(&mut self, index: usize, visible_song_count: usize) {
+        let Some(last_index) = self.filtered_songs.len().checked_sub(1) else {
+            return;
+        };
+        let index = index.min(last_index);
+        self.selected_song_id = Some(self.filtered_songs[index].id);
+        // Without a configured margin the selection is kept centered
+        let margin = self.settings.scroll_margin.unwrap_or(usize::MAX);
+        self.list_offset = scroll_offset_to_show(
+            index,
+            self.list_offset,
+            visible_song_count,
+            self.filtered_songs.len(),
+            margin,
+        );
+    }
+
+    /// Returns the position of the selected song in `filtered_songs`.
+    fn selected_song_index(&self) -> Option<usize> {
+        let selected_id = self.selected_song_id?;
+        self.filtered_songs
+            .iter()
+            .position(|song| song.id == selected_id)
+    }
+
+    /// Selects the playlist at `index`, scrolling just enough to keep it visible.
+    ///
+    /// # Arguments
+    /// * `index` - Position in the playlist pane; clamped to the last playlist.
+    /// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+    fn select_playlist_at(&mut self, index: usize, visible_playlist_count: usize) {
+        let Some(last_index) = self.playlists.len().checked_sub(1) else {
+            return;
+        };
+        self.selected_playlist_index = index.min(last_index);
+        self.playlist_list_offset = scroll_offset_to_show(
+            self.selected_playlist_index,
+            self.playlist_list_offset,
+            visible_playlist_count,
+            self.playlists.len(),
+            0,
+        );
+        self.selected_song_id = None;
+    }
+
+    /// Switches between "All Songs" and the playlist last viewed before it.
+    ///
+    /// # Arguments
+    /// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+    fn toggle_all_songs(&mut self, visible_playlist_count: usize) {
+        let target = match self.selected_playlist_name().cloned() {
+            Some(name) if name == ALL_SONGS_PLAYLIST => {
+                let last_playlist = self
+                    .last_playlist
+                    .clone()
+                    .filter(|name| self.playlists.contains_key(name));
+                let Some(last_playlist) = last_playlist else {
+                    self.set_status("No other playlist viewed yet".to_string());
+                    return;
+                };
+                last_playlist
+            }
+            name => {
+                self.last_playlist = name.or(self.last_playlist.take());
+                ALL_SONGS_PLAYLIST.to_string()
+            }
+        };
+        if let Some(index) = self
+            .playlist_names()
+            .iter()
+            .position(|name| **name == target)
+        {
+            self.select_playlist_at(index, visible_playlist_count);
+        }
+    }
+
+    /// Selects the playlist the play queue was started from, and the playing song in it.
+    ///
+    /// # Arguments
+    /// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+    fn select_queue_source(&mut self, visible_playlist_count: usize) {
+        let Some(source) = self.queue_source.clone() else {
+            self.set_status("The play queue wasn't started from a playlist".to_string());
+            return;
+        };
+        let Some(index) = self
+            .playlist_names()
+            .iter()
+            .position(|name| **name == source)
+        else {
+            self.set_status(format!("Playlist \"{}\" doesn't exist anymore", source));
+            return;
+        };
+        self.select_playlist_at(index, visible_playlist_count);
+        self.selected_song_id = self.currently_playing_song;
+    }
+
+    /// Returns the name of the selected playlist.
+    fn selected_playlist_name(&self) -> Option<&String> {
+        self.playlist_names()
+            .get(self.selected_playlist_index)
+            .copied()
+    }
+
+    /// Returns the playlist names in the order they are listed: pinned playlists in the order
+    /// they were pinned, then the rest alphabetically.
+    fn playlist_names(&self) -> Vec<&String> {
+        let pinned = &self.settings.pinned_playlists;
+        let pinned_names = pinned
+            .iter()
+            .filter_map(|name| self.playlists.get_key_value(name).map(|(name, _)| name));
+        let other_names = self.playlists.keys().filter(|name| !pinned.contains(name));
+        pinned_names.chain(other_names).collect()
+    }
+
+    /// Rebuilds `filtered_songs` from the selected playlist and the search text.
+    ///
+    /// "All Songs" follows the library's sort order, other playlists keep their stored order.
+    fn filter_songs(&mut self) {
+        let empty = Vec::new();
+        let playlist_name = self.selected_playlist_name();
+        let playlist_songs = playlist_name
+            .and_then(|name| self.playlists.get(name))
+            .unwrap_or(&empty);
+        // Fold the query once and compare it to the folded tags cached in each song
+        let search_text = fold_case_and_accents(&self.search_text);
+        let matches_search = |s: &&Song| match self.search_criteria {
+            SearchCriteria::Title => s.title_lower.contains(&search_text),
+            SearchCriteria::Artist => s.artist_lower.contains(&search_text),
+            SearchCriteria::Album => s.album_lower.contains(&search_text),
+        };
+
+        self.filtered_songs = if playlist_name.is_some_and(|name| name == ALL_SONGS_PLAYLIST) {
+            self.songs
+                .iter()
+                .filter(matches_search)
+                .filter(|song| playlist_songs.contains(&song.id))
+                .cloned()
+                .collect()
+        } else {
+            playlist_songs
+                .iter()
+                .filter_map(|id| self.get_song(*id))
+                .filter(matches_search)
+                .cloned()
+                .collect()
+        };
+    }
+
+    /// Keeps the selected song within `filtered_songs` and remembers it for the selected playlist.
+    ///
+    /// When there is no valid selection, e.g. after switching playlists, the song last selected
+    /// in that playlist is restored, falling back to the first song.
+    ///
+    /// # Arguments
+    /// * `visible_song_count` - Number of rows visible in the song list.
+    fn sync_selection(&mut self, visible_song_count: usize) {
+        let Some(playlist_name) = self.selected_playlist_name().cloned() else {
+            return;
+        };
+        if playlist_name != ALL_SONGS_PLAYLIST {
+            self.last_playlist = Some(playlist_name.clone());
+        }
+        let position = |id: Option<Uuid>| {
+            id.and_then(|id| self.filtered_songs.iter().position(|song| song.id == id))
+        };
+
+        if let Some(selected_id) = self.selected_song_id {
+            if position(Some(selected_id)).is_some() {
+                self.last_selected_songs.insert(playlist_name, selected_id);
+                return;
+            }
+        }
+
+        if let Some(index) = position(self.last_selected_songs.get(&playlist_name).copied()) {
+            self.select_song_at(index, visible_song_count);
+        } else if let Some(first_song) = self.filtered_songs.first() {
+            self.selected_song_id = Some(first_song.id);
+            self.list_offset = 0;
+        } else {
+            self.selected_song_id = None;
+        }
+    }
+
+    /// Opens the input popup to label a bookmark at the current position of the playing song.
+    fn start_bookmark(&mut self) {
+        let Some(song) = self.currently_playing_song.and_then(|id| self.get_song(id)) else {
+            self.set_status("Play a song to bookmark it".to_string());
+            return;
+        };
+        if song.duration <= 0.0 {
+            self.set_status("Streams can't be bookmarked".to_string());
+            return;
+        }
+        let path = song.path.clone();
+        let position = self.elapsed_time(song.duration);
+        self.input_purpose = InputPurpose::BookmarkLabel { path, position };
+        self.input_text.clear();
+        self.input_popup.visible = true;
+    }
+
+    /// Opens the input popup to edit the tags of the selected song, starting with its title.
+    /// With songs chosen for a playlist, the artist and album of all of them are edited
+    /// instead, starting out empty.
+    fn start_tag_edit(&mut self) {
+        let chosen: Vec<Uuid> = self
+            .chosen_song_ids
+            .iter()
+            .filter(|id| {
+                self.get_song(**id)
+                    .is_some_and(|song| stream_url(&song.path).is_none())
+            })
+            .copied()
+            .collect();
+        if !chosen.is_empty() {
+            let tags = SongTags::default();
+            self.input_text.clear();
+            self.input_purpose = InputPurpose::SongTags {
+                ids: chosen,
+                field: TagField::Artist,
+                tags,
+                current: SongTags::default(),
+            };
+            self.input_popup.visible = true;
+            return;
+        }
+
+        let Some(song) = self.selected_song_id.and_then(|id| self.get_song(id)) else {
+            self.set_status("Select a song to edit its tags".to_string());
+            return;
+        };
+        if stream_url(&song.path).is_some() {
+            self.set_status("Streams have no tags to edit".to_string());
+            return;
+        }
+        // The song shows placeholders for missing tags, so the file's own tags are edited
+        let current = read_song_tags(&song.path);
+        let id = song.id;
+        self.input_text = current.title.clone().unwrap_or_default();
+        self.input_purpose = InputPurpose::SongTags {
+            ids: vec![id],
+            field: TagField::Title,
+            tags: SongTags::default(),
+            current,
+        };
+        self.input_popup.visible = true;
+    }
+
+    /// Writes edited tags to the files of songs and updates the songs to match, then reports
+    /// how many were saved and the first that failed. Tags that weren't entered are left as
+    /// they are in each file.
+    /// The songs keep their ids, since those come from the paths, which don't change.
+    ///
+    /// # Arguments
+    /// * `ids` - The songs.
+    /// * `tags` - The new tags.
+    fn save_song_tags(&mut self, ids: &[Uuid], tags: SongTags) {
+        if tags == SongTags::default() {
+            self.set_status("No tags changed".to_string());
+            return;
+        }
+        let mut saved = Vec::new();
+        let mut failed = Vec::new();
+        for id in ids {
+            let Some(song) = self.find_song_by_id(*id) else {
+                continue;
+            };
+            if let Err(e) = write_song_tags(&song.path, &tags) {
+                failed.push((song.title.clone(), e));
+                continue;
+            }
+            if let Some(title) = &tags.title {
+                song.title = normalize_tag(title);
+            }
+            if let Some(artist) = &tags.artist {
+                song.artist = normalize_tag(artist);
+            }
+            if let Some(album) = &tags.album {
+                song.album = normalize_tag(album);
+            }
+            song.update_search_keys();
+            saved.push(song.title.clone());
+        }
+
+        let mut status = match saved.as_slice() {
+            [title] => format!("Saved the tags of {}", title),
+            _ => format!("Saved the tags of {} songs", self.format_count(saved.len())),
+        };
+        match failed.as_slice() {
+            [] => {}
+            [(title, e)] if saved.is_empty() => {
+                status = format!("Couldn't save the tags of {}: {}", title, e);
+            }
+            [(title, e), ..] => status.push_str(&format!(
+                ", {} failed ({}: {})",
+                self.format_count(failed.len()),
+                title,
+                e
+            )),
+        }
+        self.set_status(status);
+        self.sort_songs();
+    }
+
+    /// Handles Enter in the input popup, creating a playlist or a bookmark, or moving on to
+    /// the next tag of a song.
+    fn submit_input(&mut self) {
+        match self.input_purpose.clone() {
+            InputPurpose::SongTags {
+                ids,
+                field,
+                mut tags,
+                mut current,
+            } if self.input_popup.visible => {
+                // Unchanged tags are left alone
+                let unchanged = current.get_mut(field).as_deref() == Some(self.input_text.as_str());
+                *tags.get_mut(field) = Some(self.input_text.clone())
+                    .filter(|text| !text.trim().is_empty() && !unchanged);
+                match field.next() {
+                    Some(next) => {
+                        self.input_text = current.get_mut(next).clone().unwrap_or_default();
+                        self.input_purpose = InputPurpose::SongTags {
+                            ids,
+                            field: next,
+                            tags,
+                            current,
+                        };
+                    }
+                    None => {
+                        self.close_input_popup();
+                        self.save_song_tags(&ids, tags);
+                    }
+                }
+            }
+            InputPurpose::BookmarkLabel { path, position } if self.input_popup.visible => {
+                let label = match self.input_text.trim() {
+                    "" => format_time(position),
+                    label => label.to_string(),
+                };
+                self.add_bookmark(path, Bookmark { label, position });
+                self.close_input_popup();
+            }
+            _ => self.create_playlist_from_input(),
+        }
+    }
+
+    /// Hides the input popup and forgets what was typed into it.
+    fn close_input_popup(&mut self) {
+        self.input_popup.visible = false;
+        self.input_text.clear();
+        self.input_purpose = InputPurpose::PlaylistName;
+    }
+
+    /// Adds a bookmark to a song, keeping its bookmarks in order, and saves the bookmarks.
+    ///
+    /// # Arguments
+    /// * `path` - The file of the song.
+    /// * `bookmark` - The bookmark to add.
+    fn add_bookmark(&mut self, path: PathBuf, bookmark: Bookmark) {
+        let bookmarks = self.bookmarks.entry(path).or_default();
+        let index = bookmarks.partition_point(|other| other.position <= bookmark.position);
+        bookmarks.insert(index, bookmark);
+        self.save_bookmarks();
+    }
+
+    /// Returns the song whose bookmarks are listed: the playing song, or else the selected one.
+    fn bookmarked_song(&self) -> Option<&Song> {
+        self.currently_playing_song
+            .or(self.selected_song_id)
+            .and_then(|id| self.get_song(id))
+    }
+
+    /// Returns the bookmarks of the song listed in the bookmarks popup.
+    fn listed_bookmarks(&self) -> &[Bookmark] {
+        self.bookmarked_song()
+            .and_then(|song| self.bookmarks.get(&song.path))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Moves the selection in the bookmarks popup.
+    ///
+    /// # Arguments
+    /// * `down` - Whether to select the next bookmark, or else the previous one.
+    fn select_bookmark(&mut self, down: bool) {
+        let last_index = self.listed_bookmarks().len().saturating_sub(1);
+        self.bookmark_selected = if down {
+            (self.bookmark_selected + 1).min(last_index)
+        } else {
+            self.bookmark_selected.saturating_sub(1)
+        };
+    }
+
+    /// Plays the song of the bookmarks popup from the selected bookmark.
+    ///
+    /// # Arguments
+    /// * `sink` - The `Sink` the songs are played through.
+    fn jump_to_bookmark(&mut self, sink: &Arc<Mutex<Sink>>) {
+        let Some(song_id) = self.bookmarked_song().map(|song| song.id) else {
+            return;
+        };
+        let Some(position) = self
+            .listed_bookmarks()
+            .get(self.bookmark_selected)
+            .map(|bookmark| bookmark.position)
+        else {
+            return;
+        };
+        if self.currently_playing_song != Some(song_id) {
+            self.start_song(song_id, sink);
+        }
+        self.seek_to(sink, position);
+        self.bookmarks_popup.visible = false;
+    }
+
+    /// Deletes the selected bookmark in the bookmarks popup and saves the bookmarks.
+    fn remove_bookmark(&mut self) {
+        let Some(path) = self.bookmarked_song().map(|song| song.path.clone()) else {
+            return;
+        };
+        if let Some(bookmarks) = self.bookmarks.get_mut(&path) {
+            if self.bookmark_selected < bookmarks.len() {
+                bookmarks.remove(self.bookmark_selected);
+            }
+            if bookmarks.is_empty() {
+                self.bookmarks.remove(&path);
+            }
+        }
+        self.bookmark_selected = self
+            .bookmark_selected
+            .min(self.listed_bookmarks().len().saturating_sub(1));
+        self.save_bookmarks();
+    }
+
+    /// Writes the bookmarks to the bookmarks file, if there is one.
+    fn save_bookmarks(&mut self) {
+        let Some(path) = &self.bookmarks_file else {
+            return;
+        };
+        let saved = serde_json::to_string(&self.bookmarks)
+            .map_err(io::Error::from)
+            .and_then(|serialized| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                write_atomically(path, serialized.as_bytes())
+            });
+        if let Err(e) = saved {
+            self.set_status(format!("Couldn't save the bookmarks: {}", e));
+        }
+    }
+
+    /// Loads the bookmarks saved in a file.
+    ///
+    /// # Arguments
+    /// * `path` - The file holding the bookmarks.
+    fn load_bookmarks(&mut self, path: &Path) -> io::Result<()> {
+        self.bookmarks = serde_json::from_reader(File::open(path)?)?;
+        Ok(())
+    }
+
+    /// Creates a playlist of the chosen songs named after the text in the playlist name popup.
+    ///
+    /// Names are trimmed but otherwise kept as typed: playlists are stored as keys of
+    /// `data.json`, never as file names, so characters like `/` or `:` are safe. When the name
+    /// or songs are missing, or a playlist with that name already exists, the popup stays open
+    /// with the name as typed and the status line says what's wrong.
+    fn create_playlist_from_input(&mut self) {
+        let name = self.input_text.trim().to_string();
+        match (name.is_empty(), self.chosen_song_ids.is_empty()) {
+            (true, true) => self.set_status("Need a name and at least 1 song".to_string()),
+            (true, false) => self.set_status("Need a name".to_string()),
+            (false, true) => self.set_status("Need at least 1 song".to_string()),
+            (false, false) if GENERATED_PLAYLISTS.contains(&name.as_str()) => {
+                self.set_status(format!("\"{}\" is a reserved name", name))
+            }
+            (false, false) if self.playlists.contains_key(&name) => {
+                self.set_status(format!("Playlist \"{}\" already exists", name))
+            }
+            (false, false) => {
+                self.input_popup.visible = false;
+                self.playlists.insert(name, self.chosen_song_ids.clone());
+                self.playlists_unsaved = true;
+                self.chosen_song_ids.clear();
+            }
+        }
+    }
+
+    /// Marks the selected song to be moved elsewhere in the selected playlist.
+    fn cut_selected_song(&mut self) {
+        let (Some(playlist_name), Some(song_id)) =
+            (self.selected_playlist_name().cloned(), self.selected_song_id)
+        else {
+            return;
+        };
+        if GENERATED_PLAYLISTS.contains(&playlist_name.as_str()) {
+            self.set_status(format!("\"{}\" can't be reordered", playlist_name));
+            return;
+        }
+        let title = self
+            .songs
+            .iter()
+            .find(|song| song.id == song_id)
+            .map_or_else(String::new, |song| song.title.clone());
+        self.cut_song = Some((playlist_name, song_id));
+        self.set_status(format!("Cut \"{}\", paste it with Ctrl+V or Ctrl+B", title));
+    }
+
+    /// Moves the cut song next to the selected song in the same playlist.
+    ///
+    /// # Arguments
+    /// * `after` - Whether to place it after the selected song rather than before it.
+    fn paste_cut_song(&mut self, after: bool) {
+        let Some((cut_playlist, cut_id)) = self.cut_song.clone() else {
+            return;
+        };
+        let Some(target_id) = self.selected_song_id else {
+            return;
+        };
+        if self.selected_playlist_name() != Some(&cut_playlist) {
+            self.set_status(format!("The cut song belongs to \"{}\"", cut_playlist));
+            return;
+        }
+        let Some(song_ids) = self.playlists.get_mut(&cut_playlist) else {
+            return;
+        };
+        self.cut_song = None;
+        if target_id == cut_id {
+            return;
+        }
+
+        song_ids.retain(|id| *id != cut_id);
+        let Some(target_index) = song_ids.iter().position(|id| *id == target_id) else {
+            return;
+        };
+        let insert_index = if after { target_index + 1 } else { target_index };
+        song_ids.insert(insert_index, cut_id);
+        self.playlists_unsaved = true;
+        self.selected_song_id = Some(cut_id);
+        self.filter_songs();
+    }
+
+    /// Reorders the selected playlist by the active sort criteria.
+    ///
+    /// "All Songs" always follows the sort criteria, while other playlists keep their stored
+    /// order until sorted with this. The new order is saved along with the playlists. The other
+    /// generated playlists can't be sorted.
+    fn sort_selected_playlist(&mut self) {
+        let Some(playlist_name) = self.selected_playlist_name().cloned() else {
+            return;
+        };
+        if GENERATED_PLAYLISTS.contains(&playlist_name.as_str()) {
+            return;
+        }
+        let Some(song_ids) = self.playlists.get_mut(&playlist_name) else {
+            return;
+        };
+
+        let mut songs: Vec<Song> = song_ids
+            .iter()
+            .filter_map(|id| self.songs.iter().find(|song| song.id == *id).cloned())
+            .collect();
+        sort_songs(
+            &mut songs,
+            &self.sort_criteria,
+            &self.settings.sort_ignored_articles,
+        );
+        if self.sort_descending {
+            songs.reverse();
+        }
+        // Songs missing from the library keep their place at the end
+        let missing: Vec<Uuid> = song_ids
+            .iter()
+            .filter(|id| !songs.iter().any(|song| song.id == **id))
+            .copied()
+            .collect();
+        *song_ids = songs.iter().map(|song| song.id).chain(missing).collect();
+        self.playlists_unsaved = true;
+
+        self.filter_songs();
+        self.set_status(format!(
+            "Sorted \"{}\" by {}",
+            playlist_name,
+            self.sort_label()
+        ));
+    }
+
+    // Function to stop the current song
+    pub fn stop_song(&mut self) {
+        if let Some(song_id) = self.currently_playing_song.take() {
+            if let Some(song) = self.find_song_by_id(song_id) {
+                song.is_playing = false;
+            }
+        }
+    }
+
+    // Function to toggle popup visibility
+    pub fn toggle_popup(&mut self) {
+        self.hint_popup_state.toggle();
+    }
+
+    // Function to change sorting criteria
+    fn set_sort_criteria(&mut self, criteria: SortCriteria) {
+        self.sort_criteria = criteria;
+        self.sort_songs(); // Re-sort the songs based on new criteria
+    }
+
+    /// Sets the sort order the songs start out in, before they're first sorted.
+    ///
+    /// Unknown criteria fall back to sorting by title, with a message saying so.
+    ///
+    /// # Arguments
+    /// * `sort_by` - The name of the criteria, e.g. `artist`; `None` sorts by title.
+    /// * `descending` - Whether to sort in reverse.
+    fn set_initial_sort(&mut self, sort_by: Option<&str>, descending: bool) {
+        self.sort_criteria = match sort_by.map(|name| (name, SortCriteria::from_name(name))) {
+            Some((_, Some(criteria))) => criteria,
+            Some((name, None)) => {
+                self.set_status(format!(
+                    "Unknown sort criteria \"{}\", sorting by Title",
+                    name
+                ));
+                SortCriteria::Title
+            }
+            None => SortCriteria::Title,
+        };
+        self.sort_descending = descending;
+    }
+
+    /// Describes the sort order, e.g. "Artist" or "Artist (descending)".
+    fn sort_label(&self) -> String {
+        if self.sort_descending {
+            format!("{} (descending)", self.sort_criteria)
+        } else {
+            self.sort_criteria.to_string()
+        }
+    }
+
+    // Sort the list of songs based on the current sort criteria
+    fn sort_songs(&mut self) {
+        sort_songs(
+            &mut self.songs,
+            &self.sort_criteria,
+            &self.settings.sort_ignored_articles,
+        );
+        if self.sort_descending {
+            self.songs.reverse();
+        }
+    }
+
+    /// Saves the current playlists to a file.
+    ///
+    /// If another program changed the file since it was loaded, its version is kept next to it
+    /// as `data.json.bak`. Failing to keep it doesn't stop the playlists from being saved, it's
+    /// only mentioned in the summary.
+    ///
+    /// # Returns
+    /// A summary of what was saved, e.g. "Saved 3 playlists, 42 songs", or the error that kept
+    /// the playlists from being written.
+    fn save_playlist(&mut self) -> std::io::Result<String> {
+        if self.playlists_read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "running read-only",
+            ));
+        }
+        let music_dir = self
+            .settings
+            .relative_playlist_paths
+            .then(|| resolve_music_dir(self.settings.music_dir.as_deref()));
+        // All Songs is rebuilt from the library, Streams from streams.json and Radio from
+        // stations.json on every start, so they're never written out
+        let saved: BTreeMap<&String, Vec<PlaylistEntry>> = self
+            .playlists
+            .iter()
+            .filter(|(name, _)| !GENERATED_PLAYLISTS.contains(&name.as_str()))
+            .map(|(name, ids)| {
+                let entries = ids
+                    .iter()
+                    .map(|id| self.playlist_entry(*id, music_dir.as_deref()))
+                    .collect();
+                (name, entries)
+            })
+            .collect();
+        let serialized = serde_json::to_string(&saved)?;
+        let mut summary = format!(
+            "Saved {} playlists, {} songs",
+            self.format_count(saved.len()),
+            self.format_count(saved.values().map(Vec::len).sum())
+        );
+
+        if let Some(playlist_file_path) = self.playlist_file() {
+            if let Some(playlist_dir) = playlist_file_path.parent() {
+                fs::create_dir_all(playlist_dir)?;
+            }
+            if self.playlists_changed_on_disk() {
+                let backup_path = playlist_file_path.with_extension("json.bak");
+                if let Err(e) = fs::copy(&playlist_file_path, &backup_path) {
+                    summary.push_str(&format!(", couldn't keep {}: {}", backup_path.display(), e));
+                }
+            }
+
+            write_atomically(&playlist_file_path, serialized.as_bytes())?;
+            self.playlist_file_modified = modified_time(&playlist_file_path);
+        }
+        self.playlists_unsaved = false;
+        self.playlists_changed_at = None;
+
+        Ok(summary)
+    }
+
+    /// Returns how a song is written to the playlist file: by path if a music directory is
+    /// given and the song is known, otherwise by id.
+    ///
+    /// # Arguments
+    /// * `id` - The id of the song.
+    /// * `music_dir` - The directory paths are relative to, if paths are stored.
+    fn playlist_entry(&self, id: Uuid, music_dir: Option<&Path>) -> PlaylistEntry {
+        music_dir
+            .zip(self.get_song(id))
+            .and_then(|(music_dir, song)| playlist_path(&song.path, music_dir))
+            .map_or(PlaylistEntry::Id(id), PlaylistEntry::Path)
+    }
+
+    /// Saves the playlists a moment after they change, so quitting doesn't have to.
+    ///
+    /// Changes made by another program in the meantime aren't saved over until Ctrl+Q confirms
+    /// it.
+    fn autosave_playlists(&mut self) {
+        // Read-only mode was pointed out at startup; quitting says the playlists weren't saved
+        if self.playlists_read_only || !self.playlists_unsaved {
+            self.playlists_changed_at = None;
+            return;
+        }
+        let changed_at = *self.playlists_changed_at.get_or_insert_with(Instant::now);
+        if changed_at.elapsed() < PLAYLIST_SAVE_DELAY
+            || (self.playlists_changed_on_disk() && !self.overwrite_confirmed)
+        {
+            return;
+        }
+        match self.save_playlist() {
+            Ok(summary) => self.set_status(summary),
+            Err(e) => {
+                self.set_status(format!("Couldn't save the playlists: {}", e));
+                // Try again after another delay
+                self.playlists_changed_at = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Opens the folder of the selected song in the system file manager, without waiting for it.
+    fn reveal_selected_song(&mut self) {
+        let Some(song) = self.selected_song_id.and_then(|id| self.get_song(id)) else {
+            return;
+        };
+        if stream_url(&song.path).is_some() {
+            self.set_status("Streams aren't in a folder".to_string());
+            return;
+        }
+        let Some(folder) = song.path.parent().map(Path::to_path_buf) else {
+            return;
+        };
+        let launched = file_manager_command(&folder)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn();
+        match launched {
+            // Reap the file manager launcher once it exits
+            Ok(mut child) => {
+                std::thread::spawn(move || child.wait());
+            }
+            Err(e) => self.set_status(format!("Couldn't open the file manager: {}", e)),
+        }
+    }
+
+    /// Formats a count for display with the configured or the locale's thousands separator.
+    ///
+    /// # Arguments
+    /// * `count` - The number to format.
+    fn format_count(&self, count: usize) -> String {
+        let separator = self
+            .settings
+            .thousands_separator
+            .as_deref()
+            .unwrap_or_else(|| locale_thousands_separator(&locale_language()));
+        format_count(count, separator)
+    }
+
+    /// Switches between the column and stacked layouts and saves the choice to the settings.
+    fn toggle_layout(&mut self) {
+        let layout = match self.settings.layout {
+            LayoutMode::Columns => LayoutMode::Stacked,
+            LayoutMode::Stacked => LayoutMode::Columns,
+        };
+        self.change_settings(|settings| settings.layout = layout);
+    }
+
+    /// Returns the share of the screen width, in percent, the song list takes in the column
+    /// layout, from the `song_list_width` setting.
+    fn song_list_width(&self) -> u16 {
+        let (narrowest, widest) = SONG_LIST_WIDTH_RANGE;
+        self.settings
+            .song_list_width
+            .unwrap_or(DEFAULT_SONG_LIST_WIDTH)
+            .clamp(narrowest, widest)
+    }
+
+    /// Returns the widths of the playlist, song list and song details columns, in percent of
+    /// the screen width.
+    fn column_widths(&self) -> [u16; 3] {
+        let song_list = self.song_list_width();
+        let playlists = (100 - song_list) / 2;
+        [playlists, song_list, 100 - song_list - playlists]
+    }
+
+    /// Widens or narrows the song list by a step and saves its width to the settings.
+    ///
+    /// # Arguments
+    /// * `wider` - Whether to widen the song list.
+    fn resize_song_list(&mut self, wider: bool) {
+        let (narrowest, widest) = SONG_LIST_WIDTH_RANGE;
+        let width = self.song_list_width();
+        let new_width = if wider {
+            (width + SONG_LIST_WIDTH_STEP).min(widest)
+        } else {
+            width.saturating_sub(SONG_LIST_WIDTH_STEP).max(narrowest)
+        };
+        if new_width != width {
+            self.change_settings(|settings| settings.song_list_width = Some(new_width));
+        }
+        self.set_status(format!("Song list width: {}%", new_width));
+    }
+
+    /// Pins the selected playlist to the top of the playlist list, or unpins it, and saves the
+    /// pinned playlists to the settings. The same playlist stays selected.
+    fn toggle_pin_selected_playlist(&mut self) {
+        let Some(name) = self.selected_playlist_name().cloned() else {
+            return;
+        };
+        let mut pinned = self.settings.pinned_playlists.clone();
+        match pinned.iter().position(|pinned_name| *pinned_name == name) {
+            Some(index) => {
+                pinned.remove(index);
+            }
+            None => pinned.push(name.clone()),
+        }
+        self.change_settings(|settings| settings.pinned_playlists = pinned.clone());
+        self.selected_playlist_index = self
+            .playlist_names()
+            .iter()
+            .position(|playlist_name| **playlist_name == name)
+            .unwrap_or(0);
+    }
+
+    /// Applies a change to the settings and saves it to the settings file.
+    ///
+    /// Only the change is saved, so options given on the command line stay out of the file.
+    ///
+    /// # Arguments
+    /// * `change` - Changes the settings.
+    fn change_settings(&mut self, change: impl Fn(&mut Settings)) {
+        change(&mut self.settings);
+        if let Some(settings_file) = &self.settings_file {
+            let mut saved_settings = Settings::load(settings_file);
+            change(&mut saved_settings);
+            if let Err(e) = saved_settings.save(settings_file) {
+                self.set_status(format!("Couldn't save the settings: {}", e));
+            }
+        }
+    }
+
+    /// Returns whether another program changed the playlist file since it was loaded or saved.
+    fn playlists_changed_on_disk(&self) -> bool {
+        self.playlist_file()
+            .and_then(|path| modified_time(&path))
+            .is_some_and(|modified| Some(modified) != self.playlist_file_modified)
+    }
+
+    /// Stops saving the files in the config directory, for when it can't be written, and says
+    /// so in the status line. The playlists are still saved if they're kept in a directory that
+    /// can be written. The player keeps working with what was loaded.
+    ///
+    /// # Arguments
+    /// * `config_dir` - The directory that couldn't be written.
+    fn enter_read_only(&mut self, config_dir: &Path) {
+        self.read_only = true;
+        self.settings_file = None;
+        self.search_history_file = None;
+        self.bookmarks_file = None;
+        self.resume_positions_file = None;
+        self.play_queue_file = None;
+        self.playlists_read_only = !self
+            .playlist_file()
+            .and_then(|file| file.parent().map(dir_writable))
+            .unwrap_or(true);
+        let unsaved = if self.playlists_read_only {
+            "changes"
+        } else {
+            "changes other than playlists"
+        };
+        self.set_status(format!(
+            "Can't write to {}, running read-only: {} won't be saved",
+            config_dir.display(),
+            unsaved
+        ));
+    }
+
+    /// Saves the playlists and returns whether the player can quit. When another program
+    /// changed them, this only warns the first time.
+    fn save_and_quit(&mut self) -> bool {
+        self.remember_position();
+        self.save_play_queue();
+        if !self.playlists_unsaved {
+            return true;
+        }
+        if self.playlists_changed_on_disk() && !self.overwrite_confirmed {
+            self.overwrite_confirmed = true;
+            self.set_status(
+                "Playlists were changed by another program, Ctrl+Q again saves over them \
+                 (keeping data.json.bak)"
+                    .to_string(),
+            );
+            return false;
+        }
+        self.save_playlists_on_exit();
+        true
+    }
+
+    /// Saves the position, the play queue and any unsaved playlists when the player is asked to
+    /// terminate or loses its terminal, with no chance to ask about playlists changed by
+    /// another program.
+    fn save_on_shutdown(&mut self) {
+        self.remember_position();
+        self.save_play_queue();
+        if self.playlists_unsaved {
+            self.save_playlists_on_exit();
+        }
+    }
+
+    /// Saves the playlists as the player exits, keeping what happened in `quit_message` to
+    /// print once the terminal is restored.
+    fn save_playlists_on_exit(&mut self) {
+        self.quit_message = Some(match self.save_playlist() {
+            Ok(summary) => summary,
+            Err(e) => format!("Couldn't save the playlists: {}", e),
+        });
+    }
+
+    /// Returns the file the playlists are kept in, `data.json` in the configured playlist
+    /// directory or else the cli-rhythm config directory.
+    fn playlist_file(&self) -> Option<PathBuf> {
+        self.settings
+            .playlist_dir
+            .clone()
+            .or_else(app_config_dir)
+            .map(|dir| dir.join("data.json"))
+    }
+
+    /// Loads the search history saved in a file.
+    ///
+    /// # Arguments
+    /// * `path` - The file holding the search history.
+    fn load_search_history(&mut self, path: &Path) -> io::Result<()> {
+        let mut history: Vec<String> = serde_json::from_reader(File::open(path)?)?;
+        let excess = history.len().saturating_sub(SEARCH_HISTORY_LIMIT);
+        history.drain(..excess);
+        self.search_history = history;
+        Ok(())
+    }
+
+    /// Loads playlists from a file.
+    ///
+    /// An "All Songs" entry in the file is ignored; the one built from the library is kept.
+    /// Songs stored by path get the ids the library scan gives them, relative paths resolved
+    /// against the music directory.
+    ///
+    /// # Arguments
+    /// * `filepath` - The path to the file containing the playlists.
+    ///
+    /// # Returns
+    /// A `Result` indicating success or failure.
+    pub fn load_playlists(&mut self, filepath: &Path) -> std::io::Result<()> {
+        let file = File::open(filepath)?;
+        let stored: BTreeMap<String, Vec<PlaylistEntry>> = serde_json::from_reader(file)?;
+        let music_dir = resolve_music_dir(self.settings.music_dir.as_deref());
+        let mut playlists: BTreeMap<String, Vec<Uuid>> = stored
+            .into_iter()
+            .map(|(name, entries)| {
+                let ids = entries
+                    .into_iter()
+                    .map(|entry| match entry {
+                        PlaylistEntry::Id(id) => id,
+                        PlaylistEntry::Path(path) => {
+                            song_id(&resolve_playlist_path(&path, &music_dir))
+                        }
+                    })
+                    .collect();
+                (name, ids)
+            })
+            .collect();
+        // Entries for the generated playlists written by older versions are ignored
+        for name in GENERATED_PLAYLISTS {
+            playlists.remove(name);
+            if let Some(ids) = self.playlists.remove(name) {
+                playlists.insert(name.to_string(), ids);
+            }
+        }
+        self.playlists = playlists;
+        self.playlists_unsaved = false;
+        self.playlist_file_modified = modified_time(filepath);
+        Ok(())
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli_args = CliArgs::parse(env::args().skip(1));
+    let mut event_writer = match (&cli_args.json_events, cli_args.headless) {
+        (Some(path), _) => Some(EventWriter::open(path)?),
+        (None, true) => Some(EventWriter::open("-")?),
+        (None, false) => None,
+    };
+    let mut control_fifo = match &cli_args.control_fifo {
+        Some(path) => Some(ControlFifo::open(path)?),
+        None => None,
+    };
+
+    let mut myapp = MyApp::new();
+    if let Some(config_dir) = app_config_dir() {
+        let settings_file = config_dir.join("settings.json");
+        myapp.settings = Settings::load(&settings_file);
+        myapp.settings_file = Some(settings_file);
+    }
+    if let Some(config_dir) = app_config_dir() {
+        let search_history_file = config_dir.join("search_history.json");
+        let _ = myapp.load_search_history(&search_history_file);
+        myapp.search_history_file = Some(search_history_file);
+        let bookmarks_file = config_dir.join("bookmarks.json");
+        let _ = myapp.load_bookmarks(&bookmarks_file);
+        myapp.bookmarks_file = Some(bookmarks_file);
+        let resume_positions_file = config_dir.join("positions.json");
+        let _ = myapp.load_resume_positions(&resume_positions_file);
+        myapp.resume_positions_file = Some(resume_positions_file);
+    }
+    if let Some((music_dir, extra_music_dirs)) = cli_args.music_dirs.split_first() {
+        myapp.settings.music_dir = Some(music_dir.clone());
+        myapp.settings.extra_music_dirs = extra_music_dirs.to_vec();
+    }
+    if cli_args.playlist_dir.is_some() {
+        myapp.settings.playlist_dir = cli_args.playlist_dir.clone();
+    }
+    if cli_args.no_art {
+        myapp.settings.no_art = true;
+    }
+    let sort_by = cli_args.sort_by.clone().or(myapp.settings.sort_by.clone());
+    let sort_descending = cli_args.sort_descending || myapp.settings.sort_descending;
+    myapp.set_initial_sort(sort_by.as_deref(), sort_descending);
+    if let Some(playlist_file) = myapp.playlist_file() {
+        let _ = myapp.load_playlists(&playlist_file);
+    }
+    myapp.load_songs();
+    let streams_path = app_config_dir().map(|dir| dir.join("streams.json"));
+    if let Some(path) = &streams_path {
+        let _ = myapp.load_streams(path);
+    }
+    if let Some(config_dir) = app_config_dir() {
+        let _ = myapp.load_stations(&config_dir.join("stations.json"));
+        let play_queue_file = config_dir.join("queue.json");
+        let _ = myapp.load_play_queue(&play_queue_file);
+        myapp.play_queue_file = Some(play_queue_file);
+        if !dir_writable(&config_dir) {
+            myapp.enter_read_only(&config_dir);
+        }
+    }
+
+    if myapp.settings.normalize_loudness {
+        myapp.loudness_analyzer = Some(LoudnessAnalyzer::spawn());
+    }
+
+    let mut visible_song_count: usize = 0;
+    let mut visible_playlist_count: usize = 0;
+
+    // Without an audio device the library can still be browsed and the playlists edited
+    let (_stream, sink) = match OutputStream::try_default() {
+        Ok((stream, stream_handle)) => (Some(stream), Sink::try_new(&stream_handle)?),
+        Err(e) if cli_args.headless => {
+            return Err(format!("No audio output device: {}", e).into());
+        }
+        Err(e) => {
+            myapp.set_status(format!("No audio output device, playback is off: {}", e));
+            myapp.audio_error = Some(e.to_string());
+            (None, Sink::new_idle().0)
+        }
+    };
+    sink.set_volume(myapp.volume_percent() as f32 / 100.0);
+    *myapp.balance.lock().unwrap() = myapp.settings.balance as f32 / 100.0;
+    let sink = Arc::new(Mutex::new(sink));
+
+    // Save and exit cleanly on Ctrl+C outside raw mode, SIGTERM, a closed terminal (SIGHUP) or
+    // a closed console window on Windows
+    let shutdown = Arc::new(AtomicBool::new(false));
+    {
+        let shutdown = shutdown.clone();
+        if let Err(e) = ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst)) {
+            eprintln!("Couldn't install the signal handler: {}", e);
+        }
+    }
+
+    if let Some(url) = &cli_args.stream {
+        if stream_url(Path::new(url)).is_some() {
+            let song_id = myapp.add_stream(STREAMS_PLAYLIST, url, url);
+            if let Some(path) = streams_path.as_ref().filter(|_| !myapp.read_only) {
+                if let Err(e) = myapp.save_streams(path) {
+                    myapp.set_status(format!("Couldn't save the streams: {}", e));
+                }
+            }
+            myapp.start_song(song_id, &sink);
+        } else {
+            eprintln!("Not a stream URL: {}", url);
+        }
+    }
+
+    if cli_args.headless {
+        run_headless(
+            &mut myapp,
+            &sink,
+            event_writer.as_mut(),
+            control_fifo.as_mut(),
+            &shutdown,
+        );
+        myapp.clear_now_playing();
+        if let Some(message) = myapp.quit_message.take() {
+            eprintln!("{}", message);
+        }
+        return Ok(());
+    }
+
+    // Initialize terminal
+    enable_raw_mode()?;
+    let mut terminal = ratatui::init();
+    let picker = myapp.settings.art_picker();
+    let mut cover_protocol: Option<(Option<Uuid>, StatefulProtocol)> = None;
+
+    stdout().execute(Clear(crossterm::terminal::ClearType::All))?;
+    stdout().execute(EnableBracketedPaste)?;
+
+    // Run event loop. A terminal that can't be read or drawn to anymore, e.g. after SIGHUP,
+    // ends it like a shutdown request, so nothing is lost.
+    let result = loop {
+        if shutdown.load(Ordering::SeqCst) {
+            myapp.save_on_shutdown();
+            break Ok(());
+        }
+
+        // Wait for input, without delaying a pending redraw
+        let timeout = if myapp.needs_redraw {
+            Duration::ZERO
+        } else {
+            myapp.poll_interval(&sink)
+        };
+        let input = poll(timeout).and_then(|ready| ready.then(crossterm::event::read).transpose());
+        let event = match input {
+            Ok(event) => event,
+            Err(e) => {
+                myapp.save_on_shutdown();
+                break Err(e);
+            }
+        };
+        if let Some(event) = event {
+            // Any event, including a resize, changes what's on screen
+            myapp.needs_redraw = true;
+            let quit = match event {
+                Event::Key(key) => handle_key_event(
+                    &mut myapp,
+                    key,
+                    &sink,
+                    visible_song_count,
+                    visible_playlist_count,
+                ),
+                Event::Paste(text) => {
+                    myapp.paste(&text);
+                    false
+                }
+                _ => false,
+            };
+            if quit {
+                break Ok(());
+            }
+        }
+
+        myapp.update_playback(&sink);
+        myapp.update_now_playing();
+        myapp.update_loudness();
+        myapp.update_rescan();
+        myapp.update_export();
+        myapp.autosave_playlists();
+        if let Some(fifo) = control_fifo.as_mut() {
+            process_fifo_commands(&mut myapp, fifo, &sink);
+        }
+        if let Some(events) = event_writer.as_mut() {
+            let _ = events.update(&myapp, &sink);
+        }
+
+        if !myapp.take_redraw(&sink) {
+            continue;
+        }
+
+        let search_bar_title = match myapp.search_criteria {
+            SearchCriteria::Title => "Search by Title",
+            SearchCriteria::Artist => "Search by Artist",
+            SearchCriteria::Album => "Search by Album",
+        };
+
+        // Render search bar
+        let search_bar = Paragraph::new(Text::raw(myapp.search_text.clone()))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(focus_border_style(myapp.focus == Focus::Search))
+                    .title(search_bar_title),
+            )
+            .style(Style::default().fg(Color::White));
+
+        myapp.filter_songs();
+        myapp.sync_selection(visible_song_count);
+
+        let selected_song = match myapp.selected_song_id {
+            Some(index) => myapp.get_song(index),
+            None => None,
+        };
+
+        let selected_song_details = if let Some(song) = selected_song {
+            let contents = format!(
+                "Artist: {}\nSong: {}\nAlbum: {}\nDuration: {:02}:{:02}\nFormat: {}\nPath: {}",
+                song.artist,
+                song.title,
+                song.album,
+                (song.duration / 60.0).floor(),
+                (song.duration % 60.0).round(),
+                song_format(&song.path),
+                truncate_middle(&song.path.to_string_lossy(), 29 - "Path: ".len())
+            );
+            let wrapped_details = wrap(&contents, 29);
+
+            wrapped_details.join("\n")
+        } else {
+            "No song selected".to_string()
+        };
+
+        let playing_song_details = if let Some(song_id) = myapp.currently_playing_song {
+            let song = myapp.get_song(song_id).unwrap();
+            let mut contents = format!(
+                "Artist: {}\nSong: {}\nAlbum: {}\nDuration: {:02}:{:02}",
+                song.artist,
+                song.title,
+                song.album,
+                (song.duration / 60.0).floor(),
+                (song.duration % 60.0).round()
+            );
+            if let Some(on_air) = song.stream_title.lock().unwrap().as_ref() {
+                contents.push_str(&format!("\nOn air: {}", on_air));
+            }
+            if let Some((left, count)) = myapp.remaining_time() {
+                contents.push_str(&format!(
+                    "\nLeft: {}, {} songs",
+                    format_total_time(left),
+                    myapp.format_count(count)
+                ));
+            }
+            if let Some(loading) = myapp.loading_indicator() {
+                contents.push_str(&format!("\n{}", loading));
+            }
+            let wrapped_details = wrap(&contents, 29);
+
+            wrapped_details.join("\n")
+        } else {
+            "No song playing".to_string()
+        };
+
+        let selected_song_info = Paragraph::new(selected_song_details)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Selected Song"),
+            )
+            .style(Style::default().fg(Color::White));
+
+        let playing_song_info = Paragraph::new(playing_song_details)
+            .block(Block::default()).style(Style::default().fg(Color::White));
+        
+        // Only rebuild the image protocol when the playing song changes
+        let art_percentage = myapp.settings.art_percentage();
+        if let Some(picker) = picker.as_ref() {
+            if cover_protocol.as_ref().map(|(song_id, _)| *song_id) != Some(myapp.currently_playing_song) {
+                let playing_song_cover = myapp
+                    .currently_playing_song
+                    .and_then(|song_id| myapp.get_song(song_id))
+                    .and_then(|song| song.cover.as_deref().cloned())
+                    .unwrap_or_else(|| {
+                        let img = ImageBuffer::from_fn(4, 4, |_, _| Rgba([0, 0, 0, 0]));
+                        DynamicImage::ImageRgba8(img)
+                    });
+                cover_protocol = Some((
+                    myapp.currently_playing_song,
+                    picker.new_resize_protocol(playing_song_cover),
+                ));
+            }
+        }
+        let img = StatefulImage::default();
+
+        let song_id = myapp
+            .currently_playing_song
+            .or(myapp.selected_song_id)
+            .unwrap_or_else(|| myapp.songs.first().map(|song| song.id).unwrap_or_default());
+
+        let progress_ratio = match myapp.get_song(song_id).map(|song| song.duration) {
+            Some(duration) => myapp.progress_ratio(duration),
+            None => 0.0,
+        };
+
+        let mode_indicators = myapp.mode_indicators();
+
+        let song_progress = if let Some(song) = myapp.get_song(song_id) {
+            // Streams without a duration only show how long they've been playing
+            let known_duration = song.duration > 0.0;
+            let elapsed_time =
+                myapp.elapsed_time(if known_duration { song.duration } else { f64::MAX });
+            let elapsed_minutes = (elapsed_time / 60.0).floor() as u64;
+            let elapsed_seconds = (elapsed_time % 60.0).round() as u64;
+            let duration_minutes = (song.duration / 60.0).floor() as u64;
+            let duration_seconds = (song.duration % 60.0).round() as u64;
+            let label = if known_duration {
+                format!(
+                    "{:02}:{:02}/{:02}:{:02}",
+                    elapsed_minutes, elapsed_seconds, duration_minutes, duration_seconds
+                )
+            } else {
+                format!("{:02}:{:02}", elapsed_minutes, elapsed_seconds)
+            };
+            let label = myapp.loading_indicator().unwrap_or(label);
+            // The bar is dimmed while it can't show where the song is: while it loads or
+            // seeks, or for streams without a duration
+            let gauge_color =
+                if myapp.loading.is_some() || myapp.seeking.is_some() || !known_duration {
+                    Color::DarkGray
+                } else {
+                    Color::LightBlue
+                };
+
+            Gauge::default()
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Progress")
+                        .title(Line::from(mode_indicators.clone()).right_aligned()),
+                )
+                .gauge_style(Style::default().fg(gauge_color))
+                .label(label)
+                .ratio(progress_ratio)
+        } else {
+            Gauge::default()
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Progress")
+                        .title(Line::from(mode_indicators.clone()).right_aligned()),
+                )
+                .gauge_style(Style::default().fg(Color::LightBlue))
+                .label("No song selected")
+                .ratio(0.0)
+        };
+
+        // Volume bar
+        let mut volume_label = if myapp.muted {
+            format!("Muted ({}%)", myapp.volume_percent())
+        } else {
+            format!("{}%", myapp.volume_percent())
+        };
+        if let Some(balance) = myapp.balance_label() {
+            volume_label.push_str(&format!(" · {}", balance));
+        }
+        let volume_bar = Gauge::default()
+            .block(Block::default().borders(Borders::ALL).title("Volume"))
+            .gauge_style(Style::default().fg(Color::LightBlue))
+            .label(volume_label)
+            .ratio(sink.lock().unwrap().volume() as f64);
+
+        let hint = Paragraph::new("F1 for controls")
+            .style(
+                Style::default()
+                    .fg(Color::Gray)
+                    .add_modifier(Modifier::ITALIC),
+            )
+            .alignment(Alignment::Right);
+
+        let status = match myapp.rescan {
+            Some(_) => "Rescanning the library…",
+            None => myapp.current_status().unwrap_or_default(),
+        };
+        let status_line = Paragraph::new(status.to_string())
+            .style(Style::default().fg(Color::Yellow));
+
+        // Shown above the progress bar when the side panels are collapsed
+        let now_playing_title = myapp
+            .currently_playing_song
+            .and_then(|song_id| myapp.get_song(song_id))
+            .map_or_else(
+                || "Progress".to_string(),
+                |song| format!("{} - {}", song.title, song.artist),
+            );
+
+        let (lyrics, lyrics_elapsed) = if myapp.lyrics_popup.visible {
+            let duration = myapp
+                .currently_playing_song
+                .and_then(|song_id| myapp.get_song(song_id))
+                .map_or(0.0, |song| song.duration);
+            let elapsed = myapp.elapsed_time(duration);
+            (myapp.current_lyrics().cloned(), elapsed)
+        } else {
+            (None, 0.0)
+        };
+
+        let drawn = terminal.draw(|f| {
+            let vertical_layout = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(1)
+                .constraints([
+                    Constraint::Fill(1),
+                ])
+                .split(f.area());
+
+                let compact = myapp.settings.layout == LayoutMode::Stacked
+                    || is_compact_area(f.area());
+                let song_tab_layout = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints(if compact {
+                        [Constraint::Length(3), Constraint::Fill(1), Constraint::Length(3)]
+                    } else {
+                        [
+                            Constraint::Percentage(7),
+                            Constraint::Percentage(86),
+                            Constraint::Percentage(7),
+                        ]
+                    })
+                    .split(vertical_layout[0]);
+                f.render_widget(search_bar, song_tab_layout[0]);
+
+                if myapp.focus == Focus::Search && !myapp.input_popup.visible {
+                    let search_area = song_tab_layout[0];
+                    let cursor_x = (myapp.search_cursor as u16)
+                        .min(search_area.width.saturating_sub(3));
+                    f.set_cursor_position((search_area.x + 1 + cursor_x, search_area.y + 1));
+                }
+
+                // Small terminals only show the song list, giving it the full width
+                let chunks = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints(if compact {
+                        [Constraint::Length(0), Constraint::Fill(1), Constraint::Length(0)]
+                    } else {
+                        myapp.column_widths().map(Constraint::Percentage)
+                    })
+                    .split(song_tab_layout[1]);
+
+                if !compact {
+                    visible_playlist_count = chunks[0].height.saturating_sub(2) as usize;
+                }
+                visible_song_count = chunks[1].height.saturating_sub(2) as usize;
+
+                let selected_style = myapp.settings.theme.selected_style(myapp.settings.no_blink);
+                let chosen_style = myapp.settings.theme.chosen_style(myapp.settings.no_blink);
+                let song_items: Vec<ListItem> = myapp
+                    .filtered_songs
+                    .iter()
+                    .skip(myapp.list_offset)
+                    .take(visible_song_count)
+                    .map(|song| {
+                        let mut style = Style::default();
+                        if myapp.chosen_song_ids.contains(&song.id) {
+                            style = chosen_style;
+                        }
+                        if let Some(selected_id) = myapp.selected_song_id {
+                            if selected_id == song.id {
+                                style = style.patch(selected_style);
+                            }
+                        }
+                        let playing = myapp.currently_playing_song == Some(song.id);
+                        ListItem::new(song_list_label(song, playing)).style(style)
+                    })
+                    .collect();
+
+                let song_list = List::new(song_items)
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .border_style(focus_border_style(myapp.focus == Focus::SongList))
+                            .title(format!("Songs----------------------------------------------------------------------Sort by: {}", 
+                                myapp.sort_label()))
+                    )
+                    .highlight_style(
+                        Style::default()
+                            .fg(Color::Yellow)
+                            .add_modifier(Modifier::BOLD),
+                    );
+
+                let playlist_items: Vec<ListItem> = myapp
+                    .playlist_names()
+                    .into_iter()
+                    .enumerate()
+                    .skip(myapp.playlist_list_offset)
+                    .take(visible_playlist_count)
+                    .map(|(index, playlist_name)| {
+                        let mut style = Style::default();
+                        if myapp.selected_playlist_index == index {
+                            style = selected_style;
+                        }
+                        let mut label = if myapp.settings.pinned_playlists.contains(playlist_name) {
+                            format!("★ {}", playlist_name)
+                        } else {
+                            playlist_name.clone()
+                        };
+                        // Marks where the play queue came from
+                        if myapp.queue_source.as_ref() == Some(playlist_name) {
+                            label.push_str(" ♪");
+                        }
+                        ListItem::new(label).style(style)
+                    })
+                    .collect();
+
+                let playlist_list = List::new(playlist_items)
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .border_style(focus_border_style(myapp.focus == Focus::Playlists))
+                            .title("Playlists"),
+                    )
+                    .highlight_style(
+                        Style::default()
+                            .fg(Color::Yellow)
+                            .add_modifier(Modifier::BOLD),
+                    );
+
+                f.render_widget(song_list, chunks[1]);
+
+                let footer = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(80), Constraint::Percentage(20)])
+                    .split(song_tab_layout[2]);
+
+                if compact {
+                    let song_progress = song_progress.block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title(now_playing_title)
+                            .title(Line::from(mode_indicators).right_aligned()),
+                    );
+                    f.render_widget(song_progress, footer[0]);
+                } else {
+                    f.render_widget(song_progress, footer[0]);
+                }
+
+                f.render_widget(volume_bar, footer[1]);
+
+                if !compact {
+                    f.render_widget(playlist_list, chunks[0]);
+
+                    let songs_info = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+                        .split(chunks[2]);
+
+                    f.render_widget(selected_song_info, songs_info[0]);
+
+                    let playing_song_block = Block::default()
+                        .borders(Borders::ALL)
+                        .title("Currently playing");
+                    let inner_layout = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([
+                            Constraint::Fill(1),
+                            Constraint::Percentage(art_percentage),
+                        ])
+                        .split(playing_song_block.inner(songs_info[1]));
+
+                    f.render_widget(playing_song_info, inner_layout[0]);
+                    match cover_protocol.as_mut().map(|(_, pic)| pic) {
+                        _ if art_percentage == 0 => {}
+                        Some(pic) => f.render_stateful_widget(img, inner_layout[1], pic),
+                        None => {
+                            let placeholder = Paragraph::new("♪ No cover")
+                                .alignment(Alignment::Center)
+                                .style(Style::default().fg(Color::DarkGray));
+                            f.render_widget(placeholder, inner_layout[1]);
+                        }
+                    }
+                    f.render_widget(playing_song_block, songs_info[1]);
+                }
+
+                if myapp.hint_popup_state.visible {
+                    let _ = draw_popup(f);
+                }
+
+                if myapp.input_popup.visible {
+                    let title = match &myapp.input_purpose {
+                        InputPurpose::PlaylistName => "Enter Playlist Name".to_string(),
+                        InputPurpose::BookmarkLabel { .. } => "Bookmark Label".to_string(),
+                        InputPurpose::SongTags { ids, field, .. } => {
+                            let tag = match field {
+                                TagField::Title => "Title (Enter: next tag)",
+                                TagField::Artist => "Artist (Enter: next tag)",
+                                TagField::Album => "Album (Enter: save)",
+                            };
+                            if myapp.chosen_song_ids.is_empty() {
+                                tag.to_string()
+                            } else {
+                                format!("{} songs, empty keeps theirs: {}", ids.len(), tag)
+                            }
+                        }
+                    };
+                    let _ = draw_input_popup(f, &title, &myapp.input_text);
+                }
+
+                if myapp.bookmarks_popup.visible {
+                    let _ = draw_bookmarks_popup(f, &myapp);
+                }
+
+                if myapp.scan_log_popup.visible {
+                    let _ = draw_scan_log_popup(f, &myapp.scan_log, myapp.scan_log_scroll);
+                }
+
+                if myapp.queue_popup.visible {
+                    let _ = draw_queue_popup(f, &myapp);
+                }
+
+                if myapp.lyrics_popup.visible {
+                    let _ = draw_lyrics_popup(f, lyrics.as_ref(), lyrics_elapsed, myapp.lyrics_scroll);
+                }
+                
+            f.render_widget(
+                hint,
+                Rect::new(
+                    f.area().width.saturating_sub(20),
+                    f.area().height - 1,
+                    20,
+                    1,
+                ),
+            );
+            f.render_widget(
+                status_line,
+                Rect::new(
+                    1,
+                    f.area().height - 1,
+                    f.area().width.saturating_sub(22),
+                    1,
+                ),
+            );
+        });
+        if let Err(e) = drawn {
+            myapp.save_on_shutdown();
+            break Err(e);
+        }
+    };
+
+    // Cleanup
+    myapp.clear_now_playing();
+    stdout().execute(DisableBracketedPaste)?;
+    disable_raw_mode()?;
+    stdout().execute(Clear(crossterm::terminal::ClearType::All))?;
+    if let Some(message) = myapp.quit_message.take() {
+        eprintln!("{}", message);
+    }
+    Ok(result?)
+}
+
+/// Builds the command opening a folder in the system file manager.
+///
+/// # Arguments
+/// * `folder` - The folder to open.
+fn file_manager_command(folder: &Path) -> std::process::Command {
+    let program = if cfg!(target_os = "windows") {
+        "explorer"
+    } else if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    let mut command = std::process::Command::new(program);
+    command.arg(folder);
+    command
+}
+
+/// Returns the language code of the user's locale, e.g. `de` for `de_DE.UTF-8`, from the
+/// `LC_ALL`, `LC_NUMERIC` and `LANG` environment variables.
+fn locale_language() -> String {
+    ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .split(['_', '.', '@', '-'])
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the usual thousands separator for a language.
+///
+/// # Arguments
+/// * `language` - The language code, e.g. `de`.
+fn locale_thousands_separator(language: &str) -> &'static str {
+    match language {
+        "de" | "nl" | "it" | "es" | "pt" | "da" | "id" | "tr" | "el" => ".",
+        "fr" | "ru" | "pl" | "cs" | "sk" | "sv" | "fi" | "nb" | "uk" | "hu" => "\u{a0}",
+        _ => ",",
+    }
+}
+
+/// Formats a count with its digits grouped in threes, e.g. `12,345`.
+///
+/// # Arguments
+/// * `count` - The number to format.
+/// * `separator` - Put between groups of digits; empty for none.
+fn format_count(count: usize, separator: &str) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            formatted.push_str(separator);
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+/// Returns the row shown for a song in the song list, marking the playing one with `▶`.
+///
+/// # Arguments
+/// * `song` - The song.
+/// * `playing` - Whether it's the currently playing song.
+fn song_list_label(song: &Song, playing: bool) -> String {
+    if playing {
+        format!("▶ {}", song.title)
+    } else {
+        song.title.clone()
+    }
+}
+
+/// Returns the format of a song for display, e.g. `MP3`, from its file extension.
+///
+/// # Arguments
+/// * `path` - The path of the song.
+fn song_format(path: &Path) -> String {
+    if stream_url(path).is_some() {
+        return "Stream".to_string();
+    }
+    path.extension()
+        .map_or_else(|| "Unknown".to_string(), |ext| ext.to_string_lossy().to_uppercase())
+}
+
+/// Shortens text to at most `max_chars` characters by replacing its middle with `…`, keeping
+/// both ends of a path readable.
+///
+/// # Arguments
+/// * `text` - The text to shorten.
+/// * `max_chars` - The maximum length in characters.
+fn truncate_middle(text: &str, max_chars: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= max_chars {
+        return text.to_string();
+    }
+    let kept = max_chars.saturating_sub(1);
+    let head = kept / 2;
+    let tail = kept - head;
+    chars[..head]
+        .iter()
+        .chain(['…'].iter())
+        .chain(chars[chars.len() - tail..].iter())
+        .collect()
+}
+
+/// Returns when a file was last modified, if it exists.
+///
+/// # Arguments
+/// * `path` - The file to check.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Returns the directory the player keeps its data in.
+fn app_config_dir() -> Option<PathBuf> {
+    dirs::config_local_dir().map(|dir| dir.join("cli-rhythm"))
+}
+
+/// Returns whether files can be created in a directory, creating it if it's missing.
+///
+/// # Arguments
+/// * `dir` - The directory to check.
+fn dir_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    let writable = fs::create_dir_all(dir).is_ok() && fs::File::create(&probe).is_ok();
+    let _ = fs::remove_file(&probe);
+    writable
+}
+
+/// Saves the search history as a JSON list of searches, oldest first.
+///
+/// # Arguments
+/// * `path` - The file holding the search history.
+/// * `history` - The searches to save.
+fn save_search_history(path: &Path, history: &[String]) -> io::Result<()> {
+    let serialized = serde_json::to_string(history)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_atomically(path, serialized.as_bytes())
+}
+
+/// Makes a tag or playlist name usable as a single file or folder name, replacing path
+/// separators and characters Windows doesn't allow.
+///
+/// # Arguments
+/// * `name` - The name to use.
+fn path_component(name: &str) -> String {
+    let component: String = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match component.trim_matches('.') {
+        "" => "_".to_string(),
+        _ => component,
+    }
+}
+
+/// Copies songs into a folder, skipping the ones that fail, and optionally writes an `.m3u`
+/// playlist of the copies with paths relative to the folder. Returns the number of songs
+/// copied and the songs that failed.
+///
+/// # Arguments
+/// * `tracks` - The songs to copy, in playlist order.
+/// * `dir` - The folder to copy them to, created if needed.
+/// * `layout` - Whether to put songs in `Artist/Album` folders.
+/// * `m3u_name` - File name of the playlist to write, if any.
+/// * `progress` - Called with the number of songs handled so far and the number of songs.
+fn export_tracks(
+    tracks: &[ExportTrack],
+    dir: &Path,
+    layout: ExportLayout,
+    m3u_name: Option<&str>,
+    progress: impl Fn(usize, usize),
+) -> (usize, Vec<(PathBuf, io::Error)>) {
+    let mut copied = 0;
+    let mut failed = Vec::new();
+    let mut m3u = String::from("#EXTM3U\n");
+    let mut used_paths = HashSet::new();
+    for (index, track) in tracks.iter().enumerate() {
+        progress(index, tracks.len());
+        let Some(file_name) = track.path.file_name() else {
+            continue;
+        };
+        let mut relative_path = PathBuf::new();
+        if layout == ExportLayout::ArtistAlbum {
+            relative_path.push(path_component(&track.album_artist));
+            relative_path.push(path_component(&track.album));
+        }
+        relative_path.push(file_name);
+        // Songs with the same file name from different folders get numbered
+        let mut number = 1;
+        while !used_paths.insert(relative_path.clone()) {
+            number += 1;
+            let stem = Path::new(file_name)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy();
+            let mut numbered = format!("{} ({})", stem, number);
+            if let Some(extension) = Path::new(file_name).extension() {
+                numbered.push('.');
+                numbered.push_str(&extension.to_string_lossy());
+            }
+            relative_path.set_file_name(numbered);
+        }
+
+        let target = dir.join(&relative_path);
+        let copy_result = target
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::copy(&track.path, &target));
+        match copy_result {
+            Ok(_) => {
+                copied += 1;
+                let relative: Vec<_> = relative_path
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect();
+                m3u.push_str(&format!(
+                    "#EXTINF:{},{} - {}\n{}\n",
+                    track.duration.round() as i64,
+                    track.artist,
+                    track.title,
+                    relative.join("/")
+                ));
+            }
+            Err(e) => failed.push((track.path.clone(), e)),
+        }
+    }
+    progress(tracks.len(), tracks.len());
+
+    if let Some(m3u_name) = m3u_name {
+        let written = fs::create_dir_all(dir).and_then(|_| fs::write(dir.join(m3u_name), &m3u));
+        if let Err(e) = written {
+            failed.push((dir.join(m3u_name), e));
+        }
+    }
+    (copied, failed)
+}
+
+/// Replaces the contents of a file without ever leaving it half written.
+///
+/// The data goes to a temporary file in the same directory, which is then renamed over `path`,
+/// so an interrupted write leaves the previous file untouched.
+///
+/// # Arguments
+/// * `path` - The file to write.
+/// * `contents` - The new contents of the file.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Plays the whole library in order without the terminal interface.
+///
+/// # Arguments
+/// * `myapp` - The application state.
+/// * `sink` - The `Sink` the songs are played through.
+/// * `event_writer` - Where playback events are reported.
+/// * `control_fifo` - Where playback commands are read from.
+/// * `shutdown` - Set when the process is asked to terminate.
+fn run_headless(
+    myapp: &mut MyApp,
+    sink: &Arc<Mutex<Sink>>,
+    mut event_writer: Option<&mut EventWriter>,
+    mut control_fifo: Option<&mut ControlFifo>,
+    shutdown: &AtomicBool,
+) {
+    myapp.filtered_songs = myapp
+        .songs
+        .iter()
+        .filter(|song| stream_url(&song.path).is_none())
+        .cloned()
+        .collect();
+    // A stream given with `--stream` is already playing, and a restored queue continues
+    if myapp.currently_playing_song.is_none() {
+        let first_id = myapp
+            .play_queue
+            .first()
+            .copied()
+            .or_else(|| myapp.filtered_songs.first().map(|song| song.id));
+        if let Some(first_id) = first_id {
+            myapp.start_song(first_id, sink);
+        }
+    }
+
+    loop {
+        if shutdown.load(Ordering::SeqCst) {
+            myapp.save_on_shutdown();
+            break;
+        }
+        myapp.update_playback(sink);
+        myapp.update_now_playing();
+        myapp.update_loudness();
+        if let Some(fifo) = control_fifo.as_mut() {
+            process_fifo_commands(myapp, fifo, sink);
+        }
+        if let Some((message, _)) = myapp.status_message.take() {
+            eprintln!("{}", message);
+        }
+        if let Some(events) = event_writer.as_mut() {
+            // Stop once nobody is reading the events anymore.
+            if events.update(myapp, sink).is_err() {
+                break;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Applies a command from the control FIFO using the same handlers as the keyboard.
+///
+/// # Arguments
+/// * `myapp` - The application state.
+/// * `command` - The command to apply.
+/// * `sink` - The `Sink` the songs are played through.
+fn handle_command(myapp: &mut MyApp, command: Command, sink: &Arc<Mutex<Sink>>) {
+    let paused = sink.lock().unwrap().is_paused() || myapp.pausing();
+    match command {
+        Command::Play if paused => myapp.toggle_pause(sink),
+        Command::Play if myapp.currently_playing_song.is_none() => myapp.toggle_play(sink),
+        Command::Pause if !paused => myapp.toggle_pause(sink),
+        Command::Play | Command::Pause => {}
+        Command::Next => myapp.play_next(sink),
+        Command::Previous => myapp.play_previous(sink),
+        Command::Seek(seconds) => myapp.seek_by(sink, seconds),
+        Command::Volume(volume) => myapp.set_volume(sink, (volume * 100.0).round() as u32),
+    }
+}
+
+/// Reads pending commands from the control FIFO and applies them.
+///
+/// # Arguments
+/// * `myapp` - The application state.
+/// * `fifo` - The control FIFO.
+/// * `sink` - The `Sink` the songs are played through.
+fn process_fifo_commands(myapp: &mut MyApp, fifo: &mut ControlFifo, sink: &Arc<Mutex<Sink>>) {
+    for line in fifo.read_lines() {
+        myapp.needs_redraw = true;
+        match Command::parse(&line) {
+            Some(command) => handle_command(myapp, command, sink),
+            None => myapp.set_status(format!("Ignored unknown command: {}", line)),
+        }
+    }
+}
+
+/// Returns the character typed with Shift held.
+///
+/// Terminals usually deliver the shifted character already (`A`, `!`), so it is kept
+/// as is unless it is a lowercase letter with a single-character uppercase form.
+///
+/// # Arguments
+/// * `c` - The character reported with the Shift modifier.
+fn shifted_char(c: char) -> char {
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(single), None) => single,
+        _ => c,
+    }
+}
+
+/// Applies a plain character key in vim normal mode.
+///
+/// `j`/`k` move through the focused list, `h`/`l` seek and `/` enters insert mode in the
+/// search bar; other characters are ignored rather than typed.
+///
+/// # Arguments
+/// * `myapp` - The application state.
+/// * `c` - The typed character.
+/// * `sink` - The `Sink` the songs are played through.
+/// * `visible_song_count` - Number of rows visible in the song list.
+/// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+fn handle_normal_mode_key(
+    myapp: &mut MyApp,
+    c: char,
+    sink: &Arc<Mutex<Sink>>,
+    visible_song_count: usize,
+    visible_playlist_count: usize,
+) {
+    match (c, myapp.focus) {
+        ('j', Focus::Playlists) => myapp.select_next_playlist(visible_playlist_count),
+        ('k', Focus::Playlists) => myapp.select_previous_playlist(visible_playlist_count),
+        ('j', _) => myapp.select_next_song(visible_song_count),
+        ('k', _) => myapp.select_previous_song(visible_song_count),
+        ('h', _) => myapp.seek_by(sink, -5),
+        ('l', _) => myapp.seek_by(sink, 5),
+        ('/', _) => {
+            myapp.focus = Focus::Search;
+            myapp.move_search_cursor(usize::MAX);
+        }
+        _ => {}
+    }
+}
+
+/// Applies a key press to the application state.
+///
+/// # Arguments
+/// * `myapp` - The application state.
+/// * `key` - The key event to handle.
+/// * `sink` - The `Sink` the songs are played through.
+/// * `visible_song_count` - Number of rows visible in the song list.
+/// * `visible_playlist_count` - Number of rows visible in the playlist pane.
+///
+/// # Returns
+/// `true` if the application should exit.
+fn handle_key_event(
+    myapp: &mut MyApp,
+    key: KeyEvent,
+    sink: &Arc<Mutex<Sink>>,
+    visible_song_count: usize,
+    visible_playlist_count: usize,
+) -> bool {
+    if myapp.queue_popup.visible && key.kind == KeyEventKind::Press {
+        let handled = match (key.code, key.modifiers) {
+            (KeyCode::Up, KeyModifiers::NONE) => {
+                myapp.select_queue_entry(false);
+                true
+            }
+            (KeyCode::Down, KeyModifiers::NONE) => {
+                myapp.select_queue_entry(true);
+                true
+            }
+            (KeyCode::Up, KeyModifiers::SHIFT) => {
+                myapp.move_queue_entry(false);
+                true
+            }
+            (KeyCode::Down, KeyModifiers::SHIFT) => {
+                myapp.move_queue_entry(true);
+                true
+            }
+            (KeyCode::Delete, _) => {
+                myapp.remove_queue_entry();
+                true
+            }
+            _ => false,
+        };
+        if handled {
+            return false;
+        }
+    }
+
+    if myapp.bookmarks_popup.visible && key.kind == KeyEventKind::Press {
+        let handled = match key.code {
+            KeyCode::Up => {
+                myapp.select_bookmark(false);
+                true
+            }
+            KeyCode::Down => {
+                myapp.select_bookmark(true);
+                true
+            }
+            KeyCode::Enter => {
+                myapp.jump_to_bookmark(sink);
+                true
+            }
+            KeyCode::Delete => {
+                myapp.remove_bookmark();
+                true
+            }
+            _ => false,
+        };
+        if handled {
+            return false;
+        }
+    }
+
+    if myapp.scan_log_popup.visible && key.kind == KeyEventKind::Press {
+        let scroll = match key.code {
+            KeyCode::Up => Some(myapp.scan_log_scroll.saturating_sub(1)),
+            KeyCode::Down => Some(myapp.scan_log_scroll.saturating_add(1)),
+            KeyCode::PageUp => Some(myapp.scan_log_scroll.saturating_sub(10)),
+            KeyCode::PageDown => Some(myapp.scan_log_scroll.saturating_add(10)),
+            _ => None,
+        };
+        if let Some(scroll) = scroll {
+            let last_line =
+                u16::try_from(myapp.scan_log.len().saturating_sub(1)).unwrap_or(u16::MAX);
+            myapp.scan_log_scroll = scroll.min(last_line);
+            return false;
+        }
+    }
+
+    if myapp.lyrics_popup.visible && key.kind == KeyEventKind::Press {
+        let scroll = match key.code {
+            KeyCode::Up => Some(myapp.lyrics_scroll.saturating_sub(1)),
+            KeyCode::Down => Some(myapp.lyrics_scroll.saturating_add(1)),
+            KeyCode::PageUp => Some(myapp.lyrics_scroll.saturating_sub(10)),
+            KeyCode::PageDown => Some(myapp.lyrics_scroll.saturating_add(10)),
+            _ => None,
+        };
+        if let Some(scroll) = scroll {
+            let line_count = match myapp.current_lyrics() {
+                Some(Lyrics::Plain(contents)) => contents.lines().count(),
+                _ => 0,
+            };
+            let last_line = u16::try_from(line_count.saturating_sub(1)).unwrap_or(u16::MAX);
+            myapp.lyrics_scroll = scroll.min(last_line);
+            return false;
+        }
+    }
+
+    if myapp.settings.vim_mode && myapp.focus != Focus::Search && !myapp.input_popup.visible {
+        if let KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+            kind: KeyEventKind::Press,
+            ..
+        } = key
+        {
+            handle_normal_mode_key(myapp, c, sink, visible_song_count, visible_playlist_count);
+            return false;
+        }
+    }
+
+    match key {
+        KeyEvent {
+            code: KeyCode::Char('q'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            return myapp.save_and_quit();
+        }
+        KeyEvent {
+            code: KeyCode::Down,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            if myapp.focus == Focus::Playlists {
+                myapp.select_next_playlist(visible_playlist_count);
+            } else {
+                myapp.select_next_song(visible_song_count);
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Up,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            if myapp.focus == Focus::Playlists {
+                myapp.select_previous_playlist(visible_playlist_count);
+            } else {
+                myapp.select_previous_song(visible_song_count);
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Char('j'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.select_next_playlist(visible_playlist_count);
+        }
+        KeyEvent {
+            code: KeyCode::Char('k'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.select_previous_playlist(visible_playlist_count);
+        }
+        KeyEvent {
+            code: KeyCode::Char(' '),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_play(sink);
+        }
+        KeyEvent {
+            code: KeyCode::Char('p'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_pause(sink);
+        }
+        KeyEvent {
+            code: KeyCode::Char('c'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.input_purpose = InputPurpose::PlaylistName;
+            myapp.input_popup.visible = true;
+        }
+        KeyEvent {
+            code: KeyCode::Char('h'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.play_previous(sink);
+        }
+        KeyEvent {
+            code: KeyCode::Char('l'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.play_next(sink);
+        }
+        KeyEvent {
+            code: KeyCode::Left,
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            // Decrease volume by 5%
+            myapp.set_volume(sink, myapp.volume_percent().saturating_sub(5));
+        }
+        KeyEvent {
+            code: KeyCode::Right,
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            // Increase volume by 5%
+            myapp.set_volume(sink, myapp.volume_percent() + 5);
+        }
+        KeyEvent {
+            code: KeyCode::Char('m'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_mute(sink);
+        }
+        KeyEvent {
+            code: code @ (KeyCode::Left | KeyCode::Right),
+            modifiers,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if modifiers == KeyModifiers::CONTROL | KeyModifiers::SHIFT => {
+            let step = if code == KeyCode::Right { 10 } else { -10 };
+            myapp.set_balance(myapp.settings.balance.saturating_add(step));
+        }
+        KeyEvent {
+            code: KeyCode::Char('b'),
+            modifiers: KeyModifiers::ALT,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.set_balance(0);
+        }
+        KeyEvent {
+            code: KeyCode::Char('m'),
+            modifiers: KeyModifiers::ALT,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_mono(sink);
+        }
+        KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            if !myapp.input_popup.visible {
+                // Typing always goes to the search bar
+                myapp.focus = Focus::Search;
+            }
+            myapp.insert_char(c);
+        }
+        KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::SHIFT,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            if !myapp.input_popup.visible {
+                myapp.focus = Focus::Search;
+            }
+            myapp.insert_char(shifted_char(c));
+        }
+        KeyEvent {
+            code: KeyCode::Backspace,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.delete_char_before_cursor();
+        }
+        KeyEvent {
+            code: KeyCode::Char('n'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_all_songs(visible_playlist_count);
+        }
+        KeyEvent {
+            code: KeyCode::Char('u'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.clear_input();
+        }
+        KeyEvent {
+            code: KeyCode::Char('w'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.delete_word_before_cursor();
+        }
+        KeyEvent {
+            code: KeyCode::Char('s'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.search_criteria = myapp.search_criteria.next();
+        }
+        // Terminals that can't tell Ctrl+Shift+S from Ctrl+S still send Alt+S
+        KeyEvent {
+            code: KeyCode::Char('s' | 'S'),
+            modifiers,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if modifiers == KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            || modifiers == KeyModifiers::ALT =>
+        {
+            myapp.search_criteria = myapp.search_criteria.prev();
+        }
+        KeyEvent {
+            code: KeyCode::Char('t'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.set_sort_criteria(myapp.sort_criteria.next());
+        }
+        KeyEvent {
+            code: KeyCode::Char('t' | 'T'),
+            modifiers,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if modifiers == KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            || modifiers == KeyModifiers::ALT =>
+        {
+            myapp.set_sort_criteria(myapp.sort_criteria.prev());
+        }
+        KeyEvent {
+            code: code @ (KeyCode::Left | KeyCode::Right),
+            modifiers: KeyModifiers::ALT,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.resize_song_list(code == KeyCode::Right);
+        }
+        KeyEvent {
+            code: KeyCode::Right,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            if myapp.focus == Focus::Search {
+                myapp.move_search_cursor(myapp.search_cursor + 1);
+            } else {
+                myapp.seek_by(sink, 5);
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Left,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            if myapp.focus == Focus::Search {
+                myapp.move_search_cursor(myapp.search_cursor.saturating_sub(1));
+            } else {
+                myapp.seek_by(sink, -5);
+            }
+        }
+        KeyEvent {
+            code: KeyCode::F(1),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.hint_popup_state.toggle();
+        }
+        KeyEvent {
+            code: KeyCode::Esc,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            // Close the popup if it's open
+            myapp.close_input_popup();
+            myapp.bookmarks_popup.visible = false;
+            myapp.scan_log_popup.visible = false;
+            myapp.hint_popup_state.visible = false;
+            myapp.lyrics_popup.visible = false;
+            myapp.queue_popup.visible = false;
+            myapp.set_focus(Focus::SongList);
+        }
+        KeyEvent {
+            code: KeyCode::Char('r'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.cycle_repeat_mode();
+        }
+        KeyEvent {
+            code: KeyCode::Char('e'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_stop_after_current();
+        }
+        KeyEvent {
+            code: KeyCode::F(2),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.queue_popup.toggle();
+            myapp.queue_selected = 1;
+        }
+        KeyEvent {
+            code: KeyCode::F(8),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.start_bookmark();
+        }
+        KeyEvent {
+            code: KeyCode::F(11),
+            modifiers,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if modifiers == KeyModifiers::NONE || modifiers == KeyModifiers::SHIFT => {
+            let whole_library = modifiers == KeyModifiers::SHIFT;
+            myapp.play_random_song(sink, whole_library, visible_playlist_count);
+        }
+        KeyEvent {
+            code: KeyCode::F(12),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.select_queue_source(visible_playlist_count);
+        }
+        KeyEvent {
+            code: KeyCode::F(10),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.start_tag_edit();
+        }
+        KeyEvent {
+            code: KeyCode::F(9),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.bookmarks_popup.toggle();
+            myapp.bookmark_selected = 0;
+        }
+        KeyEvent {
+            code: KeyCode::F(7),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.export_selected_playlist();
+        }
+        KeyEvent {
+            code: KeyCode::F(6),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_pin_selected_playlist();
+        }
+        KeyEvent {
+            code: KeyCode::F(5),
+            modifiers: KeyModifiers::SHIFT,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.scan_log_popup.toggle();
+            myapp.scan_log_scroll = 0;
+        }
+        KeyEvent {
+            code: KeyCode::F(5),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.start_rescan();
+        }
+        KeyEvent {
+            code: KeyCode::F(4),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.reveal_selected_song();
+        }
+        KeyEvent {
+            code: KeyCode::F(3),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.toggle_layout();
+        }
+        KeyEvent {
+            code: KeyCode::Char('g'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.sort_selected_playlist();
+        }
+        KeyEvent {
+            code: KeyCode::Char('d'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.cut_selected_song();
+        }
+        KeyEvent {
+            code: KeyCode::Char('v'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.paste_cut_song(false);
+        }
+        KeyEvent {
+            code: KeyCode::Char('b'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.paste_cut_song(true);
+        }
+        KeyEvent {
+            code: KeyCode::Char('o'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.play_selected_playlist(sink);
+        }
+        KeyEvent {
+            code: KeyCode::Char('y'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.lyrics_popup.toggle();
+            myapp.lyrics_scroll = 0;
+        }
+        KeyEvent {
+            code: KeyCode::Char('f'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.set_focus(match myapp.focus {
+                Focus::Search => Focus::SongList,
+                _ => Focus::Search,
+            });
+        }
+        KeyEvent {
+            code: KeyCode::Tab,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.set_focus(myapp.focus.next());
+        }
+        KeyEvent {
+            code: KeyCode::BackTab,
+            modifiers: KeyModifiers::SHIFT,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.set_focus(myapp.focus.prev());
+        }
+        KeyEvent {
+            code: code @ (KeyCode::Up | KeyCode::Down),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if myapp.focus == Focus::Search => {
+            myapp.recall_search(code == KeyCode::Up);
+        }
+        KeyEvent {
+            code: KeyCode::Home,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if myapp.focus == Focus::Search => {
+            myapp.move_search_cursor(0);
+        }
+        KeyEvent {
+            code: KeyCode::End,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if myapp.focus == Focus::Search => {
+            myapp.move_search_cursor(usize::MAX);
+        }
+        KeyEvent {
+            code: KeyCode::Delete,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } if myapp.focus == Focus::Search => {
+            myapp.delete_char_at_cursor();
+        }
+        KeyEvent {
+            code: code @ (KeyCode::PageUp | KeyCode::PageDown | KeyCode::Home | KeyCode::End),
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            if myapp.focus == Focus::Playlists {
+                let current = myapp.selected_playlist_index;
+                let page = visible_playlist_count.max(1);
+                let target = match code {
+                    KeyCode::PageUp => current.saturating_sub(page),
+                    KeyCode::PageDown => current + page,
+                    KeyCode::Home => 0,
+                    _ => usize::MAX,
+                };
+                myapp.select_playlist_at(target, visible_playlist_count);
+            } else {
+                let current = myapp.selected_song_index().unwrap_or(0);
+                let page = visible_song_count.max(1);
+                let target = match code {
+                    KeyCode::PageUp => current.saturating_sub(page),
+                    KeyCode::PageDown => current + page,
+                    KeyCode::Home => 0,
+                    _ => usize::MAX,
+                };
+                myapp.select_song_at(target, visible_song_count);
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Enter,
+            modifiers: KeyModifiers::NONE,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            myapp.submit_input();
+        }
+        KeyEvent {
+            code: KeyCode::Char('a'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            let selected_song_id = myapp
+                .selected_song_id
+                .unwrap_or(Uuid::new_v5(&Uuid::NAMESPACE_DNS, b"rust-lang.org"));
+            match myapp.chosen_song_ids.contains(&selected_song_id) {
+                true => {
+                    myapp.chosen_song_ids.retain(|id| *id != selected_song_id);
+                }
+                false => {
+                    myapp.chosen_song_ids.push(selected_song_id);
+                }
+            }
+        }
+        KeyEvent {
+            code: KeyCode::Char('x'),
+            modifiers: KeyModifiers::CONTROL,
+            kind: KeyEventKind::Press,
+            state: KeyEventState::NONE,
+        } => {
+            // Get the playlist name at the selected index
+            let playlist_name = myapp.selected_playlist_name().cloned();
+
+            if let Some(name) = playlist_name
+                .as_ref()
+                .filter(|name| GENERATED_PLAYLISTS.contains(&name.as_str()))
+            {
+                myapp.set_status(format!("\"{}\" can't be deleted", name));
+            } else if let Some(name) = playlist_name {
+                myapp.playlists.remove(&name);
+                myapp.playlists_unsaved = true;
+                myapp.last_selected_songs.remove(&name);
+                myapp.selected_playlist_index = 0;
+            }
+        }
+        _ => {}
+    }
+    false
+}
+
+/// Reads unsynchronized lyrics from the tags of a song: ID3 `USLT` frames for MP3 and
+/// `LYRICS`/`UNSYNCEDLYRICS` comments for FLAC.
+///
+/// # Arguments
+/// * `path` - The path of the audio file.
+fn read_embedded_lyrics(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    let lyrics = match extension.as_str() {
+        "mp3" => id3::Tag::read_from_path(path)
+            .ok()?
+            .lyrics()
+            .map(|lyrics| lyrics.text.clone())
+            .find(|text| !text.trim().is_empty()),
+        "flac" => {
+            let reader = claxon::FlacReader::open(path).ok()?;
+            let lyrics = reader
+                .get_tag("LYRICS")
+                .chain(reader.get_tag("UNSYNCEDLYRICS"))
+                .find(|text| !text.trim().is_empty())
+                .map(str::to_string);
+            lyrics
+        }
+        _ => None,
+    };
+    lyrics.map(|text| text.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// Loads album art stored next to the songs, e.g. `cover.jpg` or `folder.png`.
+///
+/// # Arguments
+/// * `dir` - The directory to look in.
+fn find_folder_cover(dir: &Path) -> Option<DynamicImage> {
+    folder_cover_paths(dir)
+        .iter()
+        .find_map(|path| image::open(path).ok())
+}
+
+/// Lists the album art files next to the songs, best first.
+///
+/// File names are matched case-insensitively, preferring `cover`, then `folder`, then `front`.
+///
+/// # Arguments
+/// * `dir` - The directory to look in.
+fn folder_cover_paths(dir: &Path) -> Vec<PathBuf> {
+    const NAMES: [&str; 3] = ["cover", "folder", "front"];
+    const EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    let lowercase = |part: Option<&std::ffi::OsStr>| {
+        part.and_then(|part| part.to_str()).map(str::to_lowercase)
+    };
+
+    NAMES
+        .iter()
+        .flat_map(|name| {
+            files
+                .iter()
+                .filter(move |path| lowercase(path.file_stem()).as_deref() == Some(name))
+        })
+        .filter(|path| {
+            lowercase(path.extension()).is_some_and(|ext| EXTENSIONS.contains(&ext.as_str()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Fills in a `now_playing_format`.
+///
+/// `{artist}`, `{title}` and `{album}` are replaced by the song's tags and `{art}` by the path of
+/// the album art next to it, or nothing if there's none.
+///
+/// # Arguments
+/// * `format` - The format to fill in.
+/// * `song` - The playing song.
+fn now_playing_text(format: &str, song: &Song) -> String {
+    let (artist, title) = match song.stream_title.lock().unwrap().clone() {
+        Some(on_air) => (song.title.clone(), on_air),
+        None => (song.artist.clone(), song.title.clone()),
+    };
+    let art = if format.contains("{art}") {
+        song.path
+            .parent()
+            .and_then(|dir| folder_cover_paths(dir).into_iter().next())
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    format
+        .replace("{artist}", &artist)
+        .replace("{title}", &title)
+        .replace("{album}", &song.album)
+        .replace("{art}", &art)
+}
+
+/// Most problems kept in the scan log.
+const SCAN_LOG_LIMIT: usize = 1000;
+
+/// Ends a scan log that hit `SCAN_LOG_LIMIT`.
+const SCAN_LOG_TRUNCATED: &str = "More problems were left out";
+
+/// Adds a problem found while scanning to the scan log, unless it's full.
+///
+/// # Arguments
+/// * `scan_log` - The problems found so far.
+/// * `entry` - The problem to add.
+fn log_scan_problem(scan_log: &mut Vec<String>, entry: String) {
+    match scan_log.len().cmp(&SCAN_LOG_LIMIT) {
+        std::cmp::Ordering::Less => scan_log.push(entry),
+        std::cmp::Ordering::Equal => scan_log.push(SCAN_LOG_TRUNCATED.to_string()),
+        std::cmp::Ordering::Greater => {}
+    }
+}
+
+/// Returns the directory the library is scanned from: the configured one if it exists,
+/// otherwise the system music directory, then the current directory.
+///
+/// # Arguments
+/// * `music_dir` - The configured music directory, if any.
+fn resolve_music_dir(music_dir: Option<&Path>) -> PathBuf {
+    match music_dir {
+        Some(dir) if dir.is_dir() => dir.to_path_buf(),
+        _ => dirs::audio_dir().unwrap_or_else(|| env::current_dir().unwrap()),
+    }
+}
+
+/// Scans the music directory for songs, falling back to the system music directory and then
+/// the current directory. The current directory is also scanned when the system music directory
+/// exists but has no music in it.
+///
+/// The songs of the extra directories are added to the same library, each file only once
+/// however many of the directories it's in.
+///
+/// # Arguments
+/// * `music_dir` - The configured music directory, if any.
+/// * `extra_dirs` - Further directories scanned along with it.
+/// * `missing_title` - What songs without a title tag are called.
+/// * `load_art` - Whether to read the album art of the songs.
+/// * `scan_log` - Where problems with folders and files are added.
+fn scan_folder_for_music(
+    music_dir: Option<&Path>,
+    extra_dirs: &[PathBuf],
+    missing_title: MissingTitle,
+    load_art: bool,
+    scan_log: &mut Vec<String>,
+) -> Vec<Song> {
+    if let Some(dir) = music_dir.filter(|dir| !dir.is_dir()) {
+        log_scan_problem(
+            scan_log,
+            format!(
+                "Music directory {} not found, using the default",
+                dir.display()
+            ),
+        );
+    }
+    let candidates = match music_dir {
+        Some(dir) if dir.is_dir() => vec![dir.to_path_buf()],
+        _ => {
+            let mut candidates: Vec<PathBuf> = dirs::audio_dir().into_iter().collect();
+            if let Ok(current_dir) = env::current_dir() {
+                if !candidates.contains(&current_dir) {
+                    candidates.push(current_dir);
+                }
+            }
+            candidates
+        }
+    };
+
+    let mut song_list = scan_first_with_music(&candidates, missing_title, load_art, scan_log);
+
+    let mut seen: HashSet<PathBuf> = song_list.iter().map(|song| song.path.clone()).collect();
+    for dir in extra_dirs {
+        match scan_dir_for_music(dir, missing_title, load_art, scan_log) {
+            Ok(songs) => song_list.extend(
+                songs
+                    .into_iter()
+                    .filter(|song| seen.insert(song.path.clone())),
+            ),
+            Err(e) => log_scan_problem(scan_log, format!("Couldn't read {}: {}", dir.display(), e)),
+        }
+    }
+
+    if song_list.is_empty() {
+        song_list.push(Song::new(
+            "No songs in \"Music\" and current directory!".to_string(),
+            "No Title".to_string(),
+            None,
+            PathBuf::new(),
+            Album {
+                title: "None",
+                artist: None,
+                cover: None,
+            }
+            .title
+            .to_string(),
+            0.0_f64,
+        ));
+    }
+
+    song_list
+}
+
+/// Scans the folders in turn, returning the songs of the first one that has any.
+///
+/// # Arguments
+/// * `folders` - The folders to try, in order.
+/// * `missing_title` - What songs without a title tag are called.
+/// * `load_art` - Whether to read the album art of the songs.
+/// * `scan_log` - Where problems with folders and files are added.
+fn scan_first_with_music(
+    folders: &[PathBuf],
+    missing_title: MissingTitle,
+    load_art: bool,
+    scan_log: &mut Vec<String>,
+) -> Vec<Song> {
+    for folder in folders {
+        match scan_dir_for_music(folder, missing_title, load_art, scan_log) {
+            Ok(songs) if !songs.is_empty() => return songs,
+            Ok(_) => {}
+            Err(e) => log_scan_problem(
+                scan_log,
+                format!("Couldn't read {}: {}", folder.display(), e),
+            ),
+        }
+    }
+    Vec::new()
+}
+
+/// Collects the music files in `dir` and its subfolders, following symlinks and junctions.
+///
+/// Folders are tracked by their real paths, so a link back up the tree or two links to the
+/// same album are only scanned once. Entries are scanned in order of their names, real
+/// folders before links, so the same path is kept every time. Hidden folders are skipped.
+///
+/// # Arguments
+/// * `dir` - The folder to scan.
+/// * `visited` - Real paths of the folders scanned so far.
+/// * `song_paths` - Where the paths of the music files are added.
+/// * `scan_log` - Where subfolders that can't be read are added.
+fn collect_music_paths(
+    dir: &Path,
+    visited: &mut HashSet<PathBuf>,
+    song_paths: &mut Vec<PathBuf>,
+    scan_log: &mut Vec<String>,
+) -> io::Result<()> {
+    if !visited.insert(fs::canonicalize(dir)?) {
+        return Ok(());
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    // Song ids come from their paths, so which path a linked folder is found through mustn't
+    // depend on the order the directory is read in
+    paths.sort_by_cached_key(|path| (path.is_symlink(), path.clone()));
+    for path in paths {
+        // `is_dir` and `is_file` follow links, so linked folders are scanned like real ones
+        if path.is_dir() {
+            let hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if !hidden {
+                if let Err(e) = collect_music_paths(&path, visited, song_paths, scan_log) {
+                    log_scan_problem(scan_log, format!("Couldn't scan {}: {}", path.display(), e));
+                }
+            }
+        } else if path.is_file()
+            && path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| MUSIC_FORMATS.contains(&ext))
+        {
+            song_paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Loads the songs in `dir` and its subfolders.
+///
+/// A file whose tags can't be read is still added, titled after its file name, as long as it
+/// can be decoded; files that can't be played at all are logged and skipped.
+///
+/// # Arguments
+/// * `dir` - The directory to scan.
+/// * `missing_title` - What songs without a title tag are called.
+/// * `load_art` - Whether to read the album art, from the tags or a cover image in the folder.
+/// * `scan_log` - Where problems with subfolders and files are added.
+fn scan_dir_for_music(
+    dir: &Path,
+    missing_title: MissingTitle,
+    load_art: bool,
+    scan_log: &mut Vec<String>,
+) -> io::Result<Vec<Song>> {
+    let mut song_paths = Vec::new();
+    collect_music_paths(dir, &mut HashSet::new(), &mut song_paths, scan_log)?;
+
+    let mut song_list: Vec<Song> = Vec::new();
+    // Each folder's cover is decoded once and shared by all of its songs
+    let mut folder_covers: HashMap<PathBuf, Option<Arc<DynamicImage>>> = HashMap::new();
+    for song in song_paths {
+        let mut current_song = match read_song(&song, load_art) {
+            Ok(mut current_song) => {
+                if current_song.title.is_empty() {
+                    let title = missing_title.title_for(&song);
+                    current_song.set_title(title.as_deref().unwrap_or("No Title"));
+                }
+                current_song
+            }
+            Err(e) if is_decodable(&song) => {
+                log_scan_problem(
+                    scan_log,
+                    format!("Couldn't read the tags of {}: {}", song.display(), e),
+                );
+                let title = missing_title.title_for(&song).unwrap_or_else(|| {
+                    song.file_stem()
+                        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+                });
+                Song::new(
+                    title,
+                    "Unknown Artist".to_string(),
+                    None,
+                    song.clone(),
+                    "None".to_string(),
+                    0.0,
+                )
+            }
+            Err(e) => {
+                log_scan_problem(
+                    scan_log,
+                    format!("Skipping unreadable file {}: {}", song.display(), e),
+                );
+                continue;
+            }
+        };
+        current_song.lyrics = read_embedded_lyrics(&song);
+        if load_art && current_song.cover.is_none() {
+            if let Some(dir) = song.parent() {
+                current_song.cover = folder_covers
+                    .entry(dir.to_path_buf())
+                    .or_insert_with(|| find_folder_cover(dir).map(Arc::new))
+                    .clone();
+            }
+        }
+        song_list.push(current_song);
+    }
+
+    Ok(song_list)
+}
+
+/// Returns whether the audio decoder can open the file.
+///
+/// # Arguments
+/// * `path` - The path of the audio file.
+fn is_decodable(path: &Path) -> bool {
+    File::open(path)
+        .ok()
+        .is_some_and(|file| rodio::Decoder::new(io::BufReader::new(file)).is_ok())
+}
+
+/// Reads the duration of an MP3 from the Xing, Info or VBRI header in its first frame, which
+/// encoders write for variable bitrate files, where guessing from the bitrate is wrong.
+///
+/// # Arguments
+/// * `path` - The path of the MP3 file.
+///
+/// # Returns
+/// `None` if the file has no such header.
+fn mp3_header_duration(path: &Path) -> Option<f64> {
+    let mut file = File::open(path).ok()?;
+    let mut id3_header = [0; 10];
+    file.read_exact(&mut id3_header).ok()?;
+    // Skip an ID3v2 tag, whose size is stored in 7 bits per byte
+    let mut start = 0;
+    if id3_header.starts_with(b"ID3") {
+        let size = id3_header[6..10]
+            .iter()
+            .fold(0u64, |size, byte| (size << 7) | (*byte & 0x7f) as u64);
+        let footer = if id3_header[5] & 0x10 != 0 { 10 } else { 0 };
+        start = 10 + size + footer;
+    }
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut data = Vec::new();
+    file.take(16 * 1024).read_to_end(&mut data).ok()?;
+    vbr_header_duration(&data)
+}
+
+/// Finds the first MPEG audio frame in `data` and reads the duration from its Xing, Info or
+/// VBRI header.
+///
+/// # Arguments
+/// * `data` - The start of the audio, after any ID3v2 tag.
+fn vbr_header_duration(data: &[u8]) -> Option<f64> {
+    let frame = (0..data.len().saturating_sub(3)).find(|&index| {
+        let header = &data[index..index + 4];
+        let version = (header[1] >> 3) & 0b11;
+        let layer = (header[1] >> 1) & 0b11;
+        header[0] == 0xff
+            && header[1] & 0xe0 == 0xe0
+            && version != 0b01
+            && layer != 0
+            && header[2] >> 4 != 0b1111
+            && (header[2] >> 2) & 0b11 != 0b11
+    })?;
+    let header = &data[frame..];
+    let version = (header[1] >> 3) & 0b11;
+    let layer = (header[1] >> 1) & 0b11;
+    let mono = header[3] >> 6 == 0b11;
+    let mpeg1 = version == 0b11;
+
+    let sample_rate = [44100.0, 48000.0, 32000.0][((header[2] >> 2) & 0b11) as usize]
+        / match version {
+            0b11 => 1.0,
+            0b10 => 2.0,
+            _ => 4.0,
+        };
+    let samples_per_frame = match (layer, mpeg1) {
+        (0b11, _) => 384.0,
+        (0b10, _) | (0b01, true) => 1152.0,
+        _ => 576.0,
+    };
+    let read_u32 = |offset: usize| {
+        header
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    // The Xing or Info header follows the side information, whose size depends on the
+    // version and channels; VBRI is always 32 bytes in
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = 4 + side_info;
+    let frames = match header.get(xing..xing + 4) {
+        Some(b"Xing" | b"Info") => {
+            let flags = read_u32(xing + 4)?;
+            if flags & 1 == 0 {
+                return None;
+            }
+            read_u32(xing + 8)?
+        }
+        _ if header.get(36..40) == Some(b"VBRI") => read_u32(36 + 14)?,
+        _ => return None,
+    };
+    (frames > 0).then(|| frames as f64 * samples_per_frame / sample_rate)
+}
+
+/// Reads a song's metadata from its tags.
+///
+/// # Arguments
+/// * `song` - The path of the audio file.
+/// * `load_art` - Whether to decode the embedded album art.
+fn read_song(song: &Path, load_art: bool) -> Result<Song, String> {
+    if song.ends_with("mp3") {
+        let mp3_meta = read_from_file(song).map_err(|e| e.to_string())?;
+        let tag = mp3_meta.tag.ok_or("missing ID3 tag")?;
+
+        return Ok(Song::new(
+            tag.title,
+            tag.artist,
+            None,
+            song.to_path_buf(),
+            tag.album,
+            mp3_meta.duration.as_secs_f64(),
+        ));
+    }
+
+    let meta = Tag::new().read_from_path(song).map_err(|e| e.to_string())?;
+    let duration = match song.extension().and_then(|e| e.to_str()) {
+        // Variable bitrate files say how long they are in their first frame; adding up the
+        // frames is the next best thing
+        Some("mp3") => mp3_header_duration(song).unwrap_or_else(|| {
+            read_from_file(song)
+                .map(|mp3_meta| mp3_meta.duration.as_secs_f64())
+                .unwrap_or_else(|_| meta.duration().unwrap_or(0.0_f64))
+        }),
+        _ => meta.duration().unwrap_or(0.0_f64),
+    };
+
+    let mut current_song = Song::new(
+        meta.title().unwrap_or_default().to_string(),
+        meta.artist().unwrap_or("No Title").to_string(),
+        meta.album_cover().filter(|_| load_art).and_then(|cover| {
+            let format = match cover.mime_type {
+                audiotags::MimeType::Jpeg => ImageFormat::Jpeg,
+                audiotags::MimeType::Png => ImageFormat::Png,
+                audiotags::MimeType::Gif => ImageFormat::Gif,
+                audiotags::MimeType::Bmp => ImageFormat::Bmp,
+                audiotags::MimeType::Tiff => ImageFormat::Tiff,
+            };
+
+            load_from_memory_with_format(cover.data, format).ok()
+        }),
+        song.to_path_buf(),
+        meta.album()
+            .unwrap_or(Album {
+                title: "None",
+                artist: None,
+                cover: None,
+            })
+            .title
+            .to_string(),
+        duration,
+    );
+    current_song.album_artist = meta
+        .album_artist()
+        .map(normalize_tag)
+        .filter(|album_artist| !album_artist.is_empty());
+    Ok(current_song)
+}
+
+/// Returns the title, artist and album in a song's tags, with `None` for those it lacks or
+/// when it has no tags.
+///
+/// # Arguments
+/// * `path` - The path of the audio file.
+fn read_song_tags(path: &Path) -> SongTags {
+    let Ok(tag) = Tag::new().read_from_path(path) else {
+        return SongTags::default();
+    };
+    SongTags {
+        title: tag.title().map(str::to_string),
+        artist: tag.artist().map(str::to_string),
+        album: tag.album_title().map(str::to_string),
+    }
+}
+
+/// Writes the title, artist and album that are set to a song's tags, keeping its other tags.
+/// MP3s without a tag yet get a new one.
+///
+/// # Arguments
+/// * `path` - The path of the audio file.
+/// * `tags` - The new tags.
+fn write_song_tags(path: &Path, tags: &SongTags) -> Result<(), String> {
+    let path_str = path.to_str().ok_or("the path isn't valid UTF-8")?;
+    let mut tag: Box<dyn audiotags::AudioTag + Send + Sync> =
+        match Tag::new().read_from_path(path) {
+            Err(audiotags::Error::Id3TagError(e)) if matches!(e.kind, id3::ErrorKind::NoTag) => {
+                Box::new(audiotags::Id3v2Tag::new())
+            }
+            tag => tag.map_err(|e| e.to_string())?,
+        };
+    if let Some(title) = &tags.title {
+        tag.set_title(title);
+    }
+    if let Some(artist) = &tags.artist {
+        tag.set_artist(artist);
+    }
+    if let Some(album) = &tags.album {
+        tag.set_album_title(album);
+    }
+    tag.write_to_path(path_str).map_err(|e| e.to_string())
+}
+
+/// Terminals smaller than this collapse the playlist and song detail panels.
+const COMPACT_WIDTH: u16 = 80;
+const COMPACT_HEIGHT: u16 = 20;
+
+/// Returns whether the terminal is too small to show the side panels next to the song list.
+///
+/// # Arguments
+/// * `area` - The size of the terminal.
+fn is_compact_area(area: Rect) -> bool {
+    area.width < COMPACT_WIDTH || area.height < COMPACT_HEIGHT
+}
+
+/// Returns the list offset that keeps `index` visible with `margin` rows of context
+/// above and below it, changing `offset` as little as possible.
+///
+/// A margin of at least half the visible rows keeps the row centered; near either end of the
+/// list the offset is clamped so the view stays full.
+///
+/// # Arguments
+/// * `index` - The row that must be visible.
+/// * `offset` - The current offset of the first visible row.
+/// * `visible_count` - Number of visible rows.
+/// * `len` - Total number of rows in the list.
+/// * `margin` - Rows of context to keep between `index` and the edges of the view.
+fn scroll_offset_to_show(
+    index: usize,
+    offset: usize,
+    visible_count: usize,
+    len: usize,
+    margin: usize,
+) -> usize {
+    let margin = margin.min(visible_count.saturating_sub(1) / 2);
+    let min_offset = (index + margin + 1).saturating_sub(visible_count);
+    let max_offset = index.saturating_sub(margin);
+    offset
+        .clamp(min_offset, max_offset.max(min_offset))
+        .min(len.saturating_sub(visible_count))
+}
+
+/// Returns the border style for a pane, highlighting it when it has focus.
+///
+/// # Arguments
+/// * `focused` - Whether the pane has focus.
+fn focus_border_style(focused: bool) -> Style {
+    if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    }
+}
+
+fn draw_popup(f: &mut Frame) -> Result<(), io::Error> {
+    let size = f.area();
+    let popup_width = size.width / 3;
+    let popup_height = size.height / 3 + 8;
+    let popup_area = Rect::new(
+        (size.width - popup_width) / 2,
+        (size.height - popup_height) / 2,
+        popup_width,
+        popup_height,
+    );
+
+    f.render_widget(ratatui::widgets::Clear, popup_area);
+    f.render_widget(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_type(ratatui::widgets::BorderType::Rounded),
+        popup_area,
+    );
+
+    let popup_text = Paragraph::new(
+        "Controls
+- Use Up/Down Arrow Keys to navigate songs
+- Ctrl + Q: Save and quit
+- Ctrl + Spacebar: Play/Stop
+- Ctrl + P: Pause/Unpause
+- Ctrl + M: Mute/Unmute
+- Alt + M: Switch between mono and stereo (kept between runs)
+- Ctrl + U / Ctrl + W: Clear the search / delete a word
+- Ctrl + N: Switch between All Songs and the last playlist
+- Ctrl + S: Change search criteria (Ctrl + Shift + S or Alt + S: back)
+- Ctrl + T: Change sorting criteria (Ctrl + Shift + T or Alt + T: back)
+- Ctrl + G: Sort the selected playlist by the sorting criteria
+- Ctrl + Left/Right Arrow Keys: Adjust Volume (kept between runs)
+- Ctrl + Shift + Left/Right: Shift the balance left/right (Alt + B: center it)
+- Ctrl + L: Next song
+- Ctrl + H: Previous song, or restart the song a few seconds in
+- Left Arrow Key: -5 seconds on current song
+- Right Arrow Key: +5 seconds on current song
+- Backspace: Delete characters in the search bar
+- Tab / Shift + Tab: Move focus between the search bar, song list and playlists
+- Ctrl + F: Focus/unfocus the search bar
+- Left/Right, Home/End, Delete: Edit the focused search bar
+- Ctrl + Up/Down: Recall previous searches in the focused search bar
+- Up/Down with the playlists focused: Move playlist selection
+- PageUp/PageDown: Move the focused list by a page
+- Home/End: Jump to the first/last entry of the focused list
+- Vim mode: j/k move, h/l seek, / searches, Esc leaves search
+- Ctrl + A: Select a song to be added
+ to the new playlist
+- Ctrl + C: New playlist name input popup
+- Ctrl + K: Move playlist selection up
+- Ctrl + J: Move playlist selection down
+- Enter: Create a new playlist with given name
+- Ctrl + X: Delete selected playlist (All Songs is built from the library and can't be deleted)
+- Ctrl + O: Play the selected playlist from the start
+- Ctrl + E: Stop after the current song
+- Ctrl + R: Cycle repeat mode (off, current song, all)
+- F2: Show the play queue (Up/Down select, Shift + Up/Down move, Delete remove)
+- F3: Switch between the column and stacked layouts
+- Alt + Left/Right: Narrow/widen the song list
+- F4: Open the selected song's folder in the file manager
+- F5: Rescan the music folder
+- Shift + F5: Show problems found while scanning
+- F6: Pin/unpin the selected playlist at the top
+- F7: Copy the selected playlist's songs to a folder
+- F8: Bookmark the current position, with a label
+- F9: Show bookmarks (Enter jump, Delete remove)
+- F10: Edit the tags of the selected song, or of all chosen songs
+- F11: Play a random song from the list (Shift + F11: from the library)
+- F12: Select the playlist the play queue came from (marked ♪)
+- Ctrl + D: Cut the selected song to move it within its playlist
+- Ctrl + V / Ctrl + B: Paste the cut song before/after the selected one
+- Ctrl + Y: Toggle lyrics of the playing song (Up/Down scroll)
+- F1: Toggle Controls Popup
+- Esc or F1: Close Popup",
+    )
+    .block(Block::default().borders(Borders::NONE))
+    .alignment(Alignment::Center)
+    .style(Style::default().fg(Color::White));
+    f.render_widget(popup_text, popup_area);
+
+    Ok(())
+}
+
+/// Draws the play queue, with the current song at the top and the upcoming songs below it.
+///
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `myapp` - The application state holding the queue.
+fn draw_queue_popup(f: &mut Frame, myapp: &MyApp) -> Result<(), io::Error> {
+    let size = f.area();
+    let popup_width = size.width / 2;
+    let popup_height = size.height * 2 / 3;
+    let popup_area = Rect::new(
+        (size.width - popup_width) / 2,
+        (size.height - popup_height) / 2,
+        popup_width,
+        popup_height,
+    );
+    let title = match &myapp.queue_source {
+        Some(name) => format!("Queue: {}", name),
+        None => "Queue".to_string(),
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title(title)
+        .title_bottom("Shift + Up/Down: Move  Delete: Remove");
+    let visible_count = block.inner(popup_area).height as usize;
+
+    f.render_widget(ratatui::widgets::Clear, popup_area);
+    if myapp.play_queue.is_empty() {
+        f.render_widget(
+            Paragraph::new("The queue is empty, press Ctrl + O to play a playlist")
+                .block(block)
+                .alignment(Alignment::Center)
+                .style(Style::default().fg(Color::DarkGray)),
+            popup_area,
+        );
+        return Ok(());
+    }
+
+    let offset = scroll_offset_to_show(
+        myapp.queue_selected,
+        0,
+        visible_count,
+        myapp.play_queue.len(),
+        0,
+    );
+    let items: Vec<ListItem> = myapp
+        .play_queue
+        .iter()
+        .enumerate()
+        .skip(offset)
+        .take(visible_count)
+        .map(|(index, id)| {
+            let song = myapp.get_song(*id);
+            let label = song.map_or_else(
+                || "Unknown song".to_string(),
+                |song| format!("{} - {}", song.title, song.artist),
+            );
+            if index == 0 {
+                ListItem::new(format!("Now playing: {}", label))
+                    .style(Style::default().fg(Color::Green).add_modifier(Modifier::BOLD))
+            } else if index == myapp.queue_selected {
+                ListItem::new(format!("{}. {}", index, label))
+                    .style(Style::default().fg(Color::Black).bg(Color::Yellow))
+            } else {
+                ListItem::new(format!("{}. {}", index, label))
+            }
+        })
+        .collect();
+    f.render_widget(List::new(items).block(block), popup_area);
+
+    Ok(())
+}
+
+/// Draws the lyrics of the playing song, keeping the current line of synced lyrics centered
+/// and highlighted.
+///
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `lyrics` - The lyrics to show, or `None` if the playing song has none.
+/// * `elapsed` - How far into the song playback is, in seconds.
+/// * `scroll` - Lines scrolled past in unsynced lyrics.
+fn draw_lyrics_popup(
+    f: &mut Frame,
+    lyrics: Option<&Lyrics>,
+    elapsed: f64,
+    scroll: u16,
+) -> Result<(), io::Error> {
+    let size = f.area();
+    let popup_width = size.width / 2;
+    let popup_height = size.height * 2 / 3;
+    let popup_area = Rect::new(
+        (size.width - popup_width) / 2,
+        (size.height - popup_height) / 2,
+        popup_width,
+        popup_height,
+    );
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title("Lyrics");
+    let visible_lines = block.inner(popup_area).height as usize;
+
+    let text = match lyrics {
+        Some(Lyrics::Synced(lines)) => {
+            let current = lyrics.and_then(|lyrics| lyrics.current_line(elapsed));
+            let first = current
+                .map_or(0, |current| current.saturating_sub(visible_lines / 2))
+                .min(lines.len().saturating_sub(visible_lines));
+            Text::from(
+                lines
+                    .iter()
+                    .enumerate()
+                    .skip(first)
+                    .take(visible_lines)
+                    .map(|(index, (_, line))| {
+                        let style = if Some(index) == current {
+                            Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+                        } else {
+                            Style::default().fg(Color::Gray)
+                        };
+                        Line::styled(line.as_str(), style)
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        }
+        Some(Lyrics::Plain(contents)) => Text::raw(contents.as_str()),
+        None => Text::styled("No lyrics found", Style::default().fg(Color::DarkGray)),
+    };
+    let scroll = match lyrics {
+        Some(Lyrics::Plain(_)) => scroll,
+        _ => 0,
+    };
+
+    f.render_widget(ratatui::widgets::Clear, popup_area);
+    f.render_widget(
+        Paragraph::new(text)
+            .block(block)
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0)),
+        popup_area,
+    );
+
+    Ok(())
+}
+
+/// Lists the bookmarks of the playing song, or of the selected song when nothing plays.
+///
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `myapp` - The application state holding the bookmarks.
+fn draw_bookmarks_popup(f: &mut Frame, myapp: &MyApp) -> Result<(), io::Error> {
+    let size = f.area();
+    let popup_width = size.width / 2;
+    let popup_height = size.height * 2 / 3;
+    let popup_area = Rect::new(
+        (size.width - popup_width) / 2,
+        (size.height - popup_height) / 2,
+        popup_width,
+        popup_height,
+    );
+    let title = match myapp.bookmarked_song() {
+        Some(song) => format!("Bookmarks: {}", song.title),
+        None => "Bookmarks".to_string(),
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title(title)
+        .title_bottom("Enter: Jump  Delete: Remove");
+    let visible_count = block.inner(popup_area).height as usize;
+
+    f.render_widget(ratatui::widgets::Clear, popup_area);
+    let bookmarks = myapp.listed_bookmarks();
+    if bookmarks.is_empty() {
+        f.render_widget(
+            Paragraph::new("No bookmarks, press F8 while a song plays to add one")
+                .block(block)
+                .alignment(Alignment::Center)
+                .style(Style::default().fg(Color::DarkGray)),
+            popup_area,
+        );
+        return Ok(());
+    }
+
+    let offset = scroll_offset_to_show(
+        myapp.bookmark_selected,
+        0,
+        visible_count,
+        bookmarks.len(),
+        0,
+    );
+    let items: Vec<ListItem> = bookmarks
+        .iter()
+        .enumerate()
+        .skip(offset)
+        .take(visible_count)
+        .map(|(index, bookmark)| {
+            let item = ListItem::new(format!(
+                "{}  {}",
+                format_time(bookmark.position),
+                bookmark.label
+            ));
+            if index == myapp.bookmark_selected {
+                item.style(Style::default().fg(Color::Black).bg(Color::Yellow))
+            } else {
+                item
+            }
+        })
+        .collect();
+    f.render_widget(List::new(items).block(block), popup_area);
+
+    Ok(())
+}
+
+/// Lists the problems found by the latest library scan.
+///
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `scan_log` - The problems found.
+/// * `scroll` - Lines scrolled past.
+fn draw_scan_log_popup(f: &mut Frame, scan_log: &[String], scroll: u16) -> Result<(), io::Error> {
+    let size = f.area();
+    let popup_width = size.width * 2 / 3;
+    let popup_height = size.height * 2 / 3;
+    let popup_area = Rect::new(
+        (size.width - popup_width) / 2,
+        (size.height - popup_height) / 2,
+        popup_width,
+        popup_height,
+    );
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title("Scan log")
+        .title_bottom("Up/Down: Scroll");
+
+    let text = if scan_log.is_empty() {
+        Text::styled("No problems found", Style::default().fg(Color::DarkGray))
+    } else {
+        Text::from(
+            scan_log
+                .iter()
+                .map(|entry| Line::raw(entry.as_str()))
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    f.render_widget(ratatui::widgets::Clear, popup_area);
+    f.render_widget(
+        Paragraph::new(text)
+            .block(block)
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0)),
+        popup_area,
+    );
+
+    Ok(())
+}
+
+/// Draws the text input popup.
+///
+/// # Arguments
+/// * `f` - The frame to draw on.
+/// * `title` - What the input is for.
+/// * `input` - The text typed so far.
+fn draw_input_popup(f: &mut Frame, title: &str, input: &str) -> Result<(), io::Error> {
+    let size = f.area();
+    let popup_width = size.width / 4;
+    let popup_height = size.height / 8;
+    let popup_area = Rect::new(
+        (size.width - popup_width) / 2,
+        (size.height - popup_height) / 2,
+        popup_width,
+        popup_height,
+    );
+
+    f.render_widget(ratatui::widgets::Clear, popup_area);
+    f.render_widget(
+        Block::default().title(title).borders(Borders::ALL),
+        popup_area,
+    );
+
+    let inner_area = Rect::new(
+        popup_area.x,
+        popup_area.y + 2,
+        popup_area.width,
+        popup_area.height - 4,
+    );
+
+    // Display the current input inside the popup
+    let input_text = Paragraph::new(input)
+        .block(Block::default().borders(Borders::NONE))
+        .alignment(Alignment::Center)
+        .style(Style::default().fg(Color::White))
+        .wrap(Wrap { trim: true });
+
+    f.render_widget(input_text, inner_area);
+
+    Ok(())
+}
+
+/// Returns the artist without a leading article, so "the beatles" sorts as "beatles".
+///
+/// # Arguments
+/// * `artist_lower` - The lowercase artist name.
+/// * `articles` - Lowercase articles to skip.
+fn artist_sort_key<'a>(artist_lower: &'a str, articles: &[String]) -> &'a str {
+    articles
+        .iter()
+        .find_map(|article| {
+            artist_lower
+                .strip_prefix(article.as_str())
+                .and_then(|rest| rest.strip_prefix(' '))
+                .map(str::trim_start)
+                .filter(|rest| !rest.is_empty())
+        })
+        .unwrap_or(artist_lower)
+}
+
+/// Sorts songs by the given criteria.
+///
+/// # Arguments
+/// * `songs` - The songs to sort.
+/// * `criteria` - What to sort by.
+/// * `ignored_articles` - Leading words such as "The" skipped when sorting by artist.
+fn sort_songs(songs: &mut [Song], criteria: &SortCriteria, ignored_articles: &[String]) {
+    match criteria {
+        SortCriteria::Title => {
+            songs.sort_by(|a, b| a.title_lower.cmp(&b.title_lower));
+        }
+        SortCriteria::Artist => {
+            let articles: Vec<String> = ignored_articles
+                .iter()
+                .map(|article| fold_case_and_accents(article))
+                .collect();
+            songs.sort_by(|a, b| {
+                artist_sort_key(&a.artist_lower, &articles)
+                    .cmp(artist_sort_key(&b.artist_lower, &articles))
+            });
+        }
+        SortCriteria::Album => {
+            let articles: Vec<String> = ignored_articles
+                .iter()
+                .map(|article| fold_case_and_accents(article))
+                .collect();
+            // Songs of an album are ordered by path, which usually follows the track numbers
+            songs.sort_by_cached_key(|song| {
+                let artist_lower = fold_case_and_accents(song.grouping_artist());
+                (
+                    artist_sort_key(&artist_lower, &articles).to_string(),
+                    song.album_lower.clone(),
+                    song.path.clone(),
+                )
+            });
+        }
+        SortCriteria::Duration => {
+            songs.sort_by(|a, b| {
+                a.duration
+                    .partial_cmp(&b.duration)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    /// Creates a sink whose output is consumed by a background thread, standing in for an
+    /// audio device so songs can actually be appended and cleared.
+    fn draining_sink() -> Arc<Mutex<Sink>> {
+        let (sink, output) = Sink::new_idle();
+        std::thread::spawn(move || output.for_each(drop));
+        Arc::new(Mutex::new(sink))
+    }
+
+    /// Writes `count` short silent WAV files to `dir` and returns them as songs.
+    fn wav_songs(dir: &Path, count: usize) -> Vec<Song> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        (0..count)
+            .map(|i| {
+                let path = dir.join(format!("song{}.wav", i));
+                let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+                for _ in 0..800 {
+                    writer.write_sample(0i16).unwrap();
+                }
+                writer.finalize().unwrap();
+                Song::new(
+                    format!("Song {}", i),
+                    "Artist".to_string(),
+                    None,
+                    path,
+                    "Album".to_string(),
+                    0.1,
+                )
+            })
+            .collect()
+    }
+
+    /// Creates `count` songs titled "Song 0", "Song 1", ... with distinct paths.
+    fn numbered_songs(count: usize) -> Vec<Song> {
+        (0..count)
+            .map(|i| {
+                Song::new(
+                    format!("Song {}", i),
+                    "Artist".to_string(),
+                    None,
+                    PathBuf::from(format!("/path/to/song{}.mp3", i)),
+                    "Album".to_string(),
+                    100.0,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_song_creation() {
+        let title = String::from("Test Song");
+        let artist = String::from("Test Artist");
+        let path = PathBuf::from("/path/to/test/song.mp3");
+        let album = String::from("Test Album");
+        let duration = 180.0;
+        let song = Song::new(
+            title.clone(),
+            artist.clone(),
+            None,
+            path.clone(),
+            album.clone(),
+            duration,
+        );
+
+        assert_eq!(song.title, title);
+        assert_eq!(song.artist, artist);
+        assert_eq!(song.path, path);
+        assert_eq!(song.album, album);
+        assert_eq!(song.duration, duration);
+        assert!(!song.is_playing);
+        assert_ne!(song.id, Uuid::nil());
+    }
+
+    #[test]
+    fn test_scan_first_with_music() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let empty_dir = temp_dir.path().join("Music");
+        let music_dir = temp_dir.path().join("here");
+        fs::create_dir(&empty_dir).unwrap();
+        fs::create_dir(&music_dir).unwrap();
+        wav_songs(&music_dir, 2);
+
+        // An empty music folder falls through to the next one
+        let mut scan_log = Vec::new();
+        let folders = [
+            temp_dir.path().join("missing"),
+            empty_dir.clone(),
+            music_dir,
+        ];
+        let songs = scan_first_with_music(&folders, MissingTitle::default(), true, &mut scan_log);
+        assert_eq!(songs.len(), 2);
+        assert!(scan_log.iter().any(|line| line.contains("missing")));
+
+        let songs =
+            scan_first_with_music(&[empty_dir], MissingTitle::default(), true, &mut scan_log);
+        assert!(songs.is_empty());
+    }
+
+    #[test]
+    fn test_extra_music_dirs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let music_dir = temp_dir.path().join("music");
+        let more_music = temp_dir.path().join("more");
+        fs::create_dir(&music_dir).unwrap();
+        fs::create_dir_all(music_dir.join("album")).unwrap();
+        fs::create_dir(&more_music).unwrap();
+        wav_songs(&music_dir, 1);
+        wav_songs(&music_dir.join("album"), 2);
+        wav_songs(&more_music, 1);
+
+        // A folder inside the music directory doesn't add its songs twice
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(music_dir.clone());
+        myapp.settings.extra_music_dirs = vec![
+            more_music.clone(),
+            music_dir.join("album"),
+            temp_dir.path().join("missing"),
+        ];
+        myapp.load_songs();
+        assert_eq!(myapp.songs.len(), 4);
+        assert_eq!(myapp.playlists[ALL_SONGS_PLAYLIST].len(), 4);
+        assert!(myapp
+            .songs
+            .iter()
+            .any(|song| song.path.starts_with(&more_music)));
+        assert!(myapp.scan_log.iter().any(|line| line.contains("missing")));
+
+        // Playlists find songs from every directory again
+        let playlist_dir = temp_dir.path().join("playlists");
+        myapp.settings.playlist_dir = Some(playlist_dir.clone());
+        myapp.settings.relative_playlist_paths = true;
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.playlists.insert("Mix".to_string(), ids.clone());
+        myapp.save_playlist().unwrap();
+        let mut restored = MyApp::new();
+        restored.settings.music_dir = Some(music_dir);
+        restored.settings.extra_music_dirs = vec![more_music];
+        restored.load_songs();
+        let playlist_file = playlist_dir.join("data.json");
+        restored.load_playlists(&playlist_file).unwrap();
+        assert_eq!(restored.playlists["Mix"], ids);
+    }
+
+    #[test]
+    fn test_scan_folder_for_music() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_path = temp_dir.path();
+
+        // Create a mock MP3 file
+        let file_path = temp_path.join("test.mp3");
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(b"dummy content").unwrap();
+
+        // Create a mock FLAC file
+        let file_path_flac = temp_path.join("test.flac");
+        let mut file_flac = File::create(&file_path_flac).unwrap();
+        file_flac.write_all(b"dummy content").unwrap();
+
+        // Simulate the function behavior
+        let mut songs = Vec::new();
+
+        for entry in fs::read_dir(temp_path).unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path();
+
+            if path.is_file() {
+                let extension = path.extension().unwrap().to_str().unwrap().to_lowercase();
+                if MUSIC_FORMATS.contains(&extension.as_str()) {
+                    let song = Song::new(
+                        "Test Song".to_string(),
+                        "Test Artist".to_string(),
+                        None,
+                        path.clone(),
+                        "Test Album".to_string(),
+                        180.0,
+                    );
+                    songs.push(song);
+                }
+            }
+        }
+
+        assert_eq!(songs.len(), 2);
+        assert_eq!(songs[0].path.extension().unwrap(), "flac");
+        assert_eq!(songs[1].path.extension().unwrap(), "mp3");
+    }
+
+    #[test]
+    fn test_popup_state_toggle() {
+        let mut popup_state = PopupState { visible: false };
+
+        popup_state.toggle();
+        assert!(popup_state.visible);
+
+        popup_state.toggle();
+        assert!(!popup_state.visible);
+    }
+
+    #[test]
+    fn test_sort_criteria() {
+        assert_eq!(SortCriteria::Title.to_string(), "Title");
+        assert_eq!(SortCriteria::Artist.to_string(), "Artist");
+        assert_eq!(SortCriteria::Album.to_string(), "Album");
+        assert_eq!(SortCriteria::Duration.to_string(), "Duration");
+
+        assert_eq!(SortCriteria::Title.next(), SortCriteria::Artist);
+        assert_eq!(SortCriteria::Artist.next(), SortCriteria::Album);
+        assert_eq!(SortCriteria::Album.next(), SortCriteria::Duration);
+        assert_eq!(SortCriteria::Duration.next(), SortCriteria::Title);
+
+        assert_eq!(SortCriteria::Title.prev(), SortCriteria::Duration);
+        assert_eq!(SortCriteria::Artist.prev(), SortCriteria::Title);
+        assert_eq!(SortCriteria::Album.prev(), SortCriteria::Artist);
+        assert_eq!(SortCriteria::Duration.prev(), SortCriteria::Album);
+
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let control_shift = KeyModifiers::CONTROL | KeyModifiers::SHIFT;
+        for modifiers in [control_shift, KeyModifiers::ALT] {
+            let key = KeyEvent::new(KeyCode::Char('t'), modifiers);
+            handle_key_event(&mut myapp, key, &sink, 10, 10);
+        }
+        assert_eq!(myapp.sort_criteria, SortCriteria::Album);
+    }
+
+    #[test]
+    fn test_initial_sort() {
+        assert_eq!(
+            SortCriteria::from_name("Artist"),
+            Some(SortCriteria::Artist)
+        );
+        assert_eq!(
+            SortCriteria::from_name(" duration "),
+            Some(SortCriteria::Duration)
+        );
+        assert_eq!(SortCriteria::from_name("genre"), None);
+
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(3);
+        myapp.set_initial_sort(Some("duration"), true);
+        myapp.sort_songs();
+        assert_eq!(myapp.sort_criteria, SortCriteria::Duration);
+        assert_eq!(myapp.sort_label(), "Duration (descending)");
+        let durations: Vec<f64> = myapp.songs.iter().map(|song| song.duration).collect();
+        assert!(durations.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert_eq!(myapp.current_status(), None);
+
+        myapp.set_initial_sort(Some("genre"), false);
+        assert_eq!(myapp.sort_criteria, SortCriteria::Title);
+        assert_eq!(myapp.sort_label(), "Title");
+        assert_eq!(
+            myapp.current_status(),
+            Some("Unknown sort criteria \"genre\", sorting by Title")
+        );
+    }
+
+    #[test]
+    fn test_search_criteria() {
+        let song1 = Song::new(
+            "Song One".to_string(),
+            "Artist A".to_string(),
+            None,
+            PathBuf::from("/path/to/song1.mp3"),
+            "Album X".to_string(),
+            200.0,
+        );
+        let song2 = Song::new(
+            "Song Two".to_string(),
+            "Artist B".to_string(),
+            None,
+            PathBuf::from("/path/to/song2.mp3"),
+            "Album Y".to_string(),
+            220.0,
+        );
+
+        let mut myapp = MyApp::new();
+        myapp.songs = vec![song1, song2];
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), ids);
+
+        myapp.search_text = "Song".to_string();
+        myapp.search_criteria = SearchCriteria::Title;
+        myapp.filter_songs();
+
+        let filtered_songs = &myapp.filtered_songs;
+        assert_eq!(filtered_songs.len(), 2);
+        assert_eq!(filtered_songs[0].title, "Song One");
+        assert_eq!(filtered_songs[1].title, "Song Two");
+
+        myapp.search_text = "artist b".to_string();
+        myapp.search_criteria = SearchCriteria::Artist;
+        myapp.filter_songs();
+        assert_eq!(myapp.filtered_songs.len(), 1);
+        assert_eq!(myapp.filtered_songs[0].title, "Song Two");
+
+        myapp.search_text = "ALBUM X".to_string();
+        myapp.search_criteria = SearchCriteria::Album;
+        myapp.filter_songs();
+        assert_eq!(myapp.filtered_songs.len(), 1);
+        assert_eq!(myapp.filtered_songs[0].title, "Song One");
+    }
+
+    #[test]
+    fn test_cli_args_json_events() {
+        let args = |list: &[&str]| CliArgs::parse(list.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(args(&[]), CliArgs::default());
+        assert_eq!(args(&["--json-events"]).json_events, Some("-".to_string()));
+        assert_eq!(
+            args(&["--json-events", "/tmp/events", "--headless"]),
+            CliArgs {
+                json_events: Some("/tmp/events".to_string()),
+                headless: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            args(&["--json-events", "--headless"]).json_events,
+            Some("-".to_string())
+        );
+        assert_eq!(
+            args(&["--stream", "http://radio.example/live"]).stream,
+            Some("http://radio.example/live".to_string())
+        );
+        assert_eq!(
+            args(&["--music-dir", "/music", "--playlist-dir", "/music/playlists"]),
+            CliArgs {
+                music_dirs: vec![PathBuf::from("/music")],
+                playlist_dir: Some(PathBuf::from("/music/playlists")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            args(&["--music-dir", "/music", "--music-dir", "/more music"]).music_dirs,
+            vec![PathBuf::from("/music"), PathBuf::from("/more music")]
+        );
+        assert_eq!(
+            args(&["--sort", "artist", "--sort-descending", "--no-art"]),
+            CliArgs {
+                sort_by: Some("artist".to_string()),
+                sort_descending: true,
+                no_art: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_playback_event_change() {
+        let event = PlaybackEvent {
+            event: "state",
+            state: "playing",
+            played: false,
+            title: Some("Song One".to_string()),
+            artist: None,
+            album: None,
+            path: Some(PathBuf::from("/path/to/song1.mp3")),
+            position: 10,
+            duration: 200,
+            volume: 100,
+        };
+
+        assert_eq!(event.change_from(None), Some("track"));
+        assert_eq!(event.change_from(Some(&event)), None);
+
+        let mut next = event.clone();
+        next.position = 11;
+        assert_eq!(next.change_from(Some(&event)), Some("position"));
+        next.volume = 50;
+        assert_eq!(next.change_from(Some(&event)), Some("volume"));
+        next.played = true;
+        assert_eq!(next.change_from(Some(&event)), Some("played"));
+        next.state = "paused";
+        assert_eq!(next.change_from(Some(&event)), Some("state"));
+        next.path = Some(PathBuf::from("/path/to/song2.mp3"));
+        assert_eq!(next.change_from(Some(&event)), Some("track"));
+    }
+
+    #[test]
+    fn test_command_parse() {
+        assert_eq!(Command::parse("play"), Some(Command::Play));
+        assert_eq!(Command::parse(" pause "), Some(Command::Pause));
+        assert_eq!(Command::parse("next"), Some(Command::Next));
+        assert_eq!(Command::parse("prev"), Some(Command::Previous));
+        assert_eq!(Command::parse("seek +5"), Some(Command::Seek(5)));
+        assert_eq!(Command::parse("seek -10"), Some(Command::Seek(-10)));
+        assert_eq!(Command::parse("volume 80"), Some(Command::Volume(0.8)));
+        assert_eq!(Command::parse("volume 150"), Some(Command::Volume(1.0)));
+
+        assert_eq!(Command::parse(""), None);
+        assert_eq!(Command::parse("seek"), None);
+        assert_eq!(Command::parse("seek forward"), None);
+        assert_eq!(Command::parse("volume -1"), None);
+        assert_eq!(Command::parse("play now"), None);
+        assert_eq!(Command::parse("rewind"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_control_fifo_read_lines() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let fifo_path = temp_dir.path().join("control.fifo");
+        let mut fifo = ControlFifo::open(&fifo_path).unwrap();
+
+        assert!(fifo.read_lines().is_empty());
+
+        let mut writer = fs::OpenOptions::new().write(true).open(&fifo_path).unwrap();
+        writer.write_all(b"play\n\nseek +5\nvol").unwrap();
+        assert_eq!(fifo.read_lines(), vec!["play", "seek +5"]);
+
+        writer.write_all(b"ume 80\n").unwrap();
+        assert_eq!(fifo.read_lines(), vec!["volume 80"]);
+
+        // A character split between writes arrives whole
+        let line = "search Café\n".as_bytes();
+        writer.write_all(&line[..11]).unwrap();
+        assert!(fifo.read_lines().is_empty());
+        writer.write_all(&line[11..]).unwrap();
+        assert_eq!(fifo.read_lines(), vec!["search Café"]);
+
+        // Reopening an existing FIFO reuses it.
+        assert!(ControlFifo::open(&fifo_path).is_ok());
+    }
+
+    #[test]
+    fn test_shifted_characters_in_search() {
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+
+        for c in ['a', 'B', '!', '@', '#', '$', '%', 'é', 'Ö', 'ß'] {
+            handle_key_event(
+                &mut myapp,
+                KeyEvent::new(KeyCode::Char(c), KeyModifiers::SHIFT),
+                &sink,
+                0,
+                0,
+            );
+        }
+        assert_eq!(myapp.search_text, "AB!@#$%ÉÖß");
+
+        myapp.search_text.clear();
+        for c in ['b', 'j', 'ö', 'r', 'k', '?'] {
+            handle_key_event(
+                &mut myapp,
+                KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+                &sink,
+                0,
+                0,
+            );
+        }
+        assert_eq!(myapp.search_text, "björk?");
+    }
+
+    #[test]
+    fn test_paste_into_input_fields() {
+        let mut myapp = MyApp::new();
+
+        myapp.paste("Song\r\n One\t\u{7}");
+        assert_eq!(myapp.search_text, "Song One");
+
+        myapp.input_popup.visible = true;
+        myapp.paste("Road\ntrip");
+        assert_eq!(myapp.input_text, "Roadtrip");
+        assert_eq!(myapp.search_text, "Song One");
+    }
+
+    #[test]
+    fn test_search_cursor_editing() {
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code: KeyCode, modifiers: KeyModifiers| {
+            handle_key_event(myapp, KeyEvent::new(code, modifiers), &sink, 0, 0);
+        };
+
+        myapp.paste("björk");
+        assert_eq!(myapp.search_cursor, 5);
+
+        press(&mut myapp, KeyCode::Char('f'), KeyModifiers::CONTROL);
+        assert_eq!(myapp.focus, Focus::Search);
+
+        press(&mut myapp, KeyCode::Home, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Char('B'), KeyModifiers::SHIFT);
+        press(&mut myapp, KeyCode::Delete, KeyModifiers::NONE);
+        assert_eq!(myapp.search_text, "Björk");
+
+        press(&mut myapp, KeyCode::Right, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Right, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Backspace, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Char('o'), KeyModifiers::NONE);
+        assert_eq!(myapp.search_text, "Bjork");
+        assert_eq!(myapp.search_cursor, 3);
+
+        press(&mut myapp, KeyCode::End, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Right, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Delete, KeyModifiers::NONE);
+        assert_eq!(myapp.search_cursor, 5);
+        assert_eq!(myapp.search_text, "Bjork");
+
+        press(&mut myapp, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(myapp.focus, Focus::SongList);
+    }
+
+    #[test]
+    fn test_focus_cycling() {
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code: KeyCode, modifiers: KeyModifiers| {
+            handle_key_event(myapp, KeyEvent::new(code, modifiers), &sink, 10, 10);
+        };
+        myapp.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), Vec::new());
+        myapp.playlists.insert("Road trip".to_string(), Vec::new());
+
+        assert_eq!(myapp.focus, Focus::SongList);
+        press(&mut myapp, KeyCode::Tab, KeyModifiers::NONE);
+        assert_eq!(myapp.focus, Focus::Playlists);
+
+        press(&mut myapp, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!(myapp.selected_playlist_index, 1);
+
+        press(&mut myapp, KeyCode::Tab, KeyModifiers::NONE);
+        assert_eq!(myapp.focus, Focus::Search);
+        press(&mut myapp, KeyCode::BackTab, KeyModifiers::SHIFT);
+        assert_eq!(myapp.focus, Focus::Playlists);
+
+        // Typing moves focus to the search bar.
+        press(&mut myapp, KeyCode::Char('x'), KeyModifiers::NONE);
+        assert_eq!(myapp.focus, Focus::Search);
+        assert_eq!(myapp.search_text, "x");
+    }
+
+    #[test]
+    fn test_vim_mode_navigation() {
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code: KeyCode| {
+            handle_key_event(myapp, KeyEvent::new(code, KeyModifiers::NONE), &sink, 10, 10);
+        };
+        myapp.settings.vim_mode = true;
+        myapp.filtered_songs = numbered_songs(3);
+        myapp.selected_song_id = Some(myapp.filtered_songs[0].id);
+
+        press(&mut myapp, KeyCode::Char('j'));
+        press(&mut myapp, KeyCode::Char('j'));
+        press(&mut myapp, KeyCode::Char('k'));
+        assert_eq!(myapp.selected_song_id, Some(myapp.filtered_songs[1].id));
+        assert!(myapp.search_text.is_empty());
+
+        press(&mut myapp, KeyCode::Char('/'));
+        assert_eq!(myapp.focus, Focus::Search);
+        press(&mut myapp, KeyCode::Char('j'));
+        assert_eq!(myapp.search_text, "j");
+
+        press(&mut myapp, KeyCode::Esc);
+        press(&mut myapp, KeyCode::Char('x'));
+        assert_eq!(myapp.focus, Focus::SongList);
+        assert_eq!(myapp.search_text, "j");
+    }
+
+    #[test]
+    fn test_settings_load() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let settings_path = temp_dir.path().join("settings.json");
+
+        assert_eq!(Settings::load(&settings_path), Settings::default());
+
+        fs::write(&settings_path, r#"{"vim_mode": true}"#).unwrap();
+        assert!(Settings::load(&settings_path).vim_mode);
+
+        fs::write(&settings_path, "not json").unwrap();
+        assert_eq!(Settings::load(&settings_path), Settings::default());
+    }
+
+    #[test]
+    fn test_page_navigation() {
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code: KeyCode| {
+            handle_key_event(myapp, KeyEvent::new(code, KeyModifiers::NONE), &sink, 10, 3);
+        };
+        myapp.filtered_songs = numbered_songs(25);
+        myapp.selected_song_id = Some(myapp.filtered_songs[0].id);
+
+        myapp.settings.scroll_margin = Some(0);
+        press(&mut myapp, KeyCode::PageDown);
+        assert_eq!(myapp.selected_song_index(), Some(10));
+        assert_eq!(myapp.list_offset, 1);
+        press(&mut myapp, KeyCode::PageDown);
+        press(&mut myapp, KeyCode::PageDown);
+        assert_eq!(myapp.selected_song_index(), Some(24));
+        assert_eq!(myapp.list_offset, 15);
+        press(&mut myapp, KeyCode::PageUp);
+        assert_eq!(myapp.selected_song_index(), Some(14));
+        assert_eq!(myapp.list_offset, 14);
+        press(&mut myapp, KeyCode::Home);
+        assert_eq!(myapp.selected_song_index(), Some(0));
+        assert_eq!(myapp.list_offset, 0);
+        press(&mut myapp, KeyCode::End);
+        assert_eq!(myapp.selected_song_index(), Some(24));
+
+        for name in ["A", "B", "C", "D", "E"] {
+            myapp.playlists.insert(name.to_string(), Vec::new());
+        }
+        myapp.focus = Focus::Playlists;
+        press(&mut myapp, KeyCode::PageDown);
+        assert_eq!(myapp.selected_playlist_index, 3);
+        assert_eq!(myapp.playlist_list_offset, 1);
+        press(&mut myapp, KeyCode::End);
+        assert_eq!(myapp.selected_playlist_index, 4);
+        press(&mut myapp, KeyCode::Home);
+        assert_eq!(myapp.selected_playlist_index, 0);
+        assert_eq!(myapp.playlist_list_offset, 0);
+    }
+
+    #[test]
+    fn test_scroll_offset_to_show() {
+        // Edge scrolling without a margin.
+        assert_eq!(scroll_offset_to_show(5, 0, 10, 50, 0), 0);
+        assert_eq!(scroll_offset_to_show(12, 0, 10, 50, 0), 3);
+        assert_eq!(scroll_offset_to_show(2, 5, 10, 50, 0), 2);
+
+        // A margin keeps context around the selection.
+        assert_eq!(scroll_offset_to_show(8, 0, 10, 50, 2), 1);
+        assert_eq!(scroll_offset_to_show(6, 5, 10, 50, 2), 4);
+
+        // A large margin centers the selection, clamped at both ends of the list.
+        assert_eq!(scroll_offset_to_show(20, 0, 11, 50, usize::MAX), 15);
+        assert_eq!(scroll_offset_to_show(21, 15, 11, 50, usize::MAX), 16);
+        assert_eq!(scroll_offset_to_show(3, 15, 11, 50, usize::MAX), 0);
+        assert_eq!(scroll_offset_to_show(48, 0, 11, 50, usize::MAX), 39);
+
+        // Short lists never scroll.
+        assert_eq!(scroll_offset_to_show(4, 0, 10, 5, usize::MAX), 0);
+    }
+
+    #[test]
+    fn test_art_mode_setting() {
+        let settings: Settings = serde_json::from_str(r#"{"art_mode": "halfblocks"}"#).unwrap();
+        assert_eq!(settings.art_mode, ArtMode::Halfblocks);
+        assert_eq!(Settings::default().art_mode, ArtMode::Auto);
+
+        assert!(ArtMode::Placeholder.picker().is_none());
+        let picker = ArtMode::Sixel.picker().unwrap();
+        assert_eq!(picker.protocol_type(), ProtocolType::Sixel);
+    }
+
+    #[test]
+    fn test_find_folder_cover() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert!(find_folder_cover(temp_dir.path()).is_none());
+
+        // Unrelated images are ignored
+        let img = ImageBuffer::from_fn(2, 2, |_, _| Rgba([255u8, 0, 0, 255]));
+        img.save(temp_dir.path().join("back.png")).unwrap();
+        assert!(find_folder_cover(temp_dir.path()).is_none());
+
+        let img = ImageBuffer::from_fn(3, 3, |_, _| Rgba([0u8, 255, 0, 255]));
+        img.save(temp_dir.path().join("Folder.PNG")).unwrap();
+        assert_eq!(find_folder_cover(temp_dir.path()).unwrap().width(), 3);
+
+        // `cover` takes precedence over `folder`
+        let img = ImageBuffer::from_fn(5, 5, |_, _| Rgba([0u8, 0, 255, 255]));
+        img.save(temp_dir.path().join("cover.png")).unwrap();
+        assert_eq!(find_folder_cover(temp_dir.path()).unwrap().width(), 5);
+    }
+
+    #[test]
+    fn test_scan_without_art() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        wav_songs(temp_dir.path(), 2);
+        let img = ImageBuffer::from_fn(3, 3, |_, _| Rgba([0u8, 255, 0, 255]));
+        img.save(temp_dir.path().join("cover.png")).unwrap();
+
+        let scan = |load_art| {
+            scan_dir_for_music(
+                temp_dir.path(),
+                MissingTitle::default(),
+                load_art,
+                &mut Vec::new(),
+            )
+            .unwrap()
+        };
+        let songs = scan(true);
+        assert!(Arc::ptr_eq(
+            songs[0].cover.as_ref().unwrap(),
+            songs[1].cover.as_ref().unwrap()
+        ));
+        assert!(scan(false)[0].cover.is_none());
+    }
+
+    #[test]
+    fn test_art_size_setting() {
+        assert_eq!(Settings::default().art_size.percentage(), 60);
+
+        let settings: Settings = serde_json::from_str(r#"{"art_size": "large"}"#).unwrap();
+        assert_eq!(settings.art_size.percentage(), 80);
+        let settings: Settings = serde_json::from_str(r#"{"art_size": {"percent": 150}}"#).unwrap();
+        assert_eq!(settings.art_size.percentage(), 100);
+        let settings: Settings = serde_json::from_str(r#"{"art_size": "hidden"}"#).unwrap();
+        assert_eq!(settings.art_size.percentage(), 0);
+
+        // Hidden art doesn't query the terminal, whatever the art mode
+        let settings: Settings =
+            serde_json::from_str(r#"{"art_size": "hidden", "art_mode": "sixel"}"#).unwrap();
+        assert!(settings.art_picker().is_none());
+        let settings: Settings = serde_json::from_str(r#"{"art_mode": "sixel"}"#).unwrap();
+        assert!(settings.art_picker().is_some());
+
+        // Turning art off hides it whatever its size
+        let settings: Settings =
+            serde_json::from_str(r#"{"art_size": "large", "art_mode": "sixel", "no_art": true}"#)
+                .unwrap();
+        assert_eq!(settings.art_percentage(), 0);
+        assert!(settings.art_picker().is_none());
+    }
+
+    #[test]
+    fn test_parse_lrc_lyrics() {
+        let lyrics = Lyrics::parse_lrc(
+            "[ar:Someone]\n[ti:A Song]\n[00:12.50]First line\n[01:02]Third line\n[00:30.00][01:30.25]Chorus\nno timestamp\n",
+        )
+        .unwrap();
+        assert_eq!(
+            lyrics,
+            Lyrics::Synced(vec![
+                (12.5, "First line".to_string()),
+                (30.0, "Chorus".to_string()),
+                (62.0, "Third line".to_string()),
+                (90.25, "Chorus".to_string()),
+            ])
+        );
+
+        assert_eq!(lyrics.current_line(5.0), None);
+        assert_eq!(lyrics.current_line(12.5), Some(0));
+        assert_eq!(lyrics.current_line(45.0), Some(1));
+        assert_eq!(lyrics.current_line(500.0), Some(3));
+
+        assert_eq!(Lyrics::parse_lrc("[ar:Someone]\nplain words"), None);
+    }
+
+    #[test]
+    fn test_load_lyrics() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let song_path = temp_dir.path().join("track.mp3");
+        assert_eq!(Lyrics::load(&song_path), None);
+
+        fs::write(temp_dir.path().join("track.txt"), "Just words").unwrap();
+        assert_eq!(Lyrics::load(&song_path), Some(Lyrics::Plain("Just words".to_string())));
+
+        fs::write(temp_dir.path().join("track.lrc"), "[00:01.00]Timed words").unwrap();
+        assert_eq!(
+            Lyrics::load(&song_path),
+            Some(Lyrics::Synced(vec![(1.0, "Timed words".to_string())]))
+        );
+    }
+
+    #[test]
+    fn test_embedded_lyrics() {
+        use id3::TagLike;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let song_path = temp_dir.path().join("track.mp3");
+        File::create(&song_path).unwrap();
+        assert_eq!(read_embedded_lyrics(&song_path), None);
+
+        let mut tag = id3::Tag::new();
+        tag.add_frame(id3::frame::Lyrics {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: "First line\r\nSecond line".to_string(),
+        });
+        tag.write_to_path(&song_path, id3::Version::Id3v24).unwrap();
+        assert_eq!(
+            read_embedded_lyrics(&song_path).as_deref(),
+            Some("First line\nSecond line")
+        );
+
+        // A sidecar .lrc file takes precedence over embedded lyrics
+        let mut myapp = MyApp::new();
+        let mut song = Song::new(
+            "Track".to_string(),
+            "Artist".to_string(),
+            None,
+            song_path.clone(),
+            "Album".to_string(),
+            60.0,
+        );
+        song.lyrics = read_embedded_lyrics(&song_path);
+        myapp.currently_playing_song = Some(song.id);
+        myapp.songs.push(song);
+        assert_eq!(
+            myapp.current_lyrics(),
+            Some(&Lyrics::Plain("First line\nSecond line".to_string()))
+        );
+
+        fs::write(temp_dir.path().join("track.lrc"), "[00:01.00]Timed").unwrap();
+        myapp.lyrics = None;
+        assert_eq!(
+            myapp.current_lyrics(),
+            Some(&Lyrics::Synced(vec![(1.0, "Timed".to_string())]))
+        );
+    }
+
+    #[test]
+    fn test_play_selected_playlist() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sink = draining_sink();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 3);
+        myapp.filtered_songs = myapp.songs.clone();
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.playlists.insert("Empty".to_string(), vec![]);
+        myapp.playlists.insert("Mix".to_string(), vec![ids[2], ids[0], ids[1]]);
+
+        myapp.selected_playlist_index = 0;
+        myapp.play_selected_playlist(&sink);
+        assert_eq!(myapp.currently_playing_song, None);
+        assert!(myapp.play_queue.is_empty());
+
+        myapp.selected_playlist_index = 1;
+        handle_key_event(
+            &mut myapp,
+            KeyEvent::new(KeyCode::Char('o'), KeyModifiers::CONTROL),
+            &sink,
+            10,
+            10,
+        );
+        assert_eq!(myapp.currently_playing_song, Some(ids[2]));
+        assert_eq!(myapp.queue_source.as_deref(), Some("Mix"));
+
+        // Next and previous follow the playlist order and wrap around
+        myapp.play_next(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[0]));
+        myapp.play_next(&sink);
+        myapp.play_next(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[2]));
+        myapp.play_previous(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
+        assert!(myapp.get_song(ids[1]).unwrap().is_playing);
+        assert!(!myapp.get_song(ids[2]).unwrap().is_playing);
+    }
+
+    #[test]
+    fn test_cut_and_paste_in_playlist() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(4);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.playlists.insert("Mix".to_string(), vec![ids[3], ids[0], ids[1], ids[2]]);
+
+        // "All Songs" can't be reordered
+        myapp.selected_playlist_index = 0;
+        myapp.filter_songs();
+        myapp.selected_song_id = Some(ids[0]);
+        myapp.cut_selected_song();
+        assert_eq!(myapp.cut_song, None);
+
+        // Other playlists are listed in their stored order
+        myapp.selected_playlist_index = 1;
+        myapp.filter_songs();
+        let listed: Vec<Uuid> = myapp.filtered_songs.iter().map(|song| song.id).collect();
+        assert_eq!(listed, vec![ids[3], ids[0], ids[1], ids[2]]);
+
+        myapp.selected_song_id = Some(ids[3]);
+        myapp.cut_selected_song();
+        myapp.selected_song_id = Some(ids[2]);
+        myapp.paste_cut_song(true);
+        assert_eq!(myapp.playlists["Mix"], vec![ids[0], ids[1], ids[2], ids[3]]);
+        assert_eq!(myapp.selected_song_id, Some(ids[3]));
+        assert_eq!(myapp.filtered_songs[3].id, ids[3]);
+
+        myapp.cut_selected_song();
+        myapp.selected_song_id = Some(ids[1]);
+        myapp.paste_cut_song(false);
+        assert_eq!(myapp.playlists["Mix"], vec![ids[0], ids[3], ids[1], ids[2]]);
+
+        // Nothing happens without a cut song
+        myapp.paste_cut_song(false);
+        assert_eq!(myapp.playlists["Mix"], vec![ids[0], ids[3], ids[1], ids[2]]);
+    }
+
+    #[test]
+    fn test_queue_editing() {
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(4);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.play_queue = ids.clone();
+        let press = |myapp: &mut MyApp, code: KeyCode, modifiers: KeyModifiers| {
+            handle_key_event(myapp, KeyEvent::new(code, modifiers), &sink, 10, 10);
+        };
+
+        press(&mut myapp, KeyCode::F(2), KeyModifiers::NONE);
+        assert!(myapp.queue_popup.visible);
+        assert_eq!(myapp.queue_selected, 1);
+
+        // The current song can't be selected, moved or removed
+        press(&mut myapp, KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!(myapp.queue_selected, 1);
+        press(&mut myapp, KeyCode::Up, KeyModifiers::SHIFT);
+        assert_eq!(myapp.play_queue, ids);
+
+        press(&mut myapp, KeyCode::Down, KeyModifiers::SHIFT);
+        assert_eq!(myapp.play_queue, vec![ids[0], ids[2], ids[1], ids[3]]);
+        assert_eq!(myapp.queue_selected, 2);
+
+        press(&mut myapp, KeyCode::Down, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!(myapp.queue_selected, 3);
+        press(&mut myapp, KeyCode::Delete, KeyModifiers::NONE);
+        assert_eq!(myapp.play_queue, vec![ids[0], ids[2], ids[1]]);
+        assert_eq!(myapp.queue_selected, 2);
+        press(&mut myapp, KeyCode::Delete, KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Delete, KeyModifiers::NONE);
+        assert_eq!(myapp.play_queue, vec![ids[0]]);
+        press(&mut myapp, KeyCode::Delete, KeyModifiers::NONE);
+        assert_eq!(myapp.play_queue, vec![ids[0]]);
+
+        press(&mut myapp, KeyCode::Esc, KeyModifiers::NONE);
+        assert!(!myapp.queue_popup.visible);
+    }
+
+    #[test]
+    fn test_tags_are_normalized() {
+        assert_eq!(normalize_tag("  Artist \0\0"), "Artist");
+        assert_eq!(normalize_tag("\u{feff}Title\r\n"), "Title");
+        // "e" followed by a combining acute accent composes to "é"
+        assert_eq!(normalize_tag("Caf\u{0065}\u{0301}"), "Caf\u{e9}");
+
+        let song = Song::new(
+            "\u{feff}Song ".to_string(),
+            "Artist\0".to_string(),
+            None,
+            PathBuf::from("/path/to/song.mp3"),
+            " Album".to_string(),
+            60.0,
+        );
+        assert_eq!(song.title, "Song");
+        assert_eq!(song.artist, "Artist");
+        assert_eq!(song.album, "Album");
+        assert_eq!(song.artist_lower, "artist");
+
+        // Search keys follow changes to the tags
+        let mut song = song;
+        song.set_title("Ünïcode");
+        assert_eq!(song.title_lower, "unicode");
+        song.album = "Später".to_string();
+        song.update_search_keys();
+        assert_eq!(song.album_lower, "spater");
+        assert_eq!(song.artist_lower, "artist");
+    }
+
+    #[test]
+    fn test_sort_ignoring_articles() {
+        let mut songs: Vec<Song> = ["The Beatles", "Abba", "A Tribe Called Quest", "Coldplay", "The"]
+            .iter()
+            .enumerate()
+            .map(|(i, artist)| {
+                Song::new(
+                    format!("Song {}", i),
+                    artist.to_string(),
+                    None,
+                    PathBuf::from(format!("/path/to/song{}.mp3", i)),
+                    "Album".to_string(),
+                    60.0,
+                )
+            })
+            .collect();
+        let artists = |songs: &[Song]| songs.iter().map(|s| s.artist.clone()).collect::<Vec<_>>();
 
-    let inner_area = Rect::new(
-        popup_area.x,
-        popup_area.y + 2,
-        popup_area.width,
-        popup_area.height - 4,
-    );
+        sort_songs(&mut songs, &SortCriteria::Artist, &[]);
+        assert_eq!(
+            artists(&songs),
+            ["A Tribe Called Quest", "Abba", "Coldplay", "The", "The Beatles"]
+        );
 
-    // Display the current input inside the popup
-    let input_text = Paragraph::new(input)
-        .block(Block::default().borders(Borders::NONE))
-        .alignment(Alignment::Center)
-        .style(Style::default().fg(Color::White))
-        .wrap(Wrap { trim: true });
+        let articles = vec!["The".to_string(), "A".to_string(), "An".to_string()];
+        sort_songs(&mut songs, &SortCriteria::Artist, &articles);
+        assert_eq!(
+            artists(&songs),
+            ["Abba", "The Beatles", "Coldplay", "The", "A Tribe Called Quest"]
+        );
+    }
+
+    #[test]
+    fn test_scan_skips_unreadable_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        wav_songs(temp_dir.path(), 1);
+        fs::write(temp_dir.path().join("garbage.mp3"), b"definitely not audio").unwrap();
+        fs::write(temp_dir.path().join("truncated.flac"), b"fLaC\0\0").unwrap();
+
+        let mut tag = id3::Tag::new();
+        id3::TagLike::set_title(&mut tag, "Tagged");
+        let tagged_path = temp_dir.path().join("tagged.mp3");
+        File::create(&tagged_path).unwrap();
+        tag.write_to_path(&tagged_path, id3::Version::Id3v24).unwrap();
+
+        let mut scan_log = Vec::new();
+        let mut songs =
+            scan_dir_for_music(temp_dir.path(), MissingTitle::NoTitle, true, &mut scan_log)
+                .unwrap();
+        songs.sort_by(|a, b| a.title.cmp(&b.title));
+        let titles: Vec<&str> = songs.iter().map(|song| song.title.as_str()).collect();
+        // The WAV has no tags but can be played, so it's named after the file
+        assert_eq!(titles, ["Tagged", "song0"]);
+        // Skipped files and missing tags are logged
+        assert_eq!(scan_log.len(), 3);
+        assert!(scan_log
+            .iter()
+            .any(|entry| entry.starts_with("Skipping unreadable file")
+                && entry.contains("garbage.mp3")));
+        assert!(scan_log.iter().any(
+            |entry| entry.starts_with("Couldn't read the tags") && entry.contains("song0.wav")
+        ));
+    }
+
+    #[test]
+    fn test_seek_while_paused_updates_progress() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sink = draining_sink();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 1);
+        myapp.songs[0].duration = 100.0;
+        myapp.settings.fade_ms = Some(0);
+        let id = myapp.songs[0].id;
+
+        myapp.start_song(id, &sink);
+        myapp.toggle_pause(&sink);
+        assert!(sink.lock().unwrap().is_paused());
+        assert!(myapp.progress_ratio(100.0) < 0.01);
+
+        myapp.seek_by(&sink, 30);
+        assert!(sink.lock().unwrap().is_paused());
+        assert!((myapp.progress_ratio(100.0) - 0.3).abs() < 0.01);
+
+        myapp.seek_by(&sink, -10);
+        assert!((myapp.progress_ratio(100.0) - 0.2).abs() < 0.01);
+
+        // Resuming continues from the new position
+        myapp.toggle_pause(&sink);
+        assert!(!sink.lock().unwrap().is_paused());
+        assert!((myapp.progress_ratio(100.0) - 0.2).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_progress_is_full_at_end_of_song() {
+        let mut myapp = MyApp::new();
+        assert_eq!(myapp.progress_ratio(10.0), 0.0);
+
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(5));
+        assert!((myapp.progress_ratio(10.0) - 0.5).abs() < 0.01);
+
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(12));
+        assert_eq!(myapp.progress_ratio(10.0), 1.0);
+        assert_eq!(myapp.progress_ratio(0.0), 0.0);
+    }
+
+    #[test]
+    fn test_compact_layout_breakpoints() {
+        assert!(!is_compact_area(Rect::new(0, 0, 120, 40)));
+        assert!(is_compact_area(Rect::new(0, 0, 120, 15)));
+        assert!(is_compact_area(Rect::new(0, 0, 60, 40)));
+    }
+
+    #[test]
+    fn test_selection_remembered_per_playlist() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(5);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.playlists.insert("Mix".to_string(), vec![ids[1], ids[3], ids[4]]);
+        let refresh = |myapp: &mut MyApp| {
+            myapp.filter_songs();
+            myapp.sync_selection(10);
+        };
+
+        refresh(&mut myapp);
+        assert_eq!(myapp.selected_song_id, Some(ids[0]));
+        myapp.select_song_at(2, 10);
+        refresh(&mut myapp);
+
+        myapp.select_playlist_at(1, 10);
+        refresh(&mut myapp);
+        assert_eq!(myapp.selected_song_id, Some(ids[1]));
+        myapp.select_song_at(1, 10);
+        refresh(&mut myapp);
+
+        myapp.select_playlist_at(0, 10);
+        refresh(&mut myapp);
+        assert_eq!(myapp.selected_song_id, Some(ids[2]));
+
+        // A remembered song that left the playlist falls back to the first one
+        myapp.playlists.get_mut("Mix").unwrap().retain(|id| *id != ids[3]);
+        myapp.select_playlist_at(1, 10);
+        refresh(&mut myapp);
+        assert_eq!(myapp.selected_song_id, Some(ids[1]));
+    }
+
+    #[test]
+    fn test_stop_after_current() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sink = draining_sink();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 2);
+        myapp.filtered_songs = myapp.songs.clone();
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        let finish_song = |myapp: &mut MyApp| {
+            myapp.song_time = Some(Instant::now() - Duration::from_secs(5));
+            myapp.update_playback(&sink);
+        };
+
+        myapp.start_song(ids[0], &sink);
+        finish_song(&mut myapp);
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
+
+        handle_key_event(
+            &mut myapp,
+            KeyEvent::new(KeyCode::Char('e'), KeyModifiers::CONTROL),
+            &sink,
+            10,
+            10,
+        );
+        assert_eq!(myapp.advance_mode, AdvanceMode::StopAfterCurrent);
+        assert_eq!(myapp.mode_indicators(), "↻ ⏹");
+        finish_song(&mut myapp);
+        assert_eq!(myapp.currently_playing_song, None);
+        assert!(myapp.songs.iter().all(|song| !song.is_playing));
+        assert_eq!(myapp.advance_mode, AdvanceMode::Continue);
+    }
+
+    #[test]
+    fn test_repeat_modes() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(3);
+        myapp.filtered_songs = myapp.songs.clone();
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+
+        assert_eq!(myapp.repeat_mode, RepeatMode::All);
+        assert_eq!(myapp.next_song_after_end(ids[0]), Some(ids[1]));
+        assert_eq!(myapp.next_song_after_end(ids[2]), Some(ids[0]));
+
+        myapp.cycle_repeat_mode();
+        assert_eq!(myapp.repeat_mode, RepeatMode::Off);
+        assert_eq!(myapp.next_song_after_end(ids[1]), Some(ids[2]));
+        assert_eq!(myapp.next_song_after_end(ids[2]), None);
+
+        myapp.cycle_repeat_mode();
+        assert_eq!(myapp.repeat_mode, RepeatMode::One);
+        assert_eq!(myapp.next_song_after_end(ids[2]), Some(ids[2]));
+
+        // The queue cycles with repeat all and is used up with repeat off
+        let finish = |myapp: &mut MyApp, id: Uuid| {
+            myapp.currently_playing_song = Some(id);
+            myapp.next_song_after_end(id)
+        };
+        myapp.play_queue = vec![ids[2], ids[0]];
+        myapp.repeat_mode = RepeatMode::All;
+        assert_eq!(finish(&mut myapp, ids[2]), Some(ids[0]));
+        assert_eq!(finish(&mut myapp, ids[0]), Some(ids[2]));
+        myapp.repeat_mode = RepeatMode::Off;
+        assert_eq!(finish(&mut myapp, ids[2]), Some(ids[0]));
+        assert_eq!(finish(&mut myapp, ids[0]), None);
+        assert!(myapp.play_queue.is_empty());
+    }
+
+    #[test]
+    fn test_mode_indicators() {
+        let mut myapp = MyApp::new();
+        assert_eq!(myapp.mode_indicators(), "↻");
+        myapp.cycle_repeat_mode();
+        assert_eq!(myapp.mode_indicators(), "");
+        myapp.cycle_repeat_mode();
+        assert_eq!(myapp.mode_indicators(), "↻1");
+        myapp.toggle_stop_after_current();
+        assert_eq!(myapp.mode_indicators(), "↻1 ⏹");
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("data.json");
+        let file_count = || fs::read_dir(temp_dir.path()).unwrap().count();
+
+        write_atomically(&path, b"old").unwrap();
+        write_atomically(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(file_count(), 1);
+
+        // If the final rename fails the target is left as it was and the temporary file is
+        // cleaned up
+        let blocked = temp_dir.path().join("blocked");
+        fs::create_dir(&blocked).unwrap();
+        fs::write(blocked.join("inside"), "kept").unwrap();
+        assert!(write_atomically(&blocked, b"lost").is_err());
+        assert_eq!(fs::read_to_string(blocked.join("inside")).unwrap(), "kept");
+        assert_eq!(file_count(), 2);
+    }
+
+    #[test]
+    fn test_playlist_names() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(2);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+
+        myapp.chosen_song_ids = vec![ids[0]];
+        myapp.input_text = "   ".to_string();
+        myapp.create_playlist_from_input();
+        assert!(myapp.playlists.is_empty());
+        assert_eq!(myapp.input_text, "   ");
+        assert_eq!(myapp.current_status(), Some("Need a name"));
+
+        // Names that aren't valid file names are fine, they're only JSON keys
+        for name in ["AC/DC", " Mix: 2024 "] {
+            myapp.chosen_song_ids = vec![ids[1]];
+            myapp.input_text = name.to_string();
+            myapp.create_playlist_from_input();
+        }
+        let names: Vec<&String> = myapp.playlists.keys().collect();
+        assert_eq!(names, ["AC/DC", "Mix: 2024"]);
+
+        myapp.chosen_song_ids = vec![ids[0]];
+        myapp.input_text = "AC/DC ".to_string();
+        myapp.create_playlist_from_input();
+        assert_eq!(myapp.input_text, "AC/DC ");
+        assert_eq!(
+            myapp.current_status(),
+            Some("Playlist \"AC/DC\" already exists")
+        );
+        assert_eq!(myapp.playlists["AC/DC"], [ids[1]]);
+
+        let serialized = serde_json::to_string(&myapp.playlists).unwrap();
+        let loaded: BTreeMap<String, Vec<Uuid>> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(loaded, myapp.playlists);
+    }
+
+    #[test]
+    fn test_sort_selected_playlist() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(3);
+        myapp.songs[0].duration = 300.0;
+        myapp.songs[2].duration = 50.0;
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        let missing = Uuid::nil();
+        myapp.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.playlists.insert("Mix".to_string(), vec![missing, ids[1], ids[0], ids[2]]);
+
+        // Changing the criteria leaves user playlists alone
+        myapp.set_sort_criteria(SortCriteria::Duration);
+        assert_eq!(myapp.playlists["Mix"], vec![missing, ids[1], ids[0], ids[2]]);
+        assert!(!myapp.playlists_unsaved);
+
+        myapp.selected_playlist_index = 1;
+        myapp.sort_selected_playlist();
+        assert_eq!(myapp.playlists["Mix"], vec![ids[2], ids[1], ids[0], missing]);
+        assert!(myapp.playlists_unsaved);
+        let listed: Vec<Uuid> = myapp.filtered_songs.iter().map(|song| song.id).collect();
+        assert_eq!(listed, vec![ids[2], ids[1], ids[0]]);
+
+        myapp.selected_playlist_index = 0;
+        myapp.sort_selected_playlist();
+        assert_eq!(myapp.playlists[ALL_SONGS_PLAYLIST], ids);
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(1);
+        assert_eq!(myapp.poll_interval(&sink), IDLE_POLL_INTERVAL);
+
+        myapp.currently_playing_song = Some(myapp.songs[0].id);
+        assert_eq!(myapp.poll_interval(&sink), PLAYING_POLL_INTERVAL);
+
+        myapp.settings.progress_interval_ms = Some(250);
+        assert_eq!(myapp.poll_interval(&sink), Duration::from_millis(250));
+        myapp.settings.progress_interval_ms = Some(0);
+        assert_eq!(myapp.poll_interval(&sink), Duration::from_millis(20));
+
+        sink.lock().unwrap().pause();
+        assert_eq!(myapp.poll_interval(&sink), IDLE_POLL_INTERVAL);
+    }
+
+    #[test]
+    fn test_progress_follows_the_clock() {
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(1);
+        let id = myapp.songs[0].id;
+        myapp.songs[0].is_playing = true;
+        myapp.currently_playing_song = Some(id);
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(30));
+
+        // Updating often must not slow the progress down
+        for _ in 0..50 {
+            myapp.update_playback(&sink);
+        }
+        assert!(myapp.elapsed_time(100.0) >= 30.0);
+        assert_eq!(myapp.currently_playing_song, Some(id));
+    }
+
+    #[test]
+    fn test_take_redraw() {
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(1);
+
+        // The first frame is always drawn, then only after changes
+        assert!(myapp.take_redraw(&sink));
+        assert!(!myapp.take_redraw(&sink));
+
+        myapp.status_message = Some(("Saved".to_string(), Instant::now() - STATUS_DURATION));
+        assert!(myapp.take_redraw(&sink));
+        assert_eq!(myapp.status_message, None);
+        assert!(!myapp.take_redraw(&sink));
+
+        // Playing songs keep the progress bar moving
+        myapp.currently_playing_song = Some(myapp.songs[0].id);
+        assert!(myapp.take_redraw(&sink));
+        assert!(myapp.take_redraw(&sink));
+        sink.lock().unwrap().pause();
+        assert!(!myapp.take_redraw(&sink));
+    }
+
+    #[test]
+    fn test_stop_song() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(3);
+        let id = myapp.songs[2].id;
+        myapp.songs[2].is_playing = true;
+        myapp.currently_playing_song = Some(id);
+
+        myapp.stop_song();
+        assert_eq!(myapp.currently_playing_song, None);
+        assert!(myapp.songs.iter().all(|song| !song.is_playing));
+
+        // A song that is no longer in the library is just forgotten
+        myapp.currently_playing_song = Some(Uuid::nil());
+        myapp.stop_song();
+        assert_eq!(myapp.currently_playing_song, None);
+    }
+
+    #[test]
+    fn test_seek_near_track_ends() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sink = draining_sink();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 2);
+        // Long enough that the next song's progress doesn't depend on how fast the test runs
+        myapp.songs[0].duration = 20.0;
+        myapp.songs[1].duration = 20.0;
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.playlists.insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.filter_songs();
+
+        // Seeking back from the start stays at the start
+        myapp.start_song(ids[0], &sink);
+        myapp.seek_by(&sink, -5);
+        assert!(myapp.progress_ratio(20.0) < 0.01);
+
+        myapp.seek_by(&sink, 17);
+        assert_eq!(myapp.currently_playing_song, Some(ids[0]));
+        assert!((myapp.progress_ratio(20.0) - 0.85).abs() < 0.01);
+
+        // Seeking past the end moves on to the next song
+        myapp.seek_by(&sink, 5);
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
+        assert!(myapp.progress_ratio(myapp.songs[1].duration) < 0.01);
+
+        // With nothing left to play, playback stops
+        myapp.repeat_mode = RepeatMode::Off;
+        myapp.seek_by(&sink, 600);
+        assert_eq!(myapp.currently_playing_song, None);
+    }
+
+    #[test]
+    fn test_stream_reader_seeks_within_its_start() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut reader = StreamReader::new(io::Cursor::new(data));
+        let mut buf = [0; 16];
+
+        // Format detection reads the start and seeks back
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.seek(SeekFrom::Start(0)).unwrap(), 0);
+        reader.read_exact(&mut buf[..4]).unwrap();
+        assert_eq!(buf[..4], [0, 1, 2, 3]);
+        reader.seek(SeekFrom::Current(8)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], 12);
+        assert_eq!(buf[15], 27);
+
+        assert!(reader.seek(SeekFrom::Start(100)).is_err());
+        assert!(reader.seek(SeekFrom::End(0)).is_err());
+        assert_eq!(reader.stream_position().unwrap(), 28);
+    }
+
+    #[test]
+    fn test_streams_playlist() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("streams.json");
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(1);
+
+        assert_eq!(stream_url(Path::new("/music/song.mp3")), None);
+        let url = "https://radio.example/live";
+        let id = myapp.add_stream(STREAMS_PLAYLIST, url, url);
+        assert_eq!(myapp.add_stream(STREAMS_PLAYLIST, url, url), id);
+        assert_eq!(myapp.get_song(id).unwrap().duration, 0.0);
+        assert_eq!(myapp.playlists[STREAMS_PLAYLIST], [id]);
+        myapp.save_streams(&path).unwrap();
+
+        let mut restored = MyApp::new();
+        restored.load_streams(&path).unwrap();
+        assert_eq!(restored.stream_urls(), ["https://radio.example/live"]);
+        assert_eq!(restored.playlists[STREAMS_PLAYLIST], [id]);
+
+        // streams.json is the only place the streams are kept, and the name is reserved
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp.save_playlist().unwrap();
+        let playlist_file = temp_dir.path().join("data.json");
+        assert_eq!(fs::read_to_string(&playlist_file).unwrap(), "{}");
+        myapp.chosen_song_ids = vec![myapp.songs[0].id];
+        myapp.input_text = STREAMS_PLAYLIST.to_string();
+        myapp.create_playlist_from_input();
+        assert_eq!(myapp.playlists[STREAMS_PLAYLIST], [id]);
+        assert_eq!(
+            myapp.current_status(),
+            Some("\"Streams\" is a reserved name")
+        );
+
+        fs::write(&playlist_file, r#"{"Streams": []}"#).unwrap();
+        restored.load_playlists(&playlist_file).unwrap();
+        assert_eq!(restored.playlists[STREAMS_PLAYLIST], [id]);
+    }
+
+    #[test]
+    fn test_stream_without_duration_ends_with_its_source() {
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut myapp = MyApp::new();
+        let id = myapp.add_stream(STREAMS_PLAYLIST, "Live", "http://radio.example/live");
+        myapp.repeat_mode = RepeatMode::Off;
+        myapp.songs[0].is_playing = true;
+        myapp.currently_playing_song = Some(id);
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(600));
+
+        // Seeking does nothing
+        myapp.seek_by(&sink, 30);
+        assert_eq!(myapp.currently_playing_song, Some(id));
+
+        // Nothing is left to play, so the stream is over
+        myapp.update_playback(&sink);
+        assert_eq!(myapp.currently_playing_song, None);
+    }
+
+    #[test]
+    fn test_icy_metadata_is_separated_from_audio() {
+        let title = Arc::new(Mutex::new(None));
+        let metadata = b"StreamTitle='Artist - Song';\0\0\0\0";
+        let mut data = vec![1u8; 4];
+        data.push(2);
+        data.extend_from_slice(metadata);
+        data.extend_from_slice(&[2u8; 4]);
+        data.push(0);
+        data.extend_from_slice(&[3u8; 2]);
+        let mut reader = IcyReader::new(io::Cursor::new(data), 4, title.clone());
+
+        let mut audio = Vec::new();
+        reader.read_to_end(&mut audio).unwrap();
+        assert_eq!(audio, [1, 1, 1, 1, 2, 2, 2, 2, 3, 3]);
+        assert_eq!(title.lock().unwrap().as_deref(), Some("Artist - Song"));
+
+        assert_eq!(parse_icy_title("StreamTitle='';"), None);
+        assert_eq!(parse_icy_title("StreamUrl='x';"), None);
+    }
+
+    #[test]
+    fn test_radio_stations() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("stations.json");
+        fs::write(
+            &path,
+            r#"{"Jazz FM": "https://jazz.example/stream", "Broken": "/not/a/stream"}"#,
+        )
+        .unwrap();
+        let mut myapp = MyApp::new();
+        myapp.load_stations(&path).unwrap();
+
+        let stations: Vec<&str> = myapp.playlists[RADIO_PLAYLIST]
+            .iter()
+            .map(|id| myapp.get_song(*id).unwrap().title.as_str())
+            .collect();
+        assert_eq!(stations, ["Jazz FM"]);
+
+        // The stations aren't stored with the playlists, and a stale entry isn't read back
+        let playlist_file = temp_dir.path().join("data.json");
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp.save_playlist().unwrap();
+        assert_eq!(fs::read_to_string(&playlist_file).unwrap(), "{}");
+        fs::write(
+            &playlist_file,
+            format!(r#"{{"Radio": ["{}"]}}"#, Uuid::nil()),
+        )
+        .unwrap();
+        let mut restored = MyApp::new();
+        restored.load_playlists(&playlist_file).unwrap();
+        assert!(!restored.playlists.contains_key(RADIO_PLAYLIST));
+
+        // Nor can it be deleted, reordered or taken by another playlist
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        myapp.selected_playlist_index = 0;
+        assert_eq!(myapp.selected_playlist_name().unwrap(), RADIO_PLAYLIST);
+        handle_key_event(
+            &mut myapp,
+            KeyEvent::new(KeyCode::Char('x'), KeyModifiers::CONTROL),
+            &sink,
+            10,
+            10,
+        );
+        assert_eq!(myapp.current_status(), Some("\"Radio\" can't be deleted"));
+        myapp.selected_song_id = myapp.playlists[RADIO_PLAYLIST].first().copied();
+        myapp.cut_selected_song();
+        assert_eq!(myapp.cut_song, None);
+        myapp.chosen_song_ids = myapp.playlists[RADIO_PLAYLIST].clone();
+        myapp.input_text = RADIO_PLAYLIST.to_string();
+        myapp.create_playlist_from_input();
+        assert_eq!(myapp.current_status(), Some("\"Radio\" is a reserved name"));
+        assert_eq!(myapp.playlists[RADIO_PLAYLIST].len(), 1);
+    }
+
+    #[test]
+    fn test_loudness_normalization() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let path = temp_dir.path().join("tone.wav");
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..16000 {
+            let sample = (i as f32 * 440.0 * std::f32::consts::TAU / 8000.0).sin() * 8000.0;
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut myapp = MyApp::new();
+        myapp.songs = vec![Song::new(
+            "Tone".to_string(),
+            "Artist".to_string(),
+            None,
+            path.clone(),
+            "Album".to_string(),
+            2.0,
+        )];
+        myapp.currently_playing_song = Some(myapp.songs[0].id);
+        myapp.loudness_analyzer = Some(LoudnessAnalyzer::spawn());
+
+        myapp.update_loudness();
+        assert_eq!(myapp.loudness.get(&path), Some(&None));
+        for _ in 0..100 {
+            if myapp.loudness[&path].is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            myapp.update_loudness();
+        }
+        let loudness = myapp.loudness[&path].unwrap();
+        assert!((-20.0..-5.0).contains(&loudness));
+        assert_eq!(*myapp.songs[0].gain.lock().unwrap(), loudness_gain(loudness));
+
+        assert_eq!(loudness_gain(TARGET_LOUDNESS), 1.0);
+        assert_eq!(loudness_gain(-80.0), 4.0);
+        assert_eq!(measure_loudness(&temp_dir.path().join("missing.wav")), None);
+    }
+
+    #[test]
+    fn test_configured_directories() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let music_dir = temp_dir.path().join("music");
+        fs::create_dir(&music_dir).unwrap();
+        wav_songs(&music_dir, 2);
+
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(music_dir.clone());
+        myapp.settings.playlist_dir = Some(music_dir.join("playlists"));
+        myapp.load_songs();
+        assert_eq!(myapp.songs.len(), 2);
+        assert!(myapp.songs.iter().all(|song| song.path.starts_with(&music_dir)));
+
+        // The playlist directory is created when saving
+        let playlist_file = myapp.playlist_file().unwrap();
+        assert_eq!(playlist_file, music_dir.join("playlists").join("data.json"));
+        myapp.save_playlist().unwrap();
+        let mut restored = MyApp::new();
+        restored.settings.music_dir = Some(music_dir.clone());
+        restored.load_songs();
+        restored.load_playlists(&playlist_file).unwrap();
+        assert_eq!(restored.playlists, myapp.playlists);
+    }
+
+    #[test]
+    fn test_external_playlist_edits() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let playlist_file = temp_dir.path().join("data.json");
+        let mut myapp = MyApp::new();
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp.playlists.insert("Mine".to_string(), vec![Uuid::nil()]);
+        myapp.playlists_unsaved = true;
+
+        assert!(!myapp.playlists_changed_on_disk());
+        assert!(myapp.save_and_quit());
+        myapp.load_playlists(&playlist_file).unwrap();
+        assert!(!myapp.playlists_changed_on_disk());
+        myapp
+            .playlists
+            .insert("Also mine".to_string(), vec![Uuid::nil()]);
+        myapp.playlists_unsaved = true;
+
+        // Another program edits the file
+        fs::write(&playlist_file, r#"{"Theirs": []}"#).unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&playlist_file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(myapp.playlists_changed_on_disk());
+
+        // Quitting warns once, then saves over it keeping a backup
+        assert!(!myapp.save_and_quit());
+        assert!(myapp.current_status().is_some());
+        assert!(myapp.save_and_quit());
+        assert!(!myapp.playlists_changed_on_disk());
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("data.json.bak")).unwrap(),
+            r#"{"Theirs": []}"#
+        );
+        assert!(fs::read_to_string(&playlist_file).unwrap().contains("Mine"));
+    }
+
+    #[test]
+    fn test_read_only_config_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert!(dir_writable(&temp_dir.path().join("new")));
+        assert!(temp_dir.path().join("new").is_dir());
+        assert_eq!(
+            fs::read_dir(temp_dir.path().join("new")).unwrap().count(),
+            0
+        );
+        // A file in the way can't be made a directory, even by root
+        fs::write(temp_dir.path().join("file"), "").unwrap();
+        let config_dir = temp_dir.path().join("file").join("cli-rhythm");
+        assert!(!dir_writable(&config_dir));
+
+        let mut myapp = MyApp::new();
+        myapp.settings.playlist_dir = Some(config_dir.clone());
+        myapp.settings_file = Some(config_dir.join("settings.json"));
+        myapp.play_queue_file = Some(config_dir.join("queue.json"));
+        myapp.enter_read_only(&config_dir);
+        assert!(myapp
+            .current_status()
+            .unwrap()
+            .contains("running read-only"));
+        assert_eq!(myapp.settings_file, None);
+        assert_eq!(myapp.play_queue_file, None);
+
+        // Nothing is written, and quitting says so
+        myapp.playlists.insert("Mine".to_string(), Vec::new());
+        myapp.playlists_unsaved = true;
+        myapp.playlists_changed_at = Some(Instant::now() - PLAYLIST_SAVE_DELAY);
+        myapp.autosave_playlists();
+        assert!(myapp.save_and_quit());
+        assert!(!config_dir.join("data.json").exists());
+        assert_eq!(
+            myapp.quit_message.as_deref(),
+            Some("Couldn't save the playlists: running read-only")
+        );
+
+        // Playlists kept in a directory that can be written are still saved
+        let mut myapp = MyApp::new();
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp.enter_read_only(&config_dir);
+        assert!(myapp
+            .current_status()
+            .unwrap()
+            .ends_with("changes other than playlists won't be saved"));
+        myapp.playlists.insert("Mine".to_string(), Vec::new());
+        myapp.playlists_unsaved = true;
+        myapp.playlists_changed_at = Some(Instant::now() - PLAYLIST_SAVE_DELAY);
+        myapp.autosave_playlists();
+        assert!(!myapp.playlists_unsaved);
+        assert!(fs::read_to_string(temp_dir.path().join("data.json"))
+            .unwrap()
+            .contains("Mine"));
+    }
+
+    #[test]
+    fn test_save_playlist_summary() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let playlist_file = temp_dir.path().join("data.json");
+        let mut myapp = MyApp::new();
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), vec![Uuid::nil()]);
+        myapp.playlists.insert("One".to_string(), vec![Uuid::nil()]);
+        myapp
+            .playlists
+            .insert("Two".to_string(), vec![Uuid::nil(), Uuid::max()]);
+        myapp.playlists_unsaved = true;
+
+        assert!(myapp.save_and_quit());
+        assert_eq!(
+            myapp.quit_message.as_deref(),
+            Some("Saved 2 playlists, 3 songs")
+        );
+
+        // A backup that can't be kept is reported, but the playlists are still saved
+        myapp.playlists.insert("Three".to_string(), Vec::new());
+        myapp.playlists_unsaved = true;
+        fs::write(&playlist_file, r#"{"Theirs": []}"#).unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(1);
+        File::options()
+            .write(true)
+            .open(&playlist_file)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        fs::create_dir(temp_dir.path().join("data.json.bak")).unwrap();
+        let summary = myapp.save_playlist().unwrap();
+        assert!(summary.starts_with("Saved 3 playlists, 3 songs, couldn't keep"));
+        assert!(fs::read_to_string(&playlist_file)
+            .unwrap()
+            .contains("Three"));
+    }
+
+    #[test]
+    fn test_toggle_layout_is_saved() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let settings_file = temp_dir.path().join("settings.json");
+        let mut myapp = MyApp::new();
+        fs::write(&settings_file, r#"{"vim_mode": true}"#).unwrap();
+        myapp.settings = Settings::load(&settings_file);
+        myapp.settings.music_dir = Some(PathBuf::from("/from/the/command/line"));
+        myapp.settings_file = Some(settings_file.clone());
+
+        myapp.toggle_layout();
+        assert_eq!(myapp.settings.layout, LayoutMode::Stacked);
+        let saved_settings = Settings::load(&settings_file);
+        assert!(saved_settings.vim_mode);
+        assert_eq!(saved_settings.layout, LayoutMode::Stacked);
+        assert_eq!(saved_settings.music_dir, None);
+
+        myapp.toggle_layout();
+        assert_eq!(Settings::load(&settings_file).layout, LayoutMode::Columns);
+    }
+
+    #[test]
+    fn test_format_and_truncated_path() {
+        assert_eq!(song_format(Path::new("/music/a.flac")), "FLAC");
+        assert_eq!(song_format(Path::new("/music/no_extension")), "Unknown");
+        assert_eq!(song_format(Path::new("https://radio.example/live.mp3")), "Stream");
+
+        assert_eq!(truncate_middle("/music/a.mp3", 23), "/music/a.mp3");
+        let truncated = truncate_middle("/home/alice/Music/Artist/Album/01 Song.mp3", 23);
+        assert_eq!(truncated, "/home/alice…01 Song.mp3");
+        assert_eq!(truncated.chars().count(), 23);
+    }
+
+    #[test]
+    fn test_reveal_in_file_manager() {
+        let command = file_manager_command(Path::new("/music/Album"));
+        if cfg!(target_os = "linux") {
+            assert_eq!(command.get_program(), "xdg-open");
+        }
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["/music/Album"]);
+
+        let mut myapp = MyApp::new();
+        myapp.reveal_selected_song();
+        assert_eq!(myapp.current_status(), None);
+
+        let id = myapp.add_stream(STREAMS_PLAYLIST, "Live", "https://radio.example/live");
+        myapp.selected_song_id = Some(id);
+        myapp.reveal_selected_song();
+        assert_eq!(myapp.current_status(), Some("Streams aren't in a folder"));
+    }
+
+    #[test]
+    fn test_rescan_library() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(temp_dir.path().to_path_buf());
+        wav_songs(temp_dir.path(), 2);
+        myapp.load_songs();
+        let playing_id = myapp.songs[0].id;
+        myapp.songs[0].is_playing = true;
+        myapp.currently_playing_song = Some(playing_id);
+        let stream_id = myapp.add_stream(STREAMS_PLAYLIST, "Live", "https://radio.example/live");
+
+        wav_songs(temp_dir.path(), 3);
+        myapp.start_rescan();
+        for _ in 0..100 {
+            myapp.update_rescan();
+            if myapp.rescan.is_none() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(myapp.songs.len(), 4);
+        assert_eq!(myapp.playlists[ALL_SONGS_PLAYLIST].len(), 3);
+        assert!(myapp.get_song(playing_id).unwrap().is_playing);
+        assert!(myapp.get_song(stream_id).is_some());
+        assert_eq!(myapp.scan_log.len(), 3);
+        assert_eq!(
+            myapp.current_status(),
+            Some("Library rescanned, 3 songs; 3 problems while scanning, Shift + F5 shows them")
+        );
+    }
+
+    #[test]
+    fn test_theme_styles() {
+        let theme = Theme::default();
+        assert_eq!(
+            theme.selected_style(false),
+            Style::default().fg(Color::LightBlue).add_modifier(Modifier::BOLD)
+        );
+        // Chosen songs are marked without blinking
+        assert_eq!(
+            theme.chosen_style(false),
+            Style::default().fg(Color::LightRed).add_modifier(Modifier::REVERSED)
+        );
+
+        let settings: Settings = serde_json::from_str(
+            r##"{"theme": {"chosen": {"fg": "#ff8800", "blink": true}}, "no_blink": true}"##,
+        )
+        .unwrap();
+        let chosen = settings.theme.chosen_style(settings.no_blink);
+        assert_eq!(chosen, Style::default().fg(Color::Rgb(255, 136, 0)));
+        assert!(settings
+            .theme
+            .chosen_style(false)
+            .add_modifier
+            .contains(Modifier::SLOW_BLINK));
+    }
+
+    #[test]
+    fn test_theme_presets_differ_by_more_than_color() {
+        for preset in [ThemePreset::Default, ThemePreset::HighContrast, ThemePreset::Colorblind] {
+            let theme = Theme {
+                preset,
+                ..Default::default()
+            };
+            let selected = theme.selected_style(true);
+            let chosen = theme.chosen_style(true);
+            assert_ne!(selected.add_modifier, chosen.add_modifier, "{:?}", preset);
+        }
+
+        let theme: Theme = serde_json::from_str(r#"{"preset": "high-contrast"}"#).unwrap();
+        assert_eq!(theme.preset, ThemePreset::HighContrast);
+        assert_eq!(theme.chosen_style(false).fg, Some(Color::Yellow));
+    }
+
+    #[test]
+    fn test_song_list_label() {
+        let song = &numbered_songs(1)[0];
+        assert_eq!(song_list_label(song, false), "Song 0");
+        assert_eq!(song_list_label(song, true), "▶ Song 0");
+    }
+
+    #[test]
+    fn test_remaining_time() {
+        assert_eq!(format_total_time(59.6), "1:00");
+        assert_eq!(format_total_time(3725.0), "1:02:05");
+
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(4);
+        for (song, duration) in myapp.songs.iter_mut().zip([100.0, 200.0, 0.0, 400.0]) {
+            song.duration = duration;
+        }
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp.filtered_songs = myapp.songs.clone();
+        assert_eq!(myapp.remaining_time(), None);
+
+        // The rest of the song list, a stream adding nothing
+        myapp.currently_playing_song = Some(ids[1]);
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(50));
+        let (left, count) = myapp.remaining_time().unwrap();
+        assert_eq!(count, 3);
+        assert!((left - 550.0).abs() < 1.0);
+
+        // The rest of the queue instead, once the song is reached
+        myapp.play_queue = vec![ids[1], ids[0]];
+        let (left, count) = myapp.remaining_time().unwrap();
+        assert_eq!(count, 2);
+        assert!((left - 250.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_format_count() {
+        assert_eq!(format_count(7, ","), "7");
+        assert_eq!(format_count(999, ","), "999");
+        assert_eq!(format_count(12345, ","), "12,345");
+        assert_eq!(format_count(1234567, "."), "1.234.567");
+        assert_eq!(format_count(1234567, ""), "1234567");
+
+        assert_eq!(locale_thousands_separator("de"), ".");
+        assert_eq!(locale_thousands_separator("fr"), "\u{a0}");
+        assert_eq!(locale_thousands_separator("en"), ",");
+
+        let mut myapp = MyApp::new();
+        myapp.settings.thousands_separator = Some("'".to_string());
+        assert_eq!(myapp.format_count(10000), "10'000");
+    }
+
+    #[test]
+    fn test_pinned_playlists() {
+        let mut myapp = MyApp::new();
+        for name in [ALL_SONGS_PLAYLIST, "Chill", "Rock", "Workout"] {
+            myapp.playlists.insert(name.to_string(), Vec::new());
+        }
+        let names = |myapp: &MyApp| -> Vec<String> {
+            myapp.playlist_names().into_iter().cloned().collect()
+        };
+
+        myapp.selected_playlist_index = 3;
+        myapp.toggle_pin_selected_playlist();
+        assert_eq!(names(&myapp), ["Workout", ALL_SONGS_PLAYLIST, "Chill", "Rock"]);
+        assert_eq!(myapp.selected_playlist_name().unwrap(), "Workout");
+
+        myapp.selected_playlist_index = 3;
+        myapp.toggle_pin_selected_playlist();
+        assert_eq!(names(&myapp), ["Workout", "Rock", ALL_SONGS_PLAYLIST, "Chill"]);
+        assert_eq!(myapp.selected_playlist_index, 1);
+
+        // Pinned playlists that no longer exist are skipped
+        myapp.playlists.remove("Workout");
+        assert_eq!(names(&myapp), ["Rock", ALL_SONGS_PLAYLIST, "Chill"]);
+
+        myapp.selected_playlist_index = 0;
+        myapp.toggle_pin_selected_playlist();
+        assert_eq!(names(&myapp), [ALL_SONGS_PLAYLIST, "Chill", "Rock"]);
+        assert_eq!(myapp.settings.pinned_playlists, ["Workout"]);
+    }
+
+    #[test]
+    fn test_search_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let history_path = temp_dir.path().join("search_history.json");
+        let mut myapp = MyApp::new();
+        myapp.search_history_file = Some(history_path.clone());
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code: KeyCode, modifiers: KeyModifiers| {
+            handle_key_event(myapp, KeyEvent::new(code, modifiers), &sink, 10, 10);
+        };
+        let search = |myapp: &mut MyApp, text: &str| {
+            for c in text.chars() {
+                press(myapp, KeyCode::Char(c), KeyModifiers::NONE);
+            }
+            press(myapp, KeyCode::Esc, KeyModifiers::NONE);
+            myapp.search_text.clear();
+            myapp.search_cursor = 0;
+        };
+
+        search(&mut myapp, "abba");
+        search(&mut myapp, "queen");
+        search(&mut myapp, "queen");
+        search(&mut myapp, "  ");
+        assert_eq!(myapp.search_history, ["abba", "queen"]);
+
+        press(&mut myapp, KeyCode::Char('b'), KeyModifiers::NONE);
+        press(&mut myapp, KeyCode::Up, KeyModifiers::CONTROL);
+        assert_eq!(myapp.search_text, "queen");
+        press(&mut myapp, KeyCode::Up, KeyModifiers::CONTROL);
+        press(&mut myapp, KeyCode::Up, KeyModifiers::CONTROL);
+        assert_eq!(myapp.search_text, "abba");
+        assert_eq!(myapp.search_cursor, 4);
+        press(&mut myapp, KeyCode::Down, KeyModifiers::CONTROL);
+        assert_eq!(myapp.search_text, "queen");
+        press(&mut myapp, KeyCode::Down, KeyModifiers::CONTROL);
+        assert_eq!(myapp.search_text, "b");
+
+        let mut reloaded = MyApp::new();
+        reloaded.load_search_history(&history_path).unwrap();
+        assert_eq!(reloaded.search_history, ["abba", "queen"]);
+
+        myapp.search_text.clear();
+        myapp.search_cursor = 0;
+        for i in 0..SEARCH_HISTORY_LIMIT {
+            search(&mut myapp, &i.to_string());
+        }
+        assert_eq!(myapp.search_history.len(), SEARCH_HISTORY_LIMIT);
+        assert_eq!(myapp.search_history[0], "0");
+    }
+
+    #[test]
+    fn test_export_tracks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let music_dir = temp_dir.path().join("music");
+        fs::create_dir_all(music_dir.join("other")).unwrap();
+        let track = |path: PathBuf, artist: &str| {
+            fs::write(&path, b"audio").unwrap();
+            ExportTrack {
+                path,
+                title: "Song".to_string(),
+                artist: artist.to_string(),
+                album_artist: artist.to_string(),
+                album: "Best/Of".to_string(),
+                duration: 61.6,
+            }
+        };
+        let tracks = vec![
+            track(music_dir.join("song.mp3"), "AC/DC"),
+            track(music_dir.join("other").join("song.mp3"), "Queen"),
+            ExportTrack {
+                path: music_dir.join("missing.mp3"),
+                title: "Gone".to_string(),
+                artist: String::new(),
+                album_artist: String::new(),
+                album: String::new(),
+                duration: 0.0,
+            },
+        ];
+
+        let flat_dir = temp_dir.path().join("flat");
+        let progress = Mutex::new(Vec::new());
+        let (copied, failed) = export_tracks(
+            &tracks,
+            &flat_dir,
+            ExportLayout::Flat,
+            Some("Mix.m3u"),
+            |done, total| progress.lock().unwrap().push((done, total)),
+        );
+        assert_eq!(copied, 2);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, music_dir.join("missing.mp3"));
+        assert_eq!(
+            progress.into_inner().unwrap(),
+            [(0, 3), (1, 3), (2, 3), (3, 3)]
+        );
+        assert!(flat_dir.join("song.mp3").exists());
+        assert!(flat_dir.join("song (2).mp3").exists());
+        assert_eq!(
+            fs::read_to_string(flat_dir.join("Mix.m3u")).unwrap(),
+            "#EXTM3U\n#EXTINF:62,AC/DC - Song\nsong.mp3\n#EXTINF:62,Queen - Song\nsong (2).mp3\n"
+        );
+
+        let nested_dir = temp_dir.path().join("nested");
+        let (copied, _) = export_tracks(
+            &tracks,
+            &nested_dir,
+            ExportLayout::ArtistAlbum,
+            None,
+            |_, _| {},
+        );
+        assert_eq!(copied, 2);
+        assert!(nested_dir
+            .join("AC_DC")
+            .join("Best_Of")
+            .join("song.mp3")
+            .exists());
+        assert!(nested_dir
+            .join("Queen")
+            .join("Best_Of")
+            .join("song.mp3")
+            .exists());
+        assert!(!nested_dir.join("Mix.m3u").exists());
+    }
+
+    #[test]
+    fn test_fade_out_restores_volume() {
+        let mut myapp = MyApp::new();
+        myapp.settings.fade_ms = Some(200);
+        let sink = draining_sink();
+        sink.lock().unwrap().set_volume(0.8);
+        sink.lock().unwrap().append(with_fade(
+            rodio::source::SineWave::new(440.0).convert_samples(),
+            myapp.fade.clone(),
+        ));
+
+        // The fade runs on the audio thread, pausing once it has ended
+        myapp.fade_out(&sink, FadeEnd::Pause);
+        assert!(myapp.pausing());
+        assert!(!sink.lock().unwrap().is_paused());
+        std::thread::sleep(Duration::from_millis(210));
+        myapp.update_fade(&sink);
+        assert!(!myapp.pausing());
+        assert!(sink.lock().unwrap().is_paused());
+        assert_eq!(myapp.fade.lock().unwrap().level(), 0.0);
+        assert_eq!(sink.lock().unwrap().volume(), 0.8);
+
+        // Resuming fades back in
+        myapp.fade_in();
+        sink.lock().unwrap().play();
+        std::thread::sleep(Duration::from_millis(210));
+        assert_eq!(myapp.fade.lock().unwrap().level(), 1.0);
+
+        myapp.settings.fade_ms = Some(0);
+        myapp.fade_out(&sink, FadeEnd::Stop);
+        assert!(sink.lock().unwrap().empty());
+        assert_eq!(myapp.fade.lock().unwrap().level(), 1.0);
+        assert_eq!(sink.lock().unwrap().volume(), 0.8);
+
+        myapp.settings.fade_ms = Some(60_000);
+        assert_eq!(myapp.fade_duration(), MAX_FADE);
+    }
+
+    #[test]
+    fn test_bookmarks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let bookmarks_path = temp_dir.path().join("bookmarks.json");
+        let mut myapp = MyApp::new();
+        myapp.bookmarks_file = Some(bookmarks_path.clone());
+        myapp.songs = numbered_songs(2);
+        myapp.filtered_songs = myapp.songs.clone();
+        let song_id = myapp.songs[0].id;
+        let path = myapp.songs[0].path.clone();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code: KeyCode| {
+            handle_key_event(
+                myapp,
+                KeyEvent::new(code, KeyModifiers::NONE),
+                &sink,
+                10,
+                10,
+            );
+        };
+
+        press(&mut myapp, KeyCode::F(8));
+        assert!(!myapp.input_popup.visible);
+
+        myapp.currently_playing_song = Some(song_id);
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(75));
+        press(&mut myapp, KeyCode::F(8));
+        assert!(myapp.input_popup.visible);
+        for c in "Chapter 2".chars() {
+            press(&mut myapp, KeyCode::Char(c));
+        }
+        press(&mut myapp, KeyCode::Enter);
+        assert!(!myapp.input_popup.visible);
+        assert!(myapp.playlists.is_empty());
+
+        myapp.add_bookmark(
+            path.clone(),
+            Bookmark {
+                label: "Intro".to_string(),
+                position: 5.0,
+            },
+        );
+        let labels = |myapp: &MyApp| -> Vec<String> {
+            myapp
+                .listed_bookmarks()
+                .iter()
+                .map(|bookmark| bookmark.label.clone())
+                .collect()
+        };
+        assert_eq!(labels(&myapp), ["Intro", "Chapter 2"]);
+        assert_eq!(myapp.listed_bookmarks()[1].position.round(), 75.0);
+
+        let mut reloaded = MyApp::new();
+        reloaded.load_bookmarks(&bookmarks_path).unwrap();
+        assert_eq!(reloaded.bookmarks, myapp.bookmarks);
+
+        press(&mut myapp, KeyCode::F(9));
+        press(&mut myapp, KeyCode::Down);
+        press(&mut myapp, KeyCode::Delete);
+        assert_eq!(labels(&myapp), ["Intro"]);
+        assert_eq!(myapp.bookmark_selected, 0);
+        press(&mut myapp, KeyCode::Delete);
+        assert!(myapp.bookmarks.is_empty());
+    }
+
+    #[test]
+    fn test_remember_position_of_long_songs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let positions_path = temp_dir.path().join("positions.json");
+        let mut myapp = MyApp::new();
+        myapp.resume_positions_file = Some(positions_path.clone());
+        myapp.settings.resume_after_minutes = Some(1);
+        myapp.songs = numbered_songs(2);
+        myapp.songs[1].duration = 30.0;
+        let play = |myapp: &mut MyApp, index: usize, elapsed: u64| {
+            myapp.songs[index].is_playing = true;
+            myapp.currently_playing_song = Some(myapp.songs[index].id);
+            myapp.song_time = Some(Instant::now() - Duration::from_secs(elapsed));
+            myapp.remember_position();
+        };
+        let long_path = myapp.songs[0].path.clone();
+
+        play(&mut myapp, 0, 40);
+        assert_eq!(myapp.resume_positions[&long_path].round(), 40.0);
+        let mut reloaded = MyApp::new();
+        reloaded.load_resume_positions(&positions_path).unwrap();
+        assert_eq!(reloaded.resume_positions, myapp.resume_positions);
+
+        // Songs shorter than the threshold aren't remembered
+        play(&mut myapp, 1, 20);
+        assert_eq!(myapp.resume_positions.len(), 1);
+
+        // Near the end, or after finishing, the position is forgotten
+        play(&mut myapp, 0, 95);
+        assert!(myapp.resume_positions.is_empty());
+        play(&mut myapp, 0, 40);
+        myapp.forget_position(myapp.songs[0].id);
+        assert!(myapp.resume_positions.is_empty());
+    }
+
+    #[test]
+    fn test_skip_unplayable_songs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut songs = wav_songs(temp_dir.path(), 2);
+        let broken_songs: Vec<Song> = (0..2)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("broken{}.wav", i));
+                fs::write(&path, b"not audio").unwrap();
+                Song::new(
+                    format!("Broken {}", i),
+                    String::new(),
+                    None,
+                    path,
+                    String::new(),
+                    100.0,
+                )
+            })
+            .collect();
+        songs.splice(1..1, broken_songs.clone());
+        let mut myapp = MyApp::new();
+        myapp.songs = songs.clone();
+        myapp.filtered_songs = songs.clone();
+        let sink = draining_sink();
+
+        myapp.start_song(songs[0].id, &sink);
+        myapp.finish_song(songs[0].id, &sink);
+        assert_eq!(myapp.currently_playing_song, Some(songs[3].id));
+        assert_eq!(
+            myapp.current_status(),
+            Some("Skipped 2 songs that couldn't be played")
+        );
+
+        myapp.start_song(broken_songs[0].id, &sink);
+        assert_eq!(myapp.currently_playing_song, None);
+
+        // With nothing playable, playback stops instead of looping
+        myapp.filtered_songs = broken_songs.clone();
+        myapp.songs = broken_songs.clone();
+        myapp.currently_playing_song = Some(broken_songs[0].id);
+        myapp.finish_song(broken_songs[0].id, &sink);
+        assert_eq!(myapp.currently_playing_song, None);
+        assert_eq!(
+            myapp.current_status(),
+            Some("Stopped after 10 songs in a row couldn't be played")
+        );
+        sink.lock().unwrap().clear();
+    }
 
-    f.render_widget(input_text, inner_area);
+    #[test]
+    fn test_played_threshold() {
+        let threshold = PlayedThreshold::default();
+        assert!(!threshold.reached(59.0, 120.0));
+        assert!(threshold.reached(60.0, 120.0));
+        // Long mixes count after four minutes
+        assert!(threshold.reached(240.0, 3600.0));
+        assert!(!threshold.reached(239.0, 3600.0));
+        // Streams have no duration, so only the time counts
+        assert!(!threshold.reached(100.0, 0.0));
+
+        let percent_only: PlayedThreshold = serde_json::from_str(r#"{"seconds": null}"#).unwrap();
+        assert_eq!(percent_only.percent, Some(50.0));
+        assert!(!percent_only.reached(240.0, 3600.0));
+        assert!(percent_only.reached(1800.0, 3600.0));
+    }
 
-    Ok(())
-}
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_follows_symlinks_without_cycles() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let music_dir = temp_dir.path().join("music");
+        let album_dir = temp_dir.path().join("elsewhere").join("album");
+        fs::create_dir_all(&music_dir).unwrap();
+        fs::create_dir_all(&album_dir).unwrap();
+        wav_songs(&music_dir, 1);
+        wav_songs(&album_dir, 2);
+        std::os::unix::fs::symlink(&album_dir, music_dir.join("album")).unwrap();
+        std::os::unix::fs::symlink(&album_dir, music_dir.join("same album")).unwrap();
+        std::os::unix::fs::symlink(&music_dir, music_dir.join("loop")).unwrap();
+        std::os::unix::fs::symlink("..", album_dir.join("up")).unwrap();
+        let real_dir = music_dir.join("real");
+        fs::create_dir(&real_dir).unwrap();
+        wav_songs(&real_dir, 1);
+        std::os::unix::fs::symlink(&real_dir, music_dir.join("a link")).unwrap();
+
+        let mut song_paths = Vec::new();
+        collect_music_paths(
+            &music_dir,
+            &mut HashSet::new(),
+            &mut song_paths,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        // The linked album is scanned once, through its first link by name, and a folder that
+        // is also linked to is scanned through its own path
+        let names: Vec<String> = song_paths
+            .iter()
+            .map(|path| path.strip_prefix(&music_dir).unwrap().display().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "real/song0.wav",
+                "song0.wav",
+                "album/song0.wav",
+                "album/song1.wav"
+            ]
+        );
+    }
 
-fn sort_songs(songs: &mut Vec<Song>, criteria: &SortCriteria) {
-    match criteria {
-        SortCriteria::Title => {
-            songs.sort_by(|a, b| a.title.to_lowercase().cmp(&b.title.to_lowercase()));
+    #[test]
+    fn test_autosave_playlists() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let playlist_file = temp_dir.path().join("data.json");
+        let mut myapp = MyApp::new();
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+
+        myapp
+            .playlists
+            .insert("Mine".to_string(), vec![Uuid::nil()]);
+        myapp.playlists_unsaved = true;
+        myapp.autosave_playlists();
+        assert!(!playlist_file.exists());
+        myapp.playlists_changed_at = Some(Instant::now() - PLAYLIST_SAVE_DELAY);
+        myapp.autosave_playlists();
+        assert!(fs::read_to_string(&playlist_file).unwrap().contains("Mine"));
+        assert!(!myapp.playlists_unsaved);
+
+        // Quitting without changes leaves the file alone
+        fs::write(&playlist_file, "{}").unwrap();
+        assert!(myapp.save_and_quit());
+        assert_eq!(fs::read_to_string(&playlist_file).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_save_on_shutdown() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let playlist_file = temp_dir.path().join("data.json");
+        let mut myapp = MyApp::new();
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp.play_queue_file = Some(temp_dir.path().join("queue.json"));
+        myapp.save_on_shutdown();
+        assert!(!playlist_file.exists());
+        assert_eq!(myapp.quit_message, None);
+
+        // Playlists changed by another program are saved over without asking, keeping a backup
+        myapp.playlists.insert("Mine".to_string(), Vec::new());
+        myapp.playlists_unsaved = true;
+        fs::write(&playlist_file, r#"{"Theirs": []}"#).unwrap();
+        myapp.save_on_shutdown();
+        assert!(fs::read_to_string(&playlist_file).unwrap().contains("Mine"));
+        assert!(temp_dir.path().join("data.json.bak").exists());
+        assert_eq!(
+            myapp.quit_message.as_deref(),
+            Some("Saved 1 playlists, 0 songs")
+        );
+    }
+
+    #[test]
+    fn test_scan_log_is_capped() {
+        let mut scan_log = Vec::new();
+        for i in 0..SCAN_LOG_LIMIT + 5 {
+            log_scan_problem(&mut scan_log, format!("Problem {}", i));
         }
-        SortCriteria::Artist => {
-            songs.sort_by(|a, b| a.artist.to_lowercase().cmp(&b.artist.to_lowercase()));
+        assert_eq!(scan_log.len(), SCAN_LOG_LIMIT + 1);
+        assert_eq!(scan_log.last().unwrap(), SCAN_LOG_TRUNCATED);
+
+        let mut myapp = MyApp::new();
+        myapp.settings.thousands_separator = Some(",".to_string());
+        myapp.set_scan_log(scan_log);
+        assert_eq!(
+            myapp.current_status(),
+            Some("1,000 problems while scanning, Shift + F5 shows them")
+        );
+    }
+    #[test]
+    fn test_missing_title_from_filename() {
+        let path = Path::new("/music/03 - Some_Song.flac");
+        assert_eq!(MissingTitle::NoTitle.title_for(path), None);
+        assert_eq!(
+            MissingTitle::Filename.title_for(path).unwrap(),
+            "03 - Some Song"
+        );
+        let without_number = |name: &str| {
+            MissingTitle::FilenameWithoutNumber
+                .title_for(Path::new(name))
+                .unwrap()
+        };
+        assert_eq!(without_number("03 - Some_Song.flac"), "Some Song");
+        assert_eq!(without_number("7. Intro.mp3"), "Intro");
+        assert_eq!(without_number("01_Track.ogg"), "Track");
+        assert_eq!(without_number("1999.mp3"), "1999");
+        assert_eq!(without_number("04 Outro.mp3"), "Outro");
+        assert_eq!(without_number("99 Luftballons.mp3"), "99 Luftballons");
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let untitled_path = temp_dir.path().join("02_Untitled_Song.mp3");
+        File::create(&untitled_path).unwrap();
+        let mut tag = id3::Tag::new();
+        id3::TagLike::set_artist(&mut tag, "Someone");
+        tag.write_to_path(&untitled_path, id3::Version::Id3v24)
+            .unwrap();
+        let scan = |missing_title| {
+            scan_dir_for_music(temp_dir.path(), missing_title, true, &mut Vec::new())
+                .unwrap()
+                .remove(0)
+        };
+        assert_eq!(scan(MissingTitle::NoTitle).title, "No Title");
+        let song = scan(MissingTitle::FilenameWithoutNumber);
+        assert_eq!(song.title, "Untitled Song");
+        assert_eq!(song.title_lower, "untitled song");
+    }
+    #[test]
+    fn test_play_queue_is_restored() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let queue_path = temp_dir.path().join("queue.json");
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(4);
+        myapp.playlists.insert(
+            "Mix".to_string(),
+            myapp.songs.iter().map(|song| song.id).collect(),
+        );
+        myapp.play_queue = vec![myapp.songs[2].id, myapp.songs[3].id, myapp.songs[0].id];
+        myapp.queue_source = Some("Mix".to_string());
+        myapp.play_queue_file = Some(queue_path.clone());
+        myapp.save_play_queue();
+
+        // The restored library no longer has the last song of the queue
+        let mut restored = MyApp::new();
+        restored.songs = numbered_songs(4);
+        restored.songs.remove(0);
+        restored.playlists.insert("Mix".to_string(), Vec::new());
+        restored.load_play_queue(&queue_path).unwrap();
+        assert_eq!(
+            restored.play_queue,
+            [restored.songs[1].id, restored.songs[2].id]
+        );
+        assert_eq!(restored.queue_source.as_deref(), Some("Mix"));
+        assert_eq!(restored.selected_song_id, Some(restored.songs[1].id));
+        assert_eq!(
+            restored.current_status(),
+            Some("Restored the play queue (2 songs)")
+        );
+
+        // Playing the song the queue was left at keeps the queue
+        restored.filter_songs();
+        let sink = draining_sink();
+        restored.toggle_play(&sink);
+        assert_eq!(restored.play_queue.len(), 2);
+    }
+    #[test]
+    fn test_sort_by_album_keeps_compilations_together() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for (file, artist, album_artist) in [
+            ("01.mp3", "Zed", Some("Various Artists")),
+            ("02.mp3", "Abba", Some("Various Artists")),
+            ("solo.mp3", "Moby", None),
+        ] {
+            let mut tag = id3::Tag::new();
+            id3::TagLike::set_artist(&mut tag, artist);
+            id3::TagLike::set_album(
+                &mut tag,
+                if album_artist.is_some() {
+                    "Hits"
+                } else {
+                    "Play"
+                },
+            );
+            if let Some(album_artist) = album_artist {
+                id3::TagLike::set_album_artist(&mut tag, album_artist);
+            }
+            let path = temp_dir.path().join(file);
+            File::create(&path).unwrap();
+            tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
         }
-        SortCriteria::Duration => {
-            songs.sort_by(|a, b| {
-                a.duration
-                    .partial_cmp(&b.duration)
-                    .unwrap_or(std::cmp::Ordering::Equal)
-            });
+        let mut songs = scan_dir_for_music(
+            temp_dir.path(),
+            MissingTitle::NoTitle,
+            true,
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        sort_songs(&mut songs, &SortCriteria::Album, &[]);
+        let artists: Vec<&str> = songs.iter().map(|song| song.artist.as_str()).collect();
+        assert_eq!(artists, ["Moby", "Zed", "Abba"]);
+        assert_eq!(songs[1].grouping_artist(), "Various Artists");
+        assert_eq!(songs[0].grouping_artist(), "Moby");
+    }
+    #[test]
+    fn test_previous_restarts_a_song_in_progress() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 2);
+        for song in &mut myapp.songs {
+            song.duration = 100.0;
         }
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.filter_songs();
+        let sink = draining_sink();
+        myapp.start_song(ids[1], &sink);
+
+        // Past the threshold the song starts over
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(10));
+        myapp.play_previous(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
+        assert!(myapp.elapsed_time(100.0) < 1.0);
+
+        // Right after that it goes back a song
+        myapp.play_previous(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[0]));
+
+        // 0 always goes back
+        myapp.start_song(ids[1], &sink);
+        myapp.settings.restart_after_seconds = Some(0.0);
+        myapp.song_time = Some(Instant::now() - Duration::from_secs(10));
+        myapp.play_previous(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[0]));
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::File;
-    use std::io::Write;
+    #[test]
+    fn test_no_audio_device() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(1);
+        myapp.audio_error = Some("NoDevice".to_string());
+        let sink = draining_sink();
+        myapp.start_song(myapp.songs[0].id, &sink);
+        assert_eq!(myapp.currently_playing_song, None);
+        assert!(sink.lock().unwrap().empty());
+        assert_eq!(
+            myapp.current_status(),
+            Some("No audio output device, can't play anything: NoDevice")
+        );
+    }
+    #[test]
+    fn test_search_ignores_accents() {
+        assert_eq!(fold_case_and_accents("Björk"), "bjork");
+        assert_eq!(fold_case_and_accents("Beyonce\u{301}"), "beyonce");
+        assert_eq!(fold_case_and_accents("Café Tacvba"), "cafe tacvba");
+
+        let mut myapp = MyApp::new();
+        myapp.songs = vec![
+            Song::new(
+                "Jóga".to_string(),
+                "Björk".to_string(),
+                None,
+                PathBuf::from("/music/joga.flac"),
+                "Homogenic".to_string(),
+                300.0,
+            ),
+            numbered_songs(1).remove(0),
+        ];
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.search_criteria = SearchCriteria::Artist;
+        myapp.search_text = "BJORK".to_string();
+        myapp.filter_songs();
+        assert_eq!(myapp.filtered_songs.len(), 1);
+        assert_eq!(myapp.filtered_songs[0].id, ids[0]);
+
+        // Typing the accents still matches
+        myapp.search_criteria = SearchCriteria::Title;
+        myapp.search_text = "jóga".to_string();
+        myapp.filter_songs();
+        assert_eq!(myapp.filtered_songs.len(), 1);
+    }
+    #[test]
+    fn test_edit_song_tags() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("song.mp3");
+        File::create(&path).unwrap();
+        let mut tag = id3::Tag::new();
+        id3::TagLike::set_title(&mut tag, "Old");
+        id3::TagLike::set_artist(&mut tag, "Artist");
+        tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(temp_dir.path().to_path_buf());
+        myapp.load_songs();
+        let id = myapp.songs[0].id;
+        myapp.selected_song_id = Some(id);
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code| {
+            handle_key_event(
+                myapp,
+                KeyEvent::new(code, KeyModifiers::NONE),
+                &sink,
+                10,
+                10,
+            );
+        };
 
+        press(&mut myapp, KeyCode::F(10));
+        assert_eq!(myapp.input_text, "Old");
+        myapp.input_text = "Nü Title".to_string();
+        press(&mut myapp, KeyCode::Enter);
+        assert_eq!(myapp.input_text, "Artist");
+        press(&mut myapp, KeyCode::Enter);
+        myapp.input_text = "Album".to_string();
+        press(&mut myapp, KeyCode::Enter);
+        assert!(!myapp.input_popup.visible);
+
+        let song = myapp.get_song(id).unwrap();
+        assert_eq!(song.title, "Nü Title");
+        assert_eq!(song.title_lower, "nu title");
+        assert_eq!(song.album, "Album");
+        let reread = read_song(&path, true).unwrap();
+        assert_eq!(reread.title, "Nü Title");
+        assert_eq!(reread.artist, "Artist");
+        assert_eq!(reread.album, "Album");
+        assert_eq!(reread.id, id);
+
+        // Files whose tags can't be written are left alone
+        let wav_song = wav_songs(temp_dir.path(), 1).remove(0);
+        let wav_id = wav_song.id;
+        myapp.songs.push(wav_song);
+        let tags = SongTags {
+            title: Some("New".to_string()),
+            ..SongTags::default()
+        };
+        myapp.save_song_tags(&[wav_id], tags);
+        assert_eq!(myapp.get_song(wav_id).unwrap().title, "Song 0");
+        assert!(myapp
+            .current_status()
+            .unwrap()
+            .starts_with("Couldn't save the tags of Song 0"));
+    }
     #[test]
-    fn test_song_creation() {
-        let title = String::from("Test Song");
-        let artist = String::from("Test Artist");
-        let path = PathBuf::from("/path/to/test/song.mp3");
-        let album = String::from("Test Album");
-        let duration = 180.0;
+    fn test_edit_tags_of_chosen_songs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for (file, artist) in [("a.mp3", "One"), ("b.mp3", "Two")] {
+            let path = temp_dir.path().join(file);
+            File::create(&path).unwrap();
+            let mut tag = id3::Tag::new();
+            id3::TagLike::set_title(&mut tag, file);
+            id3::TagLike::set_artist(&mut tag, artist);
+            tag.write_to_path(&path, id3::Version::Id3v24).unwrap();
+        }
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(temp_dir.path().to_path_buf());
+        myapp.load_songs();
+        myapp.songs.extend(wav_songs(temp_dir.path(), 1));
+        myapp.chosen_song_ids = myapp.songs.iter().map(|song| song.id).collect();
+
+        myapp.start_tag_edit();
+        assert_eq!(myapp.input_text, "");
+        // The artists are kept, the album is set for all of them
+        myapp.submit_input();
+        myapp.input_text = "Loose Tracks".to_string();
+        myapp.submit_input();
+
+        assert_eq!(myapp.songs.len(), 3);
+        let albums: Vec<&str> = myapp.songs.iter().map(|song| song.album.as_str()).collect();
+        assert_eq!(albums, ["Loose Tracks", "Loose Tracks", "Album"]);
+        let reread = read_song(&temp_dir.path().join("b.mp3"), true).unwrap();
+        assert_eq!(reread.artist, "Two");
+        assert_eq!(reread.album, "Loose Tracks");
+        assert!(myapp
+            .current_status()
+            .unwrap()
+            .starts_with("Saved the tags of 2 songs, 1 failed (Song 0: "));
+    }
+    #[test]
+    fn test_edit_tags_of_untagged_songs() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("loose.mp3");
+        File::create(&path).unwrap();
+        let mut myapp = MyApp::new();
         let song = Song::new(
-            title.clone(),
-            artist.clone(),
+            "loose".to_string(),
+            "Unknown Artist".to_string(),
             None,
             path.clone(),
-            album.clone(),
-            duration,
+            "None".to_string(),
+            0.0,
+        );
+        let id = song.id;
+        myapp.songs.push(song);
+        myapp.chosen_song_ids.push(id);
+
+        myapp.start_tag_edit();
+        myapp.submit_input();
+        myapp.input_text = "Loose Tracks".to_string();
+        myapp.submit_input();
+
+        // Only the album is written, the names shown in place of the other tags aren't
+        let tag = id3::Tag::read_from_path(&path).unwrap();
+        assert_eq!(id3::TagLike::album(&tag), Some("Loose Tracks"));
+        assert_eq!(id3::TagLike::title(&tag), None);
+        assert_eq!(id3::TagLike::artist(&tag), None);
+        let song = myapp.get_song(id).unwrap();
+        assert_eq!(song.album, "Loose Tracks");
+        assert_eq!(song.artist, "Unknown Artist");
+
+        // Editing the song alone starts from its real tags and keeps unchanged ones as they are
+        myapp.chosen_song_ids.clear();
+        myapp.selected_song_id = Some(id);
+        myapp.start_tag_edit();
+        assert_eq!(myapp.input_text, "");
+        myapp.submit_input();
+        assert_eq!(myapp.input_text, "");
+        myapp.submit_input();
+        assert_eq!(myapp.input_text, "Loose Tracks");
+        myapp.submit_input();
+        assert_eq!(myapp.current_status(), Some("No tags changed"));
+        let tag = id3::Tag::read_from_path(&path).unwrap();
+        assert_eq!(id3::TagLike::title(&tag), None);
+        assert_eq!(id3::TagLike::artist(&tag), None);
+    }
+    #[test]
+    fn test_songs_loading_in_the_background() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 3);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.filter_songs();
+        let sink = draining_sink();
+
+        // A song that opens quickly starts right away
+        myapp.start_song(ids[0], &sink);
+        assert!(myapp.loading.is_none());
+        assert!(myapp.song_time.is_some());
+
+        // A slow one shows as loading, without being taken for finished
+        let (sender, receiver) = mpsc::channel();
+        myapp.stop_playback(&sink);
+        myapp.currently_playing_song = Some(ids[1]);
+        myapp.find_song_by_id(ids[1]).unwrap().is_playing = true;
+        myapp.loading = Some(LoadingSong {
+            id: ids[1],
+            started: Instant::now(),
+            receiver,
+            start_at: None,
+        });
+        myapp.update_playback(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
+        assert!(myapp.loading_indicator().unwrap().ends_with("Loading…"));
+        assert_eq!(myapp.song_time, None);
+
+        sender
+            .send(myapp.songs[1].open(Duration::ZERO, false, myapp.balance.clone()))
+            .unwrap();
+        myapp.update_playback(&sink);
+        assert!(myapp.loading.is_none());
+        assert!(myapp.song_time.is_some());
+        assert!(!sink.lock().unwrap().empty());
+
+        // A bookmark jumped to while the song is loading is where it starts
+        let (sender, receiver) = mpsc::channel();
+        myapp.loading = Some(LoadingSong {
+            id: ids[1],
+            started: Instant::now(),
+            receiver,
+            start_at: None,
+        });
+        let bookmark = Bookmark {
+            label: "Middle".to_string(),
+            position: 0.05,
+        };
+        let path = myapp.songs[1].path.clone();
+        myapp.bookmarks.insert(path, vec![bookmark]);
+        myapp.jump_to_bookmark(&sink);
+        assert_eq!(myapp.loading.as_ref().unwrap().start_at, Some(0.05));
+        sender
+            .send(myapp.songs[1].open(Duration::ZERO, false, myapp.balance.clone()))
+            .unwrap();
+        myapp.update_playback(&sink);
+        assert!(myapp.loading.is_none());
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
+        let position = match &myapp.seeking {
+            Some(seek) => seek.target,
+            None => myapp.elapsed_time(0.1),
+        };
+        assert!(position > 0.04);
+
+        // One that fails to open is skipped
+        let (sender, receiver) = mpsc::channel();
+        myapp.loading = Some(LoadingSong {
+            id: ids[1],
+            started: Instant::now(),
+            receiver,
+            start_at: None,
+        });
+        sender.send(Err(io::Error::other("broken"))).unwrap();
+        myapp.update_playback(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[2]));
+        assert_eq!(
+            myapp.current_status(),
+            Some("Skipped 1 songs that couldn't be played")
         );
+    }
+    #[test]
+    fn test_mono_downmix() {
+        let stereo = || rodio::buffer::SamplesBuffer::new(2, 44100, vec![100i16, 300, -50, 50]);
+        let mono = Downmix::new(stereo(), true);
+        assert_eq!(mono.channels(), 2);
+        assert_eq!(mono.collect::<Vec<i16>>(), vec![200, 200, 0, 0]);
+        let unchanged: Vec<i16> = Downmix::new(stereo(), false).collect();
+        assert_eq!(unchanged, vec![100, 300, -50, 50]);
+
+        // Switching while playing keeps the song going and is saved
+        let temp_dir = tempfile::tempdir().unwrap();
+        wav_songs(temp_dir.path(), 1);
+        let settings_path = temp_dir.path().join("settings.json");
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(temp_dir.path().to_path_buf());
+        myapp.settings_file = Some(settings_path.clone());
+        myapp.load_songs();
+        let song_id = myapp.songs[0].id;
+        let sink = draining_sink();
+        myapp.start_song(song_id, &sink);
+        sink.lock().unwrap().pause();
+
+        let key = KeyEvent::new(KeyCode::Char('m'), KeyModifiers::ALT);
+        handle_key_event(&mut myapp, key, &sink, 10, 10);
+        assert!(myapp.settings.mono);
+        assert!(Settings::load(&settings_path).mono);
+        assert_eq!(myapp.current_status(), Some("Mono"));
+        assert_eq!(myapp.currently_playing_song, Some(song_id));
+        assert!(!sink.lock().unwrap().empty());
+
+        handle_key_event(&mut myapp, key, &sink, 10, 10);
+        assert!(!Settings::load(&settings_path).mono);
+        assert_eq!(myapp.current_status(), Some("Stereo"));
+    }
 
-        assert_eq!(song.title, title);
-        assert_eq!(song.artist, artist);
-        assert_eq!(song.path, path);
-        assert_eq!(song.album, album);
-        assert_eq!(song.duration, duration);
-        assert_eq!(song.is_playing, false);
-        assert_ne!(song.id, Uuid::nil());
+    #[test]
+    fn test_balance() {
+        let stereo = rodio::buffer::SamplesBuffer::new(2, 44100, vec![100i16, 100, 100, 100]);
+        let balance = Arc::new(Mutex::new(-0.25));
+        let samples: Vec<i16> = with_balance(stereo, balance).collect();
+        assert_eq!(samples, vec![100, 75, 100, 75]);
+        let mono = rodio::buffer::SamplesBuffer::new(1, 44100, vec![100i16, 100]);
+        let samples: Vec<i16> = with_balance(mono, Arc::new(Mutex::new(1.0))).collect();
+        assert_eq!(samples, vec![100, 100]);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let settings_path = temp_dir.path().join("settings.json");
+        let mut myapp = MyApp::new();
+        myapp.settings_file = Some(settings_path.clone());
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code, modifiers| {
+            handle_key_event(myapp, KeyEvent::new(code, modifiers), &sink, 10, 10);
+        };
+        let control_shift = KeyModifiers::CONTROL | KeyModifiers::SHIFT;
+
+        press(&mut myapp, KeyCode::Right, control_shift);
+        press(&mut myapp, KeyCode::Right, control_shift);
+        assert_eq!(myapp.balance_label().as_deref(), Some("R20"));
+        assert_eq!(*myapp.balance.lock().unwrap(), 0.2);
+        assert_eq!(Settings::load(&settings_path).balance, 20);
+        for _ in 0..15 {
+            press(&mut myapp, KeyCode::Left, control_shift);
+        }
+        assert_eq!(myapp.settings.balance, -100);
+        assert_eq!(myapp.current_status(), Some("Balance: L100"));
+        // The volume is left alone
+        assert_eq!(sink.lock().unwrap().volume(), 1.0);
+
+        press(&mut myapp, KeyCode::Char('b'), KeyModifiers::ALT);
+        assert_eq!(myapp.balance_label(), None);
+        assert_eq!(Settings::load(&settings_path).balance, 0);
+        assert_eq!(myapp.current_status(), Some("Balance: centered"));
+
+        // A balance out of range in the settings file is clamped
+        fs::write(&settings_path, r#"{"balance": 2147483647}"#).unwrap();
+        myapp.settings = Settings::load(&settings_path);
+        assert_eq!(myapp.balance_label().as_deref(), Some("R100"));
+        press(&mut myapp, KeyCode::Right, control_shift);
+        assert_eq!(myapp.settings.balance, 100);
     }
 
     #[test]
-    fn test_scan_folder_for_music() {
+    fn test_volume_and_mute_before_playing() {
         let temp_dir = tempfile::tempdir().unwrap();
-        let temp_path = temp_dir.path();
+        let settings_path = temp_dir.path().join("settings.json");
+        let mut myapp = MyApp::new();
+        myapp.settings_file = Some(settings_path.clone());
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, code| {
+            handle_key_event(
+                myapp,
+                KeyEvent::new(code, KeyModifiers::CONTROL),
+                &sink,
+                10,
+                10,
+            );
+        };
+        let volume = || (sink.lock().unwrap().volume() * 100.0).round() as u32;
+
+        // Muting before anything played brings the full volume back
+        press(&mut myapp, KeyCode::Char('m'));
+        assert!(myapp.muted);
+        assert_eq!(volume(), 0);
+        press(&mut myapp, KeyCode::Char('m'));
+        assert!(!myapp.muted);
+        assert_eq!(volume(), 100);
+
+        press(&mut myapp, KeyCode::Left);
+        press(&mut myapp, KeyCode::Left);
+        assert_eq!(volume(), 90);
+        assert_eq!(Settings::load(&settings_path).volume, Some(90));
+
+        // Changing the volume while muted unmutes from the saved volume
+        press(&mut myapp, KeyCode::Char('m'));
+        press(&mut myapp, KeyCode::Right);
+        assert!(!myapp.muted);
+        assert_eq!(volume(), 95);
+
+        for _ in 0..25 {
+            press(&mut myapp, KeyCode::Right);
+        }
+        assert_eq!(myapp.volume_percent(), 100);
+        for _ in 0..25 {
+            press(&mut myapp, KeyCode::Left);
+        }
+        assert_eq!(volume(), 0);
+    }
+    #[test]
+    fn test_clear_search_and_delete_words() {
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let press = |myapp: &mut MyApp, c| {
+            handle_key_event(
+                myapp,
+                KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL),
+                &sink,
+                10,
+                10,
+            );
+        };
+        myapp.set_focus(Focus::Search);
+        myapp.paste("daft punk around");
+        myapp.move_search_cursor(9);
+
+        press(&mut myapp, 'w');
+        assert_eq!(myapp.search_text, "daft  around");
+        assert_eq!(myapp.search_cursor, 5);
+        press(&mut myapp, 'w');
+        assert_eq!(myapp.search_text, " around");
+        assert_eq!(myapp.search_cursor, 0);
+        press(&mut myapp, 'w');
+        assert_eq!(myapp.search_text, " around");
+
+        // Words are only deleted from the focused search bar
+        myapp.set_focus(Focus::SongList);
+        myapp.move_search_cursor(7);
+        press(&mut myapp, 'w');
+        assert_eq!(myapp.search_text, " around");
+
+        // A popup that isn't typed into keeps the search
+        myapp.queue_popup.visible = true;
+        press(&mut myapp, 'u');
+        assert_eq!(myapp.search_text, " around");
+        myapp.queue_popup.visible = false;
+        press(&mut myapp, 'u');
+        assert_eq!(myapp.search_text, "");
+        assert_eq!(myapp.search_cursor, 0);
+
+        // The input popup edits its own text
+        myapp.search_text = "kept".to_string();
+        myapp.input_popup.visible = true;
+        myapp.input_text = "Road trip mix".to_string();
+        press(&mut myapp, 'w');
+        assert_eq!(myapp.input_text, "Road trip ");
+        press(&mut myapp, 'u');
+        assert_eq!(myapp.input_text, "");
+        assert_eq!(myapp.search_text, "kept");
+    }
+    #[test]
+    fn test_select_queue_source() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        wav_songs(temp_dir.path(), 3);
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(temp_dir.path().to_path_buf());
+        myapp.load_songs();
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert("Mix".to_string(), vec![ids[2], ids[1]]);
+        let sink = draining_sink();
+        let jump = |myapp: &mut MyApp| {
+            let key = KeyEvent::new(KeyCode::F(12), KeyModifiers::NONE);
+            handle_key_event(myapp, key, &sink, 10, 10);
+        };
 
-        // Create a mock MP3 file
-        let file_path = temp_path.join("test.mp3");
-        let mut file = File::create(&file_path).unwrap();
-        file.write_all(b"dummy content").unwrap();
+        jump(&mut myapp);
+        assert_eq!(
+            myapp.current_status(),
+            Some("The play queue wasn't started from a playlist")
+        );
 
-        // Create a mock FLAC file
-        let file_path_flac = temp_path.join("test.flac");
-        let mut file_flac = File::create(&file_path_flac).unwrap();
-        file_flac.write_all(b"dummy content").unwrap();
+        // Playing "Mix", then browsing elsewhere
+        myapp.selected_playlist_index = 1;
+        myapp.play_selected_playlist(&sink);
+        sink.lock().unwrap().pause();
+        myapp.selected_playlist_index = 0;
+        myapp.selected_song_id = Some(ids[0]);
+        jump(&mut myapp);
+        assert_eq!(myapp.selected_playlist_name().unwrap(), "Mix");
+        assert_eq!(myapp.selected_song_id, Some(ids[2]));
+
+        myapp.playlists.remove("Mix");
+        myapp.selected_playlist_index = 0;
+        jump(&mut myapp);
+        assert_eq!(myapp.selected_playlist_index, 0);
+        assert_eq!(
+            myapp.current_status(),
+            Some("Playlist \"Mix\" doesn't exist anymore")
+        );
+    }
 
-        // Simulate the function behavior
-        let mut songs = Vec::new();
+    #[test]
+    fn test_toggle_all_songs() {
+        let mut myapp = MyApp::new();
+        myapp.songs = numbered_songs(3);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp
+            .playlists
+            .insert("Mix".to_string(), vec![ids[2], ids[1]]);
+        myapp.playlists.insert("Other".to_string(), vec![ids[0]]);
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let toggle = |myapp: &mut MyApp| {
+            let key = KeyEvent::new(KeyCode::Char('n'), KeyModifiers::CONTROL);
+            handle_key_event(myapp, key, &sink, 10, 10);
+            myapp.filter_songs();
+            myapp.sync_selection(10);
+        };
 
-        for entry in fs::read_dir(temp_path).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
+        myapp.selected_playlist_index = 0;
+        toggle(&mut myapp);
+        assert_eq!(myapp.current_status(), Some("No other playlist viewed yet"));
+
+        // Viewing "Mix" and picking a song there
+        myapp.selected_playlist_index = 1;
+        myapp.filter_songs();
+        myapp.selected_song_id = Some(ids[1]);
+        myapp.sync_selection(10);
+
+        toggle(&mut myapp);
+        assert_eq!(myapp.selected_playlist_name().unwrap(), ALL_SONGS_PLAYLIST);
+        toggle(&mut myapp);
+        assert_eq!(myapp.selected_playlist_name().unwrap(), "Mix");
+        assert_eq!(myapp.selected_song_id, Some(ids[1]));
+
+        // Reaching "All Songs" through the playlist pane still goes back to "Mix"
+        myapp.select_playlist_at(0, 10);
+        toggle(&mut myapp);
+        assert_eq!(myapp.selected_playlist_name().unwrap(), "Mix");
+    }
 
-            if path.is_file() {
-                let extension = path.extension().unwrap().to_str().unwrap().to_lowercase();
-                if MUSIC_FORMATS.contains(&extension.as_str()) {
-                    let song = Song::new(
-                        "Test Song".to_string(),
-                        "Test Artist".to_string(),
-                        None,
-                        path.clone(),
-                        "Test Album".to_string(),
-                        180.0,
-                    );
-                    songs.push(song);
-                }
-            }
-        }
+    #[test]
+    fn test_all_songs_playlist_not_stored() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let playlist_file = temp_dir.path().join("data.json");
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut myapp = MyApp::new();
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp.songs = numbered_songs(2);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.playlists.insert("Mine".to_string(), vec![ids[1]]);
+
+        myapp.save_playlist().unwrap();
+        let saved: BTreeMap<String, Vec<Uuid>> =
+            serde_json::from_str(&fs::read_to_string(&playlist_file).unwrap()).unwrap();
+        assert_eq!(saved.keys().collect::<Vec<_>>(), ["Mine"]);
+
+        // A rescan changing All Songs leaves nothing to save
+        myapp.playlists.get_mut(ALL_SONGS_PLAYLIST).unwrap().pop();
+        assert!(!myapp.playlists_unsaved);
+
+        // An All Songs entry written by an older version is ignored
+        fs::write(
+            &playlist_file,
+            format!(r#"{{"All Songs": ["{}"], "Mine": []}}"#, Uuid::nil()),
+        )
+        .unwrap();
+        myapp.load_playlists(&playlist_file).unwrap();
+        assert_eq!(myapp.playlists[ALL_SONGS_PLAYLIST], [ids[0]]);
+        assert!(myapp.playlists["Mine"].is_empty());
+
+        myapp.selected_playlist_index = 0;
+        assert_eq!(myapp.selected_playlist_name().unwrap(), ALL_SONGS_PLAYLIST);
+        handle_key_event(
+            &mut myapp,
+            KeyEvent::new(KeyCode::Char('x'), KeyModifiers::CONTROL),
+            &sink,
+            10,
+            10,
+        );
+        assert!(myapp.playlists.contains_key(ALL_SONGS_PLAYLIST));
+        assert_eq!(
+            myapp.current_status(),
+            Some("\"All Songs\" can't be deleted")
+        );
+    }
 
-        assert_eq!(songs.len(), 2);
-        assert_eq!(songs[0].path.extension().unwrap(), "flac");
-        assert_eq!(songs[1].path.extension().unwrap(), "mp3");
+    #[test]
+    fn test_now_playing_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let now_playing_file = temp_dir.path().join("now_playing.txt");
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 2);
+        fs::write(temp_dir.path().join("Cover.PNG"), b"").unwrap();
+
+        // Nothing is written without the setting
+        myapp.update_now_playing();
+        assert!(!now_playing_file.exists());
+
+        myapp.settings.now_playing_file = Some(now_playing_file.clone());
+        myapp.update_now_playing();
+        assert_eq!(fs::read_to_string(&now_playing_file).unwrap(), "");
+
+        myapp.currently_playing_song = Some(myapp.songs[1].id);
+        myapp.update_now_playing();
+        assert_eq!(
+            fs::read_to_string(&now_playing_file).unwrap(),
+            "Artist - Song 1"
+        );
+
+        // Only a change of song is written
+        fs::remove_file(&now_playing_file).unwrap();
+        myapp.update_now_playing();
+        assert!(!now_playing_file.exists());
+
+        myapp.settings.now_playing_format = Some("{title} ({album}) {art}".to_string());
+        myapp.currently_playing_song = Some(myapp.songs[0].id);
+        myapp.update_now_playing();
+        assert_eq!(
+            fs::read_to_string(&now_playing_file).unwrap(),
+            format!(
+                "Song 0 (Album) {}",
+                temp_dir.path().join("Cover.PNG").display()
+            )
+        );
+
+        myapp.clear_now_playing();
+        assert_eq!(fs::read_to_string(&now_playing_file).unwrap(), "");
+
+        let url = "https://radio.example/live";
+        let id = myapp.add_stream(STREAMS_PLAYLIST, "Radio", url);
+        let stream = myapp.get_song(id).unwrap();
+        *stream.stream_title.lock().unwrap() = Some("Band - Hit".to_string());
+        assert_eq!(
+            now_playing_text(DEFAULT_NOW_PLAYING_FORMAT, stream),
+            "Radio - Band - Hit"
+        );
     }
 
     #[test]
-    fn test_popup_state_toggle() {
-        let mut popup_state = PopupState { visible: false };
+    fn test_relative_playlist_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let music_dir = temp_dir.path().join("music");
+        fs::create_dir_all(music_dir.join("Album")).unwrap();
+        let mut myapp = MyApp::new();
+        myapp.settings.music_dir = Some(music_dir.clone());
+        myapp.settings.playlist_dir = Some(temp_dir.path().to_path_buf());
+        myapp.settings.relative_playlist_paths = true;
+        let mut songs = wav_songs(&music_dir.join("Album"), 1);
+        songs.extend(wav_songs(temp_dir.path(), 1));
+        let ids: Vec<Uuid> = songs.iter().map(|song| song.id).collect();
+        myapp.songs = songs;
+        myapp
+            .playlists
+            .insert("Mine".to_string(), vec![ids[0], ids[1], Uuid::nil()]);
+        myapp.save_playlist().unwrap();
+
+        // Songs outside the music directory stay absolute and unknown ones stay ids
+        let outside = temp_dir.path().join("song0.wav");
+        let stored = fs::read_to_string(temp_dir.path().join("data.json")).unwrap();
+        assert_eq!(
+            serde_json::from_str::<BTreeMap<String, Vec<PlaylistEntry>>>(&stored).unwrap()["Mine"],
+            [
+                PlaylistEntry::Path("Album/song0.wav".to_string()),
+                PlaylistEntry::Path(outside.to_str().unwrap().to_string()),
+                PlaylistEntry::Id(Uuid::nil()),
+            ]
+        );
 
-        popup_state.toggle();
-        assert_eq!(popup_state.visible, true);
+        // The music directory moves, and the playlist follows it
+        let moved_dir = temp_dir.path().join("moved");
+        fs::rename(&music_dir, &moved_dir).unwrap();
+        let mut restored = MyApp::new();
+        restored.settings.music_dir = Some(moved_dir.clone());
+        restored.load_songs();
+        restored
+            .load_playlists(&temp_dir.path().join("data.json"))
+            .unwrap();
+        let moved_id = song_id(&moved_dir.join("Album").join("song0.wav"));
+        assert!(restored.get_song(moved_id).is_some());
+        assert_eq!(restored.playlists["Mine"], [moved_id, ids[1], Uuid::nil()]);
+        assert!(!restored.playlists_unsaved);
+    }
 
-        popup_state.toggle();
-        assert_eq!(popup_state.visible, false);
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_file_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        wav_songs(temp_dir.path(), 1);
+        let odd_path = temp_dir.path().join(OsStr::from_bytes(b"caf\xe9.wav"));
+        fs::copy(temp_dir.path().join("song0.wav"), &odd_path).unwrap();
+
+        let mut scan_log = Vec::new();
+        let songs = scan_dir_for_music(
+            temp_dir.path(),
+            MissingTitle::default(),
+            true,
+            &mut scan_log,
+        )
+        .unwrap();
+        assert_eq!(songs.len(), 2);
+        assert_ne!(songs[0].id, songs[1].id);
+        let odd_song = songs.iter().find(|song| song.path == odd_path).unwrap();
+        assert_eq!(odd_song.id, song_id(&odd_path));
+
+        // Ids of UTF-8 paths are unchanged, so saved playlists still match
+        let path = Path::new("/music/Björk/song.mp3");
+        assert_eq!(
+            song_id(path),
+            Uuid::new_v5(&Uuid::NAMESPACE_DNS, "/music/Björk/song.mp3".as_bytes())
+        );
+
+        // Such songs are stored by id even with relative paths
+        let mut myapp = MyApp::new();
+        myapp.songs = songs;
+        assert_eq!(
+            myapp.playlist_entry(song_id(&odd_path), Some(temp_dir.path())),
+            PlaylistEntry::Id(song_id(&odd_path))
+        );
     }
 
     #[test]
-    fn test_sort_criteria() {
-        assert_eq!(SortCriteria::Title.to_string(), "Title");
-        assert_eq!(SortCriteria::Artist.to_string(), "Artist");
-        assert_eq!(SortCriteria::Duration.to_string(), "Duration");
+    fn test_play_random_song() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 3);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.playlists.insert("Mix".to_string(), vec![ids[2]]);
+        let sink = draining_sink();
+        let random_song = |myapp: &mut MyApp, modifiers| {
+            let key = KeyEvent::new(KeyCode::F(11), modifiers);
+            handle_key_event(myapp, key, &sink, 10, 10);
+        };
 
-        assert_eq!(SortCriteria::Title.next(), SortCriteria::Artist);
-        assert_eq!(SortCriteria::Artist.next(), SortCriteria::Duration);
-        assert_eq!(SortCriteria::Duration.next(), SortCriteria::Title);
+        // Nothing to pick from
+        myapp.selected_playlist_index = 1;
+        myapp.search_text = "nothing matches".to_string();
+        myapp.filter_songs();
+        random_song(&mut myapp, KeyModifiers::NONE);
+        assert_eq!(myapp.currently_playing_song, None);
+
+        myapp.search_text.clear();
+        myapp.filter_songs();
+        myapp.play_queue = vec![ids[0], ids[1]];
+        random_song(&mut myapp, KeyModifiers::NONE);
+        assert_eq!(myapp.currently_playing_song, Some(ids[2]));
+        assert_eq!(myapp.selected_song_id, Some(ids[2]));
+        assert!(myapp.play_queue.is_empty());
+
+        // From the library, switching to All Songs when the song isn't in "Mix"
+        myapp.search_text = "Song 2".to_string();
+        myapp.filter_songs();
+        for _ in 0..50 {
+            random_song(&mut myapp, KeyModifiers::SHIFT);
+            if myapp.currently_playing_song != Some(ids[2]) {
+                break;
+            }
+        }
+        let song_id = myapp.currently_playing_song.unwrap();
+        assert_ne!(song_id, ids[2]);
+        assert_eq!(myapp.selected_song_id, Some(song_id));
+        assert_eq!(myapp.selected_playlist_name().unwrap(), ALL_SONGS_PLAYLIST);
+        assert!(myapp.search_text.is_empty());
     }
 
     #[test]
-    fn test_search_criteria() {
-        let song1 = Song::new(
-            "Song One".to_string(),
-            "Artist A".to_string(),
-            None,
-            PathBuf::from("/path/to/song1.mp3"),
-            "Album X".to_string(),
-            200.0,
+    fn test_seeking_in_the_background() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 2);
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        myapp
+            .playlists
+            .insert(ALL_SONGS_PLAYLIST.to_string(), ids.clone());
+        myapp.filter_songs();
+        let sink = draining_sink();
+        myapp.start_song(ids[0], &sink);
+
+        // A quick seek is done right away
+        myapp.seek_to(&sink, 0.05);
+        assert!(myapp.seeking.is_none());
+        assert!((myapp.elapsed_time(0.1) - 0.05).abs() < 0.04);
+
+        // A slow one shows where it's going until it gets there
+        myapp.songs[0].duration = 100.0;
+        let (sender, receiver) = mpsc::channel();
+        myapp.seeking = Some(PendingSeek {
+            id: ids[0],
+            target: 50.0,
+            started: Instant::now(),
+            receiver,
+        });
+        myapp.update_playback(&sink);
+        assert_eq!(myapp.progress_ratio(100.0), 0.5);
+        assert!(myapp
+            .loading_indicator()
+            .unwrap()
+            .ends_with("Seeking to 0:50…"));
+
+        sender
+            .send(myapp.songs[0].open_at(Duration::ZERO, false, myapp.balance.clone()))
+            .unwrap();
+        myapp.update_playback(&sink);
+        assert!(myapp.seeking.is_none());
+        assert!((myapp.elapsed_time(100.0) - 50.0).abs() < 1.0);
+        assert_eq!(myapp.loading_indicator(), None);
+
+        // One that fails leaves the song playing
+        let (sender, receiver) = mpsc::channel();
+        myapp.seeking = Some(PendingSeek {
+            id: ids[0],
+            target: 10.0,
+            started: Instant::now(),
+            receiver,
+        });
+        sender.send(Err(io::Error::other("broken"))).unwrap();
+        myapp.update_playback(&sink);
+        assert!(myapp.seeking.is_none());
+        assert_eq!(myapp.currently_playing_song, Some(ids[0]));
+        assert_eq!(
+            myapp.current_status(),
+            Some("Couldn't seek in Song 0: broken")
         );
-        let song2 = Song::new(
-            "Song Two".to_string(),
-            "Artist B".to_string(),
-            None,
-            PathBuf::from("/path/to/song2.mp3"),
-            "Album Y".to_string(),
-            220.0,
+
+        // A seek in a file that's gone no longer brings the app down
+        fs::remove_file(&myapp.songs[0].path).unwrap();
+        myapp.seek_to(&sink, 20.0);
+        assert!(myapp
+            .current_status()
+            .unwrap()
+            .starts_with("Couldn't seek in Song 0:"));
+    }
+
+    #[test]
+    fn test_cycle_search_criteria_backwards() {
+        let mut myapp = MyApp::new();
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut press = |code, modifiers| {
+            handle_key_event(&mut myapp, KeyEvent::new(code, modifiers), &sink, 10, 10);
+            myapp.search_criteria
+        };
+
+        assert_eq!(
+            press(KeyCode::Char('s'), KeyModifiers::CONTROL),
+            SearchCriteria::Artist
+        );
+        let control_shift = KeyModifiers::CONTROL | KeyModifiers::SHIFT;
+        assert_eq!(
+            press(KeyCode::Char('S'), control_shift),
+            SearchCriteria::Title
+        );
+        assert_eq!(
+            press(KeyCode::Char('s'), control_shift),
+            SearchCriteria::Album
         );
+        assert_eq!(
+            press(KeyCode::Char('s'), KeyModifiers::ALT),
+            SearchCriteria::Artist
+        );
+        assert!(myapp.search_text.is_empty());
+    }
 
-        let songs = vec![song1, song2];
+    #[test]
+    fn test_resize_song_list() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let settings_path = temp_dir.path().join("settings.json");
+        let mut myapp = MyApp::new();
+        myapp.settings_file = Some(settings_path.clone());
+        let sink = Arc::new(Mutex::new(Sink::new_idle().0));
+        let mut press = |code| {
+            handle_key_event(
+                &mut myapp,
+                KeyEvent::new(code, KeyModifiers::ALT),
+                &sink,
+                10,
+                10,
+            );
+            myapp.column_widths()
+        };
 
-        let search_text = "Song".to_string();
-        let search_criteria = SearchCriteria::Title;
+        assert_eq!(press(KeyCode::Right), [17, 65, 18]);
+        assert_eq!(Settings::load(&settings_path).song_list_width, Some(65));
+        for _ in 0..10 {
+            press(KeyCode::Left);
+        }
+        assert_eq!(press(KeyCode::Left), [35, 30, 35]);
+        assert_eq!(myapp.current_status(), Some("Song list width: 30%"));
+
+        // Out of range settings are kept within bounds
+        myapp.settings.song_list_width = Some(100);
+        assert_eq!(myapp.column_widths(), [5, 90, 5]);
+        assert_eq!(Settings::default().song_list_width, None);
+        assert_eq!(MyApp::new().column_widths(), [20, 60, 20]);
+    }
 
-        let filtered_songs: Vec<&Song> = songs
-            .iter()
-            .filter(|s| match search_criteria {
-                SearchCriteria::Title => {
-                    s.title.to_lowercase().contains(&search_text.to_lowercase())
-                }
-                SearchCriteria::Artist => s
-                    .artist
-                    .to_lowercase()
-                    .contains(&search_text.to_lowercase()),
-                SearchCriteria::Album => {
-                    s.album.to_lowercase().contains(&search_text.to_lowercase())
+    #[test]
+    fn test_vbr_mp3_duration() {
+        // An MPEG-1 Layer III frame header: 128 kbit/s, 44.1 kHz, joint stereo
+        let frame_header = [0xff, 0xfb, 0x90, 0x64];
+        let frame = |tag: &[u8], offset: usize| {
+            let mut frame = frame_header.to_vec();
+            frame.resize(offset, 0);
+            frame.extend_from_slice(tag);
+            frame.resize(418, 0);
+            frame
+        };
+
+        let mut xing = b"Xing".to_vec();
+        xing.extend_from_slice(&1u32.to_be_bytes());
+        xing.extend_from_slice(&1000u32.to_be_bytes());
+        let expected = 1000.0 * 1152.0 / 44100.0;
+        assert_eq!(vbr_header_duration(&frame(&xing, 36)), Some(expected));
+
+        // Junk before the first frame is skipped
+        let mut data = vec![0; 20];
+        data.extend(frame(&xing, 36));
+        assert_eq!(vbr_header_duration(&data), Some(expected));
+
+        let mut vbri = b"VBRI".to_vec();
+        vbri.extend_from_slice(&[0; 10]);
+        vbri.extend_from_slice(&500u32.to_be_bytes());
+        assert_eq!(vbr_header_duration(&frame(&vbri, 36)), Some(expected / 2.0));
+
+        // A Xing header without the frame count, or none at all
+        let mut no_frames = b"Xing".to_vec();
+        no_frames.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(vbr_header_duration(&frame(&no_frames, 36)), None);
+        assert_eq!(vbr_header_duration(&frame(b"", 36)), None);
+
+        // The header is found after an ID3v2 tag
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("vbr.mp3");
+        let mut file = b"ID3\x03\x00\x00\x00\x00\x01\x00".to_vec();
+        file.resize(10 + 128, 0);
+        file.extend(frame(&xing, 36));
+        fs::write(&path, file).unwrap();
+        assert_eq!(mp3_header_duration(&path), Some(expected));
+    }
+
+    #[test]
+    fn test_song_ends_once_played_out() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut myapp = MyApp::new();
+        myapp.songs = wav_songs(temp_dir.path(), 3);
+        myapp.filtered_songs = myapp.songs.clone();
+        let ids: Vec<Uuid> = myapp.songs.iter().map(|song| song.id).collect();
+        let ended_ago =
+            |seconds: f64| Some(Instant::now() - Duration::from_secs_f64(0.1 + seconds));
+
+        // A sink whose audio is only played once it's let through
+        let (sink, output) = Sink::new_idle();
+        let sink = Arc::new(Mutex::new(sink));
+        let flowing = Arc::new(AtomicBool::new(false));
+        {
+            let flowing = flowing.clone();
+            std::thread::spawn(move || {
+                for _ in output {
+                    while !flowing.load(Ordering::SeqCst) {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
                 }
-            })
-            .collect();
+            });
+        }
 
-        assert_eq!(filtered_songs.len(), 2);
-        assert_eq!(filtered_songs[0].title, "Song One");
-        assert_eq!(filtered_songs[1].title, "Song Two");
+        // The clock says the song is over, but the sink is still playing it
+        myapp.start_song(ids[0], &sink);
+        myapp.song_time = ended_ago(1.0);
+        assert!(!myapp.song_ended(&myapp.songs[0], &sink));
+        myapp.update_playback(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[0]));
+        myapp.song_time = ended_ago(END_GRACE);
+        assert!(myapp.song_ended(&myapp.songs[0], &sink));
+
+        // Once the sink has played the song out it ends right away
+        flowing.store(true, Ordering::SeqCst);
+        while !sink.lock().unwrap().empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        myapp.song_time = ended_ago(0.0);
+        myapp.update_playback(&sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
+
+        // A song that was already moved on from doesn't advance again
+        myapp.finish_song(ids[0], &sink);
+        assert_eq!(myapp.currently_playing_song, Some(ids[1]));
     }
 }